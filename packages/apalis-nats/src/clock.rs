@@ -0,0 +1,87 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// The source of the current time for the storage's time-based features.
+///
+/// Job timestamps, `max_retry_duration`, DLQ `retry_after`, queue latency, scheduled job
+/// promotion, the [`RetryBudget`](crate::RetryBudget) refill and
+/// [`Quarantine`](crate::Quarantine) windows all read the time from here. Swap in a
+/// [`TestClock`] with [`NatsStorage::with_clock`](crate::NatsStorage::with_clock) to test them
+/// without real sleeps.
+///
+/// These deliberately stay on the runtime's clock:
+/// - timers that pace the server or must match it: `ack_wait`, the progress lease left before
+///   a redelivery, fetch expiry, polling intervals and heartbeats. The server keeps real time,
+///   so faking them would only desync the two.
+/// - the stall watchdog and the idle timeout of a worker run until the queue drains, which
+///   watch real progress.
+/// - the handler durations recorded with the `metrics` feature.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock, the default for every storage
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Clones share the same time, so keep one to advance after handing another to the storage.
+///
+/// # Example
+/// ```
+/// # use apalis_nats::{Clock, TestClock};
+/// # use std::time::Duration;
+/// let clock = TestClock::new(chrono::Utc::now());
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(3600));
+/// assert_eq!(clock.now() - start, chrono::Duration::hours(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl TestClock {
+    /// A clock stopped at `start`
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        let by = chrono::Duration::from_std(by).unwrap_or(chrono::Duration::MAX);
+        let mut now = self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *now = now.checked_add_signed(by).unwrap_or(DateTime::<Utc>::MAX_UTC);
+    }
+
+    /// Set the clock to `at`, which may be in its past
+    pub fn set(&self, at: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = at;
+    }
+}
+
+impl Default for TestClock {
+    /// A clock stopped at the current wall time
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
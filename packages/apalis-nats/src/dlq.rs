@@ -0,0 +1,555 @@
+use crate::storage::{
+    dlq_stream_name, dlq_subject, lookup_error, NatsJob, NatsPollError, Redactor,
+};
+use crate::layers::PanicError;
+use crate::{NatsContext, NatsStorage, Priority};
+use apalis_core::backend::Backend;
+use apalis_core::codec::json::JsonCodec;
+use apalis_core::error::Error;
+use apalis_core::layers::AckLayer;
+use apalis_core::poller::Poller;
+use apalis_core::request::Request;
+use apalis_core::response::Response;
+use apalis_core::task::task_id::TaskId;
+use apalis_core::worker::{Context as WorkerContext, Worker};
+use async_nats::jetstream::{self, consumer};
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{self, Sender};
+use futures::stream::BoxStream;
+use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A dead-lettered job as stored in the `{namespace}_dlq` stream (or `Config::dlq_stream`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DlqEntry {
+    /// The task id of the failed job
+    pub original_task_id: String,
+    /// The error returned by the handler on the final attempt
+    pub error: String,
+    /// The kind and source chain of [`error`](DlqEntry::error), for entries dead-lettered
+    /// because the handler returned an error. `None` otherwise and for older entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_detail: Option<DlqError>,
+    /// The attempt that dead-lettered the job, counted from 1 like
+    /// [`delivered_count`](DlqEntry::delivered_count). Entries from older versions, which
+    /// stored a debug string such as `"Attempt(5)"`, read back as the number in it.
+    #[serde(deserialize_with = "attempt_number")]
+    pub attempts: usize,
+    /// Number of deliveries recorded by JetStream for the message
+    pub delivered_count: i64,
+    /// When the entry was dead-lettered
+    pub timestamp: DateTime<Utc>,
+    /// Why the job was dead-lettered, e.g. `abort_error` or `max_deliver_exceeded`
+    pub dlq_reason: String,
+    /// The original message payload (the serialized job envelope), or its redacted form when
+    /// the storage has a redactor, see [`NatsStorage::with_redactor`]
+    pub payload: Vec<u8>,
+    /// The size of the original payload in bytes, set when it was too large for the DLQ
+    /// and [`payload`](DlqEntry::payload) holds only its first bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_truncated: Option<usize>,
+    /// Metadata attached by the handler with [`NatsContext::set_dlq_metadata`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// The namespace the job was dead-lettered from, to tell jobs apart in a DLQ shared by
+    /// several namespaces (see `Config::dlq_stream`). `None` for entries from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// When the job is worth retrying again, set from `Config::dlq_retry_after` for jobs that
+    /// ran out of retries. [`DlqBackend`] holds entries until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<DateTime<Utc>>,
+    /// The subject the failed job was consumed from, e.g. `{namespace}.high`, including its
+    /// labels. `None` for entries from older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_subject: Option<String>,
+    /// The priority stream the failed job was consumed from, which re-driving restores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_priority: Option<Priority>,
+    /// The failed job's sequence in its priority stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_sequence: Option<u64>,
+}
+
+impl DlqEntry {
+    /// Decode the original job from the entry payload
+    pub fn job<T: DeserializeOwned>(&self) -> Result<T, NatsPollError> {
+        if let Some(size) = self.payload_truncated {
+            return Err(NatsPollError::Storage(format!(
+                "The {} byte payload was truncated to fit the DLQ",
+                size
+            )));
+        }
+        let job: NatsJob<T> = serde_json::from_slice(&self.payload)?;
+        Ok(job.data)
+    }
+
+    /// Serialize the entry into at most `limit` bytes, the most the DLQ accepts, so its
+    /// publish isn't rejected and the job lost. A payload that doesn't fit is cut short and
+    /// recorded in [`payload_truncated`](DlqEntry::payload_truncated); if the entry is still
+    /// too large without it, the metadata and error sources are dropped and the error cut short.
+    pub(crate) fn to_bytes(&mut self, limit: usize) -> Result<Vec<u8>, serde_json::Error> {
+        let body = serde_json::to_vec(self)?;
+        if body.len() <= limit {
+            return Ok(body);
+        }
+        let original = std::mem::take(&mut self.payload);
+        self.payload_truncated = Some(original.len());
+        let mut body = serde_json::to_vec(self)?;
+        if body.len() > limit {
+            self.metadata = None;
+            if let Some(detail) = &mut self.error_detail {
+                detail.sources.clear();
+            }
+            body = serde_json::to_vec(self)?;
+        }
+        while body.len() > limit && !self.error.is_empty() {
+            let mut end = self.error.len().saturating_sub(body.len() - limit);
+            while !self.error.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.error.truncate(end);
+            body = serde_json::to_vec(self)?;
+        }
+        // The payload serializes as a JSON array: each byte takes its digits and a comma
+        let mut room = limit.saturating_sub(body.len());
+        let kept = original
+            .iter()
+            .take_while(|byte| {
+                let width = match byte {
+                    0..=9 => 2,
+                    10..=99 => 3,
+                    _ => 4,
+                };
+                let fits = width <= room;
+                room = room.saturating_sub(width);
+                fits
+            })
+            .count();
+        tracing::warn!(
+            "DLQ entry for task {} exceeds {} bytes, keeping {} of its {} payload bytes",
+            self.original_task_id,
+            limit,
+            kept,
+            original.len()
+        );
+        self.payload = original[..kept].to_vec();
+        serde_json::to_vec(self)
+    }
+}
+
+/// The largest DLQ entry `client` can publish: the server's `max_payload`, or
+/// `Config::max_msg_size` when that is lower
+pub(crate) fn dlq_size_limit(client: &async_nats::Client, max_msg_size: Option<usize>) -> usize {
+    let max_payload = match client.server_info().max_payload {
+        0 => usize::MAX,
+        max_payload => max_payload,
+    };
+    max_msg_size.map_or(max_payload, |limit| limit.min(max_payload))
+}
+
+/// The structured form of the error that dead-lettered a job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DlqError {
+    /// The kind of error the handler returned: `failed`, `abort`, `panic` (an abort caught by
+    /// [`CatchPanicLayer`](crate::CatchPanicLayer)), `missing_data`, `worker_error`,
+    /// `service_error`, `source_error` or `other`
+    pub kind: String,
+    /// The messages of the errors the error wraps, outermost first, as given by
+    /// [`std::error::Error::source`]
+    pub sources: Vec<String>,
+}
+
+impl DlqError {
+    pub(crate) fn new(error: &Error) -> Self {
+        let kind = match error {
+            Error::Abort(inner) if inner.downcast_ref::<PanicError>().is_some() => "panic",
+            Error::Failed(_) => "failed",
+            Error::Abort(_) => "abort",
+            Error::MissingData(_) => "missing_data",
+            Error::WorkerError(_) => "worker_error",
+            Error::ServiceError(_) => "service_error",
+            Error::SourceError(_) => "source_error",
+            _ => "other",
+        };
+        let mut sources = Vec::new();
+        let mut source = std::error::Error::source(error);
+        while let Some(e) = source {
+            sources.push(e.to_string());
+            source = e.source();
+        }
+        Self {
+            kind: kind.to_string(),
+            sources,
+        }
+    }
+}
+
+/// Read [`DlqEntry::attempts`] as a number, or from the debug string older versions wrote
+fn attempt_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Attempts {
+        Number(usize),
+        Debug(String),
+    }
+    Ok(match Attempts::deserialize(deserializer)? {
+        Attempts::Number(n) => n,
+        Attempts::Debug(s) => {
+            let digits: String = s
+                .chars()
+                .skip_while(|c| !c.is_ascii_digit())
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse().unwrap_or(0)
+        }
+    })
+}
+
+/// The payload to embed in a [`DlqEntry`]: the raw bytes, or their redacted form
+pub(crate) fn entry_payload(redact: Option<&Redactor>, payload: &[u8]) -> Vec<u8> {
+    match redact {
+        Some(redact) => redact(payload).into_bytes(),
+        None => payload.to_vec(),
+    }
+}
+
+/// A [`Backend`] that consumes the DLQ of a [`NatsStorage`] so failed jobs can be handled
+/// by a regular worker.
+///
+/// Entries are delivered as [`DlqEntry`] requests through a durable `{namespace}_dlq_consumer`.
+/// With a DLQ subject shared by several namespaces it receives all of their entries, check
+/// [`DlqEntry::namespace`] to tell them apart.
+/// Returning `Ok` acks the entry so it is not delivered again; returning an error leaves it in
+/// the DLQ and it is redelivered after the `nak_backoff` delay. Entries with a
+/// [`retry_after`](DlqEntry::retry_after) in the future are Nak'd until then without being
+/// handed to the worker, so re-driving them from the handler waits out the outage.
+///
+/// # Example
+/// ```rust,no_run
+/// use apalis::prelude::*;
+/// use apalis_nats::{DlqEntry, NatsStorage};
+///
+/// async fn handle_dlq(entry: DlqEntry) -> Result<(), Error> {
+///     println!("{} failed: {}", entry.original_task_id, entry.error);
+///     Ok(())
+/// }
+///
+/// # async fn demo(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+/// let worker = WorkerBuilder::new("dlq-worker")
+///     .backend(storage.dlq_backend())
+///     .build_fn(handle_dlq);
+///
+/// Monitor::new().register(worker).run().await?;
+/// # Ok(()) }
+/// ```
+pub struct DlqBackend<T> {
+    storage: NatsStorage<T>,
+    /// Set by [`DlqBackend::ordered`]
+    ordered: bool,
+}
+
+impl<T> fmt::Debug for DlqBackend<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DlqBackend")
+            .field("storage", &self.storage)
+            .field("ordered", &self.ordered)
+            .finish()
+    }
+}
+
+impl<T> Clone for DlqBackend<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            ordered: self.ordered,
+        }
+    }
+}
+
+impl<T> DlqBackend<T> {
+    /// Deliver entries strictly in the order they were dead-lettered, one at a time, e.g. for a
+    /// triage worker working through an incident oldest-first.
+    ///
+    /// Entries are read through their own durable `{namespace}_dlq_triage_consumer`, which
+    /// only hands out the next entry once the previous one is acked, whatever the worker's
+    /// concurrency. A failing entry is redelivered after the `nak_backoff` delay and holds up
+    /// the ones behind it until it succeeds, as does an entry waiting for its `retry_after`.
+    /// Being a second consumer, it can't be created on a DLQ stream with `WorkQueue`
+    /// retention, see `Config::dlq_retention`.
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+}
+
+impl<T> NatsStorage<T> {
+    /// Get a backend that consumes this storage's DLQ, see [`DlqBackend`]
+    pub fn dlq_backend(&self) -> DlqBackend<T> {
+        DlqBackend {
+            storage: self.clone(),
+            ordered: false,
+        }
+    }
+
+    /// Iterate the DLQ lazily, e.g. for a triage tool, without loading every entry at once.
+    ///
+    /// Entries are pulled one at a time through the same durable `{namespace}_dlq_consumer` as
+    /// [`DlqBackend`], each with a [`NatsContext`] for its message. [`NatsContext::ack`] marks
+    /// an entry as handled so it is not delivered again, while an entry left unacked is
+    /// redelivered after `ack_wait`. The stream ends once the consumer has nothing left to
+    /// deliver, or after yielding an error from the server. Entries that don't decode are
+    /// terminated, as [`DlqBackend`] does, and yielded as [`NatsPollError::Serialization`].
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use futures::StreamExt;
+    /// # use apalis_nats::NatsStorage;
+    ///
+    /// # async fn demo(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut entries = Box::pin(storage.dlq_stream().take(10));
+    /// while let Some(entry) = entries.next().await {
+    ///     let (entry, ctx) = entry?;
+    ///     if entry.dlq_reason == "decode_error" {
+    ///         ctx.ack().await?; // Nothing to retry, discard it
+    ///     }
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn dlq_stream(
+        &self,
+    ) -> impl Stream<Item = Result<(DlqEntry, NatsContext), NatsPollError>> + Send + 'static
+    where
+        T: Send + Sync + 'static,
+    {
+        let expiry = self.config.fetch_expiry;
+        futures::stream::unfold(Some((self.dlq_backend(), None)), move |state| async move {
+            let (backend, consumer) = state?;
+            let consumer = match consumer {
+                Some(consumer) => consumer,
+                None => match backend.get_or_create_consumer().await {
+                    Ok(consumer) => consumer,
+                    Err(e) => return Some((Err(e), None)),
+                },
+            };
+            let mut batch = match consumer.fetch().max_messages(1).messages().await {
+                Ok(batch) => batch,
+                Err(e) => return Some((Err(NatsPollError::Nats(e.to_string())), None)),
+            };
+            let msg = match tokio::time::timeout(expiry, batch.try_next()).await {
+                Ok(Ok(Some(msg))) => msg,
+                // Caught up with the DLQ
+                Ok(Ok(None)) | Err(_) => return None,
+                Ok(Err(e)) => return Some((Err(NatsPollError::Nats(e.to_string())), None)),
+            };
+            let item = match serde_json::from_slice::<DlqEntry>(&msg.payload) {
+                Ok(entry) => Ok((entry, NatsContext::with_message(msg))),
+                Err(e) => {
+                    if let Err(ack_err) = msg.ack_with(jetstream::AckKind::Term).await {
+                        tracing::error!("Failed to term malformed DLQ entry: {}", ack_err);
+                    }
+                    Err(NatsPollError::Serialization(e))
+                }
+            };
+            Some((item, Some((backend, Some(consumer)))))
+        })
+    }
+}
+
+impl<T> DlqBackend<T> {
+    /// Create or get the shared DLQ consumer
+    async fn get_or_create_consumer(
+        &self,
+    ) -> Result<consumer::Consumer<consumer::pull::Config>, NatsPollError> {
+        let config = &self.storage.config;
+        let consumer_name = match self.ordered {
+            true => format!("{}_dlq_triage_consumer", config.namespace),
+            false => format!("{}_dlq_consumer", config.namespace),
+        };
+
+        let consumer_config = consumer::pull::Config {
+            name: Some(consumer_name.clone()),
+            durable_name: Some(consumer_name.clone()),
+            ack_policy: consumer::AckPolicy::Explicit,
+            ack_wait: config.ack_wait,
+            // Entries dead-lettered per tenant sit under the DLQ subject
+            filter_subjects: vec![dlq_subject(config), format!("{}.>", dlq_subject(config))],
+            // Oldest entry first
+            deliver_policy: consumer::DeliverPolicy::All,
+            // One entry at a time keeps them in order, Nak'd entries included
+            max_ack_pending: match self.ordered {
+                true => 1,
+                false => config.max_ack_pending,
+            },
+            max_waiting: config.max_waiting(),
+            replay_policy: consumer::ReplayPolicy::Instant,
+            inactive_threshold: Duration::from_secs(300), // 5 minutes
+            ..Default::default()
+        };
+
+        let stream_name = dlq_stream_name(config);
+        let stream = self
+            .storage
+            .jetstream
+            .get_stream(&stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        stream
+            .get_or_create_consumer(&consumer_name, consumer_config)
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, Some(&consumer_name)))
+    }
+
+    /// Ack handled entries and Nak failed ones so they stay in the DLQ
+    async fn ack(
+        &self,
+        ctx: &NatsContext,
+        response: &Response<Vec<u8>>,
+    ) -> Result<(), NatsPollError> {
+        if ctx.settled_status().is_some() {
+            return Ok(()); // The handler acknowledged the entry itself
+        }
+        let Some(msg) = ctx.message() else {
+            tracing::warn!("No NATS message in context for task {}", response.task_id);
+            return Ok(());
+        };
+        match &response.inner {
+            Ok(_) => {
+                msg.ack()
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                tracing::debug!("Acknowledged DLQ entry for task {}", response.task_id);
+            }
+            Err(e) => {
+                let info = msg.info().map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                let delay = self.storage.nak_delay(info.delivered);
+                msg.ack_with(jetstream::AckKind::Nak(delay))
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                tracing::debug!(
+                    "DLQ entry for task {} left in place after error: {}",
+                    response.task_id,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Backend<Request<DlqEntry, NatsContext>> for DlqBackend<T>
+where
+    T: Send + Sync + 'static,
+{
+    type Stream = BoxStream<'static, Result<Option<Request<DlqEntry, NatsContext>>, Error>>;
+    type Layer = AckLayer<
+        Sender<(NatsContext, Response<Vec<u8>>)>,
+        DlqEntry,
+        NatsContext,
+        JsonCodec<Vec<u8>>,
+    >;
+    type Codec = JsonCodec<Vec<u8>>;
+
+    fn poll(self, _worker: &Worker<WorkerContext>) -> Poller<Self::Stream, Self::Layer> {
+        let (mut entry_tx, entry_rx) =
+            mpsc::channel::<Result<Option<Request<DlqEntry, NatsContext>>, Error>>(10);
+        let (ack_tx, mut ack_rx) = mpsc::channel::<(NatsContext, Response<Vec<u8>>)>(10);
+
+        let layer = AckLayer::new(ack_tx);
+
+        let ack_backend = self.clone();
+        tokio::spawn(async move {
+            while let Some((ctx, resp)) = ack_rx.next().await {
+                if let Err(e) = ack_backend.ack(&ctx, &resp).await {
+                    tracing::error!("Failed to acknowledge DLQ entry: {}", e);
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut consumer = None;
+            loop {
+                let mut entry_found = false;
+                if consumer.is_none() {
+                    match self.get_or_create_consumer().await {
+                        Ok(created) => consumer = Some(created),
+                        Err(e) => tracing::debug!("DLQ consumer unavailable: {}", e),
+                    }
+                }
+                if let Some(consumer) = &consumer {
+                    if let Ok(mut batch) = consumer.fetch().max_messages(1).messages().await {
+                        match tokio::time::timeout(
+                            self.storage.config.fetch_expiry,
+                            batch.try_next(),
+                        )
+                        .await
+                        {
+                            Ok(Ok(Some(msg))) => {
+                                match serde_json::from_slice::<DlqEntry>(&msg.payload) {
+                                    Ok(entry)
+                                        if entry.retry_after > Some(self.storage.clock.now()) =>
+                                    {
+                                        // Not due yet, come back when it is
+                                        let wait = entry
+                                            .retry_after
+                                            .and_then(|at| {
+                                                (at - self.storage.clock.now()).to_std().ok()
+                                            })
+                                            .unwrap_or_default();
+                                        if let Err(e) = msg
+                                            .ack_with(jetstream::AckKind::Nak(Some(wait)))
+                                            .await
+                                        {
+                                            tracing::error!(
+                                                "Failed to hold DLQ entry for task {}: {}",
+                                                entry.original_task_id,
+                                                e
+                                            );
+                                        }
+                                    }
+                                    Ok(entry) => {
+                                        let task_id = TaskId::from_str(&entry.original_task_id)
+                                            .unwrap_or_default();
+                                        let ctx = NatsContext::with_message(msg);
+                                        let mut request = Request::new_with_ctx(entry, ctx);
+                                        request.parts.task_id = task_id;
+                                        if entry_tx.send(Ok(Some(request))).await.is_err() {
+                                            return; // Channel closed, exit task
+                                        }
+                                        entry_found = true;
+                                    }
+                                    Err(e) => {
+                                        tracing::error!("Failed to deserialize DLQ entry: {}", e);
+                                        if let Err(ack_err) =
+                                            msg.ack_with(jetstream::AckKind::Term).await
+                                        {
+                                            tracing::error!(
+                                                "Failed to term malformed DLQ entry: {}",
+                                                ack_err
+                                            );
+                                        }
+                                    }
+                                }
+                            }
+                            Ok(Ok(None)) | Err(_) => {}
+                            Ok(Err(e)) => {
+                                tracing::debug!("DLQ fetch error: {}", e);
+                            }
+                        }
+                    }
+                }
+
+                if entry_found {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                } else {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        });
+
+        Poller::new_with_layer(entry_rx.boxed(), futures::future::pending(), layer)
+    }
+}
@@ -1,3 +1,6 @@
+use crate::dlq::{dlq_size_limit, entry_payload, DlqEntry};
+use crate::status::JobStatus;
+use crate::storage::{dlq_stream_name, subject_priority, tenant_dlq_subject, NatsJob, NatsPollError};
 use crate::{NatsContext, NatsStorage};
 use apalis_core::backend::{BackendExpose, Stat, WorkerState};
 use apalis_core::error::Error;
@@ -6,8 +9,9 @@ use apalis_core::service_fn::FromRequest;
 use apalis_core::worker::Worker;
 use async_nats::jetstream::{self, consumer};
 use serde::{de::DeserializeOwned, Serialize};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 impl<T> BackendExpose<Request<T, NatsContext>> for NatsStorage<T>
 where
@@ -22,11 +26,15 @@ where
         let success = 0usize;
 
         // Collect stats from all priority streams
-        for priority in [
-            crate::Priority::High,
-            crate::Priority::Medium,
-            crate::Priority::Low,
-        ] {
+        for priority in self.config.priorities() {
+            // A grouped stream keeps jobs other groups haven't acked, so count this group's
+            // backlog from its own consumer
+            if self.config.consumer_group.is_some() {
+                if let Ok(info) = self.consumer_info(priority).await {
+                    pending += info.num_pending as usize + info.num_ack_pending;
+                    continue;
+                }
+            }
             let stream_name = self.get_stream_name(priority);
             if let Ok(mut stream) = self.jetstream.get_stream(stream_name).await {
                 if let Ok(info) = stream.info().await {
@@ -39,7 +47,7 @@ where
 
         // Check DLQ for failed jobs
         if self.config.enable_dlq {
-            let dlq_stream_name = format!("{}_dlq", self.config.namespace);
+            let dlq_stream_name = dlq_stream_name(&self.config);
             if let Ok(mut stream) = self.jetstream.get_stream(dlq_stream_name).await {
                 if let Ok(info) = stream.info().await {
                     failed = info.state.messages as usize;
@@ -71,12 +79,8 @@ where
         // Instead, we return info about the shared consumers
         let mut workers = Vec::new();
 
-        for priority in [
-            crate::Priority::High,
-            crate::Priority::Medium,
-            crate::Priority::Low,
-        ] {
-            let consumer_name = format!("{}_{}_consumer", self.config.namespace, priority);
+        for priority in self.config.priorities() {
+            let consumer_name = self.consumer_name(priority);
             let stream_name = self.get_stream_name(priority);
 
             // Check if the shared consumer exists
@@ -97,14 +101,84 @@ where
 }
 
 impl NatsContext {
-    /// Acknowledge the message as successfully processed
+    /// Acknowledge the message as successfully processed.
+    ///
+    /// The worker then leaves the message alone, whatever the handler returns.
     pub async fn ack(&self) -> Result<(), Error> {
         if let Some(msg) = &self.message {
             msg.ack()
                 .await
-                .map_err(|e| Error::SourceError(Arc::new(e.into())))
-        } else {
-            Ok(())
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.settle(JobStatus::Completed);
+        }
+        Ok(())
+    }
+
+    /// Acknowledge every message in `batch` as successfully processed, e.g. once a handler has
+    /// gathered a group of related jobs (with `manual_ack`) and processed them together.
+    ///
+    /// Under `AckPolicy::All`, acking a message acks everything the consumer delivered before
+    /// it, so the batch is settled with a single ack of its last delivery when that covers
+    /// exactly the batch: its messages come from one consumer, were delivered back to back, and
+    /// every earlier delivery is already acked. Otherwise, including under the default
+    /// `AckPolicy::Explicit`, each message is acked on its own. The order of `batch` doesn't
+    /// matter, and contexts without a message are skipped.
+    pub async fn ack_batch(batch: &[NatsContext]) -> Result<(), Error> {
+        let mut batch: Vec<_> = batch.iter().filter(|ctx| ctx.message.is_some()).collect();
+        batch.sort_by_key(|ctx| ctx.consumer_sequence());
+        if let Some(last) = batch.last() {
+            if Self::single_ack_covers(&batch).await {
+                last.ack().await?;
+                for ctx in &batch {
+                    ctx.settle(JobStatus::Completed);
+                }
+                return Ok(());
+            }
+        }
+        for ctx in batch {
+            ctx.ack().await?;
+        }
+        Ok(())
+    }
+
+    /// The consumer's delivery sequence of the message
+    fn consumer_sequence(&self) -> Option<u64> {
+        let info = self.message.as_ref()?.info().ok()?;
+        Some(info.consumer_sequence)
+    }
+
+    /// Whether acking the last of `batch`, sorted by delivery, acks the batch and nothing else
+    async fn single_ack_covers(batch: &[&NatsContext]) -> bool {
+        let mut infos = Vec::with_capacity(batch.len());
+        for ctx in batch {
+            match ctx.message.as_ref().map(|msg| msg.info()) {
+                Some(Ok(info)) => infos.push(info),
+                _ => return false,
+            }
+        }
+        let back_to_back = infos.windows(2).all(|pair| {
+            pair[0].stream == pair[1].stream
+                && pair[0].consumer == pair[1].consumer
+                && pair[1].consumer_sequence == pair[0].consumer_sequence + 1
+        });
+        let Some(first) = infos.first() else {
+            return false;
+        };
+        if !back_to_back {
+            return false;
+        }
+        let Some(handle) = batch[0].dlq.as_ref().and_then(|dlq| dlq.upgrade()) else {
+            return false;
+        };
+        let Ok(stream) = handle.jetstream.get_stream(first.stream).await else {
+            return false;
+        };
+        match stream.consumer_info(first.consumer).await {
+            Ok(info) => {
+                info.config.ack_policy == consumer::AckPolicy::All
+                    && info.ack_floor.consumer_sequence + 1 >= first.consumer_sequence
+            }
+            Err(_) => false,
         }
     }
 
@@ -113,10 +187,34 @@ impl NatsContext {
         if let Some(msg) = &self.message {
             msg.ack_with(jetstream::AckKind::Nak(None))
                 .await
-                .map_err(|e| Error::SourceError(Arc::new(e.into())))
-        } else {
-            Ok(())
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.settle(JobStatus::Failed);
         }
+        Ok(())
+    }
+
+    /// Negatively acknowledge the message for a retry after `delay`, e.g. the `Retry-After` of
+    /// a rate limited downstream call, instead of the `nak_backoff` schedule. The retry still
+    /// counts towards `max_deliver`.
+    pub async fn nak_with_delay(&self, delay: Duration) -> Result<(), Error> {
+        if let Some(msg) = &self.message {
+            msg.ack_with(jetstream::AckKind::Nak(Some(delay)))
+                .await
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.settle(JobStatus::Failed);
+        }
+        Ok(())
+    }
+
+    /// Nak the message for redelivery after `delay`, leaving the job pending rather than failed
+    pub(crate) async fn defer(&self, delay: Duration) -> Result<(), Error> {
+        if let Some(msg) = &self.message {
+            msg.ack_with(jetstream::AckKind::Nak(Some(delay)))
+                .await
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.settle(JobStatus::Pending);
+        }
+        Ok(())
     }
 
     /// Terminate processing (send to DLQ if configured)
@@ -124,10 +222,10 @@ impl NatsContext {
         if let Some(msg) = &self.message {
             msg.ack_with(jetstream::AckKind::Term)
                 .await
-                .map_err(|e| Error::SourceError(Arc::new(e.into())))
-        } else {
-            Ok(())
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.settle(JobStatus::Dead);
         }
+        Ok(())
     }
 
     /// Request progress update (extends ack wait time)
@@ -135,18 +233,289 @@ impl NatsContext {
         if let Some(msg) = &self.message {
             msg.ack_with(jetstream::AckKind::Progress)
                 .await
-                .map_err(|e| Error::SourceError(Arc::new(e.into())))
+                .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+            self.record_progress();
+        }
+        Ok(())
+    }
+
+    /// Extend the lease on the message with a single Progress ack, returning the new deadline
+    /// after which it is redelivered.
+    ///
+    /// A Progress ack restarts `ack_wait`, so the deadline is `ack_wait` from now whatever
+    /// `needed` is. When `needed` is longer, a warning is logged and the handler has to extend
+    /// the lease again before the returned deadline. The Progress ack is sent in any case, but
+    /// without a NATS message or a known `ack_wait` there is no deadline and `None` is returned.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    ///
+    /// async fn handle(chunks: Vec<String>, ctx: NatsContext) -> Result<(), Error> {
+    ///     for chunk in chunks {
+    ///         if ctx.lease_remaining().is_some_and(|left| left < Duration::from_secs(10)) {
+    ///             ctx.extend_lease(Duration::from_secs(10)).await?;
+    ///         }
+    ///         // process the chunk, taking up to 10s
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn extend_lease(&self, needed: Duration) -> Result<Option<Instant>, Error> {
+        if self.message.is_none() {
+            return Ok(None);
+        }
+        self.progress().await?;
+        let Some(ack_wait) = self.ack_wait else {
+            return Ok(None);
+        };
+        if needed > ack_wait {
+            tracing::warn!(
+                "Asked to extend the lease by {:?}, but ack_wait only allows {:?}",
+                needed,
+                ack_wait
+            );
+        }
+        Ok(Some(Instant::now() + ack_wait))
+    }
+
+    /// How long until the message is redelivered, unless acked or extended before then.
+    ///
+    /// Computed from `ack_wait` and the time since the message was received or last extended
+    /// with [`progress`](NatsContext::progress), [`extend_lease`](NatsContext::extend_lease) or
+    /// a heartbeat, so it runs slightly ahead of the server's clock. `Some(Duration::ZERO)` once
+    /// the lease has run out, `None` without a NATS message or a known `ack_wait`.
+    pub fn lease_remaining(&self) -> Option<Duration> {
+        let ack_wait = self.ack_wait.filter(|_| self.message.is_some())?;
+        let last_progress = (*self
+            .last_progress
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))?;
+        Some(ack_wait.saturating_sub(last_progress.elapsed()))
+    }
+
+    /// When the message is redelivered unless acked or extended before then: the time it was
+    /// received (or last extended, see [`lease_remaining`](NatsContext::lease_remaining)) plus
+    /// `ack_wait`. `None` without a NATS message or a known `ack_wait`.
+    ///
+    /// Lets handlers put a cooperative timeout on their work instead of overrunning into a
+    /// redelivery.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::sync::Arc;
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    ///
+    /// # async fn render(job: String) {}
+    /// async fn handle(job: String, ctx: NatsContext) -> Result<(), Error> {
+    ///     match ctx.deadline() {
+    ///         // Fail the attempt before it is redelivered, so it's retried cleanly
+    ///         Some(deadline) => tokio::time::timeout_at(deadline.into(), render(job))
+    ///             .await
+    ///             .map_err(|e| Error::Failed(Arc::new(Box::new(e))))?,
+    ///         None => render(job).await,
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn deadline(&self) -> Option<Instant> {
+        let ack_wait = self.ack_wait.filter(|_| self.message.is_some())?;
+        let last_progress = (*self
+            .last_progress
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))?;
+        Some(last_progress + ack_wait)
+    }
+
+    /// Whether this is the last delivery before the job is dead-lettered, i.e. the message has
+    /// been delivered `max_deliver` times. Failing it with any error ends the job.
+    ///
+    /// Lets handlers run final-attempt logic like alerting or compensation without passing
+    /// `max_deliver` around. Retries can still end earlier if a `retry_budget` runs out or the
+    /// job outlives `max_retry_duration`.
+    /// `false` without a NATS message.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    ///
+    /// # async fn charge(job: &String) -> Result<(), Error> { Ok(()) }
+    /// async fn handle(job: String, ctx: NatsContext) -> Result<(), Error> {
+    ///     let result = charge(&job).await;
+    ///     if result.is_err() && ctx.is_final_attempt() {
+    ///         tracing::error!("Giving up on {}", job);
+    ///     }
+    ///     result
+    /// }
+    /// ```
+    pub fn is_final_attempt(&self) -> bool {
+        let (Some(msg), Some(max_deliver)) = (&self.message, self.max_deliver) else {
+            return false;
+        };
+        msg.info()
+            .map(|info| info.delivered >= max_deliver)
+            .unwrap_or(false)
+    }
+
+    /// Record that the lease was just extended
+    fn record_progress(&self) {
+        *self
+            .last_progress
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now());
+    }
+
+    /// Attach metadata to the DLQ entry written if this attempt dead-letters the job.
+    ///
+    /// Call it before returning `Error::Abort` (or failing the final attempt) to record
+    /// domain context, e.g. which validation rule failed. It ends up in [`DlqEntry::metadata`].
+    /// Setting it again replaces the previous value.
+    ///
+    /// [`DlqEntry::metadata`]: crate::DlqEntry::metadata
+    pub fn set_dlq_metadata(&self, metadata: impl Serialize) -> Result<(), Error> {
+        let value = serde_json::to_value(metadata)
+            .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+        *self
+            .dlq_metadata
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(value);
+        Ok(())
+    }
+
+    /// The metadata attached with [`set_dlq_metadata`](NatsContext::set_dlq_metadata), if any
+    pub fn dlq_metadata(&self) -> Option<serde_json::Value> {
+        self.dlq_metadata
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl NatsContext {
+    /// Move the message to the DLQ right away, without using up its retries.
+    ///
+    /// Publishes a [`DlqEntry`] with the given `reason` as `dlq_reason` and `metadata` (falling
+    /// back to [`set_dlq_metadata`](NatsContext::set_dlq_metadata)), then acks the original
+    /// message. With the DLQ disabled the message is terminated instead. Either way the job is
+    /// marked dead and whatever the handler returns afterwards is not acked again.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    ///
+    /// async fn handle(job: String, ctx: NatsContext) -> Result<(), Error> {
+    ///     if job.is_empty() {
+    ///         ctx.to_dlq("empty_payload", None).await?;
+    ///         return Ok(());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn to_dlq(
+        &self,
+        reason: &str,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        self.dead_letter(reason, metadata)
+            .await
+            .map_err(|e| Error::SourceError(Arc::new(e.into())))
+    }
+
+    async fn dead_letter(
+        &self,
+        reason: &str,
+        metadata: Option<serde_json::Value>,
+    ) -> Result<(), NatsPollError> {
+        let Some(msg) = &self.message else {
+            return Err(NatsPollError::Storage("No NATS message in context".to_string()));
+        };
+        let Some(handle) = self.dlq.as_ref().and_then(|dlq| dlq.upgrade()) else {
+            return Err(NatsPollError::Storage(
+                "The storage this message came from is no longer available".to_string(),
+            ));
+        };
+        if self.settled_status().is_some() {
+            return Err(NatsPollError::Storage(
+                "The message was already acknowledged".to_string(),
+            ));
+        }
+
+        let job = serde_json::from_slice::<NatsJob<serde_json::Value>>(&msg.payload)?;
+        if handle.enable_dlq {
+            let delivered = msg.info().map(|info| info.delivered).unwrap_or(1);
+            let mut entry = DlqEntry {
+                original_task_id: job.id.to_string(),
+                error: format!("Moved to the DLQ by the handler: {}", reason),
+                error_detail: None,
+                attempts: delivered as usize,
+                delivered_count: delivered,
+                timestamp: handle.clock.now(),
+                dlq_reason: reason.to_string(),
+                payload: entry_payload(handle.redact.as_ref(), &msg.payload),
+                payload_truncated: None,
+                metadata: metadata.or_else(|| self.dlq_metadata()),
+                namespace: Some(handle.namespace.clone()),
+                retry_after: None,
+                original_subject: Some(msg.subject.to_string()),
+                original_priority: subject_priority(&msg.subject),
+                original_sequence: msg.info().ok().map(|info| info.stream_sequence),
+            };
+            handle
+                .jetstream
+                .publish(
+                    tenant_dlq_subject(
+                        &handle.subject,
+                        &handle.namespace,
+                        handle.tenant_label.as_deref(),
+                        &msg.subject,
+                    ),
+                    entry
+                        .to_bytes(dlq_size_limit(&handle.client, handle.max_msg_size))?
+                        .into(),
+                )
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            msg.ack()
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
         } else {
-            Ok(())
+            msg.ack_with(jetstream::AckKind::Term)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
         }
+        self.settle(JobStatus::Dead);
+        tracing::warn!("Handler moved task {} to the DLQ: {}", job.id, reason);
+        Ok(())
+    }
+
+    /// Record that the handler acknowledged the message itself, leaving the job in `status`
+    fn settle(&self, status: JobStatus) {
+        *self
+            .settled
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(status);
+    }
+
+    /// The status the handler left the job in, if it acknowledged the message itself
+    pub(crate) fn settled_status(&self) -> Option<JobStatus> {
+        *self
+            .settled
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
 
 /// A guard that periodically sends Progress acknowledgements to extend ack wait.
-/// Drop to stop heartbeating.
+/// Drop to stop heartbeating, e.g. when a handler holding it panics.
 #[derive(Debug)]
 pub struct ProgressGuard {
-    #[allow(dead_code)]
     handle: tokio::task::JoinHandle<()>,
     stop: Option<tokio::sync::oneshot::Sender<()>>,
 }
@@ -157,7 +526,9 @@ impl Drop for ProgressGuard {
         if let Some(stop) = self.stop.take() {
             let _ = stop.send(());
         }
+        // Also cut off a Progress ack in flight, the handler it extended is gone.
         // We intentionally don't await here
+        self.handle.abort();
     }
 }
 
@@ -166,6 +537,7 @@ impl NatsContext {
     /// Returns None if no underlying message is present (e.g., synthetic requests).
     pub fn start_progress_heartbeat(&self, interval: Duration) -> Option<ProgressGuard> {
         let msg = self.message.as_ref()?.clone();
+        let last_progress = self.last_progress.clone();
         let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
         let handle = tokio::spawn(async move {
             let mut ticker = tokio::time::interval(interval);
@@ -173,7 +545,12 @@ impl NatsContext {
                 tokio::select! {
                     _ = &mut rx => break,
                     _ = ticker.tick() => {
-                        let _ = msg.ack_with(jetstream::AckKind::Progress).await;
+                        if msg.ack_with(jetstream::AckKind::Progress).await.is_ok() {
+                            let mut last_progress = last_progress
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner());
+                            *last_progress = Some(Instant::now());
+                        }
                     }
                 }
             }
@@ -182,6 +559,58 @@ impl NatsContext {
     }
 }
 
+/// Settles a `manual_ack` job from the handler's result once dropped, unless the handler
+/// acked, Naked, terminated or dead-lettered it itself. See
+/// [`NatsContext::auto_ack_on_success`].
+#[derive(Debug)]
+pub struct AckGuard {
+    ctx: NatsContext,
+}
+
+impl Drop for AckGuard {
+    fn drop(&mut self) {
+        // The ack task sees the handler's result, the guard can't
+        self.ctx.auto_ack.store(true, Ordering::Release);
+    }
+}
+
+impl NatsContext {
+    /// Make sure a `manual_ack` job is settled even if the handler forgets to: once the
+    /// returned guard is dropped, a job the handler returned `Ok` for is acked, and one it
+    /// failed for is Naked for a retry.
+    ///
+    /// An explicit outcome always wins: after `ack()`, `nack()`, `term()` or `to_dlq()` the
+    /// guard does nothing. A Nak still counts towards `max_deliver`, so a job failing its last
+    /// delivery goes to the DLQ as usual. Panics are failures only when caught, with
+    /// `catch_panic` or [`NatsLayers::recommended`](crate::NatsLayers::recommended); an
+    /// uncaught panic never reports a result, so the job is redelivered after `ack_wait`.
+    ///
+    /// Only meant for `manual_ack`: without it the worker acks and retries jobs from the
+    /// handler's result anyway and the guard changes nothing. A guard moved out of the handler,
+    /// e.g. into a spawned task, has to be dropped before the handler returns to take effect.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    ///
+    /// # async fn commit(_row: &String) -> Result<(), Error> { Ok(()) }
+    /// async fn import(row: String, ctx: NatsContext) -> Result<(), Error> {
+    ///     let _guard = ctx.auto_ack_on_success();
+    ///     commit(&row).await?; // Naked if this fails
+    ///     Ok(()) // acked
+    /// }
+    /// ```
+    pub fn auto_ack_on_success(&self) -> AckGuard {
+        AckGuard { ctx: self.clone() }
+    }
+
+    /// Whether an [`AckGuard`] of this delivery was dropped
+    pub(crate) fn auto_ack_armed(&self) -> bool {
+        self.auto_ack.load(Ordering::Acquire)
+    }
+}
+
 impl<Req> FromRequest<Request<Req, NatsContext>> for NatsContext {
     fn from_request(req: &Request<Req, NatsContext>) -> Result<Self, Error> {
         Ok(req.parts.context.clone())
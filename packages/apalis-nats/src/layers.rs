@@ -1,22 +1,108 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use apalis_core::error::{BoxDynError, Error};
 use apalis_core::request::Request;
+use futures::FutureExt;
+use tokio::sync::Semaphore;
 use tower::{Layer, Service};
+use tracing::instrument::Instrumented;
+use tracing::Instrument;
 
-use crate::NatsContext;
+use crate::expose::ProgressGuard;
+use crate::{NatsContext, Priority};
+
+/// The heartbeat tasks a layer has running, shared by its clones
+#[derive(Debug, Default)]
+struct ActiveHeartbeats(AtomicUsize);
+
+impl ActiveHeartbeats {
+    /// Start a heartbeat for the job of `ctx`, warning when it takes the count above `cap`
+    fn start(
+        self: &Arc<Self>,
+        ctx: &NatsContext,
+        interval: Duration,
+        cap: Option<usize>,
+    ) -> Option<Heartbeat> {
+        let guard = ctx.start_progress_heartbeat(interval)?;
+        let active = self.0.fetch_add(1, Ordering::AcqRel) + 1;
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("apalis_nats_active_heartbeats").increment(1.0);
+        // Warn when the count crosses the cap rather than for every job above it
+        if let Some(cap) = cap.filter(|cap| active == cap + 1) {
+            tracing::warn!(
+                "{} heartbeat tasks running, above the cap of {}: jobs regularly outlive \
+                 ack_wait, consider raising it",
+                active,
+                cap
+            );
+        }
+        Some(Heartbeat {
+            _guard: guard,
+            active: Arc::clone(self),
+        })
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A running heartbeat, counted until dropped
+struct Heartbeat {
+    _guard: ProgressGuard,
+    active: Arc<ActiveHeartbeats>,
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.active.0.fetch_sub(1, Ordering::AcqRel);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("apalis_nats_active_heartbeats").decrement(1.0);
+    }
+}
 
 /// A layer that automatically sends periodic Progress acknowledgements to extend `ack_wait`
 /// while a job is running. The heartbeat stops when the handler returns or panics.
+///
+/// Each running job has its own heartbeat task. [`active_heartbeats`] counts them, and with
+/// the `metrics` feature so does the `apalis_nats_active_heartbeats` gauge.
+///
+/// [`active_heartbeats`]: ProgressHeartbeatLayer::active_heartbeats
 #[derive(Clone, Debug)]
 pub struct ProgressHeartbeatLayer {
     interval: Duration,
+    max_active: Option<usize>,
+    active: Arc<ActiveHeartbeats>,
 }
 
 impl ProgressHeartbeatLayer {
     /// Create a new heartbeat layer with the given interval. The interval must be less
     /// than the consumer `ack_wait`.
     pub fn new(interval: Duration) -> Self {
-        Self { interval }
+        Self {
+            interval,
+            max_active: None,
+            active: Default::default(),
+        }
+    }
+
+    /// Log a warning when more than `max_active` heartbeats run at once. Many long jobs
+    /// needing a heartbeat are a sign that `ack_wait` is too short for the workload. Jobs
+    /// still get their heartbeat above the cap.
+    pub fn max_active(mut self, max_active: usize) -> Self {
+        self.max_active = Some(max_active);
+        self
+    }
+
+    /// How many heartbeat tasks this layer and its clones have running, one per job in flight
+    pub fn active_heartbeats(&self) -> usize {
+        self.active.get()
     }
 }
 
@@ -27,6 +113,8 @@ impl<S> Layer<S> for ProgressHeartbeatLayer {
         ProgressHeartbeatService {
             service,
             interval: self.interval,
+            max_active: self.max_active,
+            active: Arc::clone(&self.active),
         }
     }
 }
@@ -35,6 +123,8 @@ impl<S> Layer<S> for ProgressHeartbeatLayer {
 pub struct ProgressHeartbeatService<S> {
     service: S,
     interval: Duration,
+    max_active: Option<usize>,
+    active: Arc<ActiveHeartbeats>,
 }
 
 impl<S, Req> Service<Request<Req, NatsContext>> for ProgressHeartbeatService<S>
@@ -59,10 +149,14 @@ where
     fn call(&mut self, request: Request<Req, NatsContext>) -> Self::Future {
         let mut inner = self.service.clone();
         let interval = self.interval;
+        let max_active = self.max_active;
+        let active = Arc::clone(&self.active);
 
         let fut = async move {
-            // Start heartbeat (if this request carries a real NATS message)
-            let _guard = request.parts.context.start_progress_heartbeat(interval);
+            // Start heartbeat (if this request carries a real NATS message). The guard lives in
+            // this future, so it is dropped with it when the handler panics, and a dead handler
+            // doesn't keep extending ack_wait while a `CatchPanicLayer` aborts the job.
+            let _heartbeat = active.start(&request.parts.context, interval, max_active);
             inner.call(request).await
         };
 
@@ -70,3 +164,460 @@ where
     }
 }
 
+
+/// The error returned in place of a handler panic by [`CatchPanicLayer`]
+#[derive(Debug, Clone)]
+pub struct PanicError(pub String);
+
+impl fmt::Display for PanicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handler panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for PanicError {}
+
+/// A layer that catches handler panics and turns them into `Error::Abort`, so the message is
+/// routed to the DLQ (or terminated) instead of being redelivered after `ack_wait`.
+#[derive(Clone, Debug, Default)]
+pub struct CatchPanicLayer;
+
+impl CatchPanicLayer {
+    /// Create a new catch-panic layer
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanicService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CatchPanicService { service }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchPanicService<S> {
+    service: S,
+}
+
+impl<S, Req, Ctx> Service<Request<Req, Ctx>> for CatchPanicService<S>
+where
+    S: Service<Request<Req, Ctx>, Error = Error>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Req, Ctx>) -> Self::Future {
+        let fut = match std::panic::catch_unwind(AssertUnwindSafe(|| self.service.call(request))) {
+            Ok(fut) => fut,
+            Err(panic) => return Box::pin(futures::future::ready(Err(panic_error(panic)))),
+        };
+
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(res) => res,
+                Err(panic) => Err(panic_error(panic)),
+            }
+        })
+    }
+}
+
+fn panic_error(panic: Box<dyn Any + Send>) -> Error {
+    let message = if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    };
+    tracing::error!("Job handler panicked: {}", message);
+    Error::Abort(Arc::new(Box::new(PanicError(message))))
+}
+
+/// The layers a [`NatsStorage`](crate::NatsStorage) adds to every worker built on it, see
+/// [`NatsStorage::with_layers`](crate::NatsStorage::with_layers).
+///
+/// They are part of the backend's own layer, so `WorkerBuilder::backend(storage)` applies
+/// them without any `.layer(...)` calls. The default has none of them; start from
+/// [`NatsLayers::recommended`] and switch off what a worker doesn't want.
+#[derive(Clone, Debug, Default)]
+pub struct NatsLayers {
+    heartbeat: bool,
+    heartbeat_interval: Option<Duration>,
+    max_active_heartbeats: Option<usize>,
+    active_heartbeats: Arc<ActiveHeartbeats>,
+    catch_panic: bool,
+    #[cfg(feature = "metrics")]
+    metrics: bool,
+}
+
+impl NatsLayers {
+    /// Progress heartbeats every `ack_wait / 3`, panics turned into `Error::Abort`, and with
+    /// the `metrics` feature, job counts and durations
+    pub fn recommended() -> Self {
+        Self {
+            heartbeat: true,
+            heartbeat_interval: None,
+            max_active_heartbeats: None,
+            active_heartbeats: Default::default(),
+            catch_panic: true,
+            #[cfg(feature = "metrics")]
+            metrics: true,
+        }
+    }
+
+    /// Send progress heartbeats while a job runs, like [`ProgressHeartbeatLayer`]
+    pub fn heartbeat(mut self, enabled: bool) -> Self {
+        self.heartbeat = enabled;
+        self
+    }
+
+    /// Send heartbeats every `interval` instead of every `ack_wait / 3`. Enables them.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat = true;
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Log a warning when more than `max_active` heartbeats run at once, like
+    /// [`ProgressHeartbeatLayer::max_active`]
+    pub fn max_active_heartbeats(mut self, max_active: usize) -> Self {
+        self.max_active_heartbeats = Some(max_active);
+        self
+    }
+
+    /// How many heartbeat tasks these layers and their clones have running
+    pub fn active_heartbeats(&self) -> usize {
+        self.active_heartbeats.get()
+    }
+
+    /// Turn handler panics into `Error::Abort`, like [`CatchPanicLayer`]
+    pub fn catch_panic(mut self, enabled: bool) -> Self {
+        self.catch_panic = enabled;
+        self
+    }
+
+    /// Count finished jobs in `apalis_nats_jobs_total`, labelled with `priority` and
+    /// `outcome` (`ok` or `error`), and record their run time in the
+    /// `apalis_nats_job_duration_seconds` histogram
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// These layers with the heartbeat interval filled in for `ack_wait`
+    pub(crate) fn for_ack_wait(mut self, ack_wait: Duration) -> Self {
+        if self.heartbeat_interval.is_none() {
+            self.heartbeat_interval = Some((ack_wait / 3).max(Duration::from_secs(1)));
+        }
+        self
+    }
+}
+
+impl<S> Layer<S> for NatsLayers {
+    type Service = NatsLayersService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        NatsLayersService {
+            service,
+            layers: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NatsLayersService<S> {
+    service: S,
+    layers: NatsLayers,
+}
+
+impl<S, Req> Service<Request<Req, NatsContext>> for NatsLayersService<S>
+where
+    S: Service<Request<Req, NatsContext>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxDynError>,
+    S::Response: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(|e| Error::from(e.into()))
+    }
+
+    fn call(&mut self, request: Request<Req, NatsContext>) -> Self::Future {
+        let heartbeat = match (self.layers.heartbeat, self.layers.heartbeat_interval) {
+            (true, Some(interval)) => self.layers.active_heartbeats.start(
+                &request.parts.context,
+                interval,
+                self.layers.max_active_heartbeats,
+            ),
+            _ => None,
+        };
+        #[cfg(feature = "metrics")]
+        let metrics = self.layers.metrics.then(|| {
+            let priority = request.parts.context.priority().unwrap_or_default();
+            (priority.to_string(), std::time::Instant::now())
+        });
+
+        let catch_panic = self.layers.catch_panic;
+        let fut = match catch_panic {
+            true => match std::panic::catch_unwind(AssertUnwindSafe(|| self.service.call(request)))
+            {
+                Ok(fut) => fut,
+                Err(panic) => return Box::pin(futures::future::ready(Err(panic_error(panic)))),
+            },
+            false => self.service.call(request),
+        };
+
+        Box::pin(async move {
+            // Heartbeats stop once the handler is done
+            let _heartbeat = heartbeat;
+            let result = match catch_panic {
+                true => match AssertUnwindSafe(fut).catch_unwind().await {
+                    Ok(result) => result.map_err(|e| Error::from(e.into())),
+                    Err(panic) => Err(panic_error(panic)),
+                },
+                false => fut.await.map_err(|e| Error::from(e.into())),
+            };
+            #[cfg(feature = "metrics")]
+            if let Some((priority, started)) = metrics {
+                let outcome = if result.is_ok() { "ok" } else { "error" };
+                metrics::counter!(
+                    "apalis_nats_jobs_total",
+                    "priority" => priority.clone(),
+                    "outcome" => outcome
+                )
+                .increment(1);
+                metrics::histogram!("apalis_nats_job_duration_seconds", "priority" => priority)
+                    .record(started.elapsed().as_secs_f64());
+            }
+            result
+        })
+    }
+}
+
+/// A layer that runs each job inside an `apalis_nats.process` span with `task_id`, `priority`,
+/// `attempt` and `queue_latency_ms` fields, so plain `tracing` logs from the handler and ack are
+/// correlated.
+///
+/// Installed by the NATS backends, there is no need to add it to a worker.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessSpanLayer;
+
+impl<S> Layer<S> for ProcessSpanLayer {
+    type Service = ProcessSpanService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ProcessSpanService { service }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProcessSpanService<S> {
+    service: S,
+}
+
+impl<S, Req> Service<Request<Req, NatsContext>> for ProcessSpanService<S>
+where
+    S: Service<Request<Req, NatsContext>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Req, NatsContext>) -> Self::Future {
+        let ctx = &request.parts.context;
+        // JetStream's delivery count survives redeliveries, unlike `parts.attempt`
+        let attempt = ctx
+            .message()
+            .and_then(|msg| msg.info().ok())
+            .map(|info| info.delivered)
+            .unwrap_or(1);
+        let priority = ctx
+            .priority()
+            .map(|priority| priority.to_string())
+            .unwrap_or_default();
+        let queue_latency_ms = ctx
+            .queue_latency()
+            .map(|latency| latency.as_millis() as u64)
+            .unwrap_or_default();
+        let span = tracing::info_span!(
+            "apalis_nats.process",
+            task_id = %request.parts.task_id,
+            priority = %priority,
+            attempt,
+            queue_latency_ms
+        );
+
+        let fut = {
+            let _enter = span.enter();
+            self.service.call(request)
+        };
+        fut.instrument(span)
+    }
+}
+
+/// The error returned by [`PriorityLimitLayer`] for a job it sent back to the stream
+#[derive(Debug, Clone)]
+pub struct PriorityLimitReached(pub Priority);
+
+impl fmt::Display for PriorityLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many {} priority jobs in flight", self.0)
+    }
+}
+
+impl std::error::Error for PriorityLimitReached {}
+
+/// A layer that caps how many jobs of a priority run at once, on top of the worker's overall
+/// concurrency, e.g. so a flood of `Low` jobs can't take every slot from `High` ones.
+///
+/// A job arriving while its priority is at the limit is Nak'd with `delay` and returns
+/// [`PriorityLimitReached`] without running. That redelivery counts towards `max_deliver`,
+/// so leave room for it on busy priorities. Priorities without a limit, and requests without
+/// a NATS message, are not limited. Add it outside any retry layer, so a deferred job isn't
+/// retried in place.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::collections::HashMap;
+/// use std::time::Duration;
+/// use apalis_nats::{PriorityLimitLayer, Priority};
+///
+/// let layer = PriorityLimitLayer::new(HashMap::from([(Priority::Low, 2)]))
+///     .delay(Duration::from_secs(1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct PriorityLimitLayer {
+    per_priority_limits: HashMap<Priority, usize>,
+    permits: Arc<HashMap<Priority, Arc<Semaphore>>>,
+    delay: Duration,
+}
+
+impl PriorityLimitLayer {
+    /// Create a layer allowing at most `per_priority_limits[priority]` jobs of each listed
+    /// priority in flight. Deferred jobs are redelivered after 1s.
+    pub fn new(per_priority_limits: HashMap<Priority, usize>) -> Self {
+        let permits = per_priority_limits
+            .iter()
+            .map(|(priority, limit)| (*priority, Arc::new(Semaphore::new(*limit))))
+            .collect();
+        Self {
+            per_priority_limits,
+            permits: Arc::new(permits),
+            delay: Duration::from_secs(1),
+        }
+    }
+
+    /// How long a job over its priority's limit waits before being redelivered
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// The in-flight limit of each limited priority
+    pub fn per_priority_limits(&self) -> &HashMap<Priority, usize> {
+        &self.per_priority_limits
+    }
+}
+
+impl<S> Layer<S> for PriorityLimitLayer {
+    type Service = PriorityLimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        PriorityLimitService {
+            service,
+            permits: self.permits.clone(),
+            delay: self.delay,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PriorityLimitService<S> {
+    service: S,
+    permits: Arc<HashMap<Priority, Arc<Semaphore>>>,
+    delay: Duration,
+}
+
+impl<S, Req> Service<Request<Req, NatsContext>> for PriorityLimitService<S>
+where
+    S: Service<Request<Req, NatsContext>, Error = Error> + Send + Clone + 'static,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Req, NatsContext>) -> Self::Future {
+        let ctx = &request.parts.context;
+        let permits = match ctx.priority() {
+            Some(priority) if ctx.message().is_some() => self
+                .permits
+                .get(&priority)
+                .map(|permits| (priority, permits.clone())),
+            _ => None,
+        };
+        let Some((priority, permits)) = permits else {
+            return Box::pin(self.service.call(request));
+        };
+
+        let permit = match permits.try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let ctx = ctx.clone();
+                let delay = self.delay;
+                return Box::pin(async move {
+                    ctx.defer(delay).await?;
+                    tracing::debug!(
+                        "Deferred task {}, too many {} priority jobs in flight",
+                        request.parts.task_id,
+                        priority
+                    );
+                    Err(Error::Failed(Arc::new(Box::new(PriorityLimitReached(
+                        priority,
+                    )))))
+                });
+            }
+        };
+        let fut = self.service.call(request);
+        Box::pin(async move {
+            let res = fut.await;
+            drop(permit);
+            res
+        })
+    }
+}
@@ -11,7 +11,18 @@
 //! - DLQ routing on abort errors or after max deliveries
 //! - At-least-once delivery, configurable retries with backoff
 //! - Optional OpenTelemetry W3C trace propagation
+//! - Retry distribution via `NatsStorage::retry_stats()`, exported with the `metrics` feature
 //! - Long-running jobs: progress heartbeats to extend `ack_wait`
+//! - Pausing and resuming consumption with `NatsStorage::pause()`/`resume()`
+//! - Recommended layers bundled into the backend with `NatsStorage::with_layers`
+//! - Per-priority in-flight limits with `PriorityLimitLayer`
+//! - Dedicated worker pools per priority with `NatsStorage::for_priorities`
+//! - Labeled jobs for capability-based pools with `push_with_labels` and `NatsStorage::for_labels`
+//! - Skipping or deferring jobs by a predicate with `NatsStorage::with_filter`
+//! - One-shot batch runs that stop once drained with `NatsStorage::run_until_drained`
+//! - Opt-in job status tracking in a `{namespace}_status` KV bucket, queryable over `{namespace}.status`
+//! - Quarantining job types that fail en masse with `Config::quarantine`
+//! - Per-job state kept across retries with `NatsContext::state_get`/`state_put`
 //!
 //! Basic usage
 //! ```rust,no_run
@@ -107,25 +118,153 @@
 //! - Streams: one per priority plus optional DLQ, all under the same `namespace`.
 //! - Consumers: shared durable pull consumers per priority provide work-queue semantics.
 //! - Heartbeats: for jobs exceeding `ack_wait`, use `NatsContext::progress()` or `ProgressHeartbeatLayer`.
+//!   Workers warn once when a job goes longer than `ack_wait` without a Progress ack.
 //! - Tracing: logs use `tracing`; enable OpenTelemetry via the `otel` feature.
+//! - Shutdown: pushes return once the job is stored; call `storage.flush()` before exiting so buffered
+//!   acks reach the server.
+//! - Worker setup: `NatsWorkerExt::nats_defaults(&storage)` applies the above in one call: panics
+//!   become `Error::Abort`, heartbeats every `ack_wait / 3`, concurrency capped at `concurrency` or `max_ack_pending`.
 //!
 //! Configuration Options (Config)
 //! - `namespace: String`
 //!   The logical prefix for streams/subjects, e.g., `my_app` creates streams `my_app_high|medium|low` and `my_app_dlq`.
+//!   Must be a single subject token: not empty, without `.`, `*`, `>` or whitespace.
 //! - `max_deliver: i64`
 //!   Max delivery attempts before routing to DLQ for transient failures. Typical: 3–10.
+//! - `on_max_deliver: OnMaxDeliver`
+//!   `DeadLetter` (default) moves jobs that failed their last delivery to the DLQ. `Reschedule(delay)`
+//!   schedules them to be pushed again after `delay`, e.g. for a daily retry. Default: DeadLetter.
+//! - `max_retry_duration: Option<Duration>`
+//!   Dead-letters a failing job once this long has passed since its `created_at`, even with
+//!   deliveries left under `max_deliver`, e.g. to give up after an hour of retries. Default: None.
+//! - `sla: HashMap<Priority, Duration>`
+//!   How long jobs of a priority may wait for their first delivery. Later deliveries log a warning and
+//!   emit a `sla_breach` worker event (and metric with `metrics`). Default: empty, no SLA.
 //! - `ack_wait: Duration`
 //!   How long JetStream waits for an ack before redelivery. Must exceed your progress/heartbeat interval.
 //!   Typical: 60–120s for long-running jobs; shorter for fast jobs.
 //! - `num_replicas: usize`
 //!   Stream replicas for HA. Typical: 1 (dev), 3 (prod).
+//! - `placement: Option<Placement>`
+//!   Pins the streams and status bucket to a cluster and/or server tags, e.g. for data residency. Only
+//!   honored on clustered servers. Default: None.
+//! - `storage_type: StorageType`
+//!   `File` (default) or `Memory` for faster, ephemeral queues that lose pending jobs on restart. Memory
+//!   with `num_replicas > 1` logs a warning at startup.
+//! - `create_streams: bool`
+//!   Whether storages create the streams and status bucket, or with `false` only check they exist,
+//!   for credentials without stream management permissions. Default: true.
+//! - `track_status: bool`
+//!   Record each job's `JobStatus` in the `{namespace}_status` KV bucket for `query_status`. Costs a KV
+//!   write per push and per state change. Default: false.
+//! - `enabled_priorities: Vec<Priority>`
+//!   The priorities that get a stream and consumer; pushing at another one fails. Default: all three.
 //! - `enable_dlq: bool`
 //!   Whether to move failed jobs to `{namespace}.dlq` subject in the `{namespace}_dlq` stream.
+//! - `dlq_stream: Option<String>`, `dlq_subject: Option<String>`
+//!   Override the DLQ stream and subject, e.g. to share one DLQ across namespaces. Default: None.
+//! - `dlq_retry_after: Option<Duration>`
+//!   Stamps DLQ entries of jobs that ran out of retries with `retry_after = now + delay`; `dlq_backend()`
+//!   workers hold them until then. Default: None.
+//! - `tenant_label: Option<String>`
+//!   Label key naming a job's tenant; failed jobs with it go to `{dlq_subject}.{tenant}`, read back
+//!   with `read_dlq_for_tenant`. Default: None.
+//! - `dlq_retention: RetentionPolicy`
+//!   `Limits` (default) keeps acked DLQ entries for 30 days; `WorkQueue` removes them once acked, but
+//!   then the DLQ can only have one consumer per subject. Only applied when the stream is created.
 //! - `max_ack_pending: i64`
 //!   Limits unacked messages per consumer. Tune to match worker concurrency (e.g., 2–4x concurrency).
+//! - `concurrency: Option<usize>`
+//!   The concurrency workers run with. `Config::with_concurrency(n)` sets it and `max_ack_pending = 2n`.
+//!   Workers log a warning at startup when it, or a limit set with `NatsWorkerExt::nats_concurrency_limit`, exceeds
+//!   `max_ack_pending`.
+//! - `max_waiting: i64`
+//!   Limits outstanding pull requests per consumer. Must cover the number of workers sharing it;
+//!   pulls failing with "exceeded MaxWaiting" mean it is too low. Default: 0, derived as twice
+//!   `max_ack_pending / concurrency` (the workers that can hold a job at once), at least 512.
+//!   Only set when the consumer is created; workers warn when an existing consumer differs.
+//! - `max_batch: i64`
+//!   Caps how many messages one pull request may ask for. `0` leaves it unlimited.
+//! - `subject_transform: Option<(String, String)>`
+//!   Republishes stored jobs to an external subject, e.g. `("my_app.*", "events.jobs.{{wildcard(1)}}")`.
+//!   Purely additive: apalis still consumes from `{namespace}.{priority}`. Requires NATS 2.10+.
+//! - `republish: Option<RepublishConfig>`
+//!   Mirrors every stored job to a subject like `audit.{namespace}.{priority}`, optionally headers only.
+//!   Additive like `subject_transform`, and can't be combined with it. Default: None.
+//! - `sources: Vec<Source>` / `mirror: Option<Source>`
+//!   Create the priority streams as sources or mirrors of upstream streams, e.g. `apalis_{priority}`, for
+//!   regional worker pools draining a local copy. Acks only remove the local copy. Default: none.
+//! - `deliver_policy: DeliverPolicy`
+//!   Where the priority consumers start: `All` (default), `New`, `ByStartTime(at)` or `ByStartSequence(seq)`.
+//!   Non-`All` policies use separate `_replay_consumer` durables for replay workers.
+//!   On work-queue streams these fail with `NatsPollError::ConsumerNotUnique` while the regular consumer exists.
+//! - `ack_policy: AckPolicy`
+//!   `Explicit` (default) acks each message on its own. `All` acks every earlier delivery too, which cuts
+//!   ack overhead but is only safe with a concurrency of 1.
+//! - `replay_policy: ReplayPolicy`
+//!   `Instant` (default) delivers stored jobs as fast as workers pull them. `Original` paces them by their
+//!   stored timestamps, for replaying a captured workload at its original rate.
+//! - `consumer_group: Option<String>`
+//!   Separate `{namespace}_{priority}_{group}_consumer` durables so each group receives every job, with
+//!   Interest-retention streams. Default: None (one shared consumer per priority).
+//! - `jetstream_domain: Option<String>`
+//!   The JetStream domain to use in leaf node / multi-account deployments. A wrong or missing domain
+//!   shows up as streams that appear not to exist.
 //! - `fetch_expiry: Duration`
 //!   Client-side cap for a fetch on one priority before falling through to the next. Improves fairness and shutdown responsiveness.
 //!   Typical: 50–150ms.
+//! - `poll_mode: PollMode`
+//!   `BusyLoop` (default) does short fetches and sleeps when idle. `LongPoll` waits on the server
+//!   instead, `fetch_expiry` on High/Medium and `long_poll_expiry` (default 1s) on Low.
+//! - `idle_jitter: f64`
+//!   Randomizes the 100ms `BusyLoop` idle sleep by this fraction either way, so workers started together
+//!   don't poll in lockstep. 0.0 turns it off. Default: 0.2.
+//! - `on_stream_missing: OnStreamMissing`
+//!   When a consumed stream is deleted under a running worker, `Recreate` (default) sets it up again right away
+//!   and `Fail` stops the worker with a `StreamNotFound` error event.
+//! - `heartbeat_interval: Duration`
+//!   How often workers check their connection and streams, reporting failures as warnings and
+//!   worker `Error` events even while idle. Stops with the worker; `Duration::ZERO` turns it off.
+//!   Default: 30s.
+//! - `fetch_batch_size: usize`
+//!   Jobs pulled per fetch, once a worker slot is free. Default: 1.
+//! - `fetch_max_bytes: Option<usize>`
+//!   Caps the bytes per fetch on top of the job count; must exceed the largest job. Default: None.
+//! - `prefetch: usize`
+//!   Jobs a worker fetches ahead into an in-memory buffer, at most half of `max_ack_pending`.
+//!   Default: 0 (off, fetch only when a worker slot is free).
+//!   Buffered jobs are redelivered after `ack_wait` if the worker crashes before running them.
+//! - `strict_priority: bool`
+//!   Fetch one job at a time, so lower priority jobs never start ahead of waiting higher
+//!   priority ones at any concurrency. Costs a fetch round trip per job. Default: false.
+//! - `keep_alive: bool`
+//!   Workers extend the lease of every job in flight from one shared task, with a Progress ack every
+//!   `ack_wait / 3`, instead of a heartbeat task per job. Default: false.
+//! - `retry_budget: Option<RetryBudget>`
+//!   Caps the rate of retries (Naks) across the workers of a storage. Once spent, failing jobs go to the DLQ
+//!   with reason `retry_budget_exhausted` instead of being retried. Default: `None` (no cap).
+//! - `quarantine: Option<Quarantine>`
+//!   Stops delivering a job type once `failure_rate` of at least `min_jobs` of its jobs failed within `window`,
+//!   holding them back for `duration` while other types keep running. Default: `None`.
+//! - `ack_concurrency: usize`
+//!   Acks a worker processes concurrently, so a slow DLQ publish doesn't stall acks of other jobs. Default: 8.
+//! - `manual_ack: bool`
+//!   Handlers ack successful jobs themselves with `NatsContext::ack()`. A job returning `Ok` without acking
+//!   is redelivered after `ack_wait`. `NatsContext::auto_ack_on_success()` returns a guard that acks or
+//!   Naks from the handler's result once dropped. Default: false.
+//! - `dedup_by_payload_hash: bool`
+//!   `push_with_priority` sets `Nats-Msg-Id` to a hash of the serialized job, so byte-identical jobs pushed
+//!   within the `duplicate_window` are enqueued once. Equal jobs that serialize differently are not
+//!   caught. Default: false.
+//! - `duplicate_window: Duration`
+//!   How long the priority streams remember `Nats-Msg-Id`s to drop duplicate pushes. `Duration::ZERO` turns
+//!   deduplication off for higher throughput; repeated ids are then stored as new jobs. Default: 2 minutes.
+//! - `accept_bare_payloads: bool`
+//!   Messages whose payload is a bare `T` instead of a job envelope are run as new jobs rather than dead-lettered
+//!   as malformed, for migrating existing producers. Default: false.
+//! - `max_msg_size: Option<usize>`
+//!   Pushes of jobs serializing to more bytes fail fast with `NatsPollError::PayloadTooLarge` instead of
+//!   being rejected by the server, and DLQ entries above it have their payload truncated. Default: None.
 //! - `nak_backoff: Vec<Duration>`
 //!   Backoff schedule for transient errors (Nak with delay). The last value is reused once attempts exceed the list.
 //!   Typical: `[100ms, 200ms, 500ms, 1s, 2s, 5s]`.
@@ -143,6 +282,7 @@
 //!     num_replicas: 3,
 //!     enable_dlq: true,
 //!     max_ack_pending: 200,
+//!     max_waiting: 512,
 //!     fetch_expiry: Duration::from_millis(75),
 //!     nak_backoff: vec![
 //!         Duration::from_millis(100),
@@ -154,6 +294,7 @@
 //!     ],
 //!     #[cfg(feature = "otel")]
 //!     enable_tracing: true,
+//!     ..Default::default()
 //! };
 //! ```
 //!
@@ -162,7 +303,7 @@
 //! - Use `.catch_panic()` so panics become `Error::Abort`, which are Term/DLQ’d deterministically.
 //! - Keep handlers idempotent; duplicates can occur (at-least-once).
 //! - Monitor JetStream metrics (ack pending, redeliveries, storage) and adjust `ack_wait`, `max_ack_pending`, and backoff.
-//! - For scheduling/delays, use an external scheduler or NATS KV with TTL; builtin scheduling isn’t implemented yet.
+//! - For delayed jobs, use `NatsStorage::scheduled_jobs()`; workers enqueue them once due.
 //!
 //! Long-running jobs (auto-heartbeat layer)
 //! ```rust,no_run
@@ -195,13 +336,44 @@
 //! }
 //! ```
 
+mod clock;
+mod dlq;
 mod expose;
 mod layers;
+mod plan;
+mod quarantine;
+mod registry;
+mod retry_budget;
+mod scheduled;
+mod state;
+mod status;
 mod storage;
+mod watchdog;
+mod worker;
 
+pub use async_nats::jetstream::stream::{
+    External, Placement, RetentionPolicy, Source, StorageType,
+};
 pub use async_nats::{Client, ConnectError, ConnectOptions};
 pub use storage::{
-    connect, connect_with_credentials, connect_with_options, connect_with_user_pass, Config,
-    NatsContext, NatsPollError, NatsQueueInfo, NatsStorage, Priority,
+    connect, connect_with_credentials, connect_with_options, connect_with_user_pass, AckPolicy,
+    Config, DeliverPolicy, Disposition, ErrorClassifier, FilterAction, JobFilter, Migration,
+    NatsContext, NatsPollError, NatsQueueInfo, NatsStorage, OnMaxDeliver, OnStreamMissing,
+    PollMode, Priority, PushOutcome, QueueSummary, Redactor, ReplayPolicy, RepublishConfig,
+    CREATED_AT_HEADER, JOB_TYPE_HEADER, NAMESPACE_HEADER, PRIORITY_HEADER, SCHEMA_VERSION_HEADER,
+    TASK_ID_HEADER,
+};
+pub use crate::layers::{
+    CatchPanicLayer, NatsLayers, PanicError, PriorityLimitLayer, PriorityLimitReached,
+    ProcessSpanLayer, ProgressHeartbeatLayer,
 };
-pub use crate::layers::ProgressHeartbeatLayer;
+pub use worker::{NatsDefaults, NatsWorkerExt};
+pub use clock::{Clock, SystemClock, TestClock};
+pub use dlq::{DlqBackend, DlqEntry, DlqError};
+pub use expose::AckGuard;
+pub use plan::PlannedResource;
+pub use quarantine::Quarantine;
+pub use registry::NatsStorageRegistry;
+pub use retry_budget::RetryBudget;
+pub use scheduled::{ScheduledJob, ScheduledJobs};
+pub use status::JobStatus;
@@ -0,0 +1,61 @@
+use crate::status::status_bucket_config;
+use crate::storage::{consumer_config, stream_configs, Config, NatsPollError};
+use crate::NatsStorage;
+use async_nats::jetstream::{consumer, kv, stream};
+
+/// A JetStream resource a storage with a given [`Config`] uses, see [`NatsStorage::plan`]
+#[derive(Debug, Clone)]
+pub enum PlannedResource {
+    /// A priority stream or the DLQ stream, created by [`NatsStorage::new_with_config`]
+    Stream(stream::Config),
+    /// The `{namespace}_status` KV bucket, created by [`NatsStorage::new_with_config`] with
+    /// `Config::track_status` on
+    KeyValue(kv::Config),
+    /// A shared pull consumer, created when the first worker starts polling its priority
+    Consumer {
+        /// The stream the consumer reads from
+        stream: String,
+        /// The consumer settings
+        config: consumer::pull::Config,
+    },
+}
+
+impl<T> NatsStorage<T> {
+    /// List the streams, status bucket and consumers a storage with `config` uses, without
+    /// contacting the server.
+    ///
+    /// The settings are the ones [`new_with_config`](NatsStorage::new_with_config) and the
+    /// workers create them with, so the plan can be diffed against existing infrastructure
+    /// before deploying a config change.
+    ///
+    /// # Example
+    /// ```
+    /// # use apalis_nats::{Config, NatsStorage, PlannedResource};
+    /// let config = Config {
+    ///     namespace: "billing".into(),
+    ///     ..Default::default()
+    /// };
+    /// for resource in NatsStorage::<String>::plan(&config)? {
+    ///     if let PlannedResource::Stream(stream) = resource {
+    ///         println!("{} <- {:?}", stream.name, stream.subjects);
+    ///     }
+    /// }
+    /// # Ok::<(), apalis_nats::NatsPollError>(())
+    /// ```
+    pub fn plan(config: &Config) -> Result<Vec<PlannedResource>, NatsPollError> {
+        let mut resources: Vec<_> = stream_configs(config)?
+            .into_iter()
+            .map(PlannedResource::Stream)
+            .collect();
+        if config.track_status {
+            resources.push(PlannedResource::KeyValue(status_bucket_config(config)));
+        }
+        for priority in config.priorities() {
+            resources.push(PlannedResource::Consumer {
+                stream: format!("{}_{}", config.namespace, priority),
+                config: consumer_config(config, priority)?,
+            });
+        }
+        Ok(resources)
+    }
+}
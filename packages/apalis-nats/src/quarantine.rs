@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// When to stop delivering a job type that is failing en masse, e.g. after a bad deploy.
+///
+/// Workers count the jobs of each type (its `apalis-job-type` header, see
+/// [`NatsStorage::with_type_tag`](crate::NatsStorage::with_type_tag)) that finished within
+/// `window`. Once at least `min_jobs` did and `failure_rate` or more of them failed, the type
+/// is quarantined for `duration`: its jobs are Naked until the quarantine ends rather than
+/// run, so they don't flood the DLQ, while jobs of other types keep flowing. Each hold uses up
+/// a delivery, and a job still held on its last one under `max_deliver` is dead-lettered as
+/// `quarantined`. Each quarantine is logged as an error and emitted as a `quarantine` worker
+/// event.
+///
+/// Like the [`RetryBudget`](crate::RetryBudget), the counts are shared by a storage and its
+/// clones, and separate processes each keep their own.
+///
+/// # Example
+/// ```
+/// # use apalis_nats::{Config, Quarantine};
+/// # use std::time::Duration;
+/// let config = Config {
+///     // 80% of at least 20 jobs failing within a minute stops the type for 5 minutes
+///     quarantine: Some(Quarantine::new(
+///         20,
+///         0.8,
+///         Duration::from_secs(60),
+///         Duration::from_secs(300),
+///     )),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quarantine {
+    /// Jobs of a type that have to finish within `window` before it can be quarantined
+    pub min_jobs: u32,
+    /// The share of those jobs failing that quarantines the type, from 0.0 to 1.0
+    pub failure_rate: f64,
+    /// How long jobs are counted for before the counts start over
+    pub window: Duration,
+    /// How long a quarantined type's jobs are held back
+    pub duration: Duration,
+}
+
+impl Quarantine {
+    /// Quarantine a type for `duration` once `failure_rate` of at least `min_jobs` jobs failed
+    /// within `window`
+    pub fn new(min_jobs: u32, failure_rate: f64, window: Duration, duration: Duration) -> Self {
+        Self {
+            min_jobs,
+            failure_rate,
+            window,
+            duration,
+        }
+    }
+}
+
+/// A job type that was just quarantined
+#[derive(Debug)]
+pub(crate) struct Quarantined {
+    pub(crate) jobs: u32,
+    pub(crate) failures: u32,
+}
+
+/// The recent outcomes of one job type
+#[derive(Debug)]
+struct TypeHealth {
+    since: DateTime<Utc>,
+    jobs: u32,
+    failures: u32,
+    until: Option<DateTime<Utc>>,
+}
+
+impl TypeHealth {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            since: now,
+            jobs: 0,
+            failures: 0,
+            until: None,
+        }
+    }
+}
+
+/// The per-type counts of a [`Quarantine`]
+#[derive(Debug)]
+pub(crate) struct QuarantineTracker {
+    policy: Quarantine,
+    types: Mutex<HashMap<String, TypeHealth>>,
+}
+
+impl QuarantineTracker {
+    pub(crate) fn new(policy: Quarantine) -> Self {
+        Self {
+            policy,
+            types: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a job of `job_type` that finished at `now`, returning the counts if this
+    /// quarantined the type
+    pub(crate) fn record(
+        &self,
+        job_type: &str,
+        failed: bool,
+        now: DateTime<Utc>,
+    ) -> Option<Quarantined> {
+        let mut types = self.types.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let health = types
+            .entry(job_type.to_string())
+            .or_insert_with(|| TypeHealth::new(now));
+        match health.until {
+            // Jobs that were already running when the type was quarantined
+            Some(until) if now < until => return None,
+            Some(_) => *health = TypeHealth::new(now),
+            None => {}
+        }
+        if (now - health.since).to_std().unwrap_or_default() > self.policy.window {
+            *health = TypeHealth::new(now);
+        }
+        health.jobs += 1;
+        health.failures += failed as u32;
+        let rate = health.failures as f64 / health.jobs as f64;
+        if !failed || health.jobs < self.policy.min_jobs || rate < self.policy.failure_rate {
+            return None;
+        }
+        let duration =
+            chrono::Duration::from_std(self.policy.duration).unwrap_or(chrono::Duration::MAX);
+        health.until = Some(
+            now.checked_add_signed(duration)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        );
+        Some(Quarantined {
+            jobs: health.jobs,
+            failures: health.failures,
+        })
+    }
+
+    /// How much longer `job_type` is quarantined as of `now`, `None` if it isn't
+    pub(crate) fn remaining(&self, job_type: &str, now: DateTime<Utc>) -> Option<Duration> {
+        let mut types = self.types.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let health = types.get_mut(job_type)?;
+        let until = health.until?;
+        if now < until {
+            return Some((until - now).to_std().unwrap_or_default());
+        }
+        // Give the type a clean slate
+        *health = TypeHealth::new(now);
+        tracing::info!("Quarantine of job type {} ended", job_type);
+        None
+    }
+}
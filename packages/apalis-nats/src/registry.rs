@@ -0,0 +1,79 @@
+use crate::status::{status_tracker, StatusTracker};
+use crate::storage::{ensure_streams, jetstream_context, Config, NatsPollError};
+use crate::NatsStorage;
+use async_nats::jetstream;
+use async_nats::Client;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Vends [`NatsStorage`] handles for many job types over one connection.
+///
+/// Storages from a registry share its `Client` and JetStream context, and the streams and
+/// status bucket of a namespace are set up by the first storage created for it. Later storages
+/// in the same namespace reuse them without any server round trips, so only the first config
+/// for a namespace decides its stream settings (replicas, DLQ, subject transform).
+///
+/// # Example
+/// ```rust,no_run
+/// use apalis_nats::{Config, NatsStorageRegistry};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Debug, Clone, Deserialize, Serialize)]
+/// struct Email { to: String }
+/// #[derive(Debug, Clone, Deserialize, Serialize)]
+/// struct Invoice { id: u64 }
+///
+/// # async fn demo() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = apalis_nats::connect("nats://localhost:4222").await?;
+/// let registry = NatsStorageRegistry::new(client);
+///
+/// let emails = registry.storage::<Email>(Config::default()).await?;
+/// let invoices = registry.storage::<Invoice>(Config::default()).await?; // no stream setup
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NatsStorageRegistry {
+    client: Client,
+    jetstream: jetstream::Context,
+    /// Status trackers of the namespaces set up so far, by JetStream domain and namespace
+    namespaces: Arc<tokio::sync::Mutex<HashMap<(Option<String>, String), Option<StatusTracker>>>>,
+}
+
+impl NatsStorageRegistry {
+    /// Create a registry over `client`
+    pub fn new(client: Client) -> Self {
+        Self {
+            jetstream: jetstream::new(client.clone()),
+            client,
+            namespaces: Default::default(),
+        }
+    }
+
+    /// Get a storage for `T` with `config`, setting up its namespace on first use
+    pub async fn storage<T>(&self, config: Config) -> Result<NatsStorage<T>, NatsPollError>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+    {
+        let jetstream = match config.jetstream_domain {
+            Some(_) => jetstream_context(&self.client, &config),
+            None => self.jetstream.clone(),
+        };
+
+        // Held across setup so concurrent callers don't set up the same namespace twice
+        let mut namespaces = self.namespaces.lock().await;
+        let key = (config.jetstream_domain.clone(), config.namespace.clone());
+        let status = match namespaces.get(&key) {
+            Some(status) => status.clone(),
+            None => {
+                ensure_streams(&jetstream, &config).await?;
+                let status = status_tracker(&jetstream, &config).await?;
+                namespaces.insert(key, status.clone());
+                status
+            }
+        };
+        drop(namespaces);
+
+        Ok(NatsStorage::from_parts(self.client.clone(), jetstream, config, status))
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+/// A cap on how fast failed jobs are retried, on top of the per-job `max_deliver`.
+///
+/// Every Nak (a transient failure with deliveries left) spends one retry. When the budget is
+/// spent, failing jobs are moved to the DLQ with reason `retry_budget_exhausted` (or
+/// terminated without a DLQ) instead of being retried, until it refills. During a broad
+/// outage this sends jobs to the DLQ sooner, in exchange for not spending the cluster's
+/// capacity on retries that are bound to fail.
+///
+/// The budget is shared by a storage and its clones, so by every worker in the process
+/// built from it. Separate processes each have their own.
+///
+/// # Example
+/// ```
+/// # use apalis_nats::{Config, RetryBudget};
+/// let config = Config {
+///     // Bursts of up to 100 retries, then 10 per second
+///     retry_budget: Some(RetryBudget::new(100, 10.0)),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryBudget {
+    /// The most retries that can be spent in a burst
+    pub budget: u32,
+    /// Retries added back to the budget per second
+    pub refill_per_sec: f64,
+}
+
+impl RetryBudget {
+    /// A budget of `budget` retries that refills at `refill_per_sec`
+    pub fn new(budget: u32, refill_per_sec: f64) -> Self {
+        Self {
+            budget,
+            refill_per_sec,
+        }
+    }
+}
+
+/// The retries left in a [`RetryBudget`]
+#[derive(Debug)]
+pub(crate) struct RetryTokens {
+    budget: RetryBudget,
+    /// Tokens left and when they were last refilled, by the storage's clock
+    state: Mutex<(f64, Option<DateTime<Utc>>)>,
+}
+
+impl RetryTokens {
+    pub(crate) fn new(budget: RetryBudget) -> Self {
+        Self {
+            budget,
+            state: Mutex::new((budget.budget as f64, None)),
+        }
+    }
+
+    /// Spend one retry at `now`, `false` when the budget is exhausted
+    pub(crate) fn try_acquire(&self, now: DateTime<Utc>) -> bool {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (tokens, refilled_at) = &mut *state;
+        let elapsed = refilled_at.map_or(0.0, |at| (now - at).as_seconds_f64());
+        let refill = elapsed * self.budget.refill_per_sec;
+        *tokens = (*tokens + refill.max(0.0)).min(self.budget.budget as f64);
+        *refilled_at = Some(now);
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use crate::storage::{Config, NatsJob, NatsPollError};
+use crate::{NatsStorage, Priority};
+use apalis_core::task::attempt::Attempt;
+use apalis_core::task::namespace::Namespace;
+use apalis_core::task::task_id::TaskId;
+use async_nats::header::NATS_MESSAGE_ID;
+use async_nats::jetstream::kv;
+use async_nats::HeaderMap;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often workers look for due scheduled jobs
+const PROMOTE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A job waiting in the `{namespace}_scheduled` bucket, see [`ScheduledJobs`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledJob<T> {
+    /// The task id, kept when the job is enqueued
+    pub task_id: TaskId,
+    /// When the job becomes due
+    pub run_at: DateTime<Utc>,
+    /// The priority the job is enqueued with
+    pub priority: Priority,
+    /// The job itself
+    pub job: T,
+    /// Set once a worker started enqueueing the job, it can't be cancelled anymore
+    #[serde(default)]
+    pub promoting: bool,
+}
+
+/// Just the claim marker of a [`ScheduledJob`], whatever its job type
+#[derive(Deserialize)]
+struct ScheduleState {
+    #[serde(default)]
+    promoting: bool,
+}
+
+/// The KV bucket holding scheduled jobs until they are due
+pub(crate) fn scheduled_bucket_config(config: &Config) -> kv::Config {
+    kv::Config {
+        bucket: format!("{}_scheduled", config.namespace),
+        history: 1,
+        num_replicas: config.num_replicas,
+        ..Default::default()
+    }
+}
+
+/// Jobs scheduled to run at a later time, kept in a `{namespace}_scheduled` KV bucket.
+///
+/// Workers polling the storage check the bucket every second and enqueue due jobs on their
+/// priority stream, keeping their task id. Every worker does this, so a job is claimed with a
+/// revision-checked update before it is published: only one worker enqueues it, and a job
+/// being enqueued can no longer be cancelled. A worker that dies between claiming and
+/// publishing leaves the claim behind, and the next check finishes the job off; the publish
+/// carries a `Nats-Msg-Id` so this doesn't enqueue it twice. A job that can never be enqueued,
+/// e.g. because its priority is no longer enabled, is moved to the DLQ as `unschedulable`
+/// instead of holding up the jobs due after it.
+///
+/// Listing reads every entry of the bucket, which suits up to a few thousand scheduled jobs.
+///
+/// # Example
+/// ```rust,no_run
+/// # use apalis_nats::NatsStorage;
+/// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+/// let scheduled = storage.scheduled_jobs().await?;
+/// let task_id = scheduled
+///     .schedule("reminder".to_string(), chrono::Utc::now() + chrono::Duration::hours(1))
+///     .await?;
+/// for job in scheduled.list_pending().await? {
+///     println!("{} runs at {}", job.task_id, job.run_at);
+/// }
+/// scheduled.cancel(&task_id).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ScheduledJobs<T> {
+    storage: NatsStorage<T>,
+    store: kv::Store,
+    /// Entries already warned about as undecodable, by key and revision
+    undecodable: Arc<Mutex<HashSet<(String, u64)>>>,
+}
+
+impl<T> fmt::Debug for ScheduledJobs<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScheduledJobs")
+            .field("storage", &self.storage)
+            .field("store", &self.store.name)
+            .finish()
+    }
+}
+
+impl<T> Clone for ScheduledJobs<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            store: self.store.clone(),
+            undecodable: Arc::clone(&self.undecodable),
+        }
+    }
+}
+
+impl<T> NatsStorage<T> {
+    /// Get the scheduled jobs of this storage's namespace, creating their bucket if needed
+    pub async fn scheduled_jobs(&self) -> Result<ScheduledJobs<T>, NatsPollError> {
+        let bucket = format!("{}_scheduled", self.config.namespace);
+        let store = match self.jetstream.get_key_value(&bucket).await {
+            Ok(store) => store,
+            Err(_) => {
+                let store = self
+                    .jetstream
+                    .create_key_value(scheduled_bucket_config(&self.config))
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                tracing::info!("Scheduled jobs bucket {} ready", bucket);
+                store
+            }
+        };
+        Ok(ScheduledJobs {
+            storage: self.clone(),
+            store,
+            undecodable: Default::default(),
+        })
+    }
+}
+
+impl<T> ScheduledJobs<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Schedule a job to be enqueued with the default priority at `at`
+    pub async fn schedule(&self, job: T, at: DateTime<Utc>) -> Result<TaskId, NatsPollError> {
+        self.schedule_with_priority(job, Priority::default(), at).await
+    }
+
+    /// Schedule a job to be enqueued with `priority` at `at`. A time in the past makes it due
+    /// right away.
+    pub async fn schedule_with_priority(
+        &self,
+        job: T,
+        priority: Priority,
+        at: DateTime<Utc>,
+    ) -> Result<TaskId, NatsPollError> {
+        self.schedule_as(TaskId::new(), job, priority, at).await
+    }
+
+    /// Schedule a job under an existing task id, e.g. one rescheduled after `max_deliver`
+    pub(crate) async fn schedule_as(
+        &self,
+        task_id: TaskId,
+        job: T,
+        priority: Priority,
+        at: DateTime<Utc>,
+    ) -> Result<TaskId, NatsPollError> {
+        self.storage.config.check_priority(priority)?;
+        let scheduled = ScheduledJob {
+            task_id: task_id.clone(),
+            run_at: at,
+            priority,
+            job,
+            promoting: false,
+        };
+        self.store
+            .create(task_id.to_string(), serde_json::to_vec(&scheduled)?.into())
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        Ok(task_id)
+    }
+
+    /// Cancel a scheduled job.
+    ///
+    /// Returns `false` when there is nothing to cancel: the id is unknown, or the job was
+    /// already enqueued or is being enqueued. The entry is removed with a revision check, so
+    /// a worker enqueueing the job at the same time either sees it gone or makes this return
+    /// `false`, never both.
+    pub async fn cancel(&self, task_id: &TaskId) -> Result<bool, NatsPollError> {
+        let key = task_id.to_string();
+        let Some((state, revision)) = self.state(&key).await? else {
+            return Ok(false);
+        };
+        if state.promoting {
+            return Ok(false);
+        }
+        match self.store.delete_expect_revision(&key, Some(revision)).await {
+            Ok(()) => Ok(true),
+            Err(e) => match self.state(&key).await? {
+                // Changed in the meantime: claimed by a worker, or cancelled by someone else
+                Some((_, current)) if current == revision => {
+                    Err(NatsPollError::Nats(e.to_string()))
+                }
+                _ => Ok(false),
+            },
+        }
+    }
+
+    /// Every scheduled job, soonest first. Jobs that don't decode into `T` are skipped.
+    pub async fn list_pending(&self) -> Result<Vec<ScheduledJob<T>>, NatsPollError> {
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .map(|(scheduled, _)| scheduled)
+            .collect())
+    }
+
+    /// The scheduled jobs due at or before `before`, soonest first
+    pub async fn list_due(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledJob<T>>, NatsPollError> {
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .map(|(scheduled, _)| scheduled)
+            .filter(|scheduled| scheduled.run_at <= before)
+            .collect())
+    }
+
+    /// Enqueue every due job on its priority stream, returning how many were enqueued
+    pub(crate) async fn promote_due(&self) -> Result<usize, NatsPollError> {
+        let now = self.storage.clock.now();
+        let mut promoted = 0;
+        for (mut scheduled, revision) in self.entries().await? {
+            if scheduled.run_at > now {
+                break; // Sorted by due time
+            }
+            let key = scheduled.task_id.to_string();
+            if !scheduled.promoting {
+                // Claim it first, so a concurrent cancel or worker can't act on it too
+                scheduled.promoting = true;
+                let claim = serde_json::to_vec(&scheduled)?;
+                if self.store.update(&key, claim.into(), revision).await.is_err() {
+                    continue;
+                }
+            }
+
+            // Unique per schedule, so a job rescheduled under its task id isn't a duplicate
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                NATS_MESSAGE_ID,
+                format!("scheduled-{}-{}", key, scheduled.run_at.timestamp_millis()),
+            );
+            let ScheduledJob {
+                task_id,
+                priority,
+                job,
+                ..
+            } = scheduled;
+            match self
+                .storage
+                .push_with_headers(Some(task_id.clone()), job, priority, headers)
+                .await
+            {
+                Ok(_) => {}
+                // Retrying won't help, move it aside so it doesn't hold up the jobs due after it
+                Err(
+                    e @ (NatsPollError::PayloadTooLarge { .. }
+                    | NatsPollError::Storage(_)
+                    | NatsPollError::Serialization(_)),
+                ) => {
+                    tracing::error!("Scheduled job {} can't be enqueued: {}", key, e);
+                    self.dead_letter(&key, e).await;
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to enqueue scheduled job {}, will retry: {}", key, e);
+                    continue;
+                }
+            }
+            if let Err(e) = self.store.delete(&key).await {
+                tracing::warn!("Failed to remove promoted scheduled job {}: {}", key, e);
+            }
+            tracing::debug!("Enqueued scheduled job {}", task_id);
+            promoted += 1;
+        }
+        Ok(promoted)
+    }
+
+    /// Move a claimed job that can't be enqueued to the DLQ as `unschedulable` and remove it
+    async fn dead_letter(&self, key: &str, error: NatsPollError) {
+        // The push consumed the job, read it back from its claimed entry
+        let scheduled = match self.store.get(key).await {
+            Ok(Some(value)) => serde_json::from_slice::<ScheduledJob<T>>(&value),
+            Ok(None) => return, // Removed in the meantime
+            Err(e) => {
+                tracing::warn!("Failed to read scheduled job {}: {}", key, e);
+                return;
+            }
+        };
+        let scheduled = match scheduled {
+            Ok(scheduled) => scheduled,
+            Err(e) => {
+                tracing::warn!("Failed to decode scheduled job {}: {}", key, e);
+                return;
+            }
+        };
+        let job = NatsJob {
+            id: scheduled.task_id,
+            data: scheduled.job,
+            priority: scheduled.priority,
+            attempts: Attempt::new(),
+            created_at: scheduled.run_at,
+            namespace: Namespace::from(self.storage.config.namespace.clone()),
+        };
+        if let Err(e) = self
+            .storage
+            .dead_letter_unpublished(&job, "unschedulable", error.to_string())
+            .await
+        {
+            tracing::warn!("Failed to dead-letter scheduled job {}: {}", key, e);
+            return;
+        }
+        if let Err(e) = self.store.delete(key).await {
+            tracing::warn!("Failed to remove dead-lettered scheduled job {}: {}", key, e);
+        }
+    }
+
+    /// The claim state and revision of a scheduled job, `None` if there is none
+    async fn state(&self, key: &str) -> Result<Option<(ScheduleState, u64)>, NatsPollError> {
+        let entry = self
+            .store
+            .entry(key)
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        match entry {
+            Some(entry) if entry.operation == kv::Operation::Put => {
+                Ok(Some((serde_json::from_slice(&entry.value)?, entry.revision)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Every decodable scheduled job with its revision, soonest first
+    async fn entries(&self) -> Result<Vec<(ScheduledJob<T>, u64)>, NatsPollError> {
+        let keys: Vec<String> = self
+            .store
+            .keys()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            let entry = self
+                .store
+                .entry(&key)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            let Some(entry) = entry.filter(|entry| entry.operation == kv::Operation::Put) else {
+                continue; // Removed since the keys were listed
+            };
+            match serde_json::from_slice::<ScheduledJob<T>>(&entry.value) {
+                Ok(scheduled) => entries.push((scheduled, entry.revision)),
+                Err(e) => {
+                    // Checked again every second, only the first time is news
+                    let first = self
+                        .undecodable
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .insert((key.clone(), entry.revision));
+                    if first {
+                        tracing::warn!("Skipping undecodable scheduled job {}: {}", key, e);
+                    }
+                }
+            }
+        }
+        entries.sort_by_key(|(scheduled, _)| scheduled.run_at);
+        Ok(entries)
+    }
+}
+
+impl<T> NatsStorage<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Enqueue due scheduled jobs every [`PROMOTE_INTERVAL`] until aborted.
+    /// Does nothing until something was scheduled, so namespaces without scheduled jobs
+    /// don't get a bucket.
+    pub(crate) async fn promote_scheduled(self) {
+        let bucket = format!("{}_scheduled", self.config.namespace);
+        let mut scheduled = None;
+        let mut ticker = tokio::time::interval(PROMOTE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if scheduled.is_none() {
+                match self.jetstream.get_key_value(&bucket).await {
+                    Ok(store) => {
+                        scheduled = Some(ScheduledJobs {
+                            storage: self.clone(),
+                            store,
+                            undecodable: Default::default(),
+                        })
+                    }
+                    Err(_) => continue,
+                }
+            }
+            let Some(scheduled) = &scheduled else {
+                continue;
+            };
+            match scheduled.promote_due().await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Enqueued {} scheduled jobs", n),
+                Err(e) => tracing::warn!("Failed to enqueue scheduled jobs: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::storage::{Config, NatsPollError};
+use crate::NatsContext;
+use apalis_core::error::Error;
+use apalis_core::task::task_id::TaskId;
+use async_nats::jetstream::{self, kv};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// How long a lookup that found no state bucket is trusted before the next one asks again
+const MISSING_BUCKET_RECHECK: Duration = Duration::from_secs(5);
+
+/// The KV bucket holding the state handlers store for their jobs
+pub(crate) fn state_bucket_config(config: &Config) -> kv::Config {
+    kv::Config {
+        bucket: format!("{}_state", config.namespace),
+        history: 1,
+        max_age: Duration::from_secs(7 * 24 * 60 * 60), // 7 days, same as the job streams
+        num_replicas: config.num_replicas,
+        placement: config.placement.clone(),
+        storage: config.storage_type,
+        ..Default::default()
+    }
+}
+
+/// The `{namespace}_state` bucket of a storage, looked up or created on first use
+#[derive(Debug)]
+pub(crate) struct StateBucket {
+    jetstream: jetstream::Context,
+    config: kv::Config,
+    create: bool,
+    store: OnceCell<kv::Store>,
+    /// When a lookup last found no bucket
+    missing_since: Mutex<Option<Instant>>,
+}
+
+impl StateBucket {
+    pub(crate) fn new(jetstream: jetstream::Context, config: &Config) -> Self {
+        Self {
+            jetstream,
+            config: state_bucket_config(config),
+            create: config.create_streams,
+            store: OnceCell::new(),
+            missing_since: Mutex::new(None),
+        }
+    }
+
+    /// The bucket if it exists, without creating it. A missing bucket is only looked up again
+    /// after `MISSING_BUCKET_RECHECK`, so namespaces that never store state don't pay a round
+    /// trip per job.
+    async fn existing(&self) -> Option<&kv::Store> {
+        if let Some(store) = self.store.get() {
+            return Some(store);
+        }
+        {
+            let missing_since = self.missing_since.lock().unwrap_or_else(|e| e.into_inner());
+            if missing_since.is_some_and(|since| since.elapsed() < MISSING_BUCKET_RECHECK) {
+                return None;
+            }
+        }
+        match self.jetstream.get_key_value(&self.config.bucket).await {
+            Ok(store) => Some(self.store.get_or_init(|| async { store }).await),
+            Err(_) => {
+                *self.missing_since.lock().unwrap_or_else(|e| e.into_inner()) =
+                    Some(Instant::now());
+                None
+            }
+        }
+    }
+
+    async fn store(&self) -> Result<&kv::Store, NatsPollError> {
+        self.store
+            .get_or_try_init(|| async {
+                if let Ok(store) = self.jetstream.get_key_value(&self.config.bucket).await {
+                    return Ok(store);
+                }
+                if !self.create {
+                    return Err(NatsPollError::Storage(format!(
+                        "state bucket {} does not exist and `create_streams` is off",
+                        self.config.bucket
+                    )));
+                }
+                let store = self
+                    .jetstream
+                    .create_key_value(self.config.clone())
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                tracing::info!("State bucket {} ready", self.config.bucket);
+                Ok(store)
+            })
+            .await
+    }
+}
+
+/// The state entry of the job a [`NatsContext`] was delivered for
+#[derive(Debug, Clone)]
+pub(crate) struct JobState {
+    pub(crate) bucket: Arc<StateBucket>,
+    pub(crate) task_id: TaskId,
+}
+
+impl JobState {
+    pub(crate) fn new(bucket: Arc<StateBucket>, task_id: TaskId) -> Self {
+        Self { bucket, task_id }
+    }
+}
+
+impl NatsContext {
+    /// Read the state stored for this job by [`state_put`](NatsContext::state_put), e.g. by an
+    /// earlier attempt that failed midway, so a retry can resume where it left off.
+    ///
+    /// State lives in the `{namespace}_state` KV bucket, keyed by task id, and is removed once
+    /// the job succeeds, whether or not the attempt that succeeded touched it. Returns `None`
+    /// if nothing was stored, or without a NATS message.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsContext;
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Default, Serialize, Deserialize)]
+    /// struct Progress { imported: usize }
+    ///
+    /// # async fn import_row(_row: &String) -> Result<(), Error> { Ok(()) }
+    /// async fn import(rows: Vec<String>, ctx: NatsContext) -> Result<(), Error> {
+    ///     let mut progress: Progress = ctx.state_get().await?.unwrap_or_default();
+    ///     for row in &rows[progress.imported..] {
+    ///         import_row(row).await?;
+    ///         progress.imported += 1;
+    ///         ctx.state_put(&progress).await?;
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn state_get<S: DeserializeOwned>(&self) -> Result<Option<S>, Error> {
+        let Some(state) = self.job_state() else {
+            return Ok(None);
+        };
+        let store = state.bucket.store().await.map_err(source_error)?;
+        let value = store
+            .get(state.task_id.to_string())
+            .await
+            .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+        value
+            .map(|value| serde_json::from_slice(&value))
+            .transpose()
+            .map_err(|e| Error::SourceError(Arc::new(e.into())))
+    }
+
+    /// Store `state` for this job, replacing what was stored before. Retries of the job read
+    /// it back with [`state_get`](NatsContext::state_get).
+    ///
+    /// The entry is removed once the job succeeds, even by a later attempt that never reads it,
+    /// and otherwise expires after 7 days, so state of dead-lettered jobs is kept for
+    /// inspection. Does nothing without a NATS message.
+    pub async fn state_put<S: Serialize>(&self, state: &S) -> Result<(), Error> {
+        let Some(job) = self.job_state() else {
+            return Ok(());
+        };
+        let value = serde_json::to_vec(state).map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+        let store = job.bucket.store().await.map_err(source_error)?;
+        store
+            .put(job.task_id.to_string(), Bytes::from(value))
+            .await
+            .map_err(|e| Error::SourceError(Arc::new(e.into())))?;
+        Ok(())
+    }
+
+    fn job_state(&self) -> Option<&JobState> {
+        self.message.as_ref()?;
+        self.state.as_ref()
+    }
+
+    /// Remove the state of a job that succeeded, which an earlier attempt may have stored even
+    /// if this one didn't. Failures are logged, the job is done anyway.
+    pub(crate) async fn clear_state(&self) {
+        let Some(state) = self.job_state() else {
+            return;
+        };
+        // Without a bucket no job in the namespace has state
+        let Some(store) = state.bucket.existing().await else {
+            return;
+        };
+        let key = state.task_id.to_string();
+        // A purge stores a marker even for a missing key, so only purge what is there
+        let result = match store.get(&key).await {
+            Ok(Some(_)) => store.purge(&key).await.map_err(|e| e.to_string()),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to remove the state of task {}: {}", state.task_id, e);
+        }
+    }
+}
+
+fn source_error(e: NatsPollError) -> Error {
+    Error::SourceError(Arc::new(Box::new(e)))
+}
@@ -0,0 +1,263 @@
+use crate::storage::{Config, NatsPollError};
+use crate::NatsStorage;
+use apalis_core::task::task_id::TaskId;
+use async_nats::jetstream::{self, kv};
+use async_nats::RequestErrorKind;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The lifecycle state of a job, as tracked in the `{namespace}_status` KV bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Published and waiting for a worker
+    Pending,
+    /// Delivered to a worker and not yet acked
+    Running,
+    /// The handler succeeded and the message was acked
+    Completed,
+    /// The last attempt failed and the job is waiting to be retried
+    Failed,
+    /// Moved to the DLQ, terminated or skipped by a filter, it won't be retried
+    Dead,
+}
+
+/// The KV bucket holding the status of each job
+pub(crate) fn status_bucket_config(config: &Config) -> kv::Config {
+    kv::Config {
+        bucket: format!("{}_status", config.namespace),
+        history: 1,
+        max_age: Duration::from_secs(7 * 24 * 60 * 60), // 7 days, same as the job streams
+        num_replicas: config.num_replicas,
+        placement: config.placement.clone(),
+        storage: config.storage_type,
+        ..Default::default()
+    }
+}
+
+/// The error for status queries while `Config::track_status` is off
+fn status_off() -> NatsPollError {
+    NatsPollError::Storage("Job status tracking is off, see `Config::track_status`".to_string())
+}
+
+/// Get or create the KV bucket holding the status of each job
+pub(crate) async fn ensure_status_bucket(
+    jetstream: &jetstream::Context,
+    config: &Config,
+) -> Result<kv::Store, NatsPollError> {
+    let bucket = format!("{}_status", config.namespace);
+    if let Ok(store) = jetstream.get_key_value(&bucket).await {
+        return Ok(store);
+    }
+    if !config.create_streams {
+        return Err(NatsPollError::Storage(format!(
+            "status bucket {} does not exist and `create_streams` is off",
+            bucket
+        )));
+    }
+    let store = jetstream
+        .create_key_value(status_bucket_config(config))
+        .await
+        .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+    tracing::info!("Status bucket {} ready", bucket);
+    Ok(store)
+}
+
+/// The status bucket of a namespace, with the background task recording the statuses of
+/// delivered jobs, see `Config::track_status`
+#[derive(Debug, Clone)]
+pub(crate) struct StatusTracker {
+    store: kv::Store,
+    updates: mpsc::UnboundedSender<(TaskId, JobStatus)>,
+}
+
+impl StatusTracker {
+    /// Record updates in order on a background task, which ends with the last clone
+    pub(crate) fn new(store: kv::Store) -> Self {
+        let (updates, mut pending) = mpsc::unbounded::<(TaskId, JobStatus)>();
+        let writer = store.clone();
+        tokio::spawn(async move {
+            while let Some((task_id, status)) = pending.next().await {
+                put_status(&writer, &task_id, status).await;
+            }
+        });
+        Self { store, updates }
+    }
+}
+
+/// Write the status of a job. Failures are logged, they never fail the job itself.
+async fn put_status(store: &kv::Store, task_id: &TaskId, status: JobStatus) {
+    let value = match serde_json::to_vec(&status) {
+        Ok(value) => Bytes::from(value),
+        Err(e) => {
+            tracing::error!("Failed to serialize status for task {}: {}", task_id, e);
+            return;
+        }
+    };
+    if let Err(e) = store.put(task_id.to_string(), value).await {
+        tracing::error!("Failed to record status for task {}: {}", task_id, e);
+    }
+}
+
+/// The status tracker of a storage with `config`, `None` unless `Config::track_status` is on
+pub(crate) async fn status_tracker(
+    jetstream: &jetstream::Context,
+    config: &Config,
+) -> Result<Option<StatusTracker>, NatsPollError> {
+    if !config.track_status {
+        return Ok(None);
+    }
+    let store = ensure_status_bucket(jetstream, config).await?;
+    Ok(Some(StatusTracker::new(store)))
+}
+
+impl<T> NatsStorage<T> {
+    /// Record the status of a job before it's published, so it can be queried as soon as the
+    /// push returns. Does nothing unless `Config::track_status` is on.
+    pub(crate) async fn set_status(&self, task_id: &TaskId, status: JobStatus) {
+        if let Some(tracker) = &self.status {
+            put_status(&tracker.store, task_id, status).await;
+        }
+    }
+
+    /// Remove the status of a job whose publish was not stored
+    pub(crate) async fn clear_status(&self, task_id: &TaskId) {
+        if let Some(tracker) = &self.status {
+            if let Err(e) = tracker.store.purge(task_id.to_string()).await {
+                tracing::error!("Failed to clear status for task {}: {}", task_id, e);
+            }
+        }
+    }
+
+    /// Record the status of a delivered job without waiting for the write, which happens in
+    /// order with the job's other updates. Does nothing unless `Config::track_status` is on.
+    pub(crate) fn update_status(&self, task_id: &TaskId, status: JobStatus) {
+        if let Some(tracker) = &self.status {
+            // Only fails once the writer is gone, i.e. the runtime is shutting down
+            let _ = tracker.updates.unbounded_send((task_id.clone(), status));
+        }
+    }
+
+    /// Read the status of a job straight from the status bucket
+    async fn read_status(&self, task_id: &str) -> Result<Option<Bytes>, NatsPollError> {
+        let Some(tracker) = &self.status else {
+            return Err(status_off());
+        };
+        tracker
+            .store
+            .get(task_id)
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))
+    }
+
+    /// Answer status requests on `{namespace}.status` until the subscription ends.
+    ///
+    /// Requests carry a task id as payload and are answered with the JSON encoded
+    /// [`JobStatus`], or `null` for unknown ids. Workers share a queue group so each request
+    /// gets a single reply.
+    pub(crate) async fn serve_status(&self) -> Result<(), NatsPollError> {
+        // Without tracking there is nothing to answer, `query_status` fails on the asking side
+        if self.status.is_none() {
+            return Ok(());
+        }
+        let mut requests = self
+            .client
+            .queue_subscribe(
+                format!("{}.status", self.config.namespace),
+                format!("{}_status", self.config.namespace),
+            )
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+
+        while let Some(request) = requests.next().await {
+            let Some(reply) = request.reply else {
+                continue;
+            };
+            let task_id = String::from_utf8_lossy(&request.payload).trim().to_string();
+            let status = match self.read_status(&task_id).await {
+                Ok(Some(status)) => status,
+                Ok(None) => Bytes::from_static(b"null"),
+                Err(e) => {
+                    tracing::error!("Failed to read status for task {}: {}", task_id, e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.client.publish(reply, status).await {
+                tracing::debug!("Failed to reply to status request: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// A fresh, unique subject to receive replies on, e.g. to correlate a job with its result.
+    ///
+    /// Subscribe to it *before* publishing the request that names it as the reply subject. A
+    /// responder can answer as soon as the request is published, and the server drops replies
+    /// to a subject nobody is subscribed to yet, so subscribing afterwards loses fast replies
+    /// at random, more often the lower the latency. Subscribing first on the same connection
+    /// is enough: the server handles the subscription before the request. `Client::request`
+    /// (and so [`NatsStorage::query_status`]) already does this.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # use futures::StreamExt;
+    /// # async fn example(storage: NatsStorage<String>, client: async_nats::Client)
+    /// #     -> Result<(), Box<dyn std::error::Error>> {
+    /// let inbox = storage.new_inbox();
+    /// // Subscribe first, or the reply can arrive before anyone listens for it
+    /// let mut replies = client.subscribe(inbox.clone()).await?;
+    /// client.publish_with_reply("my_app.status", inbox, "01J...".into()).await?;
+    /// let reply = replies.next().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_inbox(&self) -> String {
+        self.client.new_inbox()
+    }
+
+    /// Ask the workers for the current status of a job over `{namespace}.status`.
+    ///
+    /// When no worker is running to answer, the status bucket is read directly.
+    /// Returns an error for task ids the storage has no record of, and when
+    /// `Config::track_status` is off. The reply inbox is subscribed before the request is
+    /// published, see [`NatsStorage::new_inbox`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{JobStatus, NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let task_id = storage.push_with_priority("report".to_string(), Priority::Medium).await?;
+    /// if storage.query_status(&task_id).await? == JobStatus::Completed {
+    ///     println!("report is ready");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn query_status(&self, task_id: &TaskId) -> Result<JobStatus, NatsPollError> {
+        if self.status.is_none() {
+            return Err(status_off());
+        }
+        let subject = format!("{}.status", self.config.namespace);
+        let payload = match self
+            .client
+            .request(subject, Bytes::from(task_id.to_string()))
+            .await
+        {
+            Ok(reply) => Some(reply.payload),
+            Err(e) if e.kind() == RequestErrorKind::NoResponders => {
+                self.read_status(&task_id.to_string()).await?
+            }
+            Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+        };
+
+        payload
+            .map(|payload| serde_json::from_slice::<Option<JobStatus>>(&payload))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| NatsPollError::Storage(format!("Unknown task {}", task_id)))
+    }
+}
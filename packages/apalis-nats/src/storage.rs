@@ -1,3 +1,14 @@
+use crate::clock::{Clock, SystemClock};
+use crate::dlq::{dlq_size_limit, entry_payload, DlqEntry, DlqError};
+use crate::layers::{NatsLayers, ProcessSpanLayer};
+use crate::quarantine::{Quarantine, QuarantineTracker};
+use crate::retry_budget::{RetryBudget, RetryTokens};
+use crate::scheduled::scheduled_bucket_config;
+use crate::state::{state_bucket_config, JobState, StateBucket};
+use crate::status::{
+    ensure_status_bucket, status_bucket_config, status_tracker, JobStatus, StatusTracker,
+};
+use crate::watchdog::LeaseWatchdog;
 use apalis_core::backend::Backend;
 use apalis_core::codec::json::JsonCodec;
 use apalis_core::codec::Codec;
@@ -10,22 +21,34 @@ use apalis_core::storage::Storage;
 use apalis_core::task::attempt::Attempt;
 use apalis_core::task::namespace::Namespace;
 use apalis_core::task::task_id::TaskId;
-use apalis_core::worker::{Context as WorkerContext, Worker};
-use async_nats::jetstream::{self, consumer, stream};
+use apalis_core::worker::{Context as WorkerContext, Event, Worker};
+use async_nats::jetstream::context::{
+    GetStreamError, GetStreamErrorKind, PublishErrorKind, RequestError, RequestErrorKind,
+};
+use async_nats::jetstream::stream::{
+    ConsumerError, ConsumerErrorKind, Placement, RetentionPolicy, Source, StorageType,
+};
+use async_nats::jetstream::{self, consumer, kv, stream, ErrorCode};
+use async_nats::header::{NATS_EXPECTED_LAST_SUBJECT_SEQUENCE, NATS_MESSAGE_ID};
 use async_nats::{Client, ConnectError, HeaderMap};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use chrono::{DateTime, Utc};
 use futures::channel::mpsc::{self, Sender};
 use futures::stream::BoxStream;
 use futures::{SinkExt, StreamExt, TryStreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::marker::PhantomData;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tower::layer::util::Stack;
 
 #[cfg(feature = "otel")]
 use opentelemetry::trace::{Span as OtelSpan, SpanKind, Status, Tracer};
@@ -65,26 +88,376 @@ impl Default for Priority {
     }
 }
 
+/// Where a priority consumer starts delivering from when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeliverPolicy {
+    /// Every message still in the stream (the default)
+    #[default]
+    All,
+    /// Only messages published after the consumer is created
+    New,
+    /// Messages published at or after the given time
+    ByStartTime(DateTime<Utc>),
+    /// Messages from the given stream sequence onwards
+    ByStartSequence(u64),
+}
+
+impl DeliverPolicy {
+    fn to_consumer_policy(self) -> Result<consumer::DeliverPolicy, NatsPollError> {
+        Ok(match self {
+            DeliverPolicy::All => consumer::DeliverPolicy::All,
+            DeliverPolicy::New => consumer::DeliverPolicy::New,
+            DeliverPolicy::ByStartTime(at) => {
+                let nanos = i128::from(at.timestamp()) * 1_000_000_000
+                    + i128::from(at.timestamp_subsec_nanos());
+                consumer::DeliverPolicy::ByStartTime {
+                    start_time: time::OffsetDateTime::from_unix_timestamp_nanos(nanos)
+                        .map_err(|e| NatsPollError::Storage(e.to_string()))?,
+                }
+            }
+            DeliverPolicy::ByStartSequence(start_sequence) => {
+                consumer::DeliverPolicy::ByStartSequence { start_sequence }
+            }
+        })
+    }
+}
+
+/// How the priority consumers expect messages to be acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AckPolicy {
+    /// Every message is acked on its own (the default)
+    #[default]
+    Explicit,
+    /// Acking a message also acks every message delivered before it. Fewer acks for the server
+    /// to track, but a job finishing early acks jobs still running, which are then lost if
+    /// they fail. Only use it with a concurrency of 1. Naks and Terms still apply to one message.
+    All,
+}
+
+impl AckPolicy {
+    fn to_consumer_policy(self) -> consumer::AckPolicy {
+        match self {
+            AckPolicy::Explicit => consumer::AckPolicy::Explicit,
+            AckPolicy::All => consumer::AckPolicy::All,
+        }
+    }
+}
+
+/// How fast the priority consumers deliver the messages already in their stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayPolicy {
+    /// As fast as workers pull them (the default)
+    #[default]
+    Instant,
+    /// At the pace they were originally published, by their stored timestamps, e.g. to replay
+    /// a captured workload with its real-world timing. Messages published after the consumer
+    /// caught up are delivered right away.
+    Original,
+}
+
+impl ReplayPolicy {
+    fn to_consumer_policy(self) -> consumer::ReplayPolicy {
+        match self {
+            ReplayPolicy::Instant => consumer::ReplayPolicy::Instant,
+            ReplayPolicy::Original => consumer::ReplayPolicy::Original,
+        }
+    }
+}
+
+/// How workers wait for jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PollMode {
+    /// Short fetches on each priority, sleeping 100ms between rounds when idle (the default)
+    #[default]
+    BusyLoop,
+    /// Pull requests that wait on the server: `fetch_expiry` for High and Medium, then
+    /// `long_poll_expiry` for Low. Idle workers block instead of spinning.
+    LongPoll,
+}
+
+/// What a worker does when a priority stream it consumes is deleted while it runs, e.g. by
+/// another process tearing the namespace down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnStreamMissing {
+    /// Re-create the streams and consumers and keep consuming (the default)
+    #[default]
+    Recreate,
+    /// Stop the worker after reporting [`NatsPollError::StreamNotFound`] as a worker error
+    /// event, so the process can exit or alert
+    Fail,
+}
+
+/// What happens to a job still failing when it has been delivered `max_deliver` times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnMaxDeliver {
+    /// Move it to the DLQ, or terminate it without a DLQ (the default)
+    #[default]
+    DeadLetter,
+    /// Schedule it to be pushed again after the given delay, e.g. for a daily retry, with a
+    /// fresh `max_deliver` deliveries. It never reaches the DLQ this way.
+    Reschedule(Duration),
+}
+
+/// Republishes every job stored in a priority stream to another subject, e.g. for an audit
+/// mirror, see [`Config::republish`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepublishConfig {
+    /// The subject copies are published to. `{namespace}` and `{priority}` are replaced with
+    /// the job's namespace and priority, e.g. `audit.{namespace}.{priority}`.
+    pub destination: String,
+    /// Only republish the headers, with the payload size in a `Nats-Msg-Size` header
+    pub headers_only: bool,
+}
+
+impl RepublishConfig {
+    /// Republish whole jobs to `destination`, see [`RepublishConfig::destination`]
+    pub fn new(destination: impl Into<String>) -> Self {
+        Self {
+            destination: destination.into(),
+            headers_only: false,
+        }
+    }
+
+    /// Only republish the headers, leaving out the payload
+    pub fn headers_only(mut self, headers_only: bool) -> Self {
+        self.headers_only = headers_only;
+        self
+    }
+
+    /// The destination for the stream of `priority` in `namespace`
+    fn destination_for(&self, namespace: &str, priority: Priority) -> String {
+        self.destination
+            .replace("{namespace}", namespace)
+            .replace("{priority}", &priority.to_string())
+    }
+}
+
 /// Configuration for NATS storage
 #[derive(Debug, Clone)]
 pub struct Config {
-    /// The namespace for all streams (e.g., "apalis")
+    /// The namespace for all streams (e.g., "apalis"). It is the first token of every subject,
+    /// so it can't be empty or contain '.', '*', '>' or whitespace.
     pub namespace: String,
     /// Maximum number of delivery attempts before moving to DLQ
     pub max_deliver: i64,
+    /// What happens to a job that failed its `max_deliver`th delivery, see [`OnMaxDeliver`].
+    /// Jobs dead-lettered for another reason (an `Error::Abort`, `retry_budget` or
+    /// `max_retry_duration`) still go to the DLQ.
+    pub on_max_deliver: OnMaxDeliver,
+    /// How long after its `created_at` a failing job is still retried. A job failing after that
+    /// is moved to the DLQ with reason `max_retry_duration_exceeded` (or terminated without a
+    /// DLQ), even if it has deliveries left under `max_deliver`. `None` only bounds by count.
+    pub max_retry_duration: Option<Duration>,
+    /// How long a job of each priority may wait for its first delivery, measured from its
+    /// `created_at`. A job delivered later logs a warning, emits a worker `Custom` event
+    /// starting with `sla_breach` and, with the `metrics` feature, counts towards
+    /// `apalis_nats_sla_breaches_total`. Priorities without an entry have no SLA.
+    pub sla: HashMap<Priority, Duration>,
     /// Ack wait time (how long to wait for a job to be acknowledged)
     pub ack_wait: Duration,
     /// Number of replicas for streams
     pub num_replicas: usize,
+    /// Pin the priority streams, the DLQ stream and the status bucket to a cluster and/or
+    /// servers with the given tags, e.g. to keep a namespace's jobs in one region for data
+    /// residency. Only honored by clustered servers, and only when the streams are created.
+    /// `None` lets the server place them.
+    pub placement: Option<Placement>,
+    /// Where the streams and the status bucket keep their messages. [`StorageType::Memory`]
+    /// is faster, for ephemeral high-throughput queues and tests, but loses every pending job
+    /// when the server restarts, replicas or not. Only applies when the streams are created.
+    pub storage_type: StorageType,
+    /// Whether a storage creates (or updates) the streams and the status bucket. With `false`
+    /// it only checks they exist and fails with [`NatsPollError::StreamNotFound`] otherwise,
+    /// for credentials that may use but not manage streams. Default: `true`.
+    pub create_streams: bool,
+    /// Record each job's [`JobStatus`] in the `{namespace}_status` KV bucket, for
+    /// [`NatsStorage::query_status`]. Costs a KV write per push and per state change; the
+    /// writes after delivery happen in the background. Default: `false`, no bucket is needed.
+    pub track_status: bool,
+    /// The priorities that get a stream and a consumer, e.g. only `Priority::Medium` for a
+    /// namespace that never uses the others. Pushing or scheduling a job at another priority
+    /// fails, and workers only consume these. Default: all three.
+    pub enabled_priorities: Vec<Priority>,
     /// Enable dead letter queue
     pub enable_dlq: bool,
+    /// Name of the DLQ stream, e.g. a central DLQ shared by several services.
+    /// `None` uses `{namespace}_dlq`. The stream is created if missing, otherwise it is used as
+    /// is and must capture `dlq_subject`. A custom DLQ stream is left alone by
+    /// [`NatsStorage::migrate_namespace`] and [`NatsStorage::set_replicas`].
+    pub dlq_stream: Option<String>,
+    /// Subject DLQ entries are published to. `None` uses `{namespace}.dlq`.
+    pub dlq_subject: Option<String>,
+    /// How long after being dead-lettered a job that ran out of retries is worth retrying,
+    /// recorded as [`DlqEntry::retry_after`]. Meant for transient failures such as a
+    /// dependency outage; jobs that returned `Error::Abort` don't get one. `None` leaves it unset.
+    pub dlq_retry_after: Option<Duration>,
+    /// The label key that identifies a job's tenant when several tenants share the streams,
+    /// see [`NatsStorage::push_with_labels`]. Failed jobs carrying that label are dead-lettered
+    /// to `{dlq_subject}.{tenant}` so each tenant's entries can be read with
+    /// [`NatsStorage::read_dlq_for_tenant`] and re-driven on their own. A custom `dlq_stream`
+    /// must then capture `{dlq_subject}.>` as well. `None` sends every entry to `dlq_subject`.
+    pub tenant_label: Option<String>,
+    /// Retention of the DLQ stream. `Limits` (the default) keeps entries for 30 days, even once
+    /// a [`NatsStorage::dlq_backend`] worker or [`NatsStorage::dlq_stream`] acked them.
+    /// `WorkQueue` removes an entry as soon as it is acked, but then the stream only allows one
+    /// consumer per subject, so nothing else can read the DLQ, e.g. another namespace sharing
+    /// it or a `nats consumer`. Only applied when the DLQ stream is created.
+    pub dlq_retention: RetentionPolicy,
     /// Maximum number of pending acknowledgments per consumer
     pub max_ack_pending: i64,
+    /// The concurrency workers on this storage run with, if known.
+    /// Workers warn at startup when it exceeds `max_ack_pending`, and
+    /// `NatsWorkerExt::nats_concurrency` uses it as the limit. See [`Config::with_concurrency`].
+    pub concurrency: Option<usize>,
+    /// Maximum number of outstanding pull requests on a shared consumer.
+    /// Every worker keeps a pull request open while it fetches, so this must cover the number of
+    /// workers sharing the consumer. When it is too low the server rejects pulls with
+    /// "exceeded MaxWaiting" and workers log fetch errors instead of receiving jobs.
+    /// Default: 0, derived from the other limits: twice the number of workers that can hold a
+    /// job at once, `max_ack_pending` divided by `concurrency` (1 if unset), leaving room for
+    /// idle and restarting workers, and never below the server's own default of 512.
+    /// Only applied when a consumer is created: workers warn when an existing consumer has
+    /// another value, delete the consumer to change it.
+    pub max_waiting: i64,
+    /// Upper bound on the number of messages a single pull request may ask for (0 = unlimited)
+    pub max_batch: i64,
     /// Maximum time to wait for a fetch on one priority before falling through
     pub fetch_expiry: Duration,
+    /// How workers wait for jobs, see [`PollMode`]
+    pub poll_mode: PollMode,
+    /// Randomizes the 100ms an idle [`PollMode::BusyLoop`] worker sleeps between fetch rounds
+    /// by up to this fraction either way, so workers started together spread their pulls out
+    /// instead of hitting the server in lockstep. Clamped to 0.0..=1.0; 0.0 turns it off.
+    pub idle_jitter: f64,
+    /// What workers do when a stream they consume is deleted under them, see [`OnStreamMissing`]
+    pub on_stream_missing: OnStreamMissing,
+    /// How often each worker checks its connection and the streams it consumes. Problems are
+    /// logged and emitted as worker `Error` events, so they show up while the queue is idle
+    /// too. The check stops with the worker. `Duration::ZERO` turns it off.
+    pub heartbeat_interval: Duration,
+    /// How long a [`PollMode::LongPoll`] pull on the Low priority (or the lowest one consumed,
+    /// see [`NatsStorage::for_priorities`]) waits for a job.
+    /// New High/Medium jobs can wait up to this long while a worker is idle.
+    pub long_poll_expiry: Duration,
+    /// Maximum number of jobs pulled per fetch. Without `prefetch`, workers fetch only when a
+    /// slot is free, and the rest of a batch waits in the worker's buffer with its `ack_wait`
+    /// running, so keep this small for slow handlers.
+    pub fetch_batch_size: usize,
+    /// Caps the bytes a single fetch may return on top of its message count, so a worker
+    /// handling large payloads can't pull several huge jobs into memory at once. A fetch stops
+    /// at whichever of `fetch_batch_size` (or `prefetch`) and this limit comes first, and
+    /// always returns at least one job if one fits. It must exceed the largest job: the server
+    /// fails pulls for a message bigger than the limit, so it is never delivered.
+    /// `None` only limits the count.
+    pub fetch_max_bytes: Option<usize>,
+    /// How many jobs a worker fetches ahead of the ones it is running (0 = off, fetch only
+    /// when a slot is free).
+    /// Buffered jobs are handed over without a fetch round trip, but their `ack_wait` is
+    /// already running and they are redelivered if the worker dies. Capped at half of
+    /// `max_ack_pending`; when set it replaces `fetch_batch_size`.
+    pub prefetch: usize,
+    /// Never start a lower priority job while a higher priority one is waiting, even with a
+    /// concurrency above 1. Workers then fetch one job at a time, only once a slot is free,
+    /// which costs a fetch round trip per job. Disables `prefetch` and `fetch_batch_size`.
+    pub strict_priority: bool,
+    /// Extend the lease of every job a worker has in flight from a single background task,
+    /// with a Progress ack every `ack_wait / 3` (at least 1s) until the job is acked. An
+    /// alternative to [`ProgressHeartbeatLayer`](crate::ProgressHeartbeatLayer), which spawns
+    /// a task per job, for workers running many long jobs at once. Turn the layer's heartbeat
+    /// off when using it, or each job gets both.
+    pub keep_alive: bool,
     /// Backoff schedule for transient failures (Nak delays by attempt index)
     /// If shorter than delivered attempts, the last value is used for subsequent attempts.
     pub nak_backoff: Vec<Duration>,
+    /// Republish every job stored in a priority stream to an external subject, as
+    /// `(source, destination)`, e.g. `("apalis.*", "events.jobs.{{wildcard(1)}}")`.
+    /// `source` is matched against `{namespace}.{priority}`; `destination` may reference its
+    /// `*` tokens with `{{wildcard(N)}}` and end in `>` when `source` does.
+    /// This is additive: the stored subject and apalis's own consumers are unchanged.
+    pub subject_transform: Option<(String, String)>,
+    /// Republish every job stored in each priority stream to a subject built from
+    /// `{namespace}` and `{priority}`, see [`RepublishConfig`]. Additive like
+    /// `subject_transform`, and since a stream has a single republish setting the two can't
+    /// be combined.
+    pub republish: Option<RepublishConfig>,
+    /// Upstream streams each priority stream also sources jobs from, e.g. so a regional worker
+    /// pool drains a local copy of another region's queue. `{priority}` in a source's `name`
+    /// and `filter_subject` is replaced with the stream's priority, so
+    /// `Source { name: "apalis_{priority}".into(), .. }` feeds `{namespace}_high` from
+    /// `apalis_high` and so on. The streams keep their own subject, so jobs can still be pushed
+    /// locally. Only applies when the streams are created. See the README for the ack caveats.
+    pub sources: Vec<Source>,
+    /// Create the priority streams as read-only mirrors of an upstream stream instead, with
+    /// `{priority}` replaced as in `sources`. A mirror has no subject of its own, so pushing
+    /// to this storage fails; it can't be combined with `sources`.
+    pub mirror: Option<Source>,
+    /// Where the priority consumers start delivering from.
+    /// Anything other than [`DeliverPolicy::All`] creates separate `{namespace}_{priority}_replay_consumer`
+    /// consumers for replaying jobs after a fix, see [`DeliverPolicy`].
+    ///
+    /// A work-queue stream (the default, without `consumer_group`) allows only one consumer per
+    /// subject, so the replay consumer can't be created while the regular
+    /// `{namespace}_{priority}_consumer` exists: it lives as long as a regular worker runs and
+    /// for `inactive_threshold` after. Replay workers fail with
+    /// [`NatsPollError::ConsumerNotUnique`] until then; stop the regular workers and delete
+    /// their consumers first.
+    pub deliver_policy: DeliverPolicy,
+    /// How the priority consumers expect acks, see [`AckPolicy`]. Only applies when a consumer
+    /// is created; an existing durable keeps its policy until it is deleted.
+    pub ack_policy: AckPolicy,
+    /// How fast the priority consumers deliver stored messages, see [`ReplayPolicy`]. Only
+    /// applies when a consumer is created; an existing durable keeps its policy until it is
+    /// deleted.
+    pub replay_policy: ReplayPolicy,
+    /// Give this deployment its own consumers, `{namespace}_{priority}_{group}_consumer`, so
+    /// several deployments sharing a namespace each receive every job (fan-out) while the
+    /// workers of one group still share its jobs. The priority streams are then created with
+    /// Interest retention instead of WorkQueue, keeping a job until every group's consumer has
+    /// acked it. Only applies when the streams are created, see the README for the caveats.
+    /// `None` uses the shared `{namespace}_{priority}_consumer`.
+    pub consumer_group: Option<String>,
+    /// The JetStream domain the streams live in, for leaf node and multi-account setups.
+    /// `None` uses the domain of the server the client is connected to. When it doesn't match,
+    /// stream requests go unanswered and the streams appear to be missing.
+    pub jetstream_domain: Option<String>,
+    /// Caps how fast failed jobs are retried. Once spent, failures go to the DLQ (or are
+    /// terminated) instead of being retried, see [`RetryBudget`]. `None` means no cap.
+    pub retry_budget: Option<RetryBudget>,
+    /// Stops delivering a job type whose jobs are failing en masse, holding them back while
+    /// other types keep running, see [`Quarantine`]. `None` never quarantines.
+    pub quarantine: Option<Quarantine>,
+    /// How many acks a worker processes at once, so a slow DLQ publish doesn't delay acking
+    /// other jobs. 1 processes them one at a time.
+    pub ack_concurrency: usize,
+    /// Leave acking successful jobs to the handler, see [`NatsContext::ack`].
+    /// Jobs whose handler returns `Ok` without acking are redelivered after `ack_wait`.
+    /// Failed jobs are still retried or dead-lettered automatically unless the handler acked them.
+    pub manual_ack: bool,
+    /// Give jobs pushed with [`NatsStorage::push_with_priority`] (and `Storage::push`) a
+    /// `Nats-Msg-Id` derived from a SHA-256 of their serialized data, so JetStream drops
+    /// byte-identical jobs pushed within the `duplicate_window`. Only exact duplicates
+    /// are caught: jobs that are equal but serialize differently, e.g. a map with another key
+    /// order, are both enqueued.
+    pub dedup_by_payload_hash: bool,
+    /// How long the priority streams remember `Nats-Msg-Id`s to drop duplicate publishes, see
+    /// [`NatsStorage::push_with_id`]. `Duration::ZERO` turns deduplication off for streams
+    /// where it isn't needed, sparing JetStream the id tracking on every publish: ids are then
+    /// forgotten right after their publish, and repeated ids are stored as new jobs. Only
+    /// applied when the streams are created. Default: 2 minutes.
+    pub duplicate_window: Duration,
+    /// Accept messages whose payload is a bare `T` rather than a job envelope, for streams
+    /// that already have producers publishing plain JSON. Such messages are run as a new job
+    /// with a fresh task id (unless they carry an `apalis-task-id` header) and first attempt,
+    /// their priority taken from the subject. They no longer go to the DLQ as malformed;
+    /// only payloads that decode as neither are rejected.
+    pub accept_bare_payloads: bool,
+    /// Largest serialized job a push may publish, in bytes. Bigger jobs fail with
+    /// [`NatsPollError::PayloadTooLarge`] before anything is sent, instead of with an opaque
+    /// error from the server. Set it to the server's `max_payload` (1 MB by default) or the
+    /// stream's `max_msg_size`, whichever is lower. `None` leaves the check to the server.
+    /// DLQ entries larger than this (or the server's `max_payload`) have their payload
+    /// truncated, see [`DlqEntry::payload_truncated`](crate::DlqEntry::payload_truncated).
+    pub max_msg_size: Option<usize>,
     /// Enable OpenTelemetry tracing
     #[cfg(feature = "otel")]
     pub enable_tracing: bool,
@@ -95,11 +468,37 @@ impl Default for Config {
         Config {
             namespace: "apalis".to_string(),
             max_deliver: 5,
+            on_max_deliver: OnMaxDeliver::DeadLetter,
+            max_retry_duration: None,
+            sla: HashMap::new(),
             ack_wait: Duration::from_secs(30),
             num_replicas: 1,
+            placement: None,
+            storage_type: StorageType::File,
+            create_streams: true,
+            track_status: false,
+            enabled_priorities: vec![Priority::High, Priority::Medium, Priority::Low],
             enable_dlq: true,
+            dlq_stream: None,
+            dlq_subject: None,
+            dlq_retry_after: None,
+            tenant_label: None,
+            dlq_retention: RetentionPolicy::Limits,
             max_ack_pending: 100, // Allow up to 100 unacknowledged messages per consumer
+            concurrency: None,
+            max_waiting: 0,       // Derived from max_ack_pending and concurrency
+            max_batch: 0,
             fetch_expiry: Duration::from_millis(75),
+            poll_mode: PollMode::BusyLoop,
+            idle_jitter: 0.2,
+            on_stream_missing: OnStreamMissing::Recreate,
+            heartbeat_interval: Duration::from_secs(30),
+            long_poll_expiry: Duration::from_secs(1),
+            fetch_batch_size: 1,
+            fetch_max_bytes: None,
+            prefetch: 0,
+            strict_priority: false,
+            keep_alive: false,
             nak_backoff: vec![
                 Duration::from_millis(100),
                 Duration::from_millis(200),
@@ -108,12 +507,76 @@ impl Default for Config {
                 Duration::from_secs(2),
                 Duration::from_secs(5),
             ],
+            subject_transform: None,
+            republish: None,
+            sources: Vec::new(),
+            mirror: None,
+            deliver_policy: DeliverPolicy::All,
+            ack_policy: AckPolicy::Explicit,
+            replay_policy: ReplayPolicy::Instant,
+            consumer_group: None,
+            jetstream_domain: None,
+            retry_budget: None,
+            quarantine: None,
+            ack_concurrency: 8,
+            manual_ack: false,
+            dedup_by_payload_hash: false,
+            duplicate_window: Duration::from_secs(120),
+            accept_bare_payloads: false,
+            max_msg_size: None,
             #[cfg(feature = "otel")]
             enable_tracing: true,
         }
     }
 }
 
+impl Config {
+    /// Set the worker concurrency and size `max_ack_pending` to twice that, so a worker can
+    /// have a full set of jobs in flight while the next ones are being fetched.
+    ///
+    /// # Example
+    /// ```
+    /// # use apalis_nats::Config;
+    /// let config = Config::default().with_concurrency(8);
+    /// assert_eq!(config.max_ack_pending, 16);
+    /// ```
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self.max_ack_pending = (concurrency as i64).saturating_mul(2);
+        self
+    }
+
+    /// `max_waiting`, or the value derived from `max_ack_pending` and `concurrency` when it
+    /// is 0
+    pub(crate) fn max_waiting(&self) -> i64 {
+        if self.max_waiting > 0 {
+            return self.max_waiting;
+        }
+        let per_worker = self.concurrency.unwrap_or(1).max(1) as i64;
+        let workers = self.max_ack_pending.max(1).saturating_add(per_worker - 1) / per_worker;
+        workers.saturating_mul(2).max(SERVER_MAX_WAITING)
+    }
+
+    /// The enabled priorities, highest first
+    pub(crate) fn priorities(&self) -> Vec<Priority> {
+        [Priority::High, Priority::Medium, Priority::Low]
+            .into_iter()
+            .filter(|priority| self.enabled_priorities.contains(priority))
+            .collect()
+    }
+
+    /// Fail unless `priority` is one of the `enabled_priorities`
+    pub(crate) fn check_priority(&self, priority: Priority) -> Result<(), NatsPollError> {
+        if self.enabled_priorities.contains(&priority) {
+            return Ok(());
+        }
+        Err(NatsPollError::Storage(format!(
+            "Priority {} is not enabled in namespace {}, see `Config::enabled_priorities`",
+            priority, self.namespace
+        )))
+    }
+}
+
 /// NATS poll error
 #[derive(Debug, Error)]
 pub enum NatsPollError {
@@ -126,6 +589,48 @@ pub enum NatsPollError {
     /// Storage error
     #[error("Storage error: {0}")]
     Storage(String),
+    /// The server rejected a conditional publish, e.g. from
+    /// [`NatsStorage::push_with_expected_seq`], because its expectation no longer held
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    /// The stream doesn't exist, e.g. it was deleted or lives in another JetStream domain
+    #[error("Stream not found: {0}")]
+    StreamNotFound(String),
+    /// The consumer doesn't exist, e.g. no worker created it yet or it was removed after
+    /// being inactive
+    #[error("Consumer not found: {0}")]
+    ConsumerNotFound(String),
+    /// The server didn't answer a JetStream request in time
+    #[error("JetStream request timed out")]
+    Timeout,
+    /// JetStream is not enabled on the server or for the account, or nothing answered in the
+    /// configured JetStream domain
+    #[error("JetStream is not enabled")]
+    JetStreamDisabled,
+    /// The serialized job is larger than `Config::max_msg_size`, so it wasn't published
+    #[error(
+        "Job payload of {size} bytes exceeds max_msg_size of {limit} bytes; store large data \
+         outside the job, e.g. in a JetStream object store, and push a reference to it"
+    )]
+    PayloadTooLarge {
+        /// Size of the serialized job in bytes
+        size: usize,
+        /// The configured `Config::max_msg_size`
+        limit: usize,
+    },
+    /// A work-queue stream refused the consumer because another consumer already filters on
+    /// the same subjects, e.g. a replay consumer while the regular one still exists, see
+    /// `Config::deliver_policy`
+    #[error(
+        "Consumer {consumer} overlaps the filter of another consumer on work-queue stream \
+         {stream}; stop the regular workers and delete their consumer before replaying"
+    )]
+    ConsumerNotUnique {
+        /// The work-queue stream
+        stream: String,
+        /// The consumer that couldn't be created
+        consumer: String,
+    },
 }
 
 // Implementation for all NATS error types
@@ -135,9 +640,78 @@ impl From<async_nats::Error> for NatsPollError {
     }
 }
 
+/// Header carrying the schema version of the job payload, see [`NatsStorage::with_migrations`]
+pub const SCHEMA_VERSION_HEADER: &str = "Apalis-Schema-Version";
+
+/// Header carrying the task id of a job, so tools can see it without decoding the payload
+pub const TASK_ID_HEADER: &str = "apalis-task-id";
+
+/// Header carrying the namespace a job was pushed to
+pub const NAMESPACE_HEADER: &str = "apalis-namespace";
+
+/// Header carrying the priority of a job: `high`, `medium` or `low`
+pub const PRIORITY_HEADER: &str = "apalis-priority";
+
+/// Header carrying when a job was pushed, as RFC 3339
+pub const CREATED_AT_HEADER: &str = "apalis-created-at";
+
+/// Header carrying the type tag of the storage that pushed a job, see
+/// [`NatsStorage::with_type_tag`]
+pub const JOB_TYPE_HEADER: &str = "apalis-job-type";
+
+/// How long a job of another type is Nak'd for, so a worker of its own type picks it up
+const FOREIGN_JOB_DELAY: Duration = Duration::from_millis(100);
+
+/// The window streams get for `Config::duplicate_window: Duration::ZERO`, as JetStream can't
+/// turn deduplication off and would take zero for its 2 minute default
+const MIN_DUPLICATE_WINDOW: Duration = Duration::from_millis(1);
+
+/// The server's default `max_waiting`, the floor for the one derived from the config
+const SERVER_MAX_WAITING: i64 = 512;
+
+/// Upgrades a job payload from one schema version to the next, see [`NatsStorage::with_migrations`]
+pub type Migration = Box<dyn Fn(serde_json::Value) -> serde_json::Value + Send + Sync>;
+
+/// Turns a job payload into the text that is logged or stored in the DLQ instead of it,
+/// see [`NatsStorage::with_redactor`]
+pub type Redactor = Arc<dyn Fn(&[u8]) -> String + Send + Sync>;
+
+/// Decides what a worker does with a job before handing it over, see [`NatsStorage::with_filter`]
+pub type JobFilter<T> = Arc<dyn Fn(&T) -> FilterAction + Send + Sync>;
+
+/// What a worker does with a job, as decided by a [`JobFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Run the job as usual
+    Process,
+    /// Ack the job without running it. It is dropped for good and marked dead.
+    Skip,
+    /// Nak the job so it is redelivered after the delay, without running it. This counts as
+    /// a delivery towards `max_deliver`.
+    Defer(Duration),
+}
+
+/// Decides what happens to a job whose handler failed, see [`NatsStorage::with_classifier`]
+pub type ErrorClassifier = Arc<dyn Fn(&Error) -> Disposition + Send + Sync>;
+
+/// What a worker does with a failed job, as decided by an [`ErrorClassifier`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Nak the job for another attempt after `delay`, or the `nak_backoff` delay when `None`.
+    /// Once `max_deliver` is reached it is dead-lettered like any other failure.
+    Retry {
+        /// How long until the retry, `None` for the `nak_backoff` schedule
+        delay: Option<Duration>,
+    },
+    /// Drop the job for good without writing a DLQ entry
+    Terminate,
+    /// Move the job to the DLQ right away (or terminate it when the DLQ is disabled)
+    DeadLetter,
+}
+
 /// Job wrapper for NATS
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct NatsJob<T> {
+pub(crate) struct NatsJob<T> {
     pub id: TaskId,
     pub data: T,
     pub priority: Priority,
@@ -150,10 +724,43 @@ struct NatsJob<T> {
 #[derive(Debug, Clone, Default)]
 pub struct NatsContext {
     pub(crate) message: Option<Arc<jetstream::Message>>,
+    /// Shared with the clone handed to the ack task, so handlers can set it
+    pub(crate) dlq_metadata: Arc<std::sync::Mutex<Option<serde_json::Value>>>,
+    pub(crate) priority: Option<Priority>,
+    pub(crate) created_at: Option<DateTime<Utc>>,
+    /// Lets [`NatsContext::to_dlq`] publish without keeping the storage alive
+    pub(crate) dlq: Option<Weak<DlqHandle>>,
+    /// Set once the handler acked the message itself, so the ack task leaves it alone
+    pub(crate) settled: Arc<std::sync::Mutex<Option<JobStatus>>>,
+    /// Set once an [`AckGuard`](crate::AckGuard) is dropped, so the ack task settles the job
+    pub(crate) auto_ack: Arc<std::sync::atomic::AtomicBool>,
+    /// The job's entry in the state bucket, see [`NatsContext::state_get`]
+    pub(crate) state: Option<JobState>,
+    /// The consumer's `ack_wait`, for [`NatsContext::lease_remaining`]
+    pub(crate) ack_wait: Option<Duration>,
+    /// The consumer's `max_deliver`, for [`NatsContext::is_final_attempt`]
+    pub(crate) max_deliver: Option<i64>,
+    /// How long the job waited before this delivery, see [`NatsContext::queue_latency`]
+    pub(crate) queue_latency: Option<Duration>,
+    /// When the message was received or last extended with a Progress ack
+    pub(crate) last_progress: Arc<std::sync::Mutex<Option<Instant>>>,
     #[cfg(feature = "otel")]
     trace_context: Option<OtelContext>,
 }
 
+/// What a [`NatsContext`] needs from its [`NatsStorage`] to dead-letter its own message
+pub(crate) struct DlqHandle {
+    pub(crate) jetstream: jetstream::Context,
+    pub(crate) namespace: String,
+    pub(crate) subject: String,
+    pub(crate) tenant_label: Option<String>,
+    pub(crate) enable_dlq: bool,
+    pub(crate) redact: Option<Redactor>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) client: Client,
+    pub(crate) max_msg_size: Option<usize>,
+}
+
 impl NatsContext {
     /// Create a new context with a message
     pub fn with_message(message: jetstream::Message) -> Self {
@@ -168,6 +775,17 @@ impl NatsContext {
 
             Self {
                 message: Some(Arc::new(message)),
+                dlq_metadata: Default::default(),
+                priority: None,
+                created_at: None,
+                dlq: None,
+                settled: Default::default(),
+                auto_ack: Default::default(),
+                state: None,
+                ack_wait: None,
+                max_deliver: None,
+                queue_latency: None,
+                last_progress: Arc::new(std::sync::Mutex::new(Some(Instant::now()))),
                 trace_context: Some(trace_context),
             }
         }
@@ -175,6 +793,17 @@ impl NatsContext {
         #[cfg(not(feature = "otel"))]
         Self {
             message: Some(Arc::new(message)),
+            dlq_metadata: Default::default(),
+            priority: None,
+            created_at: None,
+            dlq: None,
+            settled: Default::default(),
+            auto_ack: Default::default(),
+            state: None,
+            ack_wait: None,
+            max_deliver: None,
+            queue_latency: None,
+            last_progress: Arc::new(std::sync::Mutex::new(Some(Instant::now()))),
         }
     }
 
@@ -183,6 +812,34 @@ impl NatsContext {
         self.message.as_ref().map(|m| m.as_ref())
     }
 
+    /// The message payload exactly as it was published, before it was decoded into the job,
+    /// e.g. to log or forward the exact bytes or verify a signature. `None` without a message.
+    pub fn raw_payload(&self) -> Option<&Bytes> {
+        self.message.as_ref().map(|m| &m.payload)
+    }
+
+    /// The priority the job was published with, if this context carries a job
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    /// When the job was pushed, if this context carries a job.
+    ///
+    /// This is set by the producer and is kept when the job is requeued from the DLQ or migrated,
+    /// unlike the stream timestamp of the NATS message.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
+
+    /// How long the job waited between being pushed (its [`created_at`](Self::created_at)) and
+    /// this delivery reaching the worker, if this context carries a job.
+    ///
+    /// On a redelivery it includes the earlier attempts and their backoff. A producer clock
+    /// ahead of the worker's reads as zero rather than a negative wait.
+    pub fn queue_latency(&self) -> Option<Duration> {
+        self.queue_latency
+    }
+
     /// Get the OpenTelemetry trace context
     #[cfg(feature = "otel")]
     pub fn trace_context(&self) -> Option<&OtelContext> {
@@ -199,6 +856,37 @@ pub struct NatsQueueInfo {
     pub pending: u64,
 }
 
+/// A snapshot of where a storage's jobs are, see [`NatsStorage::summary`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueSummary {
+    /// Jobs waiting for a worker by priority, not counting the ones in flight
+    pub pending: HashMap<Priority, u64>,
+    /// Jobs delivered to a worker and not acked yet, across priorities
+    pub in_flight: u64,
+    /// Entries in the DLQ, 0 when it is disabled
+    pub dead: u64,
+}
+
+impl QueueSummary {
+    /// Jobs waiting for a worker across priorities
+    pub fn total_pending(&self) -> u64 {
+        self.pending.values().sum()
+    }
+}
+
+/// What [`NatsStorage::push_with_id`] did with a job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushOutcome {
+    /// The id generated for the job. When `duplicate` is set this job was not stored, so the
+    /// id doesn't refer to any job.
+    pub task_id: TaskId,
+    /// The stream sequence of the stored job, or of the original publish for a duplicate
+    pub sequence: u64,
+    /// Whether the server dropped the publish because a job with the same message id was
+    /// stored within the stream's duplicate window
+    pub duplicate: bool,
+}
+
 /// NATS JetStream storage implementation for Apalis jobs.
 ///
 /// Use [`NatsStorage::new`] or [`NatsStorage::new_with_config`] to initialize the backend and
@@ -206,9 +894,45 @@ pub struct NatsQueueInfo {
 ///
 /// See the crate-level docs and README for end-to-end examples.
 pub struct NatsStorage<T> {
-    client: Client,
+    pub(crate) client: Client,
     pub(crate) jetstream: jetstream::Context,
     pub(crate) config: Config,
+    pub(crate) status: Option<StatusTracker>,
+    dlq_handle: Arc<DlqHandle>,
+    migrations: Arc<Vec<Migration>>,
+    redact: Option<Redactor>,
+    /// Consulted before each job is handed to a worker, see [`NatsStorage::with_filter`]
+    filter: Option<JobFilter<T>>,
+    /// Consulted for each failed job, see [`NatsStorage::with_classifier`]
+    classify: Option<ErrorClassifier>,
+    /// Set by [`NatsStorage::producer`], workers on this storage stop right away
+    producer_only: bool,
+    /// Pushed as [`JOB_TYPE_HEADER`], see [`NatsStorage::with_type_tag`]
+    type_tag: String,
+    /// Where time-based features read the time, see [`NatsStorage::with_clock`]
+    pub(crate) clock: Arc<dyn Clock>,
+    retry_tokens: Option<Arc<RetryTokens>>,
+    /// Failure counts per job type, see `Config::quarantine`
+    quarantine: Option<Arc<QuarantineTracker>>,
+    /// Naks by the delivery count of the failed attempt, see [`NatsStorage::retry_stats`]
+    retry_stats: Arc<std::sync::Mutex<BTreeMap<u64, u64>>>,
+    /// Set by [`NatsStorage::pause`], workers skip fetches while it is
+    paused: Arc<AtomicBool>,
+    /// The priorities workers on this storage consume, see [`NatsStorage::for_priorities`]
+    priorities: Vec<Priority>,
+    /// Messages handed to workers and not yet acked, see [`NatsStorage::in_flight`]
+    in_flight: Arc<AtomicUsize>,
+    /// The highest concurrency limit a worker on this storage was built with through
+    /// `NatsWorkerExt`, 0 while unknown
+    pub(crate) worker_concurrency: Arc<AtomicUsize>,
+    /// The label subject tokens workers on this storage consume, see [`NatsStorage::for_labels`]
+    labels: Option<String>,
+    /// Added to every worker's service, see [`NatsStorage::with_layers`]
+    layers: NatsLayers,
+    /// Status buckets of the other namespaces pushed to, see [`NatsStorage::push_to`]
+    namespaces: Arc<tokio::sync::Mutex<HashMap<String, StatusTracker>>>,
+    /// Per-job handler state, see [`NatsContext::state_get`]
+    state: Arc<StateBucket>,
     consumers: Arc<
         std::sync::Mutex<
             HashMap<Priority, consumer::Consumer<consumer::pull::Config>>,
@@ -231,6 +955,26 @@ impl<T> Clone for NatsStorage<T> {
             client: self.client.clone(),
             jetstream: self.jetstream.clone(),
             config: self.config.clone(),
+            status: self.status.clone(),
+            dlq_handle: Arc::clone(&self.dlq_handle),
+            migrations: Arc::clone(&self.migrations),
+            redact: self.redact.clone(),
+            filter: self.filter.clone(),
+            classify: self.classify.clone(),
+            producer_only: self.producer_only,
+            type_tag: self.type_tag.clone(),
+            clock: Arc::clone(&self.clock),
+            retry_tokens: self.retry_tokens.clone(),
+            quarantine: self.quarantine.clone(),
+            retry_stats: Arc::clone(&self.retry_stats),
+            paused: Arc::clone(&self.paused),
+            priorities: self.priorities.clone(),
+            in_flight: Arc::clone(&self.in_flight),
+            worker_concurrency: Arc::clone(&self.worker_concurrency),
+            labels: self.labels.clone(),
+            layers: self.layers.clone(),
+            namespaces: Arc::clone(&self.namespaces),
+            state: Arc::clone(&self.state),
             consumers: Arc::clone(&self.consumers),
             _phantom: PhantomData,
         }
@@ -323,230 +1067,2853 @@ pub async fn connect_with_options(
     options.connect(url).await
 }
 
-impl<T> NatsStorage<T>
-where
-    T: Serialize + DeserializeOwned + Send + 'static,
-{
-    /// Create a new NATS storage instance
-    pub async fn new(client: Client) -> Result<Self, NatsPollError> {
-        Self::new_with_config(client, Config::default()).await
-    }
+/// Point a serialized `NatsJob` envelope at another namespace.
+/// Returns `None` for payloads that aren't job envelopes (e.g. DLQ entries).
+fn rewrite_namespace(payload: &[u8], namespace: &str) -> Option<Bytes> {
+    let mut job = serde_json::from_slice::<NatsJob<serde_json::Value>>(payload).ok()?;
+    job.namespace = Namespace::from(namespace.to_string());
+    serde_json::to_vec(&job).ok().map(Bytes::from)
+}
 
-    /// Create a new NATS storage instance with custom config
-    pub async fn new_with_config(client: Client, config: Config) -> Result<Self, NatsPollError> {
-        let jetstream = jetstream::new(client.clone());
-
-        // Create streams for each priority level
-        for priority in [Priority::High, Priority::Medium, Priority::Low] {
-            let stream_name = format!("{}_{}", config.namespace, priority);
-            let subject = format!("{}.{}", config.namespace, priority);
-
-            let stream_config = stream::Config {
-                name: stream_name.clone(),
-                subjects: vec![subject],
-                // Message retention settings
-                max_age: Duration::from_secs(7 * 24 * 60 * 60), // 7 days
-                storage: stream::StorageType::File,
-                num_replicas: config.num_replicas,
-                // Work queue optimizations
-                retention: stream::RetentionPolicy::WorkQueue, // Automatically remove acknowledged messages
-                discard: stream::DiscardPolicy::Old, // When stream is full, discard old messages
-                duplicate_window: Duration::from_secs(120), // Prevent duplicate messages within 2 minutes
-                ..Default::default()
-            };
+/// Point a serialized `NatsJob` envelope at another priority.
+/// Returns `None` for payloads that aren't job envelopes (e.g. bare payloads).
+fn rewrite_priority(payload: &[u8], priority: Priority) -> Option<Bytes> {
+    let mut job = serde_json::from_slice::<NatsJob<serde_json::Value>>(payload).ok()?;
+    job.priority = priority;
+    serde_json::to_vec(&job).ok().map(Bytes::from)
+}
 
-            // Create or update stream
-            match jetstream.get_or_create_stream(stream_config).await {
-                Ok(_) => tracing::info!("Stream {} ready", stream_name),
-                Err(e) => {
-                    tracing::error!("Failed to create stream {}: {}", stream_name, e);
-                    return Err(NatsPollError::Nats(e.to_string()));
+/// Map `subject` through a `(source, destination)` transform.
+/// Returns `None` when `subject` does not match `source`.
+fn transform_subject(source: &str, destination: &str, subject: &str) -> Option<String> {
+    let mut wildcards = Vec::new();
+    let mut rest = None;
+    let mut tokens = subject.split('.');
+    for pattern in source.split('.') {
+        match (pattern, tokens.next()) {
+            (">", Some(token)) => {
+                rest = Some(std::iter::once(token).chain(tokens.by_ref()).collect::<Vec<_>>());
+                break;
+            }
+            ("*", Some(token)) => wildcards.push(token),
+            (pattern, Some(token)) if pattern == token => {}
+            _ => return None,
+        }
+    }
+    if rest.is_none() && tokens.next().is_some() {
+        return None;
+    }
+
+    let mapped = destination
+        .split('.')
+        .map(|token| {
+            if token == ">" {
+                return rest.as_ref().map(|rest| rest.join("."));
+            }
+            match token
+                .strip_prefix("{{wildcard(")
+                .and_then(|t| t.strip_suffix(")}}"))
+            {
+                Some(index) => {
+                    let index = index.trim().parse::<usize>().ok()?;
+                    wildcards.get(index.checked_sub(1)?).map(|t| t.to_string())
                 }
+                None => Some(token.to_string()),
             }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(mapped.join("."))
+}
+
+/// The priority streams and the optional DLQ stream for `config.namespace`
+pub(crate) fn stream_configs(config: &Config) -> Result<Vec<stream::Config>, NatsPollError> {
+    if config.republish.is_some() && config.subject_transform.is_some() {
+        return Err(NatsPollError::Storage(
+            "`republish` and `subject_transform` both set the streams' republish, use only one"
+                .to_string(),
+        ));
+    }
+
+    if config.mirror.is_some() && !config.sources.is_empty() {
+        return Err(NatsPollError::Storage(
+            "A stream is either a mirror or has sources, set only one of `mirror` and `sources`"
+                .to_string(),
+        ));
+    }
+
+    // Subjects are matched token by token, so a namespace that is a single plain token can't
+    // catch another one's jobs, e.g. `app.high` never matches `app_2.high`
+    if !is_subject_token(&config.namespace) {
+        return Err(NatsPollError::Storage(format!(
+            "Invalid namespace {:?}: it can't be empty or contain '.', '*', '>' or whitespace",
+            config.namespace
+        )));
+    }
+    if let Some(group) = &config.consumer_group {
+        if !is_subject_token(group) {
+            return Err(NatsPollError::Storage(format!(
+                "Invalid consumer_group {:?}: it can't be empty or contain '.', '*', '>' or \
+                 whitespace",
+                group
+            )));
         }
+    }
 
-        // Create DLQ stream if enabled
-        if config.enable_dlq {
-            let dlq_stream_name = format!("{}_dlq", config.namespace);
-            let dlq_subject = format!("{}.dlq", config.namespace);
+    if config.enabled_priorities.is_empty() {
+        return Err(NatsPollError::Storage(
+            "No priorities enabled, `enabled_priorities` needs at least one".to_string(),
+        ));
+    }
 
-            let dlq_config = stream::Config {
-                name: dlq_stream_name.clone(),
-                subjects: vec![dlq_subject],
-                max_age: Duration::from_secs(30 * 24 * 60 * 60), // 30 days
-                storage: stream::StorageType::File,
-                num_replicas: config.num_replicas,
-                ..Default::default()
-            };
+    let mut streams = Vec::new();
+    // Create streams for each enabled priority level
+    for priority in config.priorities() {
+        let stream_name = format!("{}_{}", config.namespace, priority);
+        let subject = format!("{}.{}", config.namespace, priority);
+        let republish = match (&config.republish, &config.subject_transform) {
+            (Some(republish), _) => Some(stream::Republish {
+                source: subject.clone(),
+                destination: republish.destination_for(&config.namespace, priority),
+                headers_only: republish.headers_only,
+            }),
+            (None, Some((source, destination))) => {
+                transform_subject(source, destination, &subject).map(|destination| {
+                    stream::Republish {
+                        source: subject.clone(),
+                        destination,
+                        headers_only: false,
+                    }
+                })
+            }
+            (None, None) => None,
+        };
 
-            match jetstream.get_or_create_stream(dlq_config).await {
-                Ok(_) => tracing::info!("DLQ stream {} ready", dlq_stream_name),
-                Err(e) => {
-                    tracing::error!("Failed to create DLQ stream {}: {}", dlq_stream_name, e);
-                    return Err(NatsPollError::Nats(e.to_string()));
-                }
+        let mirror = config
+            .mirror
+            .as_ref()
+            .map(|mirror| source_for(mirror, priority));
+        let sources = match config.sources.is_empty() {
+            true => None,
+            false => Some(
+                config
+                    .sources
+                    .iter()
+                    .map(|source| source_for(source, priority))
+                    .collect(),
+            ),
+        };
+
+        streams.push(stream::Config {
+            name: stream_name,
+            // A mirror only holds what it copies
+            // Labeled jobs go below the priority subject, see `NatsStorage::push_with_labels`
+            subjects: match mirror {
+                Some(_) => Vec::new(),
+                None => vec![subject.clone(), format!("{}.>", subject)],
+            },
+            // Optional copy to an external subject, see `Config::republish`/`subject_transform`
+            republish,
+            // Optional upstream streams, see `Config::sources`/`mirror`
+            mirror,
+            sources,
+            // Message retention settings
+            max_age: Duration::from_secs(7 * 24 * 60 * 60), // 7 days
+            storage: config.storage_type,
+            num_replicas: config.num_replicas,
+            placement: config.placement.clone(),
+            // Work queue optimizations
+            retention: match config.consumer_group {
+                // Keep jobs until every group has acked them
+                Some(_) => stream::RetentionPolicy::Interest,
+                // Automatically remove acknowledged messages
+                None => stream::RetentionPolicy::WorkQueue,
+            },
+            discard: stream::DiscardPolicy::Old, // When stream is full, discard old messages
+            // Drop publishes repeating a `Nats-Msg-Id` within the window
+            duplicate_window: match config.duplicate_window {
+                // JetStream reads a zero window as its 2 minute default
+                Duration::ZERO => MIN_DUPLICATE_WINDOW,
+                window => window,
+            },
+            ..Default::default()
+        });
+    }
+
+    // Create DLQ stream if enabled
+    if config.enable_dlq {
+        streams.push(stream::Config {
+            name: dlq_stream_name(config),
+            subjects: vec![dlq_subject(config), format!("{}.>", dlq_subject(config))],
+            max_age: Duration::from_secs(30 * 24 * 60 * 60), // 30 days
+            // Optionally drop entries once they are acked, see `Config::dlq_retention`
+            retention: config.dlq_retention,
+            storage: config.storage_type,
+            num_replicas: config.num_replicas,
+            placement: config.placement.clone(),
+            ..Default::default()
+        });
+    }
+
+    Ok(streams)
+}
+
+/// An upstream stream of `Config::sources`/`mirror` for `priority`
+fn source_for(source: &Source, priority: Priority) -> Source {
+    let priority = priority.to_string();
+    Source {
+        name: source.name.replace("{priority}", &priority),
+        filter_subject: source
+            .filter_subject
+            .as_ref()
+            .map(|filter| filter.replace("{priority}", &priority)),
+        ..source.clone()
+    }
+}
+
+/// Create (or update) the priority streams and the optional DLQ stream for `config.namespace`.
+pub(crate) async fn ensure_streams(
+    jetstream: &jetstream::Context,
+    config: &Config,
+) -> Result<(), NatsPollError> {
+    if config.storage_type == StorageType::Memory && config.num_replicas > 1 {
+        tracing::warn!(
+            "Namespace {} uses memory storage with {} replicas: replicas only survive the loss \
+             of a server while the others stay up, and a restart of the whole cluster loses \
+             every pending job. Use file storage for jobs that must not be lost",
+            config.namespace,
+            config.num_replicas
+        );
+    }
+    if config.duplicate_window.is_zero() && config.dedup_by_payload_hash {
+        tracing::warn!(
+            "Namespace {} has `dedup_by_payload_hash` on but `duplicate_window` is zero, so \
+             duplicate jobs are enqueued anyway",
+            config.namespace
+        );
+    }
+    for stream_config in stream_configs(config)? {
+        let stream_name = stream_config.name.clone();
+        if !config.create_streams {
+            // Only check it exists, without the permissions to create or update it
+            jetstream
+                .get_stream(&stream_name)
+                .await
+                .map_err(|e| lookup_error(e, &stream_name, None))?;
+            tracing::info!("Stream {} found", stream_name);
+            continue;
+        }
+        // Create or update stream
+        match jetstream.get_or_create_stream(stream_config).await {
+            Ok(_) => tracing::info!("Stream {} ready", stream_name),
+            Err(e) => {
+                tracing::error!("Failed to create stream {}: {}", stream_name, e);
+                return Err(lookup_error(e, &stream_name, None));
             }
         }
+    }
 
-        Ok(Self {
-            client,
-            jetstream,
-            config,
-            consumers: Arc::new(std::sync::Mutex::new(HashMap::new())),
-            _phantom: PhantomData,
-        })
+    Ok(())
+}
+
+/// The DLQ stream, `Config::dlq_stream` or `{namespace}_dlq`
+pub(crate) fn dlq_stream_name(config: &Config) -> String {
+    config
+        .dlq_stream
+        .clone()
+        .unwrap_or_else(|| format!("{}_dlq", config.namespace))
+}
+
+/// The subject DLQ entries are published to, `Config::dlq_subject` or `{namespace}.dlq`
+pub(crate) fn dlq_subject(config: &Config) -> String {
+    config
+        .dlq_subject
+        .clone()
+        .unwrap_or_else(|| format!("{}.dlq", config.namespace))
+}
+
+/// The DLQ subject for a message published to `subject` in `namespace`: `{dlq}.{tenant}` when
+/// it carries the `tenant_label` label, `dlq` otherwise
+pub(crate) fn tenant_dlq_subject(
+    dlq: &str,
+    namespace: &str,
+    tenant_label: Option<&str>,
+    subject: &str,
+) -> String {
+    let tenant = tenant_label.and_then(|key| {
+        // `{namespace}.{priority}` is followed by the `key.value` label pairs
+        let labels = subject.strip_prefix(namespace)?.strip_prefix('.')?;
+        let tokens: Vec<&str> = labels.split('.').skip(1).collect();
+        tokens
+            .chunks_exact(2)
+            .find(|pair| pair[0] == key)
+            .map(|pair| pair[1])
+    });
+    match tenant {
+        Some(tenant) => format!("{}.{}", dlq, tenant),
+        None => dlq.to_string(),
     }
+}
 
-    /// Get the stream name for a priority level
-    pub(crate) fn get_stream_name(&self, priority: Priority) -> String {
-        format!("{}_{}", self.config.namespace, priority)
+/// The `Nats-Msg-Id` of a job pushed with `Config::dedup_by_payload_hash`
+fn payload_hash(data: &[u8]) -> String {
+    format!("sha256-{:x}", Sha256::digest(data))
+}
+
+/// Whether `token` is a single literal subject token: not empty, no `.` separator, no
+/// wildcards and no whitespace
+fn is_subject_token(token: &str) -> bool {
+    !token.is_empty()
+        && !token
+            .chars()
+            .any(|c| c == '.' || c == '*' || c == '>' || c.is_whitespace())
+}
+
+/// The subject tokens for `labels`, as `key.value` pairs sorted by key. `*` values match any
+/// value when `wildcards` is set.
+fn label_tokens(
+    labels: &HashMap<String, String>,
+    wildcards: bool,
+) -> Result<String, NatsPollError> {
+    if labels.is_empty() {
+        return Err(NatsPollError::Storage("No labels given".to_string()));
     }
+    let labels: BTreeMap<_, _> = labels.iter().collect();
+    let mut tokens = Vec::with_capacity(labels.len() * 2);
+    for (key, value) in labels {
+        if !is_subject_token(key) || !(is_subject_token(value) || (wildcards && value == "*")) {
+            return Err(NatsPollError::Storage(format!(
+                "Invalid label {}={}: keys and values can't be empty or contain '.', '*', '>' \
+                 or whitespace",
+                key, value
+            )));
+        }
+        tokens.push(key.as_str());
+        tokens.push(value.as_str());
+    }
+    Ok(tokens.join("."))
+}
 
-    /// Get the subject for a priority level
-    fn get_subject(&self, priority: Priority) -> String {
-        format!("{}.{}", self.config.namespace, priority)
+/// Copy the envelope of `job` into headers, for tools that don't decode the payload
+fn insert_job_headers<T>(headers: &mut HeaderMap, job: &NatsJob<T>) {
+    headers.insert(TASK_ID_HEADER, job.id.to_string());
+    headers.insert(NAMESPACE_HEADER, job.namespace.to_string());
+    headers.insert(PRIORITY_HEADER, job.priority.to_string());
+    headers.insert(CREATED_AT_HEADER, job.created_at.to_rfc3339());
+}
+
+/// The schema version `msg` was published with, 1 without the header
+fn message_schema_version(msg: &jetstream::Message) -> usize {
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get(SCHEMA_VERSION_HEADER))
+        .and_then(|value| value.as_str().parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Override the envelope of `job` with the values of its headers, where they are valid
+fn apply_job_headers<T>(job: &mut NatsJob<T>, headers: Option<&HeaderMap>) {
+    let Some(headers) = headers else {
+        return;
+    };
+    let header = |name: &str| headers.get(name).map(|value| value.as_str());
+    if let Some(id) = header(TASK_ID_HEADER).and_then(|id| id.parse().ok()) {
+        job.id = id;
+    }
+    if let Some(namespace) = header(NAMESPACE_HEADER) {
+        job.namespace = Namespace::from(namespace.to_string());
+    }
+    let priority = match header(PRIORITY_HEADER) {
+        Some("high") => Some(Priority::High),
+        Some("medium") => Some(Priority::Medium),
+        Some("low") => Some(Priority::Low),
+        _ => None,
+    };
+    if let Some(priority) = priority {
+        job.priority = priority;
     }
+    if let Some(created_at) = header(CREATED_AT_HEADER)
+        .and_then(|created_at| DateTime::parse_from_rfc3339(created_at).ok())
+    {
+        job.created_at = created_at.with_timezone(&Utc);
+    }
+}
 
-    /// Push a job with a specific priority
-    ///
-    /// Jobs are published to priority-specific streams and will be processed
-    /// in priority order (High -> Medium -> Low).
-    ///
-    /// # Example
-    /// ```no_run
-    /// # use apalis_nats::{NatsStorage, Priority};
-    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
-    /// storage.push_with_priority("urgent_task".to_string(), Priority::High).await?;
-    /// # Ok(())
-    /// # }
-    /// ```
-    pub async fn push_with_priority(
-        &self,
-        job: T,
-        priority: Priority,
-    ) -> Result<TaskId, NatsPollError> {
-        #[cfg(feature = "otel")]
-        let mut _span = if self.config.enable_tracing {
-            let tracer = global::tracer("apalis-nats");
-            let span = tracer
-                .span_builder("job.push")
-                .with_kind(SpanKind::Producer)
-                .with_attributes(vec![
-                    KeyValue::new("job.priority", priority.to_string()),
-                    KeyValue::new("job.namespace", self.config.namespace.clone()),
-                ])
-                .start(&tracer);
-            Some(span)
-        } else {
-            None
-        };
+/// The priority of a message published to `{namespace}.{priority}` or below it
+pub(crate) fn subject_priority(subject: &str) -> Option<Priority> {
+    match subject.split('.').nth(1) {
+        Some("high") => Some(Priority::High),
+        Some("medium") => Some(Priority::Medium),
+        Some("low") => Some(Priority::Low),
+        _ => None,
+    }
+}
 
-        let task_id = TaskId::new();
-        let nats_job = NatsJob {
-            id: task_id.clone(),
-            data: job,
-            priority,
-            attempts: Attempt::new(),
-            created_at: Utc::now(),
-            namespace: Namespace::from(self.config.namespace.clone()),
-        };
+/// `headers` without the `Nats-Expected-*` publish conditions, which were checked when the
+/// message was first stored and don't hold for republishing it elsewhere
+fn without_expectations(headers: &HeaderMap) -> HeaderMap {
+    let mut kept = HeaderMap::new();
+    for (name, values) in headers.iter() {
+        if name.to_string().to_ascii_lowercase().starts_with("nats-expected-") {
+            continue;
+        }
+        for value in values {
+            kept.append(name.clone(), value.clone());
+        }
+    }
+    kept
+}
 
-        let payload = serde_json::to_vec(&nats_job)?;
-        let subject = self.get_subject(priority);
+/// Wrap a payload published without the `NatsJob` envelope as a fresh job, taking the priority
+/// from its subject and the creation time from its stream timestamp, or `now` without one
+fn bare_job<T>(
+    data: T,
+    msg: &jetstream::Message,
+    namespace: &str,
+    now: DateTime<Utc>,
+) -> NatsJob<T> {
+    let priority = subject_priority(&msg.subject).unwrap_or_default();
+    let created_at = msg
+        .info()
+        .ok()
+        .and_then(|info| {
+            DateTime::from_timestamp(info.published.unix_timestamp(), info.published.nanosecond())
+        })
+        .unwrap_or(now);
+    NatsJob {
+        id: TaskId::new(),
+        data,
+        priority,
+        attempts: Attempt::new(),
+        created_at,
+        namespace: Namespace::from(namespace.to_string()),
+    }
+}
 
-        // Prepare headers with OpenTelemetry trace context
-        let mut headers = HeaderMap::new();
+/// The shared consumer name for a priority level
+pub(crate) fn consumer_name(config: &Config, priority: Priority) -> String {
+    // Every group gets its own durable, and so its own copy of each job
+    let prefix = match &config.consumer_group {
+        Some(group) => format!("{}_{}_{}", config.namespace, priority, group),
+        None => format!("{}_{}", config.namespace, priority),
+    };
+    match config.deliver_policy {
+        DeliverPolicy::All => format!("{}_consumer", prefix),
+        // Replay consumers start elsewhere, so they can't reuse the regular durable
+        _ => format!("{}_replay_consumer", prefix),
+    }
+}
 
-        #[cfg(feature = "otel")]
-        if self.config.enable_tracing {
-            // Inject current trace context into headers
-            let cx = Span::current().context();
-            global::get_text_map_propagator(|propagator| {
-                let mut injector = NatsHeaderInjector::new(headers.clone());
-                propagator.inject_context(&cx, &mut injector);
-                headers = injector.into();
-            });
+/// The shared pull consumer workers use for a priority level
+pub(crate) fn consumer_config(
+    config: &Config,
+    priority: Priority,
+) -> Result<consumer::pull::Config, NatsPollError> {
+    // Use a shared consumer name for all workers of the same priority
+    // This ensures work queue semantics - each message delivered to only one worker
+    let consumer_name = consumer_name(config, priority);
+
+    Ok(consumer::pull::Config {
+        name: Some(consumer_name.clone()),
+        durable_name: Some(consumer_name), // Make consumer durable
+        // Work queue settings - ensure only one worker gets each message
+        ack_policy: config.ack_policy.to_consumer_policy(),
+        ack_wait: config.ack_wait,
+        max_deliver: config.max_deliver,
+        // Sourced and mirrored jobs keep their upstream subject, and a priority stream only
+        // holds one priority anyway
+        filter_subject: match config.mirror.is_some() || !config.sources.is_empty() {
+            true => String::new(),
+            false => format!("{}.{}", config.namespace, priority),
+        },
+        // Where delivery starts, `All` unless replaying
+        deliver_policy: config.deliver_policy.to_consumer_policy()?,
+        // Control message delivery
+        max_ack_pending: config.max_ack_pending,
+        // Pull request limits
+        max_waiting: config.max_waiting(),
+        max_batch: config.max_batch,
+        // Replay policy - as fast as possible, or at the original publishing pace
+        replay_policy: config.replay_policy.to_consumer_policy(),
+        // Inactive threshold - remove consumer if inactive
+        inactive_threshold: Duration::from_secs(300), // 5 minutes
+        ..Default::default()
+    })
+}
+
+/// The settings `consumer_config` picks that `existing` has differently, as
+/// `field: existing (wanted wanted)`
+fn consumer_conflicts(wanted: &consumer::pull::Config, existing: &consumer::Config) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    let mut check = |field: &str, existing: String, wanted: String| {
+        if existing != wanted {
+            conflicts.push(format!("{}: {} (wanted {})", field, existing, wanted));
         }
+    };
+    check(
+        "ack_policy",
+        format!("{:?}", existing.ack_policy),
+        format!("{:?}", wanted.ack_policy),
+    );
+    check(
+        "ack_wait",
+        format!("{:?}", existing.ack_wait),
+        format!("{:?}", wanted.ack_wait),
+    );
+    check(
+        "max_deliver",
+        existing.max_deliver.to_string(),
+        wanted.max_deliver.to_string(),
+    );
+    check(
+        "filter_subject",
+        existing.filter_subject.clone(),
+        wanted.filter_subject.clone(),
+    );
+    check(
+        "deliver_policy",
+        format!("{:?}", existing.deliver_policy),
+        format!("{:?}", wanted.deliver_policy),
+    );
+    check(
+        "max_ack_pending",
+        existing.max_ack_pending.to_string(),
+        wanted.max_ack_pending.to_string(),
+    );
+    check(
+        "replay_policy",
+        format!("{:?}", existing.replay_policy),
+        format!("{:?}", wanted.replay_policy),
+    );
+    conflicts
+}
 
-        // Publish with headers
+/// `error` without the payload values serde may quote in its message
+fn redacted_error(error: &NatsPollError) -> String {
+    match error {
+        NatsPollError::Serialization(e) => format!(
+            "{:?} error at line {} column {}",
+            e.classify(),
+            e.line(),
+            e.column()
+        ),
+        other => other.to_string(),
+    }
+}
+
+/// Map an error from looking up `stream` (and `consumer`) to a typed [`NatsPollError`] where
+/// one fits, falling back to [`NatsPollError::Nats`]
+pub(crate) fn lookup_error(
+    error: impl Into<async_nats::Error>,
+    stream: &str,
+    consumer: Option<&str>,
+) -> NatsPollError {
+    let error: async_nats::Error = error.into();
+    let from_code = |e: &jetstream::Error| {
+        let code = e.error_code();
+        if code == ErrorCode::STREAM_NOT_FOUND {
+            Some(NatsPollError::StreamNotFound(stream.to_string()))
+        } else if code == ErrorCode::CONSUMER_NOT_FOUND {
+            Some(NatsPollError::ConsumerNotFound(
+                consumer.unwrap_or_default().to_string(),
+            ))
+        } else if code == ErrorCode::JETSTREAM_NOT_ENABLED
+            || code == ErrorCode::JETSTREAM_NOT_ENABLED_FOR_ACCOUNT
+        {
+            Some(NatsPollError::JetStreamDisabled)
+        } else if code == ErrorCode::CONSUMER_WQ_CONSUMER_NOT_UNIQUE
+            || code == ErrorCode::CONSUMER_WQ_MULTIPLE_UNFILTERED
+        {
+            Some(NatsPollError::ConsumerNotUnique {
+                stream: stream.to_string(),
+                consumer: consumer.unwrap_or_default().to_string(),
+            })
+        } else {
+            None
+        }
+    };
+
+    // The JetStream error may be the error itself, its kind, or further down the chain
+    let mut current: Option<&(dyn std::error::Error + 'static)> = Some(&*error);
+    while let Some(e) = current {
+        let mapped = if let Some(e) = e.downcast_ref::<jetstream::Error>() {
+            from_code(e)
+        } else if let Some(e) = e.downcast_ref::<GetStreamError>() {
+            match e.kind() {
+                GetStreamErrorKind::JetStream(e) => from_code(&e),
+                _ => None,
+            }
+        } else if let Some(e) = e.downcast_ref::<ConsumerError>() {
+            match e.kind() {
+                ConsumerErrorKind::JetStream(e) => from_code(&e),
+                ConsumerErrorKind::TimedOut => Some(NatsPollError::Timeout),
+                _ => None,
+            }
+        } else if let Some(e) = e.downcast_ref::<RequestError>() {
+            match e.kind() {
+                RequestErrorKind::TimedOut => Some(NatsPollError::Timeout),
+                RequestErrorKind::NoResponders => Some(NatsPollError::JetStreamDisabled),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if let Some(mapped) = mapped {
+            return mapped;
+        }
+        current = e.source();
+    }
+    NatsPollError::Nats(error.to_string())
+}
+
+/// Consecutive failed fetch rounds after which a worker re-creates its streams and consumers
+const RECOVERY_THRESHOLD: usize = 5;
+
+/// How long an idle worker waits between fetch rounds, before `Config::idle_jitter`
+const IDLE_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often a worker's heartbeat checks for shutdown between health checks
+const HEARTBEAT_SHUTDOWN_CHECK: Duration = Duration::from_millis(100);
+
+/// `base` scaled by a random factor between `1 - jitter` and `1 + jitter`
+fn jittered(base: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return base;
+    }
+    // Every `RandomState` gets fresh random keys, plenty for spreading out polls
+    let unit = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    base.mul_f64(1.0 + jitter * (2.0 * unit - 1.0))
+}
+
+impl<T> NatsStorage<T> {
+    /// Re-create the streams, status bucket and consumers, e.g. after a failover or a server
+    /// restart lost them. Cached consumers are dropped so they are looked up again.
+    pub(crate) async fn recover(&self) -> Result<(), NatsPollError> {
+        self.consumers
+            .lock()
+            .map_err(|_| NatsPollError::Storage("Consumer cache poisoned".into()))?
+            .clear();
+        ensure_streams(&self.jetstream, &self.config).await?;
+        if self.config.track_status {
+            ensure_status_bucket(&self.jetstream, &self.config).await?;
+        }
+        Ok(())
+    }
+
+    /// The first consumed priority stream that no longer exists, if any
+    async fn missing_stream(&self) -> Option<String> {
+        for &priority in &self.priorities {
+            let stream_name = self.get_stream_name(priority);
+            if let Err(e) = self.jetstream.get_stream(&stream_name).await {
+                if let NatsPollError::StreamNotFound(_) = lookup_error(e, &stream_name, None) {
+                    return Some(stream_name);
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the client is connected and every consumed stream can be looked up
+    async fn check_health(&self) -> Result<(), NatsPollError> {
+        let state = self.client.connection_state();
+        if state != async_nats::connection::State::Connected {
+            return Err(NatsPollError::Nats(format!("connection is {}", state)));
+        }
+        for &priority in &self.priorities {
+            let stream_name = self.get_stream_name(priority);
+            if let Err(e) = self.jetstream.get_stream(&stream_name).await {
+                return Err(lookup_error(e, &stream_name, None));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs [`check_health`](Self::check_health) every `heartbeat_interval` while `worker` runs,
+    /// and returns once it shuts down. This is the heartbeat of the worker's [`Poller`].
+    async fn heartbeat(self, worker: Worker<WorkerContext>) {
+        let interval = self.config.heartbeat_interval;
+        let mut healthy = true;
+        let mut next_check = Instant::now() + interval;
+        while !worker.is_shutting_down() {
+            let now = Instant::now();
+            if interval.is_zero() || now < next_check {
+                // Short naps, so shutting down doesn't wait for the next check
+                let nap = match interval.is_zero() {
+                    true => HEARTBEAT_SHUTDOWN_CHECK,
+                    false => (next_check - now).min(HEARTBEAT_SHUTDOWN_CHECK),
+                };
+                tokio::time::sleep(nap).await;
+                continue;
+            }
+            next_check = now + interval;
+            match self.check_health().await {
+                Ok(()) if healthy => {
+                    tracing::debug!("Heartbeat of worker {}: healthy", worker.id())
+                }
+                Ok(()) => {
+                    tracing::info!("Worker {} is healthy again", worker.id());
+                    healthy = true;
+                }
+                Err(e) => {
+                    tracing::warn!("Heartbeat of worker {} failed: {}", worker.id(), e);
+                    worker.emit(Event::Error(Box::new(e)));
+                    healthy = false;
+                }
+            }
+        }
+        tracing::debug!("Worker {} is shutting down, stopping its heartbeat", worker.id());
+    }
+
+    /// Spend a retry from the retry budget, `false` when it is exhausted
+    pub(crate) fn take_retry(&self) -> bool {
+        self.retry_tokens
+            .as_ref()
+            .map_or(true, |tokens| tokens.try_acquire(self.clock.now()))
+    }
+
+    /// How often jobs were retried, by the delivery count of the attempt that failed.
+    ///
+    /// `{1: 10, 2: 3}` means 10 first attempts and 3 second attempts failed and were scheduled
+    /// for a retry. Counts cover the Naks of every worker built from this storage (or its clones)
+    /// since it was created, and are useful to tune `max_deliver` and `nak_backoff`. With the
+    /// `metrics` feature they are also exported as `apalis_nats_retries_total`.
+    pub fn retry_stats(&self) -> BTreeMap<u64, u64> {
+        self.retry_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Stop fetching new jobs, e.g. for a deploy or a maintenance window.
+    ///
+    /// Workers built from this storage (or its clones) stay alive: jobs already running finish
+    /// and are acked, and jobs already fetched into a worker's buffer still run. Fetches stop
+    /// from the next fetch round on, so a pull already waiting on the server (see
+    /// [`PollMode::LongPoll`]) may still deliver a job. Unlike stopping the monitor, nothing has
+    /// to be rebuilt to start again, see [`resume`](NatsStorage::resume).
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Start fetching jobs again after [`pause`](NatsStorage::pause)
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    /// Whether fetching is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// How many messages workers on this storage (or its clones) received and haven't acked,
+    /// Nak'd or terminated yet, including fetched jobs waiting to start.
+    ///
+    /// These are redelivered after `ack_wait` if their worker stops now. Each worker logs the
+    /// count when it shuts down, which helps size its shutdown timeout.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Count a Nak of the `delivered`th attempt of a job
+    fn record_retry(&self, delivered: i64) {
+        let delivered = delivered.max(0) as u64;
+        *self
+            .retry_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(delivered)
+            .or_default() += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!(
+            "apalis_nats_retries_total",
+            "namespace" => self.config.namespace.clone(),
+            "delivered" => delivered.to_string()
+        )
+        .increment(1);
+    }
+
+    /// Record how long a job waited for its first delivery. Redeliveries are left out, their
+    /// wait includes the earlier attempts.
+    #[cfg(feature = "metrics")]
+    fn record_queue_latency(
+        &self,
+        msg: &jetstream::Message,
+        priority: Priority,
+        latency: Duration,
+    ) {
+        if msg.info().map_or(true, |info| info.delivered <= 1) {
+            metrics::histogram!(
+                "apalis_nats_queue_latency_seconds",
+                "priority" => priority.to_string()
+            )
+            .record(latency.as_secs_f64());
+        }
+    }
+
+    /// Report a job whose first delivery came later than the `sla` of its priority allows.
+    /// Redeliveries are left out, so a job breaches its SLA at most once.
+    fn check_sla(
+        &self,
+        worker: &Worker<WorkerContext>,
+        msg: &jetstream::Message,
+        task_id: &TaskId,
+        priority: Priority,
+        latency: Duration,
+    ) {
+        let Some(&sla) = self.config.sla.get(&priority) else {
+            return;
+        };
+        if latency <= sla || msg.info().is_ok_and(|info| info.delivered > 1) {
+            return;
+        }
+        let overage = latency - sla;
+        tracing::warn!(
+            "Task {} waited {:?} for a worker, {:?} over the {} priority SLA",
+            task_id,
+            latency,
+            overage,
+            priority
+        );
+        #[cfg(feature = "metrics")]
+        metrics::counter!(
+            "apalis_nats_sla_breaches_total",
+            "priority" => priority.to_string()
+        )
+        .increment(1);
+        worker.emit(Event::Custom(format!(
+            "sla_breach task_id={} priority={} overage_ms={}",
+            task_id,
+            priority,
+            overage.as_millis()
+        )));
+    }
+
+    /// The job type of a message, its [`JOB_TYPE_HEADER`] or this storage's type tag
+    fn job_type<'a>(&'a self, msg: &'a jetstream::Message) -> &'a str {
+        msg.headers
+            .as_ref()
+            .and_then(|headers| headers.get(JOB_TYPE_HEADER))
+            .map_or(self.type_tag.as_str(), |tag| tag.as_str())
+    }
+
+    /// Count the outcome of a job towards its type's quarantine, reporting a new quarantine
+    fn record_outcome(&self, worker: &Worker<WorkerContext>, ctx: &NatsContext, failed: bool) {
+        let (Some(tracker), Some(msg)) = (&self.quarantine, ctx.message()) else {
+            return;
+        };
+        let job_type = self.job_type(msg);
+        let Some(quarantined) = tracker.record(job_type, failed, self.clock.now()) else {
+            return;
+        };
+        let duration = self.config.quarantine.map(|policy| policy.duration);
+        tracing::error!(
+            "Quarantined job type {} for {:?}: {} of its last {} jobs failed",
+            job_type,
+            duration.unwrap_or_default(),
+            quarantined.failures,
+            quarantined.jobs
+        );
+        #[cfg(feature = "metrics")]
+        metrics::counter!("apalis_nats_quarantines_total", "job_type" => job_type.to_string())
+            .increment(1);
+        worker.emit(Event::Custom(format!(
+            "quarantine job_type={} failures={} jobs={} duration_ms={}",
+            job_type,
+            quarantined.failures,
+            quarantined.jobs,
+            duration.unwrap_or_default().as_millis()
+        )));
+    }
+
+    /// Nak a job whose type is quarantined until the quarantine ends, returning whether it was.
+    /// A job on its last delivery is dead-lettered as `quarantined` instead.
+    async fn hold_quarantined(&self, msg: &jetstream::Message) -> bool {
+        let Some(tracker) = &self.quarantine else {
+            return false;
+        };
+        let job_type = self.job_type(msg);
+        let Some(remaining) = tracker.remaining(job_type, self.clock.now()) else {
+            return false;
+        };
+        // Another Nak would leave the job in the stream with no deliveries left
+        if self.on_last_delivery(msg) {
+            let error = format!("Job type {} was quarantined on its last delivery", job_type);
+            if let Err(e) = self.dead_letter_unrun(msg, "quarantined", error).await {
+                tracing::error!("Failed to dead-letter job of type {}: {}", job_type, e);
+            }
+            return true;
+        }
+        match msg.ack_with(jetstream::AckKind::Nak(Some(remaining))).await {
+            Ok(()) => tracing::debug!(
+                "Held back job of quarantined type {} for {:?}",
+                job_type,
+                remaining
+            ),
+            Err(e) => tracing::error!("Failed to hold back job of type {}: {}", job_type, e),
+        }
+        true
+    }
+
+    /// The Nak delay for a message that has been delivered `delivered` times, from `nak_backoff`
+    pub(crate) fn nak_delay(&self, delivered: i64) -> Option<Duration> {
+        let idx = delivered.saturating_sub(1) as usize;
+        self.config
+            .nak_backoff
+            .get(idx)
+            .or(self.config.nak_backoff.last())
+            .copied()
+    }
+}
+
+/// The JetStream context for `config`, in its `jetstream_domain` if set
+pub(crate) fn jetstream_context(client: &Client, config: &Config) -> jetstream::Context {
+    match &config.jetstream_domain {
+        Some(domain) => jetstream::with_domain(client.clone(), domain),
+        None => jetstream::new(client.clone()),
+    }
+}
+
+impl<T> NatsStorage<T> {
+    /// Assemble a storage whose streams and status bucket already exist
+    pub(crate) fn from_parts(
+        client: Client,
+        jetstream: jetstream::Context,
+        config: Config,
+        status: Option<StatusTracker>,
+    ) -> Self {
+        let dlq_handle = Arc::new(DlqHandle {
+            jetstream: jetstream.clone(),
+            namespace: config.namespace.clone(),
+            subject: dlq_subject(&config),
+            tenant_label: config.tenant_label.clone(),
+            enable_dlq: config.enable_dlq,
+            redact: None,
+            clock: Arc::new(SystemClock),
+            client: client.clone(),
+            max_msg_size: config.max_msg_size,
+        });
+        let retry_tokens = config
+            .retry_budget
+            .map(|budget| Arc::new(RetryTokens::new(budget)));
+        let quarantine = config
+            .quarantine
+            .map(|policy| Arc::new(QuarantineTracker::new(policy)));
+        let state = Arc::new(StateBucket::new(jetstream.clone(), &config));
+
+        Self {
+            client,
+            jetstream,
+            config,
+            status,
+            dlq_handle,
+            migrations: Arc::new(Vec::new()),
+            redact: None,
+            filter: None,
+            classify: None,
+            producer_only: false,
+            type_tag: std::any::type_name::<T>().to_string(),
+            clock: Arc::new(SystemClock),
+            retry_tokens,
+            quarantine,
+            retry_stats: Default::default(),
+            paused: Default::default(),
+            priorities: config.priorities(),
+            in_flight: Default::default(),
+            worker_concurrency: Default::default(),
+            labels: None,
+            layers: NatsLayers::default(),
+            namespaces: Default::default(),
+            state,
+            consumers: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> NatsStorage<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Create a new NATS storage instance
+    pub async fn new(client: Client) -> Result<Self, NatsPollError> {
+        Self::new_with_config(client, Config::default()).await
+    }
+
+    /// Create a new NATS storage instance with custom config
+    pub async fn new_with_config(client: Client, config: Config) -> Result<Self, NatsPollError> {
+        Self::new_with_jetstream(jetstream_context(&client, &config), config).await
+    }
+
+    /// Create a new NATS storage instance on a pre-built JetStream context, e.g. one with a
+    /// custom request timeout or API prefix. The context's domain and prefix are used as they
+    /// are, so `Config::jetstream_domain` is ignored.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{Config, NatsStorage};
+    /// # use std::time::Duration;
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut jetstream = async_nats::jetstream::new(client);
+    /// jetstream.set_timeout(Duration::from_secs(10));
+    /// let storage = NatsStorage::<String>::new_with_jetstream(jetstream, Config::default()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn new_with_jetstream(
+        jetstream: jetstream::Context,
+        config: Config,
+    ) -> Result<Self, NatsPollError> {
+        ensure_streams(&jetstream, &config).await?;
+        let status = status_tracker(&jetstream, &config).await?;
+        Ok(Self::from_parts(jetstream.client(), jetstream, config, status))
+    }
+
+    /// Create a storage for a process that only pushes jobs, e.g. one whose credentials may
+    /// publish but not create consumers.
+    ///
+    /// The streams and the status bucket are prepared like in [`NatsStorage::new_with_config`]
+    /// (with `create_streams: false`, only checked), and no consumer is ever created or looked
+    /// up. All the `push*` methods work as usual, but a worker built on it stops right away
+    /// with a [`NatsPollError::Storage`] error instead of polling.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{Config, NatsStorage, Priority};
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let config = Config {
+    ///     create_streams: false,
+    ///     ..Default::default()
+    /// };
+    /// let producer = NatsStorage::<String>::producer(client, config).await?;
+    /// producer.push_with_priority("hello".to_string(), Priority::Medium).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn producer(client: Client, config: Config) -> Result<Self, NatsPollError> {
+        let mut storage = Self::new_with_config(client, config).await?;
+        storage.producer_only = true;
+        Ok(storage)
+    }
+
+    /// Register migrations that upgrade payloads published with an older schema of `T`.
+    ///
+    /// `migrations[0]` upgrades version 1 to 2, `migrations[1]` version 2 to 3 and so on; each
+    /// receives the job's `data` as JSON. Jobs are published with an `Apalis-Schema-Version`
+    /// header of `migrations.len() + 1`, and jobs without the header are treated as version 1.
+    /// Jobs from a newer (unknown) version, or that still don't decode after migrating, are
+    /// routed to the DLQ (or terminated when it is disabled).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # use serde::{Deserialize, Serialize};
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Email { to: Vec<String> } // v1 had `to: String`
+    ///
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let storage = NatsStorage::<Email>::new(client).await?.with_migrations(vec![
+    ///     Box::new(|mut v1: serde_json::Value| {
+    ///         v1["to"] = serde_json::json!([v1["to"].take()]);
+    ///         v1
+    ///     }),
+    /// ]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_migrations(mut self, migrations: Vec<Migration>) -> Self {
+        self.migrations = Arc::new(migrations);
+        self
+    }
+
+    /// Only consume `priorities` in workers polling this storage, e.g. for a pool of workers
+    /// dedicated to High jobs and a cheaper one draining Low jobs, each scaled on its own.
+    ///
+    /// They are still fetched from highest to lowest, whatever order they are given in.
+    /// Pushing is unaffected, and jobs of a priority no worker consumes just wait in their
+    /// stream, so make sure every priority in use has a pool.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) {
+    /// let high_only = storage.clone().for_priorities(&[Priority::High]);
+    /// let background = storage.for_priorities(&[Priority::Medium, Priority::Low]);
+    /// # }
+    /// ```
+    pub fn for_priorities(mut self, priorities: &[Priority]) -> Self {
+        self.priorities = self
+            .config
+            .priorities()
+            .into_iter()
+            .filter(|priority| priorities.contains(priority))
+            .collect();
+        self
+    }
+
+    /// Add `layers` to the service of every worker built on this storage, e.g.
+    /// [`NatsLayers::recommended`] for heartbeats, panic handling and metrics without any
+    /// `.layer(...)` calls. They run inside the backend's ack, around the handler and any
+    /// layers added on the `WorkerBuilder`. Pass [`NatsLayers::default`] to opt out again.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsLayers, NatsStorage};
+    /// # fn example(storage: NatsStorage<String>) {
+    /// let storage = storage.with_layers(NatsLayers::recommended().catch_panic(false));
+    /// # }
+    /// ```
+    pub fn with_layers(mut self, layers: NatsLayers) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    /// Only consume jobs pushed with [`NatsStorage::push_with_labels`] and a matching label
+    /// set in workers polling this storage, e.g. for a pool of GPU nodes.
+    ///
+    /// Jobs match when they carry exactly the keys of `filter`, with the same values; a `*`
+    /// value matches any value. Workers get their own consumer per priority, filtered on the
+    /// label subject. Priority streams are work queues, which refuse consumers with
+    /// overlapping filters, so pools must not share a label set: `gpu=*` next to `gpu=true`
+    /// fails to create its consumer.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # use std::collections::HashMap;
+    /// # fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let labels = HashMap::from([("gpu".to_string(), "true".to_string())]);
+    /// let gpu_pool = storage.for_labels(labels)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn for_labels(mut self, filter: HashMap<String, String>) -> Result<Self, NatsPollError> {
+        self.labels = Some(label_tokens(&filter, true)?);
+        // Consumers cached for the unlabeled pool don't apply anymore
+        self.consumers = Default::default();
+        Ok(self)
+    }
+
+    /// The priorities workers on this storage consume, highest first
+    pub fn priorities(&self) -> &[Priority] {
+        &self.priorities
+    }
+
+    /// Redact job payloads wherever they would leave the job stream.
+    ///
+    /// By default DLQ entries embed the raw payload, so anything sensitive in a job (emails,
+    /// tokens, card numbers) is copied into the DLQ stream, which keeps entries for 30 days and
+    /// is often readable by more people than the job streams. With a redactor, DLQ entries
+    /// store `redact(payload)` instead, and undecodable payloads are logged only in redacted
+    /// form, with serde's error reduced to its category and position since it may quote values.
+    ///
+    /// Redacted entries can no longer be decoded, so [`DlqEntry::job`](crate::DlqEntry::job)
+    /// fails for them and requeuing from the DLQ skips them.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let storage = NatsStorage::<String>::new(client)
+    ///     .await?
+    ///     .with_redactor(|payload| format!("<{} bytes redacted>", payload.len()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_redactor<F>(mut self, redact: F) -> Self
+    where
+        F: Fn(&[u8]) -> String + Send + Sync + 'static,
+    {
+        let redact: Redactor = Arc::new(redact);
+        self.dlq_handle = Arc::new(DlqHandle {
+            jetstream: self.jetstream.clone(),
+            namespace: self.config.namespace.clone(),
+            subject: dlq_subject(&self.config),
+            tenant_label: self.config.tenant_label.clone(),
+            enable_dlq: self.config.enable_dlq,
+            redact: Some(redact.clone()),
+            clock: Arc::clone(&self.clock),
+            client: self.client.clone(),
+            max_msg_size: self.config.max_msg_size,
+        });
+        self.redact = Some(redact);
+        self
+    }
+
+    /// Read the current time from `clock` instead of the system clock.
+    ///
+    /// Covers job timestamps, `max_retry_duration`, DLQ `retry_after`, queue latency and
+    /// scheduled job promotion, so a [`TestClock`](crate::TestClock) can step through them in
+    /// tests without waiting. Set it before building workers from the storage.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        self.dlq_handle = Arc::new(DlqHandle {
+            jetstream: self.jetstream.clone(),
+            namespace: self.config.namespace.clone(),
+            subject: dlq_subject(&self.config),
+            tenant_label: self.config.tenant_label.clone(),
+            enable_dlq: self.config.enable_dlq,
+            redact: self.redact.clone(),
+            clock: clock.clone(),
+            client: self.client.clone(),
+            max_msg_size: self.config.max_msg_size,
+        });
+        self.clock = clock;
+        self
+    }
+
+    /// Decide per job, after decoding it, whether workers run it, drop it or put it off.
+    ///
+    /// Meant for temporary interventions without a deploy, e.g. pausing one tenant's jobs. The
+    /// filter runs on the worker for every delivered job, so keep it cheap.
+    /// [`FilterAction::Skip`] acks the job, which drops it permanently: it is neither run
+    /// nor sent to the DLQ. [`FilterAction::Defer`] Naks it with a delay, and each deferral
+    /// uses up one of its `max_deliver` deliveries.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use apalis_nats::{FilterAction, NatsStorage};
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let storage = NatsStorage::<String>::new(client)
+    ///     .await?
+    ///     .with_filter(|job: &String| match job.as_str() {
+    ///         "tenant-x" => FilterAction::Defer(Duration::from_secs(60)),
+    ///         "obsolete" => FilterAction::Skip,
+    ///         _ => FilterAction::Process,
+    ///     });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&T) -> FilterAction + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Decide by its error what happens to a failed job, instead of retrying everything but
+    /// `Error::Abort`.
+    ///
+    /// The classifier sees the error the handler returned, so it can downcast the source of an
+    /// `Error::Failed` to tell a missing record from a timeout. [`Disposition::DeadLetter`]
+    /// entries get the `dlq_reason` `classified` (`abort_error` for an `Error::Abort`), and
+    /// [`Disposition::Retry`] still stops at `max_deliver` and counts against a
+    /// `retry_budget`. Errors of jobs acked by their handler are not classified.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use apalis::prelude::Error;
+    /// # use apalis_nats::{Disposition, NatsStorage};
+    /// #[derive(Debug)]
+    /// struct NotFound;
+    /// # impl std::fmt::Display for NotFound {
+    /// #     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// #         write!(f, "not found")
+    /// #     }
+    /// # }
+    /// # impl std::error::Error for NotFound {}
+    ///
+    /// # async fn example(client: async_nats::Client) -> Result<(), Box<dyn std::error::Error>> {
+    /// let storage = NatsStorage::<String>::new(client)
+    ///     .await?
+    ///     .with_classifier(|error: &Error| match error {
+    ///         Error::Failed(e) if e.is::<NotFound>() => Disposition::DeadLetter,
+    ///         Error::Abort(_) => Disposition::DeadLetter,
+    ///         _ => Disposition::Retry { delay: None },
+    ///     });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_classifier<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&Error) -> Disposition + Send + Sync + 'static,
+    {
+        self.classify = Some(Arc::new(classify));
+        self
+    }
+
+    /// Tag the jobs this storage pushes with `tag` instead of `std::any::type_name::<T>()`.
+    ///
+    /// Workers leave jobs tagged for another type to that type's workers instead of failing to
+    /// decode them, see the README on mixing job types in a namespace. Jobs from an older
+    /// schema version (see [`NatsStorage::with_migrations`]) are always taken. The type name
+    /// depends on the crate and module `T` is defined in, so set a tag when producers and
+    /// workers are built from different crates, or keep the old name when renaming `T`.
+    pub fn with_type_tag(mut self, tag: impl Into<String>) -> Self {
+        self.type_tag = tag.into();
+        self
+    }
+
+    /// The tag jobs pushed by this storage carry, see [`NatsStorage::with_type_tag`]
+    pub fn type_tag(&self) -> &str {
+        &self.type_tag
+    }
+
+    /// Leave a job pushed by a storage of another type to its own workers, returning whether
+    /// `msg` was such a job. Jobs without a type tag, or from a schema version the migrations
+    /// upgrade, are taken as this storage's. A job on its last delivery is dead-lettered as
+    /// `foreign_type` rather than Naked once more.
+    async fn skip_foreign_job(&self, msg: &jetstream::Message) -> bool {
+        let Some(tag) = msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(JOB_TYPE_HEADER))
+        else {
+            return false;
+        };
+        // An older schema of `T` may have been pushed under an older type name, the
+        // migrations take care of it
+        if tag.as_str() == self.type_tag || message_schema_version(msg) < self.schema_version() {
+            return false;
+        }
+        // A group has its own copy of the job, so this group is done with it
+        let settled = match self.config.consumer_group {
+            Some(_) => msg.ack().await,
+            // Another Nak would leave the job in the stream with no deliveries left
+            None if self.on_last_delivery(msg) => {
+                tracing::error!(
+                    "Job of type {} reached only workers of {} before max_deliver ran out",
+                    tag,
+                    self.type_tag
+                );
+                let error = format!(
+                    "Every delivery went to workers of {}, not {}",
+                    self.type_tag, tag
+                );
+                if let Err(e) = self.dead_letter_unrun(msg, "foreign_type", error).await {
+                    tracing::error!("Failed to dead-letter job of type {}: {}", tag, e);
+                }
+                return true;
+            }
+            None => {
+                msg.ack_with(jetstream::AckKind::Nak(Some(FOREIGN_JOB_DELAY)))
+                    .await
+            }
+        };
+        match settled {
+            Ok(()) => tracing::debug!(
+                "Left job of type {} to its own workers, this storage handles {}",
+                tag,
+                self.type_tag
+            ),
+            Err(e) => tracing::error!("Failed to settle job of type {}: {}", tag, e),
+        }
+        true
+    }
+
+    /// Apply the filter to a decoded job, returning whether it was skipped or deferred
+    async fn filter_job(&self, msg: &jetstream::Message, job: &NatsJob<T>) -> bool {
+        let Some(filter) = &self.filter else {
+            return false;
+        };
+        let action = filter(&job.data);
+        let (acked, status) = match action {
+            FilterAction::Process => return false,
+            FilterAction::Skip => (msg.ack().await, JobStatus::Dead),
+            FilterAction::Defer(delay) => (
+                msg.ack_with(jetstream::AckKind::Nak(Some(delay))).await,
+                JobStatus::Pending,
+            ),
+        };
+        match acked {
+            Ok(()) => {
+                self.update_status(&job.id, status);
+                tracing::debug!("Filter returned {:?} for task {}", action, job.id);
+            }
+            Err(e) => tracing::error!("Failed to settle filtered task {}: {}", job.id, e),
+        }
+        true
+    }
+
+    /// The schema version new jobs are published with
+    pub fn schema_version(&self) -> usize {
+        self.migrations.len() + 1
+    }
+
+    /// Decode a job envelope, upgrading payloads from older schema versions
+    fn decode_job(&self, msg: &jetstream::Message) -> Result<NatsJob<T>, NatsPollError> {
+        let current = self.schema_version();
+        let version = message_schema_version(msg);
+
+        if version == current {
+            let mut job = match serde_json::from_slice(&msg.payload) {
+                Ok(job) => job,
+                Err(e) => match self.decode_bare(msg) {
+                    Some(data) => bare_job(data, msg, &self.config.namespace, self.clock.now()),
+                    None => return Err(e.into()),
+                },
+            };
+            apply_job_headers(&mut job, msg.headers.as_ref());
+            return Ok(job);
+        }
+        if version > current {
+            return Err(NatsPollError::Storage(format!(
+                "Unsupported schema version {} (current is {})",
+                version, current
+            )));
+        }
+
+        let job: NatsJob<serde_json::Value> = match serde_json::from_slice(&msg.payload) {
+            Ok(job) => job,
+            Err(e) => match self.decode_bare(msg) {
+                Some(data) => bare_job(data, msg, &self.config.namespace, self.clock.now()),
+                None => return Err(e.into()),
+            },
+        };
+        let data = self.migrations[version - 1..]
+            .iter()
+            .fold(job.data, |data, migrate| migrate(data));
+        let mut job = NatsJob {
+            id: job.id,
+            data: serde_json::from_value(data)?,
+            priority: job.priority,
+            attempts: job.attempts,
+            created_at: job.created_at,
+            namespace: job.namespace,
+        };
+        apply_job_headers(&mut job, msg.headers.as_ref());
+        Ok(job)
+    }
+
+    /// The payload as a bare value without the `NatsJob` envelope, if
+    /// `Config::accept_bare_payloads` allows it
+    fn decode_bare<D: DeserializeOwned>(&self, msg: &jetstream::Message) -> Option<D> {
+        if !self.config.accept_bare_payloads {
+            return None;
+        }
+        let data = serde_json::from_slice(&msg.payload).ok()?;
+        tracing::debug!("Decoded a message on {} without a job envelope", msg.subject);
+        Some(data)
+    }
+
+    /// Route a message that can't be decoded into a job to the DLQ, or Term it without one
+    async fn reject_undecodable(
+        &self,
+        msg: &jetstream::Message,
+        error: &NatsPollError,
+    ) -> Result<(), NatsPollError> {
+        let error = match self.redact {
+            Some(_) => redacted_error(error),
+            None => error.to_string(),
+        };
+        self.dead_letter_unrun(msg, "decode_error", error).await
+    }
+
+    /// Whether JetStream stops delivering `msg` once it is Naked, leaving it in the stream
+    fn on_last_delivery(&self, msg: &jetstream::Message) -> bool {
+        let max_deliver = self.config.max_deliver;
+        max_deliver > 0 && msg.info().is_ok_and(|info| info.delivered >= max_deliver)
+    }
+
+    /// Route a message that never reached the handler to the DLQ with `reason`, or Term it
+    /// without one
+    async fn dead_letter_unrun(
+        &self,
+        msg: &jetstream::Message,
+        reason: &str,
+        error: String,
+    ) -> Result<(), NatsPollError> {
+        if self.config.enable_dlq {
+            let delivered = msg.info().map(|info| info.delivered).unwrap_or(1);
+            let original_task_id =
+                serde_json::from_slice::<NatsJob<serde_json::Value>>(&msg.payload)
+                    .map(|job| job.id.to_string())
+                    .unwrap_or_default();
+            let mut entry = DlqEntry {
+                original_task_id,
+                error,
+                error_detail: None,
+                attempts: delivered as usize,
+                delivered_count: delivered,
+                timestamp: self.clock.now(),
+                dlq_reason: reason.to_string(),
+                payload: entry_payload(self.redact.as_ref(), &msg.payload),
+                payload_truncated: None,
+                metadata: None,
+                namespace: Some(self.config.namespace.clone()),
+                retry_after: None,
+                original_subject: Some(msg.subject.to_string()),
+                original_priority: subject_priority(&msg.subject),
+                original_sequence: msg.info().ok().map(|info| info.stream_sequence),
+            };
+            let body = entry.to_bytes(self.dlq_size_limit())?;
+            self.jetstream
+                .publish(self.dlq_subject_for(&msg.subject), body.into())
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            msg.ack()
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        } else {
+            msg.ack_with(jetstream::AckKind::Term)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Move a job that never made it onto its stream, e.g. a scheduled job that can't be
+    /// enqueued, to the DLQ with `reason`. Dropped when the DLQ is disabled.
+    pub(crate) async fn dead_letter_unpublished(
+        &self,
+        job: &NatsJob<T>,
+        reason: &str,
+        error: String,
+    ) -> Result<(), NatsPollError>
+    where
+        T: Serialize,
+    {
+        if !self.config.enable_dlq {
+            return Ok(());
+        }
+        let subject = self.get_subject(job.priority);
+        let payload = serde_json::to_vec(job)?;
+        let mut entry = DlqEntry {
+            original_task_id: job.id.to_string(),
+            error,
+            error_detail: None,
+            attempts: 0,
+            delivered_count: 0,
+            timestamp: self.clock.now(),
+            dlq_reason: reason.to_string(),
+            payload: entry_payload(self.redact.as_ref(), &payload),
+            payload_truncated: None,
+            metadata: None,
+            namespace: Some(self.config.namespace.clone()),
+            retry_after: None,
+            original_subject: Some(subject.clone()),
+            original_priority: Some(job.priority),
+            original_sequence: None,
+        };
+        let body = entry.to_bytes(self.dlq_size_limit())?;
+        self.jetstream
+            .publish(self.dlq_subject_for(&subject), body.into())
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Warn when a worker could have more jobs in flight than the consumer hands out
+    fn check_concurrency(&self, worker: &Worker<WorkerContext>) {
+        let max_ack_pending = self.config.max_ack_pending;
+        // Only limits set through `NatsWorkerExt` are visible here, `.concurrency(n)` isn't
+        let concurrency = match self.worker_concurrency.load(Ordering::Acquire) {
+            0 => self.config.concurrency,
+            recorded => Some(recorded),
+        };
+        let Some(concurrency) = concurrency else {
+            return;
+        };
+        if concurrency as i64 > max_ack_pending {
+            tracing::warn!(
+                "Worker {} concurrency ({}) exceeds max_ack_pending ({}), throughput is \
+                 capped at {} jobs in flight. Use `Config::with_concurrency({})`",
+                worker.id(),
+                concurrency,
+                max_ack_pending,
+                max_ack_pending,
+                concurrency
+            );
+        }
+    }
+
+    /// Get the stream name for a priority level
+    pub(crate) fn get_stream_name(&self, priority: Priority) -> String {
+        format!("{}_{}", self.config.namespace, priority)
+    }
+
+    /// Messages stored in the streams this storage's workers consume, acked or not
+    pub(crate) async fn queued(&self) -> Result<u64, NatsPollError> {
+        let mut total = 0;
+        for &priority in &self.priorities {
+            let stream_name = self.get_stream_name(priority);
+            let mut stream = self
+                .jetstream
+                .get_stream(&stream_name)
+                .await
+                .map_err(|e| lookup_error(e, &stream_name, None))?;
+            let info = stream
+                .info()
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            total += info.state.messages;
+        }
+        Ok(total)
+    }
+
+    /// Get the shared consumer name for a priority level
+    pub(crate) fn consumer_name(&self, priority: Priority) -> String {
+        let name = consumer_name(&self.config, priority);
+        match &self.labels {
+            // Every label filter gets its own durable next to the unlabeled one
+            Some(labels) => {
+                let hash = format!("{:x}", Sha256::digest(labels.as_bytes()));
+                format!("{}_{}", name, &hash[..12])
+            }
+            None => name,
+        }
+    }
+
+    /// Get the subject for a priority level
+    fn get_subject(&self, priority: Priority) -> String {
+        format!("{}.{}", self.config.namespace, priority)
+    }
+
+    /// Refuse a serialized job larger than `Config::max_msg_size`
+    fn check_size(&self, payload: &[u8]) -> Result<(), NatsPollError> {
+        match self.config.max_msg_size {
+            Some(limit) if payload.len() > limit => Err(NatsPollError::PayloadTooLarge {
+                size: payload.len(),
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The largest DLQ entry this storage can publish
+    fn dlq_size_limit(&self) -> usize {
+        dlq_size_limit(&self.client, self.config.max_msg_size)
+    }
+
+    /// The DLQ subject for a message published to `subject`, see `Config::tenant_label`
+    fn dlq_subject_for(&self, subject: &str) -> String {
+        tenant_dlq_subject(
+            &dlq_subject(&self.config),
+            &self.config.namespace,
+            self.config.tenant_label.as_deref(),
+            subject,
+        )
+    }
+
+    /// Push a job with a specific priority
+    ///
+    /// Jobs are published to priority-specific streams and will be processed
+    /// in priority order (High -> Medium -> Low).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// storage.push_with_priority("urgent_task".to_string(), Priority::High).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_with_priority(
+        &self,
+        job: T,
+        priority: Priority,
+    ) -> Result<TaskId, NatsPollError> {
+        let mut headers = HeaderMap::new();
+        if self.config.dedup_by_payload_hash {
+            headers.insert(NATS_MESSAGE_ID, payload_hash(&serde_json::to_vec(&job)?));
+        }
+        self.push_with_headers(None, job, priority, headers)
+            .await
+            .map(|outcome| outcome.task_id)
+    }
+
+    /// Push a job to the `priority` stream of another namespace, over this storage's
+    /// connection. A lighter alternative to a storage per namespace (see
+    /// [`NatsStorageRegistry`](crate::NatsStorageRegistry)) for a service feeding several
+    /// queues of the same job type.
+    ///
+    /// The namespace's streams (and status bucket, with `Config::track_status`) must already
+    /// exist, set up by a storage for it; `push_to` never creates them, and fails with
+    /// [`NatsPollError::StreamNotFound`] when they are missing. The status bucket is looked up
+    /// on the first push to a namespace and reused after that. Everything else, like the type
+    /// tag, schema version and `max_msg_size`, comes from this storage.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// storage.push_to("billing", "invoice 42".to_string(), Priority::High).await?;
+    /// storage.push_to("reports", "weekly".to_string(), Priority::Low).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_to(
+        &self,
+        namespace: &str,
+        job: T,
+        priority: Priority,
+    ) -> Result<TaskId, NatsPollError> {
+        if namespace == self.config.namespace {
+            return self.push_with_priority(job, priority).await;
+        }
+        self.in_namespace(namespace)
+            .await?
+            .push_with_priority(job, priority)
+            .await
+    }
+
+    /// This storage pushing to `namespace` instead, for [`NatsStorage::push_to`]
+    async fn in_namespace(&self, namespace: &str) -> Result<Self, NatsPollError> {
+        if !is_subject_token(namespace) {
+            return Err(NatsPollError::Storage(format!(
+                "Invalid namespace {:?}: it can't be empty or contain '.', '*', '>' or whitespace",
+                namespace
+            )));
+        }
+        // Held across the lookup so concurrent pushes don't look up the same bucket twice
+        let mut namespaces = self.namespaces.lock().await;
+        let status = match namespaces.get(namespace) {
+            Some(status) => Some(status.clone()),
+            // Without a bucket to look up, a missing namespace fails the publish instead
+            None if !self.config.track_status => None,
+            None => {
+                let bucket = format!("{}_status", namespace);
+                let stream_name = format!("KV_{}", bucket);
+                self.jetstream
+                    .get_stream(&stream_name)
+                    .await
+                    .map_err(|e| lookup_error(e, &stream_name, None))?;
+                let status = self
+                    .jetstream
+                    .get_key_value(&bucket)
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                let status = StatusTracker::new(status);
+                namespaces.insert(namespace.to_string(), status.clone());
+                Some(status)
+            }
+        };
+        drop(namespaces);
+
+        let mut target = self.clone();
+        target.config.namespace = namespace.to_string();
+        // Its own config decides which priorities have a stream, a missing one fails the publish
+        target.config.enabled_priorities = vec![Priority::High, Priority::Medium, Priority::Low];
+        target.status = status;
+        Ok(target)
+    }
+
+    /// Flush everything the client has buffered to the server, e.g. before a process exits.
+    ///
+    /// Pushes wait for JetStream to acknowledge the stored job, so a job whose push returned is
+    /// already durable. What the client may still hold in its write buffer are the fire-and-forget
+    /// messages: acks, Naks and progress acks sent by workers and handlers, and anything
+    /// published with core NATS on a client shared with this storage. Call this on shutdown
+    /// after the last push or job, so those reach the server before the connection drops.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// storage.push_with_priority("last job".to_string(), Priority::Low).await?;
+    /// storage.flush().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn flush(&self) -> Result<(), NatsPollError> {
+        self.client
+            .flush()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))
+    }
+
+    /// Push a job with a `Nats-Msg-Id`, so JetStream stores it only once per `msg_id` within
+    /// the stream's duplicate window, `Config::duplicate_window` (2 minutes by default). With
+    /// a zero window every push is stored.
+    ///
+    /// A push that hits the window isn't an error: the returned [`PushOutcome`] has `duplicate`
+    /// set, and the job is not enqueued again. Idempotent producers can retry a push with the
+    /// same id after a timeout and log or count the duplicates.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let outcome = storage
+    ///     .push_with_id("invoice 42".to_string(), Priority::High, "invoice-42")
+    ///     .await?;
+    /// if outcome.duplicate {
+    ///     println!("invoice 42 was already enqueued at sequence {}", outcome.sequence);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_with_id(
+        &self,
+        job: T,
+        priority: Priority,
+        msg_id: &str,
+    ) -> Result<PushOutcome, NatsPollError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(NATS_MESSAGE_ID, msg_id);
+        self.push_with_headers(None, job, priority, headers).await
+    }
+
+    /// Push a critical job, returning only once JetStream acknowledged storing it, after
+    /// checking its stream is replicated enough to keep it through a leader failover.
+    ///
+    /// Every push awaits the stream's PubAck, and on a replicated stream the leader only sends
+    /// it once a quorum (a majority of the `num_replicas` copies) stored the job, so an
+    /// acknowledged job survives losing the leader. What a plain push doesn't check is that
+    /// the stream is replicated as configured: streams are never updated by a storage, so one
+    /// created earlier keeps its replica count whatever `Config::num_replicas` says now.
+    /// `push_durable` first looks up the stream and fails with [`NatsPollError::Storage`],
+    /// before publishing anything, when it has fewer replicas than `num_replicas` or fewer
+    /// than a quorum of them are current. That costs a round trip per push, on top of the
+    /// publish.
+    ///
+    /// With `num_replicas: 1` the job is durable on the one server holding the stream, same as
+    /// with [`push_with_priority`](Self::push_with_priority).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let outcome = storage.push_durable("payout 42".to_string(), Priority::High).await?;
+    /// println!("payout 42 stored at sequence {}", outcome.sequence);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_durable(
+        &self,
+        job: T,
+        priority: Priority,
+    ) -> Result<PushOutcome, NatsPollError> {
+        self.config.check_priority(priority)?;
+        self.check_replication(priority).await?;
+        let mut headers = HeaderMap::new();
+        if self.config.dedup_by_payload_hash {
+            headers.insert(NATS_MESSAGE_ID, payload_hash(&serde_json::to_vec(&job)?));
+        }
+        self.push_with_headers(None, job, priority, headers).await
+    }
+
+    /// Fail unless `priority`'s stream has `Config::num_replicas` replicas and a quorum of
+    /// them is current
+    async fn check_replication(&self, priority: Priority) -> Result<(), NatsPollError> {
+        let stream_name = self.get_stream_name(priority);
+        let mut stream = self
+            .jetstream
+            .get_stream(&stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        let info = stream
+            .info()
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        let replicas = info.config.num_replicas.max(1);
+        if replicas < self.config.num_replicas {
+            return Err(NatsPollError::Storage(format!(
+                "Stream {} has {} replicas, fewer than num_replicas ({})",
+                stream_name, replicas, self.config.num_replicas
+            )));
+        }
+        if replicas > 1 {
+            // The leader and the followers that are caught up with it
+            let current = info.cluster.as_ref().map_or(0, |cluster| {
+                cluster.leader.is_some() as usize
+                    + cluster.replicas.iter().filter(|peer| peer.current).count()
+            });
+            let quorum = replicas / 2 + 1;
+            if current < quorum {
+                return Err(NatsPollError::Storage(format!(
+                    "Stream {} has {} of {} replicas current, short of a quorum of {}",
+                    stream_name, current, replicas, quorum
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Push a job only if the last job on its priority subject has sequence `expected_last_seq`.
+    ///
+    /// Sets the `Nats-Expected-Last-Subject-Sequence` header, so the server rejects the publish
+    /// when anything newer was stored on `{namespace}.{priority}` in the meantime. Use `0` to
+    /// require that nothing was ever published there. A rejection is returned as
+    /// [`NatsPollError::PreconditionFailed`] and leaves no trace in the status bucket.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsPollError, NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// match storage.push_with_expected_seq("sync".to_string(), Priority::Low, 0).await {
+    ///     Ok(task_id) => println!("enqueued {}", task_id),
+    ///     Err(NatsPollError::PreconditionFailed(_)) => println!("already enqueued"),
+    ///     Err(e) => return Err(e.into()),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_with_expected_seq(
+        &self,
+        job: T,
+        priority: Priority,
+        expected_last_seq: u64,
+    ) -> Result<TaskId, NatsPollError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            NATS_EXPECTED_LAST_SUBJECT_SEQUENCE,
+            expected_last_seq.to_string(),
+        );
+        self.push_with_headers(None, job, priority, headers)
+            .await
+            .map(|outcome| outcome.task_id)
+    }
+
+    /// Push a job with labels, so only workers consuming a matching label set run it, e.g.
+    /// `gpu=true` jobs on GPU nodes, see [`NatsStorage::for_labels`].
+    ///
+    /// Labels are published as subject tokens, `{namespace}.{priority}.{key}.{value}...`
+    /// sorted by key, so keys and values can't be empty or contain `.`, `*`, `>` or
+    /// whitespace; invalid ones return [`NatsPollError::Storage`]. Workers that don't use
+    /// `for_labels` never receive labeled jobs.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # use std::collections::HashMap;
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let labels = HashMap::from([("gpu".to_string(), "true".to_string())]);
+    /// storage
+    ///     .push_with_labels("render".to_string(), Priority::High, labels)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_with_labels(
+        &self,
+        job: T,
+        priority: Priority,
+        labels: HashMap<String, String>,
+    ) -> Result<TaskId, NatsPollError> {
+        let subject = format!("{}.{}", self.get_subject(priority), label_tokens(&labels, false)?);
+        self.push_to_subject(subject, None, job, priority, HeaderMap::new(), &[])
+            .await
+            .map(|outcome| outcome.task_id)
+    }
+
+    /// Push a job and broadcast a copy of it to each of `mirror_subjects`, e.g. for other
+    /// services that want to see every order without being apalis workers.
+    ///
+    /// The job is stored and run as with [`push_with_priority`](Self::push_with_priority).
+    /// Once JetStream has stored it, the exact message (payload and job headers) is published
+    /// to every mirror subject with core NATS: subscribers that are not connected miss it,
+    /// copies are not retried or acked, and nothing orders them relative to the job being
+    /// run. A failed mirror publish is logged rather than returned, because the job is already
+    /// enqueued. Mirror subjects can't be inside the namespace, where the streams would store
+    /// the copies as jobs; those return [`NatsPollError::Storage`] before anything is published.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// storage
+    ///     .push_with_mirror(
+    ///         "order 42".to_string(),
+    ///         Priority::High,
+    ///         &["events.orders".to_string(), "audit.orders".to_string()],
+    ///     )
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn push_with_mirror(
+        &self,
+        job: T,
+        priority: Priority,
+        mirror_subjects: &[String],
+    ) -> Result<TaskId, NatsPollError> {
+        let namespace = &self.config.namespace;
+        if let Some(subject) = mirror_subjects.iter().find(|subject| {
+            *subject == namespace || subject.starts_with(&format!("{}.", namespace))
+        }) {
+            return Err(NatsPollError::Storage(format!(
+                "Mirror subject {} is inside namespace {}, its copies would be stored as jobs",
+                subject, namespace
+            )));
+        }
+        self.push_to_subject(
+            self.get_subject(priority),
+            None,
+            job,
+            priority,
+            HeaderMap::new(),
+            mirror_subjects,
+        )
+        .await
+        .map(|outcome| outcome.task_id)
+    }
+
+    /// Publish a job as `task_id`, or under a new id when `None`, with `headers` added to the
+    /// ones every job carries
+    pub(crate) async fn push_with_headers(
+        &self,
+        task_id: Option<TaskId>,
+        job: T,
+        priority: Priority,
+        headers: HeaderMap,
+    ) -> Result<PushOutcome, NatsPollError> {
+        self.push_to_subject(self.get_subject(priority), task_id, job, priority, headers, &[])
+            .await
+    }
+
+    /// Publish a job as `task_id`, or under a new id when `None`, to `subject`, one of
+    /// `priority`'s, and once stored, copy it to `mirrors` with core NATS
+    async fn push_to_subject(
+        &self,
+        subject: String,
+        task_id: Option<TaskId>,
+        job: T,
+        priority: Priority,
+        mut headers: HeaderMap,
+        mirrors: &[String],
+    ) -> Result<PushOutcome, NatsPollError> {
+        self.config.check_priority(priority)?;
+        // A job republished under its own id, e.g. a promoted scheduled job, already has a
+        // status that a duplicate or failed publish must leave alone
+        let fresh = task_id.is_none();
+        let task_id = task_id.unwrap_or_else(TaskId::new);
+        #[cfg(feature = "otel")]
+        let mut _span = if self.config.enable_tracing {
+            let tracer = global::tracer("apalis-nats");
+            let span = tracer
+                .span_builder("job.push")
+                .with_kind(SpanKind::Producer)
+                .with_attributes(vec![
+                    KeyValue::new("job.priority", priority.to_string()),
+                    KeyValue::new("job.namespace", self.config.namespace.clone()),
+                ])
+                .start(&tracer);
+            Some(span)
+        } else {
+            None
+        };
+
+        let nats_job = NatsJob {
+            id: task_id.clone(),
+            data: job,
+            priority,
+            attempts: Attempt::new(),
+            created_at: self.clock.now(),
+            namespace: Namespace::from(self.config.namespace.clone()),
+        };
+
+        let payload = serde_json::to_vec(&nats_job)?;
+        self.check_size(&payload)?;
+
+        // Prepare headers with OpenTelemetry trace context
+        headers.insert(SCHEMA_VERSION_HEADER, self.schema_version().to_string());
+        headers.insert(JOB_TYPE_HEADER, self.type_tag.as_str());
+        insert_job_headers(&mut headers, &nats_job);
+
+        #[cfg(feature = "otel")]
+        if self.config.enable_tracing {
+            // Inject current trace context into headers
+            let cx = Span::current().context();
+            global::get_text_map_propagator(|propagator| {
+                let mut injector = NatsHeaderInjector::new(headers.clone());
+                propagator.inject_context(&cx, &mut injector);
+                headers = injector.into();
+            });
+        }
+
+        // Record the status first so a fast worker's `Running` isn't overwritten
+        if fresh {
+            self.set_status(&task_id, JobStatus::Pending).await;
+        }
+
+        // Publish with headers
+        let payload = Bytes::from(payload);
+        let mirror_headers = headers.clone();
+        let (sent, published) = match self
+            .jetstream
+            .publish_with_headers(subject, headers, payload.clone())
+            .await
+        {
+            Ok(ack) => (true, ack.await),
+            Err(e) => (false, Err(e)),
+        };
+        let ack = match published {
+            Ok(ack) => ack,
+            Err(e) => {
+                let kind = e.kind();
+                // The job was never stored, so it shouldn't show up as pending. Without a PubAck
+                // for a sent job it may have been, so its status stays.
+                let stored = sent
+                    && kind != PublishErrorKind::WrongLastSequence
+                    && kind != PublishErrorKind::StreamNotFound;
+                if fresh && !stored {
+                    self.clear_status(&task_id).await;
+                }
+                return Err(match kind {
+                    PublishErrorKind::WrongLastSequence => {
+                        NatsPollError::PreconditionFailed(e.to_string())
+                    }
+                    PublishErrorKind::StreamNotFound => {
+                        NatsPollError::StreamNotFound(self.get_stream_name(priority))
+                    }
+                    _ => NatsPollError::Nats(e.to_string()),
+                });
+            }
+        };
+        if ack.duplicate {
+            // Same as above, the original job keeps its own status
+            if fresh {
+                self.clear_status(&task_id).await;
+            }
+        } else {
+            if !fresh {
+                // Only once stored, a deduplicated republish would overwrite where the job got to
+                self.set_status(&task_id, JobStatus::Pending).await;
+            }
+            for mirror in mirrors {
+                if let Err(e) = self
+                    .client
+                    .publish_with_headers(mirror.clone(), mirror_headers.clone(), payload.clone())
+                    .await
+                {
+                    tracing::warn!("Failed to mirror task {} to {}: {}", task_id, mirror, e);
+                }
+            }
+        }
+
+        #[cfg(feature = "otel")]
+        if let Some(ref mut span) = _span {
+            use OtelSpan;
+            span.set_attribute(KeyValue::new("job.id", task_id.to_string()));
+            span.set_status(Status::Ok);
+        }
+
+        Ok(PushOutcome {
+            task_id,
+            sequence: ack.sequence,
+            duplicate: ack.duplicate,
+        })
+    }
+
+    /// Push a job with a specific priority and trace context
+    ///
+    /// This method allows manual specification of the OpenTelemetry trace context
+    /// that will be propagated to the job consumer. This is useful when you need
+    /// to link job processing to a specific trace.
+    ///
+    /// Only available when the `otel` feature is enabled.
+    #[cfg(feature = "otel")]
+    pub async fn push_with_priority_and_context(
+        &self,
+        job: T,
+        priority: Priority,
+        context: &OtelContext,
+    ) -> Result<TaskId, NatsPollError> {
+        self.config.check_priority(priority)?;
+        let tracer = global::tracer("apalis-nats");
+        let mut span = tracer
+            .span_builder("job.push")
+            .with_kind(SpanKind::Producer)
+            .with_attributes(vec![
+                KeyValue::new("job.priority", priority.to_string()),
+                KeyValue::new("job.namespace", self.config.namespace.clone()),
+            ])
+            .start_with_context(&tracer, context);
+
+        let task_id = TaskId::new();
+        let nats_job = NatsJob {
+            id: task_id.clone(),
+            data: job,
+            priority,
+            attempts: Attempt::new(),
+            created_at: self.clock.now(),
+            namespace: Namespace::from(self.config.namespace.clone()),
+        };
+
+        let payload = serde_json::to_vec(&nats_job)?;
+        self.check_size(&payload)?;
+        let subject = self.get_subject(priority);
+
+        // Prepare headers with provided trace context
+        let mut headers = HeaderMap::new();
+        headers.insert(SCHEMA_VERSION_HEADER, self.schema_version().to_string());
+        headers.insert(JOB_TYPE_HEADER, self.type_tag.as_str());
+        insert_job_headers(&mut headers, &nats_job);
+
+        if self.config.enable_tracing {
+            global::get_text_map_propagator(|propagator| {
+                let mut injector = NatsHeaderInjector::new(headers.clone());
+                propagator.inject_context(context, &mut injector);
+                headers = injector.into();
+            });
+        }
+
+        // Record the status first so a fast worker's `Running` isn't overwritten
+        self.set_status(&task_id, JobStatus::Pending).await;
+
+        // Publish with headers
         self.jetstream
             .publish_with_headers(subject, headers, Bytes::from(payload))
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+
+        use OtelSpan;
+        span.set_attribute(KeyValue::new("job.id", task_id.to_string()));
+        span.set_status(Status::Ok);
+
+        Ok(task_id)
+    }
+
+    /// Move all pending jobs (and DLQ entries) from this storage's namespace into `new`.
+    ///
+    /// The streams for `new` are created if needed, every message is republished to the
+    /// matching subject under `new` and the old streams are deleted once drained. Jobs waiting
+    /// in the `{namespace}_scheduled` bucket, the handlers' `{namespace}_state` and the
+    /// `{namespace}_status` bucket are moved to their `{new}_*` buckets the same way. A DLQ
+    /// with a custom `Config::dlq_stream` or `Config::dlq_subject` isn't tied to the namespace
+    /// and stays where it is. `Nats-Expected-*` headers from conditional pushes are dropped,
+    /// they only held for the original publish.
+    ///
+    /// Each republish carries a `Nats-Msg-Id` derived from the source stream and sequence and
+    /// the source message is removed once stored, so an interrupted migration can be re-run
+    /// without duplicating jobs. Only a message that was republished but not yet removed when
+    /// the run was interrupted can be stored twice, and only if the re-run comes after the
+    /// target stream's `duplicate_window` has passed. Errors other than a stream that is
+    /// already gone abort the migration.
+    ///
+    /// Returns the number of messages and scheduled jobs migrated. This handle keeps pointing
+    /// at the old namespace; create a new storage for `new` to consume the migrated jobs.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let migrated = storage.migrate_namespace("billing_v2").await?;
+    /// println!("moved {migrated} jobs");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn migrate_namespace(&self, new: &str) -> Result<usize, NatsPollError> {
+        // A custom DLQ may be shared, and `{new}_dlq` would overlap a custom subject
+        let own_dlq = self.config.dlq_stream.is_none() && self.config.dlq_subject.is_none();
+        let target = Config {
+            namespace: new.to_string(),
+            enable_dlq: self.config.enable_dlq && own_dlq,
+            ..self.config.clone()
+        };
+        ensure_streams(&self.jetstream, &target).await?;
+
+        // (stream, its subject, the subject to move its messages to)
+        let mut routes: Vec<(String, String, String)> = self
+            .config
+            .priorities()
+            .into_iter()
+            .map(|priority| {
+                (
+                    self.get_stream_name(priority),
+                    self.get_subject(priority),
+                    format!("{}.{}", new, priority),
+                )
+            })
+            .collect();
+        if target.enable_dlq {
+            routes.push((
+                dlq_stream_name(&self.config),
+                dlq_subject(&self.config),
+                dlq_subject(&target),
+            ));
+        }
+
+        let mut migrated = 0;
+        for (stream_name, source_subject, target_subject) in routes {
+            let mut stream = match self.jetstream.get_stream(&stream_name).await {
+                Ok(stream) => stream,
+                Err(e) => match lookup_error(e, &stream_name, None) {
+                    // Already drained and deleted by a previous run
+                    NatsPollError::StreamNotFound(_) => continue,
+                    e => return Err(e),
+                },
+            };
+            let state = stream
+                .info()
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                .state
+                .clone();
+
+            for sequence in state.first_sequence..=state.last_sequence {
+                let msg = match stream.get_raw_message(sequence).await {
+                    Ok(msg) => msg,
+                    Err(e) if e.kind() == stream::RawMessageErrorKind::NoMessageFound => continue,
+                    Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+                };
+
+                let mut headers = without_expectations(&msg.headers);
+                headers.insert(
+                    NATS_MESSAGE_ID,
+                    format!("migrate-{}-{}", stream_name, sequence),
+                );
+                if headers.get(NAMESPACE_HEADER).is_some() {
+                    headers.insert(NAMESPACE_HEADER, new);
+                }
+                let payload = rewrite_namespace(&msg.payload, new).unwrap_or(msg.payload);
+                // Keep the labels or tenant that follow the subject
+                let suffix = msg.subject.strip_prefix(source_subject.as_str()).unwrap_or("");
+
+                self.jetstream
+                    .publish_with_headers(format!("{}{}", target_subject, suffix), headers, payload)
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                stream
+                    .delete_message(sequence)
+                    .await
+                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                migrated += 1;
+            }
+
+            self.jetstream
+                .delete_stream(&stream_name)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            tracing::info!("Migrated stream {} to {}", stream_name, target_subject);
+        }
+        let namespace = &self.config.namespace;
+        migrated += self
+            .move_bucket(
+                &format!("{}_scheduled", namespace),
+                scheduled_bucket_config(&target),
+            )
+            .await?;
+        // Keyed by task id, which the jobs keep
+        self.move_bucket(&format!("{}_state", namespace), state_bucket_config(&target))
+            .await?;
+        self.move_bucket(&format!("{}_status", namespace), status_bucket_config(&target))
+            .await?;
+
+        // Cached consumers belonged to the deleted streams
+        self.consumers
+            .lock()
+            .map_err(|_| NatsPollError::Storage("Consumer cache poisoned".into()))?
+            .clear();
+
+        Ok(migrated)
+    }
+
+    /// Move the entries of the KV bucket `bucket` into the one `target` describes, creating it
+    /// if needed, then delete `bucket`. Returns how many entries were moved.
+    async fn move_bucket(&self, bucket: &str, target: kv::Config) -> Result<usize, NatsPollError> {
+        // Namespaces that never used it have no bucket
+        let bucket_stream = format!("KV_{}", bucket);
+        match self.jetstream.get_stream(&bucket_stream).await {
+            Ok(_) => {}
+            Err(e) => match lookup_error(e, &bucket_stream, None) {
+                NatsPollError::StreamNotFound(_) => return Ok(0),
+                e => return Err(e),
+            },
+        }
+        let source = self
+            .jetstream
+            .get_key_value(bucket)
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        let target_bucket = target.bucket.clone();
+        let target = match self.jetstream.get_key_value(&target_bucket).await {
+            Ok(store) => store,
+            Err(_) => self
+                .jetstream
+                .create_key_value(target)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?,
+        };
+
+        let keys: Vec<String> = source
+            .keys()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .try_collect()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        let mut moved = 0;
+        for key in keys {
+            let entry = source
+                .entry(&key)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            let Some(entry) = entry.filter(|entry| entry.operation == kv::Operation::Put) else {
+                continue; // Removed since the keys were listed
+            };
+            match target.create(&key, entry.value).await {
+                Ok(_) => {}
+                // Copied by an earlier, interrupted run, or already written under `new`
+                Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {}
+                Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+            }
+            source
+                .purge(&key)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            moved += 1;
+        }
+
+        self.jetstream
+            .delete_key_value(bucket)
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        tracing::info!("Migrated bucket {} to {}", bucket, target_bucket);
+        Ok(moved)
+    }
+
+    /// Move up to `limit` pending jobs (all of them with `None`) from the `from` priority to
+    /// `to`, oldest first, e.g. to push a flood of High jobs out of the way of other work
+    /// during an incident.
+    ///
+    /// Each job is republished to `to` with its task id, attempts, labels and headers, and its
+    /// priority updated, then removed from the `from` stream. `Nats-Expected-*` headers from
+    /// conditional pushes are dropped, they only held for the original publish. The republish carries a
+    /// `Nats-Msg-Id` derived from the source stream and sequence, so an interrupted run can be
+    /// repeated without duplicating jobs. Jobs already handed to a worker are moved too and may
+    /// run twice, so pause or stop the workers on `from` first.
+    ///
+    /// Returns the number of jobs moved.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let moved = storage
+    ///     .reprioritize(Priority::High, Priority::Low, Some(10_000))
+    ///     .await?;
+    /// println!("demoted {moved} jobs");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn reprioritize(
+        &self,
+        from: Priority,
+        to: Priority,
+        limit: Option<usize>,
+    ) -> Result<usize, NatsPollError> {
+        if from == to {
+            return Ok(0);
+        }
+        self.config.check_priority(from)?;
+        self.config.check_priority(to)?;
+        let stream_name = self.get_stream_name(from);
+        let mut stream = self
+            .jetstream
+            .get_stream(&stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        let state = stream
+            .info()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .state
+            .clone();
+
+        let source_subject = self.get_subject(from);
+        let target_subject = self.get_subject(to);
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut moved = 0;
+        for sequence in state.first_sequence..=state.last_sequence {
+            if moved >= limit {
+                break;
+            }
+            let msg = match stream.get_raw_message(sequence).await {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == stream::RawMessageErrorKind::NoMessageFound => continue,
+                Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+            };
+
+            let mut headers = without_expectations(&msg.headers);
+            headers.insert(
+                NATS_MESSAGE_ID,
+                format!("reprioritize-{}-{}", stream_name, sequence),
+            );
+            // The header wins over the payload when consuming
+            headers.insert(PRIORITY_HEADER, to.to_string());
+            let payload = rewrite_priority(&msg.payload, to).unwrap_or(msg.payload);
+            // Keep the labels that follow the subject
+            let suffix = msg.subject.strip_prefix(source_subject.as_str()).unwrap_or("");
+
+            self.jetstream
+                .publish_with_headers(format!("{}{}", target_subject, suffix), headers, payload)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            stream
+                .delete_message(sequence)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            moved += 1;
+        }
+
+        tracing::info!("Moved {} jobs from {} to {} priority", moved, from, to);
+        Ok(moved)
+    }
+
+    /// Edit DLQ jobs in place and re-drive the ones that were fixed.
+    ///
+    /// `f` is called with each job in the DLQ. When it returns `true` the edited job is
+    /// republished to its original priority, keeping its task id, and removed from the DLQ.
+    /// When it returns `false` the entry is left untouched. Entries whose payload can't be
+    /// decoded into `T` are skipped.
+    ///
+    /// Returns the number of jobs requeued.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # use serde::{Deserialize, Serialize};
+    /// # #[derive(Serialize, Deserialize)]
+    /// # struct Email { to: String }
+    /// # async fn example(storage: NatsStorage<Email>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let fixed = storage
+    ///     .requeue_dlq_edited(|email| {
+    ///         if email.to.ends_with("@exmaple.com") {
+    ///             email.to = email.to.replace("@exmaple.com", "@example.com");
+    ///             true
+    ///         } else {
+    ///             false
+    ///         }
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn requeue_dlq_edited<F>(&self, mut f: F) -> Result<usize, NatsPollError>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let dlq_stream_name = dlq_stream_name(&self.config);
+        let mut stream = self
+            .jetstream
+            .get_stream(&dlq_stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &dlq_stream_name, None))?;
+        let state = stream
+            .info()
+            .await
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .state
+            .clone();
+
+        let mut requeued = 0;
+        for sequence in state.first_sequence..=state.last_sequence {
+            let msg = match stream.get_raw_message(sequence).await {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == stream::RawMessageErrorKind::NoMessageFound => continue,
+                Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+            };
+            let Ok(entry) = serde_json::from_slice::<DlqEntry>(&msg.payload) else {
+                continue;
+            };
+            // Leave other namespaces' entries in a shared DLQ alone
+            if entry
+                .namespace
+                .as_ref()
+                .is_some_and(|namespace| *namespace != self.config.namespace)
+            {
+                continue;
+            }
+            let Ok(mut job) = serde_json::from_slice::<NatsJob<T>>(&entry.payload) else {
+                tracing::debug!("Skipping undecodable DLQ entry {}", sequence);
+                continue;
+            };
+            if !f(&mut job.data) {
+                continue;
+            }
+
+            job.attempts = Attempt::new();
+            // The payload may predate a move to another priority, the subject doesn't
+            if let Some(priority) = entry.original_priority {
+                job.priority = priority;
+            }
+            let mut headers = HeaderMap::new();
+            headers.insert(SCHEMA_VERSION_HEADER, self.schema_version().to_string());
+            headers.insert(JOB_TYPE_HEADER, self.type_tag.as_str());
+            insert_job_headers(&mut headers, &job);
+            self.set_status(&job.id, JobStatus::Pending).await;
+            self.jetstream
+                .publish_with_headers(
+                    self.get_subject(job.priority),
+                    headers,
+                    Bytes::from(serde_json::to_vec(&job)?),
+                )
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            stream
+                .delete_message(sequence)
+                .await
+                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            tracing::info!("Requeued edited DLQ job {} as {}", sequence, job.id);
+            requeued += 1;
+        }
+
+        Ok(requeued)
+    }
+
+    /// Read the DLQ entries of one tenant, dead-lettered to `{dlq_subject}.{tenant}` because
+    /// their job carried `tenant` as its `Config::tenant_label` label.
+    ///
+    /// Entries are returned oldest first and left in the DLQ. Entries of other tenants, of jobs
+    /// without the label and of other namespaces sharing the DLQ stream are skipped.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// for entry in storage.read_dlq_for_tenant("acme").await? {
+    ///     println!("{} failed: {}", entry.original_task_id, entry.error);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn read_dlq_for_tenant(&self, tenant: &str) -> Result<Vec<DlqEntry>, NatsPollError> {
+        let subject = format!("{}.{}", dlq_subject(&self.config), tenant);
+        let dlq_stream_name = dlq_stream_name(&self.config);
+        let mut stream = self
+            .jetstream
+            .get_stream(&dlq_stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &dlq_stream_name, None))?;
+        let state = stream
+            .info()
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .state
+            .clone();
 
-        #[cfg(feature = "otel")]
-        if let Some(ref mut span) = _span {
-            use OtelSpan;
-            span.set_attribute(KeyValue::new("job.id", task_id.to_string()));
-            span.set_status(Status::Ok);
+        let mut entries = Vec::new();
+        for sequence in state.first_sequence..=state.last_sequence {
+            let msg = match stream.get_raw_message(sequence).await {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == stream::RawMessageErrorKind::NoMessageFound => continue,
+                Err(e) => return Err(NatsPollError::Nats(e.to_string())),
+            };
+            if msg.subject.as_str() != subject {
+                continue;
+            }
+            let Ok(entry) = serde_json::from_slice::<DlqEntry>(&msg.payload) else {
+                continue;
+            };
+            if entry
+                .namespace
+                .as_ref()
+                .is_some_and(|namespace| *namespace != self.config.namespace)
+            {
+                continue;
+            }
+            entries.push(entry);
         }
 
-        Ok(task_id)
+        Ok(entries)
     }
 
-    /// Push a job with a specific priority and trace context
+    /// Change the replica count of every stream in the namespace: the priority streams, the
+    /// DLQ stream and, with `Config::track_status`, the status bucket.
     ///
-    /// This method allows manual specification of the OpenTelemetry trace context
-    /// that will be propagated to the job consumer. This is useful when you need
-    /// to link job processing to a specific trace.
+    /// `n` must be between 1 and 5, and more than one replica needs a clustered server. The
+    /// streams are updated one by one; on error the ones already updated keep the new count.
+    /// This storage's `Config::num_replicas` is not changed.
     ///
-    /// Only available when the `otel` feature is enabled.
-    #[cfg(feature = "otel")]
-    pub async fn push_with_priority_and_context(
-        &self,
-        job: T,
-        priority: Priority,
-        context: &OtelContext,
-    ) -> Result<TaskId, NatsPollError> {
-        let tracer = global::tracer("apalis-nats");
-        let mut span = tracer
-            .span_builder("job.push")
-            .with_kind(SpanKind::Producer)
-            .with_attributes(vec![
-                KeyValue::new("job.priority", priority.to_string()),
-                KeyValue::new("job.namespace", self.config.namespace.clone()),
-            ])
-            .start_with_context(&tracer, context);
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::NatsStorage;
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// storage.set_replicas(3).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn set_replicas(&self, n: usize) -> Result<(), NatsPollError> {
+        if !(1..=5).contains(&n) {
+            return Err(NatsPollError::Storage(format!(
+                "Stream replicas must be between 1 and 5, got {}",
+                n
+            )));
+        }
 
-        let task_id = TaskId::new();
-        let nats_job = NatsJob {
-            id: task_id.clone(),
-            data: job,
-            priority,
-            attempts: Attempt::new(),
-            created_at: Utc::now(),
-            namespace: Namespace::from(self.config.namespace.clone()),
-        };
+        let mut stream_names: Vec<String> = self
+            .config
+            .priorities()
+            .into_iter()
+            .map(|priority| self.get_stream_name(priority))
+            .collect();
+        // A custom DLQ stream is managed by whoever owns it
+        if self.config.enable_dlq && self.config.dlq_stream.is_none() {
+            stream_names.push(dlq_stream_name(&self.config));
+        }
+        if self.config.track_status {
+            stream_names.push(format!("KV_{}_status", self.config.namespace));
+        }
 
-        let payload = serde_json::to_vec(&nats_job)?;
-        let subject = self.get_subject(priority);
+        for stream_name in stream_names {
+            let mut stream = self
+                .jetstream
+                .get_stream(&stream_name)
+                .await
+                .map_err(|e| lookup_error(e, &stream_name, None))?;
+            let info = stream
+                .info()
+                .await
+                .map_err(|e| lookup_error(e, &stream_name, None))?;
+            if info.config.num_replicas == n {
+                continue;
+            }
 
-        // Prepare headers with provided trace context
-        let mut headers = HeaderMap::new();
+            // A standalone server reports no cluster name and can only hold one replica
+            let clustered = info
+                .cluster
+                .as_ref()
+                .is_some_and(|cluster| cluster.name.is_some());
+            if n > 1 && !clustered {
+                return Err(NatsPollError::Storage(format!(
+                    "Cannot set {} replicas on stream {}: the server is not clustered",
+                    n, stream_name
+                )));
+            }
 
-        if self.config.enable_tracing {
-            global::get_text_map_propagator(|propagator| {
-                let mut injector = NatsHeaderInjector::new(headers.clone());
-                propagator.inject_context(context, &mut injector);
-                headers = injector.into();
-            });
+            let mut config = info.config.clone();
+            config.num_replicas = n;
+            self.jetstream.update_stream(&config).await.map_err(|e| {
+                NatsPollError::Nats(format!(
+                    "Failed to set {} replicas on stream {}: {}",
+                    n, stream_name, e
+                ))
+            })?;
+            tracing::info!("Stream {} now has {} replicas", stream_name, n);
         }
 
-        // Publish with headers
-        self.jetstream
-            .publish_with_headers(subject, headers, Bytes::from(payload))
+        Ok(())
+    }
+
+    /// Create the shared consumers of the priorities this storage consumes, which workers
+    /// otherwise do on their first fetch, e.g. to fail at startup rather than on the first poll.
+    ///
+    /// Safe to call from many processes at once: losing the race to create a consumer is not an
+    /// error, unless the winner created it with different settings (`ack_wait`, `max_deliver`,
+    /// ...). Those are logged and listed in the error.
+    pub async fn create_consumers(&self) -> Result<(), NatsPollError> {
+        for &priority in &self.priorities {
+            self.get_or_create_consumer(priority).await?;
+        }
+        Ok(())
+    }
+
+    /// Look up the shared consumer of a priority, without creating it.
+    ///
+    /// Fails with [`NatsPollError::ConsumerNotFound`] until a worker has polled that priority,
+    /// or after the consumer was removed, e.g. for being inactive longer than its
+    /// `inactive_threshold`, and with [`NatsPollError::StreamNotFound`] when the stream is gone.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsPollError, NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// match storage.consumer_info(Priority::High).await {
+    ///     Ok(info) => println!("{} jobs waiting", info.num_pending),
+    ///     Err(NatsPollError::ConsumerNotFound(name)) => println!("no worker has created {}", name),
+    ///     Err(e) => return Err(e.into()),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn consumer_info(&self, priority: Priority) -> Result<consumer::Info, NatsPollError> {
+        let stream_name = self.get_stream_name(priority);
+        let consumer_name = self.consumer_name(priority);
+        let stream = self
+            .jetstream
+            .get_stream(&stream_name)
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        stream
+            .consumer_info(&consumer_name)
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+            .map_err(|e| lookup_error(e, &stream_name, Some(&consumer_name)))
+    }
 
-        use OtelSpan;
-        span.set_attribute(KeyValue::new("job.id", task_id.to_string()));
-        span.set_status(Status::Ok);
+    /// Pending jobs per priority, jobs in flight and DLQ entries in one snapshot, e.g. for a
+    /// dashboard.
+    ///
+    /// Every priority stream, its consumer and the DLQ stream are looked up concurrently.
+    /// Pending and in-flight counts come from the priority's consumer (this group's, with a
+    /// `consumer_group`); until a worker has created it, every job in the stream counts as
+    /// pending. The counts are read at slightly different moments, so a job moving on while
+    /// they are read may be counted twice or not at all.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use apalis_nats::{NatsStorage, Priority};
+    /// # async fn example(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let summary = storage.summary().await?;
+    /// println!(
+    ///     "{} high priority jobs waiting, {} running, {} dead",
+    ///     summary.pending[&Priority::High],
+    ///     summary.in_flight,
+    ///     summary.dead
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn summary(&self) -> Result<QueueSummary, NatsPollError> {
+        let backlogs = futures::future::try_join_all(
+            self.config
+                .priorities()
+                .into_iter()
+                .map(|priority| self.priority_backlog(priority)),
+        );
+        let dead = async {
+            if !self.config.enable_dlq {
+                return Ok(0);
+            }
+            let stream_name = dlq_stream_name(&self.config);
+            let stream = self
+                .jetstream
+                .get_stream(&stream_name)
+                .await
+                .map_err(|e| lookup_error(e, &stream_name, None))?;
+            Ok(stream.cached_info().state.messages)
+        };
+        let (backlogs, dead) = futures::future::try_join(backlogs, dead).await?;
 
-        Ok(task_id)
+        let mut summary = QueueSummary {
+            dead,
+            ..Default::default()
+        };
+        for (priority, pending, in_flight) in backlogs {
+            summary.pending.insert(priority, pending);
+            summary.in_flight += in_flight;
+        }
+        Ok(summary)
+    }
+
+    /// The pending and in-flight jobs of a priority, from its consumer or, before a worker
+    /// created it, the whole stream
+    async fn priority_backlog(
+        &self,
+        priority: Priority,
+    ) -> Result<(Priority, u64, u64), NatsPollError> {
+        let stream_name = self.get_stream_name(priority);
+        let consumer_name = self.consumer_name(priority);
+        let stream = self
+            .jetstream
+            .get_stream(&stream_name)
+            .await
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        match stream.consumer_info(&consumer_name).await {
+            Ok(info) => Ok((priority, info.num_pending, info.num_ack_pending as u64)),
+            Err(e) => match lookup_error(e, &stream_name, Some(&consumer_name)) {
+                NatsPollError::ConsumerNotFound(_) => {
+                    Ok((priority, stream.cached_info().state.messages, 0))
+                }
+                e => Err(e),
+            },
+        }
     }
 
     /// Create or get a shared consumer for a specific priority
@@ -566,38 +3933,60 @@ where
         }
 
         let stream_name = self.get_stream_name(priority);
-        // Use a shared consumer name for all workers of the same priority
-        // This ensures work queue semantics - each message delivered to only one worker
-        let consumer_name = format!("{}_{}_consumer", self.config.namespace, priority);
-
-        let config = consumer::pull::Config {
-            name: Some(consumer_name.clone()),
-            durable_name: Some(consumer_name.clone()), // Make consumer durable
-            // Work queue settings - ensure only one worker gets each message
-            ack_policy: consumer::AckPolicy::Explicit,
-            ack_wait: self.config.ack_wait,
-            max_deliver: self.config.max_deliver,
-            filter_subject: self.get_subject(priority),
-            // Critical for work queue behavior - only deliver to one consumer
-            deliver_policy: consumer::DeliverPolicy::All,
-            // Control message delivery
-            max_ack_pending: self.config.max_ack_pending,
-            // Replay policy - start from beginning or new messages only
-            replay_policy: consumer::ReplayPolicy::Instant,
-            // Inactive threshold - remove consumer if inactive
-            inactive_threshold: Duration::from_secs(300), // 5 minutes
-            ..Default::default()
-        };
+        let consumer_name = self.consumer_name(priority);
+        let mut config = consumer_config(&self.config, priority)?;
+        if let Some(labels) = &self.labels {
+            config.filter_subject = format!("{}.{}", self.get_subject(priority), labels);
+        }
 
         let stream = self
             .jetstream
-            .get_stream(stream_name)
+            .get_stream(&stream_name)
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
-        let consumer = stream
-            .get_or_create_consumer(&consumer_name, config)
+            .map_err(|e| lookup_error(e, &stream_name, None))?;
+        let consumer = match stream
+            .get_or_create_consumer(&consumer_name, config.clone())
             .await
-            .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+        {
+            Ok(consumer) => consumer,
+            // Workers booting together all find the consumer missing and race to create it,
+            // so a failed create usually means another one won
+            Err(e) => match stream
+                .get_consumer::<consumer::pull::Config>(&consumer_name)
+                .await
+            {
+                Ok(existing) => {
+                    let conflicts = consumer_conflicts(&config, &existing.cached_info().config);
+                    if !conflicts.is_empty() {
+                        tracing::error!(
+                            "Consumer {} exists with a different config: {}",
+                            consumer_name,
+                            conflicts.join(", ")
+                        );
+                        return Err(NatsPollError::Storage(format!(
+                            "Consumer {} exists with a different config: {}",
+                            consumer_name,
+                            conflicts.join(", ")
+                        )));
+                    }
+                    tracing::debug!("Consumer {} was created concurrently", consumer_name);
+                    existing
+                }
+                Err(_) => return Err(lookup_error(e, &stream_name, Some(&consumer_name))),
+            },
+        };
+
+        // `get_or_create_consumer` hands back an existing consumer as it is
+        let existing_max_waiting = consumer.cached_info().config.max_waiting;
+        if existing_max_waiting != config.max_waiting() {
+            tracing::warn!(
+                "Consumer {} has max_waiting {} but the config asks for {}; it is only set when \
+                 the consumer is created, delete the consumer to apply it",
+                consumer_name,
+                existing_max_waiting,
+                config.max_waiting()
+            );
+        }
 
         // Insert into cache and return a clone
         let mut guard = self
@@ -644,19 +4033,23 @@ where
 
     async fn schedule_request(
         &mut self,
-        _request: Request<Self::Job, Self::Context>,
-        _on: i64,
+        request: Request<Self::Job, Self::Context>,
+        on: i64,
     ) -> Result<Parts<Self::Context>, Self::Error> {
-        // Scheduling is not yet implemented for NATS
-        Err(NatsPollError::Storage(
-            "Scheduling not yet implemented".to_string(),
-        ))
+        let at = DateTime::from_timestamp(on, 0)
+            .ok_or_else(|| NatsPollError::Storage(format!("Invalid timestamp {}", on)))?;
+        let task_id = self.scheduled_jobs().await?.schedule(request.args, at).await?;
+        let mut parts = Parts::default();
+        parts.task_id = task_id;
+        parts.context = NatsContext::default();
+        parts.namespace = Some(Namespace::from(self.config.namespace.clone()));
+        Ok(parts)
     }
 
     async fn len(&mut self) -> Result<i64, Self::Error> {
         let mut total = 0u64;
 
-        for priority in [Priority::High, Priority::Medium, Priority::Low] {
+        for priority in self.config.priorities() {
             let stream_name = self.get_stream_name(priority);
             match self.jetstream.get_stream(stream_name).await {
                 Ok(mut stream) => {
@@ -719,6 +4112,29 @@ where
         ctx: &Self::Context,
         response: &Response<Res>,
     ) -> Result<(), Self::AckError> {
+        if let Some(status) = ctx.settled_status() {
+            // Acking again would be a double ack, possibly contradicting the handler
+            if status == JobStatus::Completed {
+                ctx.clear_state().await;
+            }
+            self.update_status(&response.task_id, status);
+            tracing::debug!(
+                "Task {} was already acknowledged by its handler",
+                response.task_id
+            );
+            return Ok(());
+        }
+        // A dropped `AckGuard` stands in for the handler's own ack or Nak
+        let auto_ack = self.config.manual_ack && ctx.auto_ack_armed();
+        if self.config.manual_ack && response.inner.is_ok() && !auto_ack {
+            tracing::warn!(
+                "Task {} succeeded without acknowledging its message, it will be redelivered \
+                 after ack_wait (manual_ack is enabled)",
+                response.task_id
+            );
+            return Ok(());
+        }
+
         // Get the NATS message from context
         if let Some(msg) = ctx.message() {
             match &response.inner {
@@ -727,42 +4143,113 @@ where
                     msg.ack()
                         .await
                         .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                    ctx.clear_state().await;
+                    self.update_status(&response.task_id, JobStatus::Completed);
                     tracing::debug!("Acknowledged message for task {}", response.task_id);
                 }
                 Err(e) => {
                     // Check if we should move to DLQ
                     let info = msg.info().map_err(|e| NatsPollError::Nats(e.to_string()))?;
-                    let should_dlq = match e {
-                        Error::Abort(_) => true, // Non-transient errors go to DLQ
-                        _ => {
-                            // Check if we've exceeded max deliveries
-                            info.delivered as i64 >= self.config.max_deliver
+                    let disposition = match &self.classify {
+                        _ if auto_ack => Disposition::Retry { delay: None },
+                        Some(classify) => classify(e),
+                        // Non-transient errors go to DLQ, everything else is retried
+                        None => match e {
+                            Error::Abort(_) => Disposition::DeadLetter,
+                            _ => Disposition::Retry { delay: None },
+                        },
+                    };
+                    // Check if the job has been retried for too long
+                    let out_of_time = match (self.config.max_retry_duration, ctx.created_at()) {
+                        (Some(max), Some(created_at)) => (self.clock.now() - created_at)
+                            .to_std()
+                            .is_ok_and(|age| age > max),
+                        _ => false,
+                    };
+                    // Check if we've exceeded max deliveries
+                    let max_delivered = (info.delivered as i64) >= self.config.max_deliver;
+                    let retrying = matches!(disposition, Disposition::Retry { .. })
+                        && !max_delivered
+                        && !out_of_time;
+
+                    // Out of deliveries, but worth another round much later
+                    if let OnMaxDeliver::Reschedule(delay) = self.config.on_max_deliver {
+                        if matches!(disposition, Disposition::Retry { .. })
+                            && max_delivered
+                            && !out_of_time
+                        {
+                            let at = self.clock.now()
+                                + chrono::Duration::from_std(delay)
+                                    .map_err(|e| NatsPollError::Storage(e.to_string()))?;
+                            let job = self.decode_job(msg)?;
+                            self.scheduled_jobs()
+                                .await?
+                                .schedule_as(job.id, job.data, job.priority, at)
+                                .await?;
+                            msg.ack()
+                                .await
+                                .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                            self.update_status(&response.task_id, JobStatus::Failed);
+                            tracing::warn!(
+                                "Rescheduled task {} for {} after {} deliveries",
+                                response.task_id,
+                                at,
+                                info.delivered
+                            );
+                            return Ok(());
                         }
+                    }
+
+                    // A retry the budget can't cover fails the job right away
+                    let budget_exhausted = retrying && !self.take_retry();
+                    let should_dlq = match disposition {
+                        Disposition::DeadLetter => true,
+                        Disposition::Terminate => false,
+                        Disposition::Retry { .. } => !retrying || budget_exhausted,
                     };
 
                     if should_dlq && self.config.enable_dlq {
                         // Move to DLQ by publishing to DLQ stream
-                        let dlq_subject = format!("{}.dlq", self.config.namespace);
+                        let dlq_subject = self.dlq_subject_for(&msg.subject);
 
                         // Determine DLQ reason
-                        let dlq_reason = match e {
-                            Error::Abort(_) => "abort_error",
+                        let dlq_reason = match (e, disposition) {
+                            (Error::Abort(_), Disposition::DeadLetter) => "abort_error",
+                            (_, Disposition::DeadLetter) => "classified",
+                            _ if budget_exhausted => "retry_budget_exhausted",
+                            _ if out_of_time => "max_retry_duration_exceeded",
                             _ => "max_deliver_exceeded",
                         };
 
                         // Create DLQ message with metadata
-                        let dlq_job = json!({
-                            "original_task_id": response.task_id.to_string(),
-                            "error": e.to_string(),
-                            "attempts": format!("{:?}", response.attempt),
-                            "delivered_count": info.delivered,
-                            "timestamp": chrono::Utc::now().to_rfc3339(),
-                            "dlq_reason": dlq_reason,
-                            "payload": msg.payload.clone(),
-                        });
+                        let mut dlq_job = DlqEntry {
+                            original_task_id: response.task_id.to_string(),
+                            error: e.to_string(),
+                            error_detail: Some(DlqError::new(e)),
+                            attempts: info.delivered as usize,
+                            delivered_count: info.delivered,
+                            timestamp: self.clock.now(),
+                            dlq_reason: dlq_reason.to_string(),
+                            payload: entry_payload(self.redact.as_ref(), &msg.payload),
+                            payload_truncated: None,
+                            metadata: ctx.dlq_metadata(),
+                            namespace: Some(self.config.namespace.clone()),
+                            retry_after: match disposition {
+                                Disposition::DeadLetter => None,
+                                _ => self.config.dlq_retry_after.and_then(|after| {
+                                    chrono::Duration::from_std(after)
+                                        .ok()
+                                        .map(|after| self.clock.now() + after)
+                                }),
+                            },
+                            original_subject: Some(msg.subject.to_string()),
+                            original_priority: subject_priority(&msg.subject),
+                            original_sequence: Some(info.stream_sequence),
+                        };
 
                         // Publish to DLQ
-                        let body = serde_json::to_vec(&dlq_job)
+                        let body = dlq_job
+                            .to_bytes(self.dlq_size_limit())
                             .map_err(|e| NatsPollError::Serialization(e))?;
                         self.jetstream
                             .publish(dlq_subject, body.into())
@@ -775,6 +4262,7 @@ where
                         msg.ack()
                             .await
                             .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                        self.update_status(&response.task_id, JobStatus::Dead);
 
                         tracing::warn!(
                             "Moved task {} to DLQ after {} deliveries",
@@ -782,32 +4270,51 @@ where
                             info.delivered
                         );
                     } else {
-                        // Check error type to determine acknowledgment strategy
-                        match e {
-                            Error::Abort(_) => {
+                        // Check the disposition to determine acknowledgment strategy
+                        match disposition {
+                            Disposition::DeadLetter | Disposition::Terminate => {
                                 // Non-transient error - terminate to prevent redelivery
                                 msg.ack_with(jetstream::AckKind::Term)
                                     .await
                                     .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                                self.update_status(&response.task_id, JobStatus::Dead);
+                                tracing::warn!(
+                                    "Terminated message for task {} due to non-retryable error: {}",
+                                    response.task_id,
+                                    e
+                                );
+                            }
+                            _ if budget_exhausted => {
+                                // No retries left in the budget, don't redeliver
+                                msg.ack_with(jetstream::AckKind::Term)
+                                    .await
+                                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                                self.update_status(&response.task_id, JobStatus::Dead);
                                 tracing::warn!(
-                                    "Terminated message for task {} due to abort error",
+                                    "Terminated message for task {}: retry budget exhausted",
                                     response.task_id
                                 );
                             }
-                            _ => {
+                            _ if out_of_time => {
+                                // Retried for longer than max_retry_duration, don't redeliver
+                                msg.ack_with(jetstream::AckKind::Term)
+                                    .await
+                                    .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                                self.update_status(&response.task_id, JobStatus::Dead);
+                                tracing::warn!(
+                                    "Terminated message for task {}: max_retry_duration exceeded",
+                                    response.task_id
+                                );
+                            }
+                            Disposition::Retry { delay } => {
                                 // Transient error - negative acknowledge for retry, with backoff
-                                let idx = info.delivered.saturating_sub(1) as usize;
-                                let delay = if self.config.nak_backoff.is_empty() {
-                                    None
-                                } else if idx < self.config.nak_backoff.len() {
-                                    Some(self.config.nak_backoff[idx])
-                                } else {
-                                    Some(*self.config.nak_backoff.last().unwrap())
-                                };
+                                let delay = delay.or_else(|| self.nak_delay(info.delivered));
 
                                 msg.ack_with(jetstream::AckKind::Nak(delay))
                                     .await
                                     .map_err(|e| NatsPollError::Nats(e.to_string()))?;
+                                self.record_retry(info.delivered);
+                                self.update_status(&response.task_id, JobStatus::Failed);
                                 if let Some(d) = delay {
                                     tracing::debug!(
                                         "Nacked message for task {} for retry in {:?} (attempt {})",
@@ -839,117 +4346,426 @@ where
     T: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     type Stream = BoxStream<'static, Result<Option<Request<T, NatsContext>>, Error>>;
-    type Layer =
-        AckLayer<Sender<(NatsContext, Response<Vec<u8>>)>, T, NatsContext, JsonCodec<Vec<u8>>>;
+    type Layer = Stack<
+        NatsLayers,
+        Stack<
+            AckLayer<Sender<(NatsContext, Response<Vec<u8>>)>, T, NatsContext, JsonCodec<Vec<u8>>>,
+            ProcessSpanLayer,
+        >,
+    >;
     type Codec = JsonCodec<Vec<u8>>;
 
     fn poll(self, worker: &Worker<WorkerContext>) -> Poller<Self::Stream, Self::Layer> {
-        let _worker_id = worker.id().to_string();
+        let worker_id = worker.id().to_string();
+        self.check_concurrency(worker);
+        let worker = worker.clone();
+
+        // At most half of max_ack_pending, so other workers on the consumer still get jobs
+        let strict_priority = self.config.strict_priority;
+        let prefetch = match strict_priority {
+            true => 0,
+            false => self
+                .config
+                .prefetch
+                .min(self.config.max_ack_pending.max(0) as usize / 2),
+        };
+        // Jobs fetched but not yet taken by the worker
+        let buffered = Arc::new(AtomicUsize::new(0));
+        // Whether the worker is waiting for a job, i.e. has a free slot and an empty buffer
+        let worker_waiting = Arc::new(AtomicBool::new(false));
+        let job_wanted = Arc::new(tokio::sync::Notify::new());
 
         // Create channels for job streaming and acknowledgments
         let (mut job_tx, job_rx) =
-            mpsc::channel::<Result<Option<Request<T, NatsContext>>, Error>>(10);
+            mpsc::channel::<Result<Option<Request<T, NatsContext>>, Error>>(prefetch.max(10));
         let (ack_tx, mut ack_rx) = mpsc::channel::<(NatsContext, Response<Vec<u8>>)>(10);
 
-        // Create the AckLayer with the sender
-        let layer = AckLayer::new(ack_tx);
+        // Create the AckLayer with the sender, inside the per-job span. The bundled layers go
+        // inside the ack, so e.g. a caught panic is acked as an abort.
+        let layer = Stack::new(
+            self.layers.clone().for_ack_wait(self.config.ack_wait),
+            Stack::new(AckLayer::new(ack_tx), ProcessSpanLayer),
+        );
+
+        if self.producer_only {
+            let error = NatsPollError::Storage(
+                "storage was created with `NatsStorage::producer` and can't be polled".to_string(),
+            );
+            tracing::error!("Worker {} can't start: {}", worker_id, error);
+            let stream = futures::stream::once(async move {
+                Err(Error::SourceError(Arc::new(Box::new(error))))
+            })
+            .boxed();
+            return Poller::new_with_layer(stream, async {}, layer);
+        }
+
+        // Answer status queries while this worker is alive
+        let status_storage = self.clone();
+        let status_responder = tokio::spawn(async move {
+            if let Err(e) = status_storage.serve_status().await {
+                tracing::error!("Status responder stopped: {}", e);
+            }
+        });
+
+        // Enqueue due scheduled jobs while this worker is alive
+        let promoter = tokio::spawn(self.clone().promote_scheduled());
+
+        // Warn about handlers outliving their lease while this worker is alive, and with
+        // `keep_alive` extend the leases
+        let watchdog = LeaseWatchdog::default();
+        let keep_alive = self
+            .config
+            .keep_alive
+            .then(|| (self.config.ack_wait / 3).max(Duration::from_secs(1)));
+        let lease_watcher = tokio::spawn(watchdog.clone().run(self.config.ack_wait, keep_alive));
 
         // Clone storage for the ack task
-        let mut ack_storage = self.clone();
+        let ack_storage = self.clone();
+        let ack_worker = worker.clone();
+        let ack_concurrency = self.config.ack_concurrency.max(1);
 
-        // Spawn dedicated ack handling task
+        // Spawn dedicated ack handling task. Acks run concurrently so a slow DLQ publish
+        // doesn't hold up unrelated ones; the bounded channel and limit cap what's in flight.
         tokio::spawn(async move {
-            while let Some((ctx, resp)) = ack_rx.next().await {
-                if let Err(e) = <NatsStorage<T> as Ack<T, Vec<u8>, JsonCodec<Vec<u8>>>>::ack(
-                    &mut ack_storage,
-                    &ctx,
-                    &resp,
-                )
-                .await
-                {
-                    tracing::error!("Failed to acknowledge message: {}", e);
-                }
+            ack_rx
+                .for_each_concurrent(ack_concurrency, |(ctx, resp)| {
+                    let mut ack_storage = ack_storage.clone();
+                    let worker = ack_worker.clone();
+                    async move {
+                        if let Err(e) =
+                            <NatsStorage<T> as Ack<T, Vec<u8>, JsonCodec<Vec<u8>>>>::ack(
+                                &mut ack_storage,
+                                &ctx,
+                                &resp,
+                            )
+                            .await
+                        {
+                            tracing::error!("Failed to acknowledge message: {}", e);
+                        }
+                        ack_storage.record_outcome(&worker, &ctx, resp.inner.is_err());
+                        if ctx.message().is_some() {
+                            ack_storage.in_flight.fetch_sub(1, Ordering::AcqRel);
+                        }
+                    }
+                })
+                .await;
+            // The worker is gone
+            status_responder.abort();
+            promoter.abort();
+            lease_watcher.abort();
+            match ack_storage.in_flight() {
+                0 => tracing::info!("Worker stopped with no messages in flight"),
+                in_flight => tracing::warn!(
+                    "Worker stopped with {} unacked messages in flight, they are redelivered \
+                     after ack_wait",
+                    in_flight
+                ),
             }
         });
 
+        // Check the connection and streams while this worker is alive
+        let heartbeat = self.clone().heartbeat(worker.clone());
+
         // Spawn the fetch loop (no select!, no always-ready branch)
         tokio::spawn(async move {
+            let long_poll = self.config.poll_mode == PollMode::LongPoll;
+            let fetch_batch_size = match strict_priority {
+                true => 1,
+                false => self.config.fetch_batch_size.max(1),
+            };
+            let fetch_buffered = buffered.clone();
+            let fetch_waiting = worker_waiting.clone();
+            let fetch_wanted = job_wanted.clone();
+            let mut consecutive_failures = 0;
+            // Priorities that came back empty while a lower one had jobs. Until their consumer
+            // has something pending again they are skipped, so a busy Medium or Low doesn't pay
+            // a fetch (and its `fetch_expiry` wait) for an empty High on every job.
+            let mut empty_priorities: HashSet<Priority> = HashSet::new();
+            // Priorities whose consumer clashes with another consumer's filter, logged once
+            let mut overlapping: HashSet<Priority> = HashSet::new();
             loop {
+                if self.is_paused() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+
+                // Without prefetch, fetch only when the worker can start a job right away, so
+                // jobs don't pile up in the buffer with their ack_wait running, or wait there
+                // while a higher priority one is published
+                if prefetch == 0
+                    && !(fetch_waiting.load(Ordering::Acquire)
+                        && fetch_buffered.load(Ordering::Acquire) == 0)
+                {
+                    let _ =
+                        tokio::time::timeout(Duration::from_millis(100), fetch_wanted.notified())
+                            .await;
+                    continue;
+                }
+
+                // With a prefetch buffer, pull only what fits and wait while the worker catches up
+                let room = prefetch.saturating_sub(fetch_buffered.load(Ordering::Acquire));
+                if prefetch > 0 && room == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+                let batch_size = if prefetch > 0 { room } else { fetch_batch_size };
+
                 let mut job_found = false;
-                // Try to fetch a job from each priority level in order
-                for priority in [Priority::High, Priority::Medium, Priority::Low] {
+                let mut fetch_failed = false;
+                let mut empty = Vec::new();
+                let mut skipped = false;
+                // Try to fetch a job from each consumed priority level in order
+                for &priority in &self.priorities {
                     // Use shared consumer for work queue semantics
-                    if let Ok(consumer) = self.get_or_create_consumer(priority).await {
-                        if let Ok(mut batch) = consumer.fetch().max_messages(1).messages().await {
-                            // Apply client-side expiry to avoid blocking on empty queues
-                            match tokio::time::timeout(
-                                self.config.fetch_expiry,
-                                batch.try_next(),
-                            )
-                            .await
-                            {
-                                Ok(Ok(Some(msg))) => {
-                                    match serde_json::from_slice::<NatsJob<T>>(&msg.payload) {
-                                        Ok(job) => {
-                                            let ctx = NatsContext::with_message(msg);
-                                            let request = Request::new_with_ctx(job.data, ctx);
-                                            // Send job to worker
-                                            if job_tx.send(Ok(Some(request))).await.is_err() {
-                                                return; // Channel closed, exit task
-                                            }
+                    let mut consumer = match self.get_or_create_consumer(priority).await {
+                        Ok(consumer) => consumer,
+                        // Keeps failing until the other consumer is gone, say so once
+                        Err(e @ NatsPollError::ConsumerNotUnique { .. }) => {
+                            if overlapping.insert(priority) {
+                                tracing::error!("{}", e);
+                            }
+                            fetch_failed = true;
+                            continue;
+                        }
+                        Err(_) => {
+                            fetch_failed = true;
+                            continue;
+                        }
+                    };
+                    overlapping.remove(&priority);
+                    if empty_priorities.contains(&priority) {
+                        // A quick info request instead of a fetch waiting out its expiry. Jobs
+                        // awaiting redelivery only show as ack pending, so those count too.
+                        match consumer.info().await {
+                            Ok(info) if info.num_pending == 0 && info.num_ack_pending == 0 => {
+                                skipped = true;
+                                continue;
+                            }
+                            _ => {
+                                empty_priorities.remove(&priority);
+                            }
+                        }
+                    }
+                    let (batch, wait) = if long_poll {
+                        // Wait on the server instead of sleeping. Only the last priority waits
+                        // long, so new higher priority jobs are picked up on the next round.
+                        let expiry = match self.priorities.last() == Some(&priority) {
+                            true => self.config.long_poll_expiry,
+                            false => self.config.fetch_expiry,
+                        };
+                        let mut pull = consumer.batch().max_messages(batch_size).expires(expiry);
+                        if let Some(max_bytes) = self.config.fetch_max_bytes {
+                            pull = pull.max_bytes(max_bytes);
+                        }
+                        (pull.messages().await, expiry + Duration::from_secs(1))
+                    } else {
+                        let mut pull = consumer.fetch().max_messages(batch_size);
+                        if let Some(max_bytes) = self.config.fetch_max_bytes {
+                            pull = pull.max_bytes(max_bytes);
+                        }
+                        let batch = pull.messages().await;
+                        // Apply client-side expiry to avoid blocking on empty queues
+                        (batch, self.config.fetch_expiry)
+                    };
+                    let Ok(mut batch) = batch else {
+                        fetch_failed = true;
+                        continue;
+                    };
+
+                    loop {
+                        match tokio::time::timeout(wait, batch.try_next()).await {
+                            Ok(Ok(Some(msg))) => {
+                                if self.skip_foreign_job(&msg).await {
+                                    // Not ours, look for the next job right away
+                                    job_found = true;
+                                    continue;
+                                }
+                                if self.hold_quarantined(&msg).await {
+                                    // Failing en masse, leave it for after the quarantine
+                                    job_found = true;
+                                    continue;
+                                }
+                                match self.decode_job(&msg) {
+                                    Ok(job) => {
+                                        if self.filter_job(&msg, &job).await {
+                                            // Nothing to run, look for the next job right away
                                             job_found = true;
-                                            break; // Break the for loop to restart from high priority
+                                            continue;
                                         }
-                                        Err(e) => {
-                                            // Malformed payload: log and terminate to avoid endless redelivery
-                    tracing::error!("Failed to deserialize job payload: {}", e);
-                                            if let Err(ack_err) =
-                                                msg.ack_with(jetstream::AckKind::Term).await
-                                            {
+                                        self.update_status(&job.id, JobStatus::Running);
+                                        // Time in queue, clamped at zero for skewed clocks
+                                        let latency = (self.clock.now() - job.created_at)
+                                            .to_std()
+                                            .unwrap_or_default();
+                                        #[cfg(feature = "metrics")]
+                                        self.record_queue_latency(&msg, job.priority, latency);
+                                        self.check_sla(
+                                            &worker,
+                                            &msg,
+                                            &job.id,
+                                            job.priority,
+                                            latency,
+                                        );
+                                        let mut ctx = NatsContext::with_message(msg);
+                                        ctx.priority = Some(job.priority);
+                                        ctx.created_at = Some(job.created_at);
+                                        ctx.queue_latency = Some(latency);
+                                        ctx.dlq = Some(Arc::downgrade(&self.dlq_handle));
+                                        ctx.ack_wait = Some(self.config.ack_wait);
+                                        ctx.max_deliver = Some(self.config.max_deliver);
+                                        ctx.state = Some(JobState::new(
+                                            Arc::clone(&self.state),
+                                            job.id.clone(),
+                                        ));
+                                        watchdog.watch(&job.id, &ctx);
+                                        let mut request = Request::new_with_ctx(job.data, ctx);
+                                        request.parts.task_id = job.id;
+                                        // Send job to worker
+                                        fetch_buffered.fetch_add(1, Ordering::AcqRel);
+                                        self.in_flight.fetch_add(1, Ordering::AcqRel);
+                                        if job_tx.send(Ok(Some(request))).await.is_err() {
+                                            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                                            return; // Channel closed, exit task
+                                        }
+                                        job_found = true;
+                                    }
+                                    Err(e) => {
+                                        // Malformed payload: move it out of the way to avoid endless redelivery
+                                        match &self.redact {
+                                            Some(redact) => tracing::error!(
+                                                "Failed to deserialize job payload {}: {}",
+                                                redact(&msg.payload),
+                                                redacted_error(&e)
+                                            ),
+                                            None => tracing::error!(
+                                                "Failed to deserialize job payload: {}",
+                                                e
+                                            ),
+                                        }
+                                        if let Err(ack_err) =
+                                            self.reject_undecodable(&msg, &e).await
+                                        {
                                             tracing::error!(
-                                                "Failed to term malformed message: {}",
+                                                "Failed to reject malformed message: {}",
                                                 ack_err
                                             );
                                         }
-                                        }
                                     }
                                 }
-                                Ok(Ok(None)) => {
-                                    // No message in this batch
-                                }
-                                Ok(Err(e)) => {
-                                    tracing::debug!("Fetch error on priority {}: {}", priority, e);
-                                }
-                                Err(_elapsed) => {
-                                    // Timeout: fall through to next priority
+                            }
+                            Ok(Ok(None)) => {
+                                // Batch exhausted or expired
+                                break;
+                            }
+                            Ok(Err(e)) => {
+                                tracing::debug!("Fetch error on priority {}: {}", priority, e);
+                                fetch_failed = true;
+                                break;
+                            }
+                            Err(_elapsed) => {
+                                // Timeout: fall through to next priority
+                                break;
+                            }
+                        }
+                    }
+
+                    if job_found {
+                        break; // Break the for loop to restart from high priority
+                    }
+                    empty.push(priority);
+                }
+
+                // Skip the priorities that were empty ahead of one with jobs while they stay
+                // empty. An idle round fetches them all again, and strict_priority never skips.
+                if job_found && !strict_priority {
+                    empty_priorities.extend(empty);
+                } else if !job_found {
+                    empty_priorities.clear();
+                    if skipped && !fetch_failed {
+                        continue; // Check the skipped priorities before idling
+                    }
+                }
+
+                // A deleted stream won't come back by itself, act on it right away
+                if fetch_failed && !job_found {
+                    if let Some(stream) = self.missing_stream().await {
+                        match self.config.on_stream_missing {
+                            OnStreamMissing::Recreate => {
+                                tracing::warn!("Stream {} is gone, re-creating it", stream);
+                                if let Err(e) = self.recover().await {
+                                    tracing::warn!("Failed to re-create {}: {}", stream, e);
                                 }
                             }
+                            OnStreamMissing::Fail => {
+                                tracing::error!("Stream {} is gone, stopping the worker", stream);
+                                let error = NatsPollError::StreamNotFound(stream);
+                                // Taken off again when the worker receives it, like a job
+                                fetch_buffered.fetch_add(1, Ordering::AcqRel);
+                                let _ = job_tx
+                                    .send(Err(Error::SourceError(Arc::new(Box::new(error)))))
+                                    .await;
+                                worker.stop();
+                                return;
+                            }
                         }
                     }
                 }
 
+                // Streams may have moved or been lost, e.g. after a failover: set them up again
+                // instead of retrying against stale state forever
+                if fetch_failed && !job_found {
+                    consecutive_failures += 1;
+                } else {
+                    consecutive_failures = 0;
+                }
+                if consecutive_failures >= RECOVERY_THRESHOLD {
+                    tracing::warn!(
+                        "{} consecutive fetch rounds failed, re-creating streams and consumers for {}",
+                        consecutive_failures,
+                        self.config.namespace
+                    );
+                    match self.recover().await {
+                        Ok(()) => tracing::info!("Recovered streams for {}", self.config.namespace),
+                        Err(e) => tracing::warn!("Recovery attempt failed: {}", e),
+                    }
+                    consecutive_failures = 0;
+                }
+
                 // Apply backoff based on whether we found a job
                 if job_found {
-                    // Short wait when actively processing
-                    tokio::time::sleep(Duration::from_millis(10)).await;
-                } else {
-                    // Longer wait when no jobs available
-                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    // Short wait when actively processing, prefetching keeps going until full
+                    if prefetch == 0 {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                } else if !long_poll || fetch_failed {
+                    // Longer wait when no jobs available (long polls already waited), jittered
+                    // so a fleet started together doesn't poll in lockstep
+                    let backoff = jittered(IDLE_BACKOFF, self.config.idle_jitter);
+                    tracing::trace!("No jobs for {}, fetching again in {:?}", worker_id, backoff);
+                    tokio::time::sleep(backoff).await;
                 }
             }
         });
 
-        // Return the job stream as a boxed stream
-        let stream = job_rx.boxed();
-
-        Poller::new_with_layer(
-            stream,
-            async {
-                loop {
-                    tokio::time::sleep(Duration::from_secs(30)).await;
+        // Return the job stream as a boxed stream, the worker takes a job once it can run it
+        let mut job_rx = job_rx;
+        let stream = futures::stream::poll_fn(move |cx| {
+            let next = job_rx.poll_next_unpin(cx);
+            match &next {
+                Poll::Ready(Some(_)) => {
+                    worker_waiting.store(false, Ordering::Release);
+                    buffered.fetch_sub(1, Ordering::AcqRel);
                 }
-            },
-            layer,
-        )
+                Poll::Pending => {
+                    worker_waiting.store(true, Ordering::Release);
+                    job_wanted.notify_one();
+                }
+                Poll::Ready(None) => {}
+            }
+            next
+        })
+        .boxed();
+
+        Poller::new_with_layer(stream, heartbeat, layer)
     }
 }
@@ -0,0 +1,101 @@
+use crate::status::JobStatus;
+use crate::NatsContext;
+use apalis_core::task::task_id::TaskId;
+use async_nats::jetstream;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// How often the watchdog looks at the jobs in flight
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A job handed to the worker, watched until its message is settled or dropped
+struct Watched {
+    task_id: TaskId,
+    /// Gone once the handler and the ack task are done with the message
+    message: Weak<jetstream::Message>,
+    last_progress: Arc<Mutex<Option<Instant>>>,
+    settled: Arc<Mutex<Option<JobStatus>>>,
+}
+
+/// Looks for jobs that outlived their `ack_wait` without a progress ack. JetStream has
+/// redelivered those, so they may run twice, which is easy to miss without a heartbeat.
+/// Warns the first time it finds one, then only keeps its list pruned.
+///
+/// With `Config::keep_alive` it also sends the Progress acks itself, for every job in flight,
+/// so a worker needs one task for all its heartbeats instead of one per job.
+#[derive(Clone, Default)]
+pub(crate) struct LeaseWatchdog {
+    jobs: Arc<Mutex<Vec<Watched>>>,
+}
+
+impl LeaseWatchdog {
+    /// Watch a job that is about to be handed to the worker
+    pub(crate) fn watch(&self, task_id: &TaskId, ctx: &NatsContext) {
+        let Some(message) = &ctx.message else {
+            return;
+        };
+        lock(&self.jobs).push(Watched {
+            task_id: task_id.clone(),
+            message: Arc::downgrade(message),
+            last_progress: ctx.last_progress.clone(),
+            settled: ctx.settled.clone(),
+        });
+    }
+
+    /// Check the watched jobs every [`CHECK_INTERVAL`] until aborted, extending the leases of
+    /// the ones without a Progress ack for `keep_alive`
+    pub(crate) async fn run(self, ack_wait: Duration, keep_alive: Option<Duration>) {
+        let mut warned = false;
+        // Often enough that no lease goes much past `keep_alive` unextended
+        let period = keep_alive.map_or(CHECK_INTERVAL, |every| CHECK_INTERVAL.min(every / 2));
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+            if let Some(every) = keep_alive {
+                self.extend_leases(every).await;
+            }
+            let overdue = {
+                let mut jobs = lock(&self.jobs);
+                jobs.retain(|job| job.message.strong_count() > 0 && lock(&job.settled).is_none());
+                if warned {
+                    continue;
+                }
+                jobs.iter().find_map(|job| {
+                    let elapsed = (*lock(&job.last_progress))?.elapsed();
+                    (elapsed > ack_wait).then(|| (job.task_id.clone(), elapsed))
+                })
+            };
+            if let Some((task_id, elapsed)) = overdue {
+                tracing::warn!(
+                    "Task {} has gone {:?} without a progress ack, longer than ack_wait ({:?}); \
+                     JetStream redelivers it, so it may run twice. Add a ProgressHeartbeatLayer \
+                     or call NatsContext::progress() in long-running handlers",
+                    task_id,
+                    elapsed,
+                    ack_wait
+                );
+                warned = true;
+            }
+        }
+    }
+
+    /// Send a Progress ack for each job in flight that went `every` without one
+    async fn extend_leases(&self, every: Duration) {
+        let due: Vec<_> = lock(&self.jobs)
+            .iter()
+            .filter(|job| lock(&job.settled).is_none())
+            .filter(|job| (*lock(&job.last_progress)).map_or(true, |at| at.elapsed() >= every))
+            .filter_map(|job| Some((job.message.upgrade()?, job.last_progress.clone())))
+            .collect();
+        for (message, last_progress) in due {
+            match message.ack_with(jetstream::AckKind::Progress).await {
+                Ok(()) => *lock(&last_progress) = Some(Instant::now()),
+                Err(e) => tracing::warn!("Failed to extend the lease of a job in flight: {}", e),
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
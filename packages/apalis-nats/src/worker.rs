@@ -0,0 +1,208 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use apalis_core::backend::Backend;
+use apalis_core::builder::WorkerBuilder;
+use apalis_core::error::BoxDynError;
+use apalis_core::request::Request;
+use apalis_core::worker::{Ready, Worker};
+use serde::{de::DeserializeOwned, Serialize};
+use tower::layer::util::Stack;
+use tower::limit::ConcurrencyLimitLayer;
+use tower::{Layer, Service};
+
+use crate::layers::{CatchPanicLayer, ProgressHeartbeatLayer};
+use crate::{NatsContext, NatsPollError, NatsStorage};
+
+/// How often [`NatsStorage::run_until_drained`] checks whether the queue is empty
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The middleware the NATS backend wraps a worker's service in
+type NatsLayer<T> = <NatsStorage<T> as Backend<Request<T, NatsContext>>>::Layer;
+
+/// The middleware added by [`NatsWorkerExt::nats_defaults`]
+pub type NatsDefaults<M> =
+    Stack<ProgressHeartbeatLayer, Stack<CatchPanicLayer, Stack<ConcurrencyLimitLayer, M>>>;
+
+/// NATS-specific helpers for [`WorkerBuilder`].
+///
+/// Each method adds one layer, so they can be mixed with any other `.layer(...)` calls.
+/// [`nats_defaults`](NatsWorkerExt::nats_defaults) applies all of them.
+///
+/// # Example
+/// ```rust,no_run
+/// use apalis::prelude::*;
+/// use apalis_nats::{NatsStorage, NatsWorkerExt};
+///
+/// async fn handle(job: String) -> Result<(), Error> {
+///     Ok(())
+/// }
+///
+/// # async fn demo(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+/// let worker = WorkerBuilder::new("nats-worker")
+///     .nats_defaults(&storage)
+///     .backend(storage.clone())
+///     .build_fn(handle);
+///
+/// Monitor::new().register(worker).run().await?;
+/// # Ok(()) }
+/// ```
+pub trait NatsWorkerExt<Req, Ctx, M, Serv>: Sized {
+    /// Limit in-flight jobs to the storage's `concurrency`, or `max_ack_pending` (the most the
+    /// consumer will hand out before an ack) when it isn't set
+    fn nats_concurrency<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ConcurrencyLimitLayer, M>, Serv>;
+
+    /// Limit in-flight jobs to `max`, like `.concurrency(max)`, and record the limit on the
+    /// storage, so workers warn at startup when it exceeds `max_ack_pending`. A plain
+    /// `.concurrency(max)` isn't visible to the storage.
+    fn nats_concurrency_limit<T>(
+        self,
+        storage: &NatsStorage<T>,
+        max: usize,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ConcurrencyLimitLayer, M>, Serv>;
+
+    /// Turn handler panics into `Error::Abort` so the job goes to the DLQ instead of being
+    /// redelivered after `ack_wait`
+    fn nats_catch_panic(self) -> WorkerBuilder<Req, Ctx, (), Stack<CatchPanicLayer, M>, Serv>;
+
+    /// Send progress heartbeats every third of the storage's `ack_wait` while a job runs
+    fn nats_heartbeat<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ProgressHeartbeatLayer, M>, Serv>;
+
+    /// Apply [`nats_concurrency`](NatsWorkerExt::nats_concurrency),
+    /// [`nats_catch_panic`](NatsWorkerExt::nats_catch_panic) and
+    /// [`nats_heartbeat`](NatsWorkerExt::nats_heartbeat)
+    fn nats_defaults<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), NatsDefaults<M>, Serv>;
+}
+
+impl<Req, Ctx, M, Serv> NatsWorkerExt<Req, Ctx, M, Serv> for WorkerBuilder<Req, Ctx, (), M, Serv> {
+    fn nats_concurrency<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ConcurrencyLimitLayer, M>, Serv> {
+        let max = storage
+            .config
+            .concurrency
+            .unwrap_or(storage.config.max_ack_pending.max(1) as usize);
+        self.nats_concurrency_limit(storage, max)
+    }
+
+    fn nats_concurrency_limit<T>(
+        self,
+        storage: &NatsStorage<T>,
+        max: usize,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ConcurrencyLimitLayer, M>, Serv> {
+        // Workers sharing the storage check the highest limit among them
+        storage.worker_concurrency.fetch_max(max, Ordering::AcqRel);
+        self.chain(|svc| svc.layer(ConcurrencyLimitLayer::new(max)))
+    }
+
+    fn nats_catch_panic(self) -> WorkerBuilder<Req, Ctx, (), Stack<CatchPanicLayer, M>, Serv> {
+        self.chain(|svc| svc.layer(CatchPanicLayer::new()))
+    }
+
+    fn nats_heartbeat<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), Stack<ProgressHeartbeatLayer, M>, Serv> {
+        let interval = (storage.config.ack_wait / 3).max(Duration::from_secs(1));
+        self.chain(|svc| svc.layer(ProgressHeartbeatLayer::new(interval)))
+    }
+
+    fn nats_defaults<T>(
+        self,
+        storage: &NatsStorage<T>,
+    ) -> WorkerBuilder<Req, Ctx, (), NatsDefaults<M>, Serv> {
+        self.nats_concurrency(storage)
+            .nats_catch_panic()
+            .nats_heartbeat(storage)
+    }
+}
+
+impl<T> NatsStorage<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Run `worker` until everything queued has been processed, then stop it and return.
+    ///
+    /// The worker is stopped once the streams it consumes (see
+    /// [`for_priorities`](NatsStorage::for_priorities)) have been empty, with nothing in flight,
+    /// for `idle_timeout`. Stopping is graceful: running jobs finish and are acked first. Jobs
+    /// pushed while the worker runs are processed too, so a steady trickle can keep it going;
+    /// `idle_timeout` is the gap that counts as done. Meant for batch runs started by cron.
+    ///
+    /// The worker must have been built on this storage or a clone of it.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use apalis::prelude::*;
+    /// use apalis_nats::NatsStorage;
+    ///
+    /// async fn handle(job: String) -> Result<(), Error> {
+    ///     Ok(())
+    /// }
+    ///
+    /// # async fn demo(storage: NatsStorage<String>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let worker = WorkerBuilder::new("nightly-batch")
+    ///     .backend(storage.clone())
+    ///     .build_fn(handle);
+    /// storage.run_until_drained(worker, Duration::from_secs(5)).await?;
+    /// # Ok(()) }
+    /// ```
+    pub async fn run_until_drained<S>(
+        &self,
+        worker: Worker<Ready<S, NatsStorage<T>>>,
+        idle_timeout: Duration,
+    ) -> Result<(), NatsPollError>
+    where
+        S: Service<Request<T, NatsContext>> + 'static,
+        S::Error: Send + 'static + Into<BoxDynError>,
+        NatsLayer<T>: Layer<S>,
+        <NatsLayer<T> as Layer<S>>::Service: Service<Request<T, NatsContext>> + Send,
+        <<NatsLayer<T> as Layer<S>>::Service as Service<Request<T, NatsContext>>>::Future: Send,
+        <<NatsLayer<T> as Layer<S>>::Service as Service<Request<T, NatsContext>>>::Error:
+            Send + Into<BoxDynError>,
+    {
+        let runnable = worker.run();
+        let handle = runnable.get_handle();
+        let mut running = tokio::spawn(runnable);
+
+        let mut idle_since: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                // Stopped by something else
+                _ = &mut running => return Ok(()),
+                _ = tokio::time::sleep(DRAIN_CHECK_INTERVAL) => {}
+            }
+
+            let queued = match self.queued().await {
+                Ok(queued) => queued,
+                Err(e) => {
+                    handle.stop();
+                    let _ = running.await;
+                    return Err(e);
+                }
+            };
+            if queued > 0 || self.in_flight() > 0 {
+                idle_since = None;
+                continue;
+            }
+            let since = *idle_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= idle_timeout {
+                tracing::info!("Queue drained, stopping worker {}", handle.id());
+                handle.stop();
+                let _ = running.await;
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,8 +1,15 @@
 use apalis::prelude::*;
-use apalis_nats::{Config, NatsStorage, Priority};
+use apalis_nats::{
+    AckPolicy, CatchPanicLayer, Clock, Config, DeliverPolicy, Disposition, DlqEntry, DlqError, FilterAction, JobStatus, NatsContext, NatsLayers,
+    NatsPollError, NatsStorage, NatsStorageRegistry, NatsWorkerExt, OnMaxDeliver, OnStreamMissing, Placement,
+    PlannedResource, PollMode, Priority, PriorityLimitLayer, ProgressHeartbeatLayer, Quarantine, QueueSummary,
+    ReplayPolicy, RepublishConfig, RetentionPolicy, RetryBudget, Source, StorageType, TestClock, CREATED_AT_HEADER, JOB_TYPE_HEADER, NAMESPACE_HEADER,
+    PRIORITY_HEADER, TASK_ID_HEADER,
+};
 use async_nats::jetstream::{self, consumer};
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -31,38 +38,17 @@ impl TestJob {
 
 // Helper to setup NATS container and storage
 async fn setup_nats() -> (ContainerAsync<Nats>, NatsStorage<TestJob>) {
-    // Start NATS container with JetStream enabled
-    let container = Nats::default()
-        .with_cmd(["-js"]) // Enable JetStream
-        .start()
-        .await
-        .expect("Failed to start NATS container");
-
-    let host = container.get_host().await.expect("Failed to get host");
-    let port = container
-        .get_host_port_ipv4(4222)
-        .await
-        .expect("Failed to get port");
-
-    let nats_url = format!("nats://{}:{}", host, port);
-
-    // Give NATS a moment to fully initialize
-    tokio::time::sleep(Duration::from_secs(1)).await;
-
-    // Connect to NATS
-    let client = apalis_nats::connect(&nats_url)
-        .await
-        .expect("Failed to connect to NATS");
+    let (container, client) = setup_nats_raw().await;
 
     // Create storage with test configuration
     let config = Config {
-        namespace: format!("test_{}", Uuid::new_v4().to_string().replace('-', "_")),
         max_deliver: 3,
         ack_wait: Duration::from_secs(5),
         num_replicas: 1,
+        storage_type: StorageType::Memory, // Nothing needs to outlive the container
         enable_dlq: true,
         max_ack_pending: 10, // Lower for testing to avoid message duplication
-        ..Default::default()
+        ..test_config()
     };
 
     let storage = NatsStorage::new_with_config(client, config)
@@ -74,6 +60,8 @@ async fn setup_nats() -> (ContainerAsync<Nats>, NatsStorage<TestJob>) {
 
 // Helper to setup NATS container and return raw client
 async fn setup_nats_raw() -> (ContainerAsync<Nats>, async_nats::Client) {
+    init_tracing();
+
     // Start NATS container with JetStream enabled
     let container = Nats::default()
         .with_cmd(["-js"]) // Enable JetStream
@@ -100,13 +88,30 @@ async fn setup_nats_raw() -> (ContainerAsync<Nats>, async_nats::Client) {
     (container, client)
 }
 
-#[tokio::test]
-async fn test_end_to_end_job_execution() {
-    // Initialize tracing for debugging
+// Log apalis at debug level. The first subscriber installed in the test binary wins, so a test
+// wanting another filter installs it before `setup_nats_raw`
+fn init_tracing() {
     let _ = tracing_subscriber::fmt()
         .with_env_filter("apalis=debug,apalis_nats=debug")
         .try_init();
+}
+
+// A namespace no other test uses, so tests sharing a server don't see each other's streams
+fn test_namespace() -> String {
+    format!("test_{}", Uuid::new_v4().to_string().replace('-', "_"))
+}
+
+// The default configuration in a fresh namespace
+fn test_config() -> Config {
+    Config {
+        namespace: test_namespace(),
+        ..Default::default()
+    }
+}
 
+#[tokio::test]
+async fn test_end_to_end_job_execution() {
+    // Initialize tracing for debugging
     let (_container, mut storage) = setup_nats().await;
 
     // Track job execution
@@ -172,10 +177,6 @@ async fn test_end_to_end_job_execution() {
 
 #[tokio::test]
 async fn test_priority_queue_ordering() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, storage) = setup_nats().await;
 
     // Track execution order
@@ -269,10 +270,6 @@ async fn test_priority_queue_ordering() {
 
 #[tokio::test]
 async fn test_job_retry_and_failure() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, mut storage) = setup_nats().await;
 
     // Track retry attempts
@@ -333,10 +330,6 @@ async fn test_job_retry_and_failure() {
 
 #[tokio::test]
 async fn test_concurrent_workers() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, mut storage) = setup_nats().await;
 
     // Track which worker processed each job
@@ -421,10 +414,6 @@ async fn test_concurrent_workers() {
 
 #[tokio::test]
 async fn test_storage_stats() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, mut storage) = setup_nats().await;
 
     // Initially, storage should be empty
@@ -477,18 +466,10 @@ async fn test_storage_stats() {
 
 #[tokio::test]
 async fn test_dlq_on_max_deliveries() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, client) = setup_nats_raw().await;
 
     // Create storage with max_deliver = 2 for faster testing
-    let mut config = Config::default();
-    config.namespace = format!(
-        "test_{}",
-        uuid::Uuid::new_v4().to_string().replace("-", "_")
-    );
+    let mut config = test_config();
     config.max_deliver = 2; // Only 2 attempts before DLQ
     config.enable_dlq = true;
 
@@ -610,18 +591,10 @@ async fn test_dlq_on_max_deliveries() {
 
 #[tokio::test]
 async fn test_dlq_on_abort_error() {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter("apalis=debug,apalis_nats=debug")
-        .try_init();
-
     let (_container, client) = setup_nats_raw().await;
 
     // Create storage with DLQ enabled
-    let mut config = Config::default();
-    config.namespace = format!(
-        "test_{}",
-        uuid::Uuid::new_v4().to_string().replace("-", "_")
-    );
+    let mut config = test_config();
     config.enable_dlq = true;
 
     let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
@@ -684,6 +657,81 @@ async fn test_dlq_on_abort_error() {
     let _ = handle.await;
 }
 
+#[tokio::test]
+async fn test_dlq_backend_delivers_entries_to_worker() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "boom",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let job = TestJob::new("DLQ worker job");
+    let job_id = job.id.clone();
+    storage.push(job).await.expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("dlq-source-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    let received = Arc::new(Mutex::new(Vec::<DlqEntry>::new()));
+
+    async fn handle_dlq(
+        entry: DlqEntry,
+        received: Data<Arc<Mutex<Vec<DlqEntry>>>>,
+    ) -> Result<(), Error> {
+        received.lock().await.push(entry);
+        Ok(())
+    }
+
+    let dlq_worker = WorkerBuilder::new("dlq-worker")
+        .data(received.clone())
+        .backend(storage.dlq_backend())
+        .build_fn(handle_dlq);
+    let dlq_handle = tokio::spawn(async move {
+        dlq_worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let received = received.lock().await.clone();
+    assert_eq!(received.len(), 1, "DLQ worker should receive the aborted job");
+    assert_eq!(received[0].dlq_reason, "abort_error");
+    let job: TestJob = received[0].job().expect("Failed to decode DLQ payload");
+    assert_eq!(job.id, job_id);
+
+    // The handled entry is acked on the DLQ consumer
+    let jetstream = jetstream::new(client);
+    let stream = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = stream
+        .consumer_info(format!("{}_dlq_consumer", config.namespace))
+        .await
+        .expect("DLQ consumer should exist");
+    assert_eq!(info.num_ack_pending, 0);
+    assert_eq!(info.num_pending, 0);
+
+    handle.abort();
+    dlq_handle.abort();
+    let _ = handle.await;
+    let _ = dlq_handle.await;
+}
+
 #[tokio::test]
 async fn test_long_running_with_heartbeat_prevents_redelivery() {
     let _ = tracing_subscriber::fmt()
@@ -693,11 +741,7 @@ async fn test_long_running_with_heartbeat_prevents_redelivery() {
     let (_container, client) = setup_nats_raw().await;
 
     // Small ack_wait to force redelivery quickly if no progress
-    let mut config = Config::default();
-    config.namespace = format!(
-        "test_{}",
-        uuid::Uuid::new_v4().to_string().replace("-", "_")
-    );
+    let mut config = test_config();
     config.ack_wait = Duration::from_secs(2);
     config.max_deliver = 2;
     config.enable_dlq = true;
@@ -773,3 +817,7215 @@ async fn test_long_running_with_heartbeat_prevents_redelivery() {
     handle.abort();
     let _ = handle.await;
 }
+
+#[tokio::test]
+async fn test_migrate_namespace_moves_pending_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for priority in [Priority::High, Priority::Medium, Priority::Low, Priority::Medium] {
+        storage
+            .push_with_priority(TestJob::new(format!("{:?} job", priority)), priority)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let new_namespace = format!("{}_v2", config.namespace);
+    let migrated = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("Failed to migrate namespace");
+    assert_eq!(migrated, 4, "All pending jobs should be migrated");
+
+    let mut new_config = config.clone();
+    new_config.namespace = new_namespace.clone();
+    let mut migrated_storage = NatsStorage::<TestJob>::new_with_config(client.clone(), new_config)
+        .await
+        .expect("Failed to create storage for new namespace");
+    assert_eq!(
+        migrated_storage.len().await.expect("Failed to get length"),
+        4,
+        "New namespace should hold the migrated jobs"
+    );
+
+    // The old streams are torn down
+    let js = jetstream::new(client);
+    for priority in ["high", "medium", "low"] {
+        assert!(
+            js.get_stream(format!("{}_{}", config.namespace, priority))
+                .await
+                .is_err(),
+            "Old {} stream should be deleted",
+            priority
+        );
+    }
+
+    // Re-running is a no-op and doesn't duplicate jobs
+    let migrated_again = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("Failed to re-run migration");
+    assert_eq!(migrated_again, 0);
+    assert_eq!(
+        migrated_storage.len().await.expect("Failed to get length"),
+        4
+    );
+}
+
+#[tokio::test]
+async fn test_migrate_namespace_moves_scheduled_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let run_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    let task_id = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs")
+        .schedule(TestJob::new("later"), run_at)
+        .await
+        .expect("Failed to schedule job");
+
+    let new_namespace = format!("{}_v2", config.namespace);
+    let migrated = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("Failed to migrate namespace");
+    assert_eq!(migrated, 1, "The scheduled job should be migrated");
+
+    let mut new_config = config.clone();
+    new_config.namespace = new_namespace.clone();
+    let migrated_storage = NatsStorage::<TestJob>::new_with_config(client.clone(), new_config)
+        .await
+        .expect("Failed to create storage for new namespace");
+    let pending = migrated_storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs")
+        .list_pending()
+        .await
+        .expect("Failed to list scheduled jobs");
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].task_id, task_id);
+    assert_eq!(pending[0].run_at, run_at);
+
+    // The old bucket is gone, so a re-run has nothing left to move
+    let js = jetstream::new(client);
+    assert!(js
+        .get_key_value(format!("{}_scheduled", config.namespace))
+        .await
+        .is_err());
+    let migrated_again = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("Failed to re-run migration");
+    assert_eq!(migrated_again, 0);
+}
+
+#[tokio::test]
+async fn test_migrate_namespace_leaves_custom_dlq_subject_alone() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.dlq_subject = Some(format!("dead.{}", config.namespace));
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("pending"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let new_namespace = format!("{}_v2", config.namespace);
+    let migrated = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("A custom DLQ subject shouldn't block the migration");
+    assert_eq!(migrated, 1);
+
+    // The DLQ keeps capturing the custom subject, no second stream competes for it
+    let js = jetstream::new(client);
+    assert!(js
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .is_ok());
+    assert!(js.get_stream(format!("{}_dlq", new_namespace)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_migrate_namespace_drops_publish_expectations() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_expected_seq(TestJob::new("first"), Priority::High, 0)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push_with_expected_seq(TestJob::new("second"), Priority::High, 1)
+        .await
+        .expect("Failed to push job");
+
+    // The first one ran, so the second expects a sequence the new stream never had
+    let js = jetstream::new(client.clone());
+    js.get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist")
+        .delete_message(1)
+        .await
+        .expect("Failed to remove the first job");
+
+    let new_namespace = format!("{}_v2", config.namespace);
+    let migrated = storage
+        .migrate_namespace(&new_namespace)
+        .await
+        .expect("Stale expectations shouldn't abort the migration");
+    assert_eq!(migrated, 1);
+
+    let msg = js
+        .get_stream(format!("{}_high", new_namespace))
+        .await
+        .expect("New high stream should exist")
+        .get_raw_message(1)
+        .await
+        .expect("The second job should be migrated");
+    assert!(msg
+        .headers
+        .get("Nats-Expected-Last-Subject-Sequence")
+        .is_none());
+}
+
+/// Open `pulls` long polls at once on a priority's consumer, returning how many the server
+/// rejected with "Exceeded MaxWaiting"
+async fn rejected_pulls(client: &async_nats::Client, namespace: &str, pulls: usize) -> usize {
+    let inbox = client.new_inbox();
+    let mut replies = client.subscribe(inbox.clone()).await.expect("Failed to subscribe");
+    let subject = format!(
+        "$JS.API.CONSUMER.MSG.NEXT.{}_medium.{}_medium_consumer",
+        namespace, namespace
+    );
+    // The stream is empty, so every accepted pull stays open until it expires
+    let request = r#"{"batch":1,"expires":3000000000}"#;
+    for _ in 0..pulls {
+        client
+            .publish_with_reply(subject.clone(), inbox.clone(), request.into())
+            .await
+            .expect("Failed to send pull request");
+    }
+    client.flush().await.expect("Failed to flush");
+
+    // Rejections are immediate, the expiries only come after 3 seconds
+    let mut rejected = 0;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+    while let Ok(Some(reply)) = tokio::time::timeout_at(deadline, replies.next()).await {
+        let description = reply.description.unwrap_or_default().to_lowercase();
+        if reply.status.map(|status| status.as_u16()) == Some(409)
+            && description.contains("maxwaiting")
+        {
+            rejected += 1;
+        }
+    }
+    rejected
+}
+
+#[tokio::test]
+async fn test_max_waiting_limits_outstanding_pulls() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let consumer_max_waiting = |storage: NatsStorage<TestJob>| async move {
+        storage
+            .consumer_info(Priority::Medium)
+            .await
+            .expect("Failed to get consumer info")
+            .config
+            .max_waiting
+    };
+
+    // More workers polling than the limit allows: the extra pulls are rejected
+    let mut config = test_config();
+    config.max_waiting = 4;
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage.create_consumers().await.expect("Failed to create consumers");
+    assert_eq!(consumer_max_waiting(storage).await, 4);
+    assert_eq!(rejected_pulls(&client, &config.namespace, 6).await, 2);
+
+    // Raising the limit makes room for all of them
+    config.namespace = test_namespace();
+    config.max_waiting = 8;
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage.create_consumers().await.expect("Failed to create consumers");
+    assert_eq!(consumer_max_waiting(storage).await, 8);
+    assert_eq!(rejected_pulls(&client, &config.namespace, 6).await, 0);
+
+    // Left at 0, it is derived from the limits: twice the 1000 single-job workers that
+    // max_ack_pending lets hold a job at once
+    let mut config = test_config();
+    config.max_ack_pending = 1000;
+    config.concurrency = Some(1);
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage.create_consumers().await.expect("Failed to create consumers");
+    assert_eq!(consumer_max_waiting(storage).await, 2000);
+
+    // Never below the server's default
+    config.namespace = test_namespace();
+    config.max_ack_pending = 100;
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage.create_consumers().await.expect("Failed to create consumers");
+    assert_eq!(consumer_max_waiting(storage).await, 512);
+}
+
+#[tokio::test]
+async fn test_subject_transform_republishes_to_external_subject() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    let external_prefix = format!("ext_{}", config.namespace);
+    config.subject_transform = Some((
+        format!("{}.*", config.namespace),
+        format!("{}.jobs.{{{{wildcard(1)}}}}", external_prefix),
+    ));
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let mut subscriber = client
+        .subscribe(format!("{}.jobs.>", external_prefix))
+        .await
+        .expect("Failed to subscribe to external subject");
+
+    let job = TestJob::new("Republished job");
+    storage
+        .push_with_priority(job.clone(), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), subscriber.next())
+        .await
+        .expect("Timed out waiting for the transformed copy")
+        .expect("Subscription closed");
+    assert_eq!(msg.subject.as_str(), format!("{}.jobs.high", external_prefix));
+    let payload: serde_json::Value =
+        serde_json::from_slice(&msg.payload).expect("Copy should carry the job envelope");
+    assert_eq!(payload["data"]["id"], job.id);
+
+    // The copy is additive: the job is still queued for apalis workers
+    assert_eq!(storage.len().await.expect("Failed to get length"), 1);
+}
+
+#[tokio::test]
+async fn test_nats_defaults_applies_recommended_layers() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn panic_job(_job: TestJob) -> Result<(), Error> {
+        panic!("handler blew up");
+    }
+
+    let builder = WorkerBuilder::new("nats-defaults-worker")
+        .nats_defaults(&storage)
+        .backend(storage.clone());
+    let layers = format!("{:?}", builder);
+    for layer in [
+        "ConcurrencyLimitLayer",
+        "CatchPanicLayer",
+        "ProgressHeartbeatLayer",
+    ] {
+        assert!(layers.contains(layer), "{} missing from {}", layer, layers);
+    }
+
+    storage
+        .push(TestJob::new("Panicking job"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = builder.build_fn(panic_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // The panic became Error::Abort, so the job went straight to the DLQ
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the panicked job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    assert!(entry.error.contains("handler blew up"), "{}", entry.error);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_query_status_tracks_job_lifecycle() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("Status job"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    // No worker yet, answered from the status bucket
+    assert_eq!(
+        storage.query_status(&task_id).await.expect("Failed to query status"),
+        JobStatus::Pending
+    );
+
+    async fn slow_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("status-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(
+        storage.query_status(&task_id).await.expect("Failed to query status"),
+        JobStatus::Running
+    );
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(
+        storage.query_status(&task_id).await.expect("Failed to query status"),
+        JobStatus::Completed
+    );
+
+    assert!(
+        storage
+            .query_status(&apalis_core::task::task_id::TaskId::new())
+            .await
+            .is_err(),
+        "Unknown task ids should be an error"
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_status_tracking_is_off_by_default() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    // Provision the streams only, as an operator would for workers that may not create them
+    NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let jetstream = jetstream::new(client.clone());
+    assert!(jetstream
+        .get_stream(format!("KV_{}_status", config.namespace))
+        .await
+        .is_err());
+
+    let mut verify_only = config.clone();
+    verify_only.create_streams = false;
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), verify_only)
+        .await
+        .expect("Pre-provisioned streams should be enough without status tracking");
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("Untracked job"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    assert!(matches!(
+        storage.query_status(&task_id).await,
+        Err(NatsPollError::Storage(_))
+    ));
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("untracked-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_handler_dlq_metadata_is_recorded() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn reject_job(_job: TestJob, ctx: NatsContext) -> Result<(), Error> {
+        ctx.set_dlq_metadata(serde_json::json!({
+            "rule": "email_format",
+            "field": "to",
+        }))?;
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "validation failed",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    storage
+        .push(TestJob::new("Invalid job"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("metadata-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(reject_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the rejected job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    assert_eq!(
+        entry.metadata,
+        Some(serde_json::json!({ "rule": "email_format", "field": "to" }))
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_replay_consumer_by_start_time_skips_older_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("old {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let cutoff = chrono::Utc::now();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("new {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let mut replay_config = config.clone();
+    replay_config.deliver_policy = DeliverPolicy::ByStartTime(cutoff);
+    let replay_storage = NatsStorage::<TestJob>::new_with_config(client.clone(), replay_config)
+        .await
+        .expect("Failed to create replay storage");
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        job: TestJob,
+        delivered: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        delivered.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("replay-worker")
+        .concurrency(1)
+        .data(delivered.clone())
+        .backend(replay_storage)
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let mut delivered = delivered.lock().await.clone();
+    delivered.sort();
+    assert_eq!(delivered, vec!["new 0".to_string(), "new 1".to_string()]);
+
+    // The replay ran on its own durable, not the regular one
+    let stream = jetstream::new(client)
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Medium stream should exist");
+    assert!(stream
+        .consumer_info(format!("{}_medium_replay_consumer", config.namespace))
+        .await
+        .is_ok());
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_replay_consumer_is_refused_while_the_regular_worker_runs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        job: TestJob,
+        delivered: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        delivered.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let regular = WorkerBuilder::new("regular-worker")
+        .concurrency(1)
+        .data(delivered.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let regular_handle = tokio::spawn(async move {
+        regular.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let mut replay_config = config.clone();
+    replay_config.deliver_policy = DeliverPolicy::New;
+    let replay_storage = NatsStorage::<TestJob>::new_with_config(client.clone(), replay_config)
+        .await
+        .expect("Failed to create replay storage");
+
+    match replay_storage.create_consumers().await {
+        Err(NatsPollError::ConsumerNotUnique { stream, consumer }) => {
+            assert_eq!(stream, format!("{}_high", config.namespace));
+            assert_eq!(consumer, format!("{}_high_replay_consumer", config.namespace));
+        }
+        other => panic!("Expected ConsumerNotUnique, got {:?}", other),
+    }
+
+    // A replay worker started anyway keeps failing to fetch, the regular one still runs jobs
+    let replay = WorkerBuilder::new("replay-worker")
+        .concurrency(1)
+        .data(delivered.clone())
+        .backend(replay_storage)
+        .build_fn(record_job);
+    let replay_handle = tokio::spawn(async move {
+        replay.run().await;
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    storage
+        .push_with_priority(TestJob::new("during replay"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    assert_eq!(*delivered.lock().await, vec!["during replay".to_string()]);
+    let stream = jetstream::new(client)
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Medium stream should exist");
+    assert!(stream
+        .consumer_info(format!("{}_medium_replay_consumer", config.namespace))
+        .await
+        .is_err());
+
+    replay_handle.abort();
+    let _ = replay_handle.await;
+    regular_handle.abort();
+    let _ = regular_handle.await;
+}
+
+#[tokio::test]
+async fn test_migrations_upgrade_old_payloads() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct GreetingV1 {
+        name: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+    struct GreetingV2 {
+        first_name: String,
+        last_name: String,
+    }
+
+    // An old producer publishes v1 payloads
+    let v1_storage = NatsStorage::<GreetingV1>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create v1 storage");
+    assert_eq!(v1_storage.schema_version(), 1);
+    v1_storage
+        .push_with_priority(
+            GreetingV1 {
+                name: "Ada Lovelace".to_string(),
+            },
+            Priority::Medium,
+        )
+        .await
+        .expect("Failed to push v1 job");
+
+    // The new worker decodes them via a migration
+    let v2_storage = NatsStorage::<GreetingV2>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create v2 storage")
+        .with_migrations(vec![Box::new(|v1: serde_json::Value| {
+            let name = v1["name"].as_str().unwrap_or_default();
+            let (first, last) = name.split_once(' ').unwrap_or((name, ""));
+            serde_json::json!({ "first_name": first, "last_name": last })
+        })]);
+    assert_eq!(v2_storage.schema_version(), 2);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+
+    async fn greet(
+        job: GreetingV2,
+        received: Data<Arc<Mutex<Vec<GreetingV2>>>>,
+    ) -> Result<(), Error> {
+        received.lock().await.push(job);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("migration-worker")
+        .concurrency(1)
+        .data(received.clone())
+        .backend(v2_storage)
+        .build_fn(greet);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    assert_eq!(
+        *received.lock().await,
+        vec![GreetingV2 {
+            first_name: "Ada".to_string(),
+            last_name: "Lovelace".to_string(),
+        }]
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+/// Log output written to a buffer, for asserting on warnings
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_worker_warns_when_concurrency_exceeds_max_ack_pending() {
+    let (_container, client) = setup_nats_raw().await;
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=warn")
+        .with_writer(move || writer.clone())
+        .finish();
+
+    // Concurrency 20 against max_ack_pending 10
+    let mut config = test_config();
+    config.max_ack_pending = 10;
+    config.concurrency = Some(20);
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let worker = WorkerBuilder::new("mismatched-worker")
+        .concurrency(20)
+        .backend(storage)
+        .build_fn(noop);
+    // The check runs when the worker starts polling
+    drop(tracing::subscriber::with_default(subscriber, || worker.run()));
+
+    let output = logs.take();
+    assert!(
+        output.contains("concurrency (20) exceeds max_ack_pending (10)"),
+        "Expected a concurrency warning, got: {}",
+        output
+    );
+
+    // with_concurrency keeps them consistent
+    let config = config.with_concurrency(20);
+    assert_eq!(config.max_ack_pending, 40);
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config)
+        .await
+        .expect("Failed to create storage");
+
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=warn")
+        .with_writer(move || writer.clone())
+        .finish();
+    let worker = WorkerBuilder::new("matched-worker")
+        .concurrency(20)
+        .backend(storage)
+        .build_fn(noop);
+    drop(tracing::subscriber::with_default(subscriber, || worker.run()));
+
+    assert!(
+        !logs.take().contains("exceeds max_ack_pending"),
+        "No warning expected when concurrency fits"
+    );
+
+    // Only the worker knows its limit
+    let mut config = test_config();
+    config.max_ack_pending = 100;
+    assert!(config.concurrency.is_none());
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config)
+        .await
+        .expect("Failed to create storage");
+
+    // Without a known limit there is nothing to warn about
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=warn")
+        .with_writer(move || writer.clone())
+        .finish();
+    let worker = WorkerBuilder::new("unlimited-worker")
+        .backend(storage.clone())
+        .build_fn(noop);
+    drop(tracing::subscriber::with_default(subscriber, || worker.run()));
+    let output = logs.take();
+    assert!(!output.contains("concurrency"), "No warning expected, got: {}", output);
+
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=warn")
+        .with_writer(move || writer.clone())
+        .finish();
+    let worker = WorkerBuilder::new("worker-limited")
+        .nats_concurrency_limit(&storage, 200)
+        .backend(storage.clone())
+        .build_fn(noop);
+    drop(tracing::subscriber::with_default(subscriber, || worker.run()));
+    let output = logs.take();
+    assert!(
+        output.contains("concurrency (200) exceeds max_ack_pending (100)"),
+        "Expected a concurrency warning, got: {}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn test_set_replicas_on_single_node() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // A standalone server can't hold 3 replicas
+    let err = storage
+        .set_replicas(3)
+        .await
+        .expect_err("3 replicas should fail on a single node");
+    assert!(
+        err.to_string().contains("not clustered"),
+        "Unexpected error: {}",
+        err
+    );
+    assert!(storage.set_replicas(0).await.is_err());
+
+    storage.set_replicas(1).await.expect("1 replica should succeed");
+
+    let js = jetstream::new(client);
+    for suffix in ["high", "medium", "low", "dlq"] {
+        let mut stream = js
+            .get_stream(format!("{}_{}", config.namespace, suffix))
+            .await
+            .expect("Stream should exist");
+        let info = stream.info().await.expect("Failed to get stream info");
+        assert_eq!(info.config.num_replicas, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_requeue_dlq_edited_redrives_fixed_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Aborts on malformed jobs, succeeds otherwise
+    async fn validate_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        if job.message.starts_with("malformed") {
+            return Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "malformed message",
+            ))
+                as Box<dyn std::error::Error + Send + Sync>)));
+        }
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    storage
+        .push(TestJob::new("malformed: fix me"))
+        .await
+        .expect("Failed to push job");
+    storage
+        .push(TestJob::new("malformed: leave me"))
+        .await
+        .expect("Failed to push job");
+
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let worker = WorkerBuilder::new("requeue-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(validate_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(processed.lock().await.is_empty());
+
+    let requeued = storage
+        .requeue_dlq_edited(|job| {
+            if job.message == "malformed: fix me" {
+                job.message = "fixed".to_string();
+                true
+            } else {
+                false
+            }
+        })
+        .await
+        .expect("Failed to requeue DLQ jobs");
+    assert_eq!(requeued, 1);
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(*processed.lock().await, vec!["fixed".to_string()]);
+
+    // The job that wasn't edited stays in the DLQ
+    let mut dlq = jetstream::new(client)
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.state.messages, 1);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_process_span_is_created_per_job() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    struct FieldsVisitor(String);
+
+    impl tracing::field::Visit for FieldsVisitor {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = FieldsVisitor(String::new());
+            attrs.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name().to_string(), fields.0));
+        }
+    }
+
+    // `#[tokio::test]` runs every task on this thread, so a scoped subscriber sees the worker
+    let capture = SpanCapture::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let mut task_ids = Vec::new();
+    for i in 0..2 {
+        task_ids.push(
+            storage
+                .push_with_priority(TestJob::new(format!("Span job {}", i)), Priority::High)
+                .await
+                .expect("Failed to push job"),
+        );
+    }
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        tracing::info!("handling job");
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("span-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(noop);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let spans: Vec<String> = capture
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name == "apalis_nats.process")
+        .map(|(_, fields)| fields.clone())
+        .collect();
+    assert_eq!(spans.len(), 2, "Expected one process span per job: {:?}", spans);
+    for task_id in task_ids {
+        let fields = spans
+            .iter()
+            .find(|fields| fields.contains(&format!("task_id={}", task_id)))
+            .unwrap_or_else(|| panic!("No span for task {}: {:?}", task_id, spans));
+        assert!(fields.contains("priority=high"), "{}", fields);
+        assert!(fields.contains("attempt=1"), "{}", fields);
+    }
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_long_poll_reduces_idle_fetches() {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("apalis=info,apalis_nats=info")
+        .try_init();
+
+    let (_container, client) = setup_nats_raw().await;
+
+    type Received = Arc<Mutex<Vec<(String, std::time::Instant)>>>;
+
+    async fn record_job(job: TestJob, received: Data<Received>) -> Result<(), Error> {
+        received.lock().await.push((job.id, std::time::Instant::now()));
+        Ok(())
+    }
+
+    const IDLE: Duration = Duration::from_secs(3);
+
+    // The pull requests an idle worker sends in `IDLE`, and the slowest pickup of Low jobs
+    // pushed to it afterwards
+    async fn idle_pulls(client: async_nats::Client, config: Config) -> (usize, Duration) {
+        let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+            .await
+            .expect("Failed to create storage");
+        storage.create_consumers().await.expect("Failed to create consumers");
+
+        // Pull requests are plain messages, so a subscriber sees a copy of each
+        let mut pulls = client
+            .subscribe("$JS.API.CONSUMER.MSG.NEXT.>")
+            .await
+            .expect("Failed to subscribe");
+        client.flush().await.expect("Failed to flush");
+
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let worker = WorkerBuilder::new(format!("{:?}-worker", config.poll_mode))
+            .concurrency(1)
+            .data(received.clone())
+            .backend(storage.clone())
+            .build_fn(record_job);
+        let handle = tokio::spawn(async move {
+            worker.run().await;
+        });
+
+        let mut sent = 0;
+        let deadline = tokio::time::Instant::now() + IDLE;
+        while let Ok(Some(pull)) = tokio::time::timeout_at(deadline, pulls.next()).await {
+            if pull.subject.contains(config.namespace.as_str()) {
+                sent += 1;
+            }
+        }
+
+        let mut pushed = Vec::new();
+        for i in 0..4 {
+            let job = TestJob::new(format!("pickup {}", i));
+            pushed.push((job.id.clone(), std::time::Instant::now()));
+            storage
+                .push_with_priority(job, Priority::Low)
+                .await
+                .expect("Failed to push job");
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        handle.abort();
+        let _ = handle.await;
+
+        let received = received.lock().await;
+        assert_eq!(received.len(), pushed.len(), "all jobs should be processed");
+        let slowest = pushed
+            .iter()
+            .map(|(id, at)| {
+                let (_, got) = received.iter().find(|(got_id, _)| got_id == id).unwrap();
+                got.duration_since(*at)
+            })
+            .max()
+            .unwrap_or_default();
+        (sent, slowest)
+    }
+
+
+    let mut config = test_config();
+    let (busy_loop, _) = idle_pulls(client.clone(), config).await;
+
+    let mut config = test_config();
+    config.poll_mode = PollMode::LongPoll;
+    let long_poll_expiry = config.long_poll_expiry;
+    let (long_poll, slowest) = idle_pulls(client.clone(), config).await;
+
+    // A long-polling round is one pull per priority and lasts at least `long_poll_expiry`,
+    // as the Low pull waits it out on the server
+    let rounds = (IDLE.as_secs_f64() / long_poll_expiry.as_secs_f64()).ceil() as usize + 1;
+    assert!(
+        long_poll <= 3 * rounds,
+        "an idle long-polling worker sent {} pulls in {:?}, at most {} expected",
+        long_poll,
+        IDLE,
+        3 * rounds
+    );
+    // Busy looping sends a round every 100ms or so
+    assert!(
+        busy_loop > 3 * rounds,
+        "an idle busy-looping worker sent only {} pulls in {:?}",
+        busy_loop,
+        IDLE
+    );
+    // The waiting Low pull hands a job over as soon as it is pushed, rather than at its expiry
+    assert!(
+        slowest < long_poll_expiry,
+        "a long-polling worker took {:?} to pick up a job",
+        slowest
+    );
+}
+
+#[tokio::test]
+async fn test_handler_moves_job_to_dlq() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    async fn poison_job(
+        _job: TestJob,
+        ctx: NatsContext,
+        calls: Data<Arc<AtomicUsize>>,
+    ) -> Result<(), Error> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        ctx.to_dlq("poison", Some(serde_json::json!({ "field": "message" })))
+            .await?;
+        Ok(())
+    }
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("Poison job"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("to-dlq-worker")
+        .concurrency(1)
+        .data(calls.clone())
+        .backend(storage.clone())
+        .build_fn(poison_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "poison");
+    assert_eq!(entry.original_task_id, task_id.to_string());
+    assert_eq!(entry.delivered_count, 1);
+    assert_eq!(entry.metadata, Some(serde_json::json!({ "field": "message" })));
+    assert_eq!(entry.job::<TestJob>().unwrap().message, "Poison job");
+
+    let dlq_info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(dlq_info.state.messages, 1, "The job should be dead-lettered once");
+    assert_eq!(calls.load(Ordering::SeqCst), 1, "The job should not be retried");
+    assert_eq!(
+        storage.query_status(&task_id).await.unwrap(),
+        JobStatus::Dead,
+        "Returning Ok after to_dlq should not mark the job completed"
+    );
+
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Medium stream should exist");
+    let info = medium.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 0, "The original message should be acked");
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_push_with_stale_expected_seq_is_rejected() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Nothing has been published on the subject yet
+    let first = storage
+        .push_with_expected_seq(TestJob::new("first"), Priority::High, 0)
+        .await
+        .expect("Publishing onto an empty subject should succeed");
+
+    // A second producer still believing the subject is empty loses the race
+    let stale = storage
+        .push_with_expected_seq(TestJob::new("duplicate"), Priority::High, 0)
+        .await;
+    assert!(
+        matches!(stale, Err(NatsPollError::PreconditionFailed(_))),
+        "Expected PreconditionFailed, got {:?}",
+        stale
+    );
+
+    // Other priorities have their own subject and sequence
+    storage
+        .push_with_expected_seq(TestJob::new("other subject"), Priority::Low, 0)
+        .await
+        .expect("Other subjects should be unaffected");
+
+    // Publishing against the current sequence succeeds
+    storage
+        .push_with_expected_seq(TestJob::new("second"), Priority::High, 1)
+        .await
+        .expect("Publishing with the current sequence should succeed");
+
+    let jetstream = jetstream::new(client);
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let info = high.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2, "The stale publish should not be stored");
+    assert_eq!(storage.query_status(&first).await.unwrap(), JobStatus::Pending);
+}
+
+#[tokio::test]
+async fn test_processing_resumes_after_server_restart() {
+    use testcontainers::core::IntoContainerPort;
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("apalis=info,apalis_nats=info")
+        .try_init();
+
+    // Pin the host port so the client can reconnect to the restarted container
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .expect("Failed to find a free port")
+        .local_addr()
+        .unwrap()
+        .port();
+    let container = Nats::default()
+        .with_cmd(["-js"])
+        .with_mapped_port(port, 4222.tcp())
+        .start()
+        .await
+        .expect("Failed to start NATS container");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let client = apalis_nats::connect(format!("nats://127.0.0.1:{}", port))
+        .await
+        .expect("Failed to connect to NATS");
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("restart-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    storage
+        .push(TestJob::new("before restart"))
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+
+    container.stop().await.expect("Failed to stop NATS");
+    // Long enough for several failed fetch rounds and a recovery attempt
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    container.start().await.expect("Failed to restart NATS");
+
+    // The client reconnects in the background, retry until the push goes through
+    let mut pushed = false;
+    for _ in 0..30 {
+        if storage.push(TestJob::new("after restart")).await.is_ok() {
+            pushed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert!(pushed, "Failed to push after the restart");
+
+    let mut resumed = false;
+    for _ in 0..30 {
+        if processed.load(Ordering::SeqCst) >= 2 {
+            resumed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    assert!(resumed, "Processing should resume after NATS restarts");
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_redactor_keeps_payload_out_of_dlq() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_redactor(|payload| format!("<redacted {} bytes>", payload.len()));
+
+    async fn reject_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "card declined",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    storage
+        .push_with_priority(TestJob::new("card 4111-1111-1111-1111"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("redacting-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(reject_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the rejected job");
+    assert!(
+        !String::from_utf8_lossy(&msg.payload).contains("4111"),
+        "The DLQ message should not contain the original payload"
+    );
+
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    let payload = String::from_utf8(entry.payload.clone()).unwrap();
+    assert!(
+        payload.starts_with("<redacted ") && payload.ends_with(" bytes>"),
+        "Expected the redacted form, got {}",
+        payload
+    );
+    assert!(entry.job::<TestJob>().is_err());
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_jetstream_domain_is_used_for_stream_setup() {
+    // The test server has no JetStream domain, so a domain that doesn't exist must not
+    // silently fall back to the default one
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.jetstream_domain = Some("missing".to_string());
+
+    let result = tokio::time::timeout(
+        Duration::from_secs(15),
+        NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone()),
+    )
+    .await
+    .expect("Storage setup should fail rather than hang");
+    assert!(
+        result.is_err(),
+        "Streams should not be found through an unknown domain"
+    );
+
+    let jetstream = jetstream::new(client.clone());
+    assert!(
+        jetstream
+            .get_stream(format!("{}_high", config.namespace))
+            .await
+            .is_err(),
+        "No streams should be created outside the configured domain"
+    );
+
+    // Without a domain the same namespace is set up in the server's own domain
+    config.jetstream_domain = None;
+    NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage without a domain");
+}
+
+#[tokio::test]
+async fn test_prefetch_buffers_up_to_its_limit() {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("apalis=info,apalis_nats=info")
+        .try_init();
+
+    let (_container, client) = setup_nats_raw().await;
+
+    const JOBS: usize = 100;
+    const CONCURRENCY: usize = 4;
+
+    // Never finishes, so every job a worker holds stays pending on the consumer
+    async fn stuck_job(_job: TestJob, started: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        started.fetch_add(1, Ordering::SeqCst);
+        std::future::pending::<()>().await;
+        Ok(())
+    }
+
+    // The jobs one busy worker holds on to over a backlog of JOBS jobs: the most delivered and
+    // unacked at once, and how many of them it started
+    async fn held_jobs(
+        client: async_nats::Client,
+        prefetch: usize,
+        max_ack_pending: i64,
+    ) -> (u64, usize) {
+        let mut config = test_config();
+        config.prefetch = prefetch;
+        config.max_ack_pending = max_ack_pending;
+
+        let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+            .await
+            .expect("Failed to create storage");
+        for i in 0..JOBS {
+            storage
+                .push_with_priority(TestJob::new(format!("job {}", i)), Priority::Medium)
+                .await
+                .expect("Failed to push job");
+        }
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let worker = WorkerBuilder::new(format!("prefetch-{}-worker", prefetch))
+            .concurrency(CONCURRENCY)
+            .data(started.clone())
+            .backend(storage.clone())
+            .build_fn(stuck_job);
+        let handle = tokio::spawn(async move {
+            worker.run().await;
+        });
+
+        let mut most = 0;
+        for _ in 0..60 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            if let Ok(info) = storage.consumer_info(Priority::Medium).await {
+                most = most.max(info.num_ack_pending as u64);
+            }
+        }
+        handle.abort();
+        let _ = handle.await;
+        (most, started.load(Ordering::SeqCst))
+    }
+
+    // Without prefetch a worker only fetches for a free slot
+    let (held, started) = held_jobs(client.clone(), 0, 100).await;
+    assert_eq!(started, CONCURRENCY);
+    assert_eq!(held, CONCURRENCY as u64, "nothing should be fetched ahead");
+
+    // With it, it fills a buffer of `prefetch` jobs on top of the running ones, and no more
+    let (held, started) = held_jobs(client.clone(), 16, 100).await;
+    assert_eq!(started, CONCURRENCY);
+    assert_eq!(held, (CONCURRENCY + 16) as u64, "the buffer should fill up to prefetch");
+
+    // The buffer is capped at half of max_ack_pending, leaving room for other workers
+    let (held, started) = held_jobs(client.clone(), 80, 20).await;
+    assert_eq!(started, CONCURRENCY);
+    assert_eq!(held, (CONCURRENCY + 10) as u64, "the buffer should stop at max_ack_pending / 2");
+}
+
+#[tokio::test]
+async fn test_spent_retry_budget_skips_retries() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 5;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+    // A single retry that never comes back
+    config.retry_budget = Some(RetryBudget::new(1, 0.0));
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    async fn failing_job(_job: TestJob, attempts: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "downstream unavailable",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("outage job {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("budget-worker")
+        .concurrency(1)
+        .data(attempts.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // The first failure spends the budget on one retry, every failure after that is final
+    assert_eq!(
+        attempts.load(Ordering::SeqCst),
+        3,
+        "Without a budget both jobs would be attempted max_deliver times"
+    );
+
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.state.messages, 2, "Both jobs should be dead-lettered");
+    for sequence in 1..=2 {
+        let msg = dlq
+            .get_raw_message(sequence)
+            .await
+            .expect("Failed to read DLQ entry");
+        let entry: DlqEntry =
+            serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+        assert_eq!(entry.dlq_reason, "retry_budget_exhausted");
+    }
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_handler_sees_job_created_at_and_priority() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    storage
+        .push_with_priority(TestJob::new("SLA job"), Priority::Low)
+        .await
+        .expect("Failed to push job");
+
+    // Read the created_at the job was pushed with before a worker takes it
+    let jetstream = jetstream::new(client);
+    let stream = jetstream
+        .get_stream(format!("{}_low", config.namespace))
+        .await
+        .expect("Low stream should exist");
+    let msg = stream
+        .get_raw_message(1)
+        .await
+        .expect("Job should be in the stream");
+    let envelope: serde_json::Value =
+        serde_json::from_slice(&msg.payload).expect("Failed to parse job envelope");
+    let pushed_at: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(envelope["created_at"].clone()).expect("Missing created_at");
+
+    type Seen = Arc<Mutex<Vec<(Option<chrono::DateTime<chrono::Utc>>, Option<Priority>)>>>;
+    let seen: Seen = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_metadata(
+        _job: TestJob,
+        ctx: NatsContext,
+        seen: Data<Seen>,
+    ) -> Result<(), Error> {
+        seen.lock().await.push((ctx.created_at(), ctx.priority()));
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("metadata-reader")
+        .concurrency(1)
+        .data(seen.clone())
+        .backend(storage.clone())
+        .build_fn(record_metadata);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let seen = seen.lock().await;
+    assert_eq!(seen.len(), 1, "The job should be processed once");
+    assert_eq!(seen[0], (Some(pushed_at), Some(Priority::Low)));
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_slow_dlq_publish_does_not_delay_other_acks() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Make DLQ publishes hang: without the stream, a plain subscriber that never replies
+    // leaves the publish waiting for its ack until the JetStream timeout
+    let jetstream = jetstream::new(client.clone());
+    jetstream
+        .delete_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("Failed to delete DLQ stream");
+    let _black_hole = client
+        .subscribe(format!("{}.dlq", config.namespace))
+        .await
+        .expect("Failed to subscribe");
+
+    async fn mixed_job(job: TestJob) -> Result<(), Error> {
+        if job.message == "poison" {
+            return Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "poison",
+            ))
+                as Box<dyn std::error::Error + Send + Sync>)));
+        }
+        // Finish after the poison job's DLQ publish has started
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Ok(())
+    }
+
+    storage
+        .push_with_priority(TestJob::new("poison"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    let mut task_ids = Vec::new();
+    for i in 0..4 {
+        let task_id = storage
+            .push_with_priority(TestJob::new(format!("ok {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+        task_ids.push(task_id);
+    }
+
+    let worker = WorkerBuilder::new("ack-concurrency-worker")
+        .concurrency(4)
+        .backend(storage.clone())
+        .build_fn(mixed_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // Well before the stuck DLQ publish times out
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    for task_id in &task_ids {
+        assert_eq!(
+            storage.query_status(task_id).await.unwrap(),
+            JobStatus::Completed,
+            "Successful jobs should be acked while the DLQ publish is stuck"
+        );
+    }
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Medium stream should exist");
+    let info = medium.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 0, "Successful jobs should be removed");
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_plan_matches_created_resources() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.max_deliver = 7;
+    config.max_ack_pending = 42;
+
+    let plan = NatsStorage::<TestJob>::plan(&config).expect("Failed to plan");
+
+    // Planning must not touch the server
+    let jetstream = jetstream::new(client.clone());
+    let existing: Vec<String> = jetstream.stream_names().try_collect().await.unwrap();
+    assert!(existing.iter().all(|name| !name.contains(&config.namespace)));
+
+    // Consumers are created once a worker polls
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+    let worker = WorkerBuilder::new("plan-worker")
+        .concurrency(1)
+        .backend(storage)
+        .build_fn(noop);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let (mut streams, mut buckets, mut consumers) = (0, 0, 0);
+    for resource in plan {
+        match resource {
+            PlannedResource::Stream(planned) => {
+                streams += 1;
+                let mut stream = jetstream
+                    .get_stream(&planned.name)
+                    .await
+                    .expect("Planned stream should exist");
+                let actual = stream.info().await.unwrap().config.clone();
+                assert_eq!(actual.subjects, planned.subjects);
+                assert_eq!(actual.retention, planned.retention);
+                assert_eq!(actual.storage, planned.storage);
+                assert_eq!(actual.max_age, planned.max_age);
+                assert_eq!(actual.num_replicas, planned.num_replicas);
+                assert_eq!(actual.discard, planned.discard);
+            }
+            PlannedResource::KeyValue(planned) => {
+                buckets += 1;
+                let mut stream = jetstream
+                    .get_stream(format!("KV_{}", planned.bucket))
+                    .await
+                    .expect("Planned bucket should exist");
+                let actual = stream.info().await.unwrap().config.clone();
+                assert_eq!(actual.max_age, planned.max_age);
+                assert_eq!(actual.max_messages_per_subject, planned.history);
+                assert_eq!(actual.num_replicas, planned.num_replicas);
+            }
+            PlannedResource::Consumer { stream, config } => {
+                consumers += 1;
+                let name = config.durable_name.clone().unwrap();
+                let mut consumer = jetstream
+                    .get_stream(&stream)
+                    .await
+                    .expect("Consumer stream should exist")
+                    .get_consumer::<consumer::pull::Config>(&name)
+                    .await
+                    .expect("Planned consumer should exist");
+                let actual = consumer.info().await.unwrap().config.clone();
+                assert_eq!(actual.filter_subject, config.filter_subject);
+                assert_eq!(actual.ack_wait, config.ack_wait);
+                assert_eq!(actual.max_deliver, 7);
+                assert_eq!(actual.max_deliver, config.max_deliver);
+                assert_eq!(actual.max_ack_pending, config.max_ack_pending);
+                assert_eq!(actual.max_waiting, config.max_waiting);
+            }
+        }
+    }
+    assert_eq!((streams, buckets, consumers), (4, 1, 3));
+}
+
+#[tokio::test]
+async fn test_manual_ack_is_not_doubled() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.manual_ack = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Watch the acks workers send for the medium stream
+    let mut acks = client
+        .subscribe(format!("$JS.ACK.{}_medium.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to acks");
+
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    async fn commit_then_ack(
+        _job: TestJob,
+        ctx: NatsContext,
+        calls: Data<Arc<AtomicUsize>>,
+    ) -> Result<(), Error> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        // e.g. an external commit happens here
+        ctx.ack().await
+    }
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("manual"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("manual-ack-worker")
+        .concurrency(1)
+        .data(calls.clone())
+        .backend(storage.clone())
+        .build_fn(commit_then_ack);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut ack_count = 0;
+    while let Ok(Some(ack)) = tokio::time::timeout(Duration::from_millis(200), acks.next()).await {
+        assert_eq!(&ack.payload[..], b"+ACK", "Only a positive ack is expected");
+        ack_count += 1;
+    }
+    assert_eq!(ack_count, 1, "The message should be acked exactly once");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(storage.query_status(&task_id).await.unwrap(), JobStatus::Completed);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_registry_sets_up_namespace_once() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct OtherJob {
+        n: u32,
+    }
+
+    // Every stream lookup or creation for the namespace goes through the JetStream API
+    let mut stream_requests = client
+        .subscribe(format!("$JS.API.STREAM.*.{}_high", config.namespace))
+        .await
+        .expect("Failed to subscribe to the JetStream API");
+
+    let registry = NatsStorageRegistry::new(client.clone());
+    let jobs = registry
+        .storage::<TestJob>(config.clone())
+        .await
+        .expect("Failed to create storage");
+    let others = registry
+        .storage::<OtherJob>(config.clone())
+        .await
+        .expect("Failed to create second storage");
+
+    let mut requests = Vec::new();
+    while let Ok(Some(msg)) =
+        tokio::time::timeout(Duration::from_millis(500), stream_requests.next()).await
+    {
+        requests.push(msg.subject.to_string());
+    }
+    assert_eq!(
+        requests,
+        vec![
+            format!("$JS.API.STREAM.INFO.{}_high", config.namespace),
+            format!("$JS.API.STREAM.CREATE.{}_high", config.namespace),
+        ],
+        "The stream should be looked up and created by the first storage only"
+    );
+
+    // Both storages work against the shared streams
+    jobs.push_with_priority(TestJob::new("typed"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    others
+        .push_with_priority(OtherJob { n: 1 }, Priority::High)
+        .await
+        .expect("Failed to push other job");
+    let jetstream = jetstream::new(client);
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    assert_eq!(high.info().await.unwrap().state.messages, 2);
+}
+
+#[tokio::test]
+async fn test_ack_policy_is_set_on_consumers() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_policy = AckPolicy::All;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    for i in 0..3 {
+        storage
+            .push(TestJob::new(format!("ack all {}", i)))
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("ack-all-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 3);
+
+    let jetstream = jetstream::new(client);
+    for priority in ["high", "medium", "low"] {
+        let mut consumer = jetstream
+            .get_stream(format!("{}_{}", config.namespace, priority))
+            .await
+            .expect("Stream should exist")
+            .get_consumer::<consumer::pull::Config>(&format!(
+                "{}_{}_consumer",
+                config.namespace, priority
+            ))
+            .await
+            .expect("Consumer should exist");
+        let info = consumer.info().await.expect("Failed to get consumer info");
+        assert_eq!(info.config.ack_policy, consumer::AckPolicy::All);
+    }
+
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .unwrap();
+    assert_eq!(medium.info().await.unwrap().state.messages, 0);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_retry_stats_count_failed_attempts() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 3;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    assert!(storage.retry_stats().is_empty());
+
+    async fn failing_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "always fails",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("retried job {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("retry-stats-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // Both jobs are retried after their first and second attempts, the third one is final
+    let stats = storage.retry_stats();
+    assert_eq!(stats.get(&1), Some(&2));
+    assert_eq!(stats.get(&2), Some(&2));
+    assert_eq!(stats.len(), 2, "Final attempts are not retries: {:?}", stats);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_pause_stops_fetching_until_resumed() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("pause-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    storage
+        .push(TestJob::new("before pause".to_string()))
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+
+    storage.pause();
+    assert!(storage.is_paused());
+    // Let the round in progress finish
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    for i in 0..3 {
+        storage
+            .push(TestJob::new(format!("while paused {}", i)))
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(
+        processed.load(Ordering::SeqCst),
+        1,
+        "No jobs should be fetched while paused"
+    );
+
+    let jetstream = jetstream::new(client);
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .unwrap();
+    assert_eq!(medium.info().await.unwrap().state.messages, 3);
+
+    storage.resume();
+    assert!(!storage.is_paused());
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 4);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_strict_priority_with_concurrency() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.strict_priority = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let started = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn slow_job(job: TestJob, started: Data<Arc<Mutex<Vec<String>>>>) -> Result<(), Error> {
+        started.lock().await.push(job.message.clone());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        Ok(())
+    }
+
+    for i in 1..=8 {
+        storage
+            .push_with_priority(TestJob::new(format!("Low priority {}", i)), Priority::Low)
+            .await
+            .expect("Failed to push low priority job");
+    }
+
+    let worker = WorkerBuilder::new("strict-priority-worker")
+        .concurrency(4)
+        .data(started.clone())
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // Let the worker fill its slots with low priority jobs
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(started.lock().await.len(), 4);
+
+    // Without strict priority the next low jobs would already be buffered ahead of these
+    for i in 1..=3 {
+        storage
+            .push_with_priority(TestJob::new(format!("High priority {}", i)), Priority::High)
+            .await
+            .expect("Failed to push high priority job");
+    }
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    let started = started.lock().await;
+    assert_eq!(started.len(), 11, "All jobs should run: {:?}", *started);
+    for (i, message) in started.iter().enumerate() {
+        let expected = if (4..7).contains(&i) { "High" } else { "Low" };
+        assert!(
+            message.starts_with(expected),
+            "Job {} should be {} priority, start order: {:?}",
+            i,
+            expected,
+            *started
+        );
+    }
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_push_with_id_reports_duplicates() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let first = storage
+        .push_with_id(TestJob::new("invoice 42"), Priority::High, "invoice-42")
+        .await
+        .expect("Failed to push job");
+    assert!(!first.duplicate);
+    assert_eq!(first.sequence, 1);
+
+    let second = storage
+        .push_with_id(TestJob::new("invoice 42"), Priority::High, "invoice-42")
+        .await
+        .expect("A duplicate push is not an error");
+    assert!(second.duplicate, "The second push should hit the dedup window");
+    assert_eq!(second.sequence, first.sequence);
+    assert_ne!(second.task_id, first.task_id);
+
+    let other = storage
+        .push_with_id(TestJob::new("invoice 43"), Priority::High, "invoice-43")
+        .await
+        .expect("Failed to push job");
+    assert!(!other.duplicate);
+    assert_eq!(other.sequence, 2);
+
+    let jetstream = jetstream::new(client);
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let info = high.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2, "The duplicate should not be stored");
+
+    // Only the stored job shows up as pending
+    assert_eq!(
+        storage.query_status(&first.task_id).await.unwrap(),
+        JobStatus::Pending
+    );
+    assert!(storage.query_status(&second.task_id).await.is_err());
+}
+
+#[tokio::test]
+async fn test_namespaces_share_custom_dlq() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let suffix = uuid::Uuid::new_v4().to_string().replace("-", "_");
+    let dlq_stream = format!("central_dlq_{}", suffix);
+    let dlq_subject = format!("central.dlq.{}", suffix);
+
+    async fn aborting_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "poison",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let mut namespaces = Vec::new();
+    let mut handles = Vec::new();
+    for service in ["orders", "billing"] {
+        let mut config = Config::default();
+        config.namespace = format!("test_{}_{}", service, suffix);
+        config.dlq_stream = Some(dlq_stream.clone());
+        config.dlq_subject = Some(dlq_subject.clone());
+
+        let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+            .await
+            .expect("Failed to create storage");
+        storage
+            .push_with_priority(TestJob::new(format!("{} job", service)), Priority::High)
+            .await
+            .expect("Failed to push job");
+
+        let worker = WorkerBuilder::new(format!("{}-worker", service))
+            .concurrency(1)
+            .backend(storage)
+            .build_fn(aborting_job);
+        handles.push(tokio::spawn(async move {
+            worker.run().await;
+        }));
+        namespaces.push(config.namespace);
+    }
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let jetstream = jetstream::new(client);
+    for namespace in &namespaces {
+        assert!(
+            jetstream.get_stream(format!("{}_dlq", namespace)).await.is_err(),
+            "No per-namespace DLQ should be created for {}",
+            namespace
+        );
+    }
+
+    let mut dlq = jetstream
+        .get_stream(&dlq_stream)
+        .await
+        .expect("Shared DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.state.messages, 2, "Both failures should land in the shared DLQ");
+
+    let mut origins = Vec::new();
+    for sequence in 1..=2 {
+        let msg = dlq
+            .get_raw_message(sequence)
+            .await
+            .expect("Failed to read DLQ entry");
+        assert_eq!(msg.subject.as_str(), dlq_subject);
+        let entry: DlqEntry =
+            serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+        let job: TestJob = entry.job().expect("Failed to decode job");
+        let origin = entry.namespace.expect("Entry should record its namespace");
+        assert!(origin.starts_with(&format!("test_{}", job.message.trim_end_matches(" job"))));
+        origins.push(origin);
+    }
+    origins.sort();
+    namespaces.sort();
+    assert_eq!(origins, namespaces);
+
+    for handle in handles {
+        handle.abort();
+        let _ = handle.await;
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_backlog_is_bounded_by_concurrency() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn slow_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        Ok(())
+    }
+
+    for i in 0..20 {
+        storage
+            .push_with_priority(TestJob::new(format!("slow job {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("backpressure-worker")
+        .concurrency(2)
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Only the two running jobs are delivered, the rest stay in the stream
+    let jetstream = jetstream::new(client);
+    let mut consumer = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Stream should exist")
+        .get_consumer::<consumer::pull::Config>(&format!("{}_medium_consumer", config.namespace))
+        .await
+        .expect("Consumer should exist");
+    let info = consumer.info().await.expect("Failed to get consumer info");
+    assert_eq!(
+        info.num_ack_pending, 2,
+        "Fetched jobs should be bounded by the concurrency"
+    );
+    assert_eq!(info.num_pending, 18);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_missing_stream_and_consumer_errors_are_typed() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // No worker has polled yet, so the shared consumer doesn't exist
+    match storage.consumer_info(Priority::High).await {
+        Err(NatsPollError::ConsumerNotFound(name)) => {
+            assert_eq!(name, format!("{}_high_consumer", config.namespace))
+        }
+        other => panic!("Expected ConsumerNotFound, got {:?}", other),
+    }
+
+    let jetstream = jetstream::new(client);
+    jetstream
+        .delete_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Failed to delete stream");
+
+    match storage.consumer_info(Priority::Medium).await {
+        Err(NatsPollError::StreamNotFound(name)) => {
+            assert_eq!(name, format!("{}_medium", config.namespace))
+        }
+        other => panic!("Expected StreamNotFound, got {:?}", other),
+    }
+    match storage.set_replicas(1).await {
+        Err(NatsPollError::StreamNotFound(name)) => {
+            assert_eq!(name, format!("{}_medium", config.namespace))
+        }
+        other => panic!("Expected StreamNotFound, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_scheduled_jobs_are_listed_by_due_time() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+
+    let now = chrono::Utc::now();
+    let later = scheduled
+        .schedule(TestJob::new("in two hours"), now + chrono::Duration::hours(2))
+        .await
+        .expect("Failed to schedule job");
+    let sooner = scheduled
+        .schedule_with_priority(
+            TestJob::new("in one hour"),
+            Priority::High,
+            now + chrono::Duration::hours(1),
+        )
+        .await
+        .expect("Failed to schedule job");
+
+    let pending = scheduled.list_pending().await.expect("Failed to list");
+    let ids: Vec<_> = pending.iter().map(|job| job.task_id.clone()).collect();
+    assert_eq!(ids, vec![sooner.clone(), later]);
+    assert_eq!(pending[0].priority, Priority::High);
+    assert_eq!(pending[0].job.message, "in one hour");
+    assert!(!pending[0].promoting);
+
+    assert!(scheduled.list_due(now).await.unwrap().is_empty());
+    let due = scheduled
+        .list_due(now + chrono::Duration::minutes(90))
+        .await
+        .unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].task_id, sooner);
+}
+
+#[tokio::test]
+async fn test_cancelled_scheduled_job_never_runs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let task_id = scheduled
+        .schedule(
+            TestJob::new("cancelled"),
+            chrono::Utc::now() + chrono::Duration::seconds(2),
+        )
+        .await
+        .expect("Failed to schedule job");
+
+    let worker = WorkerBuilder::new("cancel-scheduled-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    assert!(scheduled.cancel(&task_id).await.expect("Failed to cancel"));
+    assert!(
+        !scheduled.cancel(&task_id).await.expect("Failed to cancel"),
+        "A cancelled job can't be cancelled again"
+    );
+    assert!(scheduled.list_pending().await.unwrap().is_empty());
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert_eq!(processed.load(Ordering::SeqCst), 0);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_due_scheduled_job_is_enqueued() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        _job: TestJob,
+        task_id: TaskId,
+        seen: Data<Arc<Mutex<Vec<TaskId>>>>,
+    ) -> Result<(), Error> {
+        seen.lock().await.push(task_id);
+        Ok(())
+    }
+
+    let task_id = scheduled
+        .schedule_with_priority(
+            TestJob::new("due soon"),
+            Priority::High,
+            chrono::Utc::now() + chrono::Duration::seconds(2),
+        )
+        .await
+        .expect("Failed to schedule job");
+
+    let worker = WorkerBuilder::new("scheduled-worker")
+        .concurrency(1)
+        .data(seen.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(seen.lock().await.is_empty(), "The job isn't due yet");
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(*seen.lock().await, vec![task_id.clone()]);
+    assert!(scheduled.list_pending().await.unwrap().is_empty());
+    assert!(
+        !scheduled.cancel(&task_id).await.unwrap(),
+        "An enqueued job can't be cancelled"
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_repromoted_scheduled_job_keeps_its_status() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, runs: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let task_id = scheduled
+        .schedule(TestJob::new("promoted twice"), chrono::Utc::now())
+        .await
+        .expect("Failed to schedule job");
+    let bucket = jetstream::new(client.clone())
+        .get_key_value(format!("{}_scheduled", config.namespace))
+        .await
+        .expect("Scheduled bucket should exist");
+    let entry = bucket
+        .get(task_id.to_string())
+        .await
+        .expect("Failed to read scheduled job")
+        .expect("The job should be scheduled");
+
+    let worker = WorkerBuilder::new("repromoted-worker")
+        .concurrency(1)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        storage.query_status(&task_id).await.expect("Failed to query status"),
+        JobStatus::Completed
+    );
+
+    // As if the promoter died after publishing the job but before deleting its entry
+    bucket
+        .put(task_id.to_string(), entry)
+        .await
+        .expect("Failed to restore scheduled job");
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    // The second publish was deduplicated, leaving the finished job as it was
+    assert!(scheduled.list_pending().await.unwrap().is_empty());
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        storage.query_status(&task_id).await.expect("Failed to query status"),
+        JobStatus::Completed
+    );
+}
+
+#[tokio::test]
+async fn test_priority_limit_caps_low_in_flight() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    // Deferrals are redeliveries, leave room for them
+    config.max_deliver = 50;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..6 {
+        storage
+            .push_with_priority(TestJob::new(format!("low {}", i)), Priority::Low)
+            .await
+            .expect("Failed to push job");
+    }
+
+    #[derive(Default)]
+    struct InFlight {
+        current: AtomicUsize,
+        max: AtomicUsize,
+        done: AtomicUsize,
+    }
+
+    async fn slow_job(_job: TestJob, in_flight: Data<Arc<InFlight>>) -> Result<(), Error> {
+        let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+        in_flight.max.fetch_max(current, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        in_flight.current.fetch_sub(1, Ordering::SeqCst);
+        in_flight.done.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let in_flight = Arc::new(InFlight::default());
+    let worker = WorkerBuilder::new("priority-limit-worker")
+        .concurrency(6)
+        .layer(
+            PriorityLimitLayer::new(HashMap::from([(Priority::Low, 2)]))
+                .delay(Duration::from_millis(200)),
+        )
+        .data(in_flight.clone())
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(8)).await;
+
+    assert_eq!(in_flight.done.load(Ordering::SeqCst), 6);
+    let max = in_flight.max.load(Ordering::SeqCst);
+    assert!(max <= 2, "{} low priority jobs ran at once", max);
+    assert_eq!(max, 2, "The limit should still allow two at once");
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_dedup_by_payload_hash_drops_identical_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.dedup_by_payload_hash = true;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let job = TestJob::new("same payload");
+    storage
+        .push_with_priority(job.clone(), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push_with_priority(job.clone(), Priority::Medium)
+        .await
+        .expect("A duplicate push isn't an error");
+    assert_eq!(storage.len().await.expect("Failed to get length"), 1);
+
+    storage
+        .push_with_priority(TestJob::new("other payload"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    assert_eq!(storage.len().await.expect("Failed to get length"), 2);
+
+    // Without the option nothing is deduplicated
+    let mut config = config.clone();
+    config.namespace = format!("{}_plain", config.namespace);
+    config.dedup_by_payload_hash = false;
+    let mut plain = NatsStorage::<TestJob>::new_with_config(client.clone(), config)
+        .await
+        .expect("Failed to create storage");
+    plain.push(job.clone()).await.expect("Failed to push job");
+    plain.push(job).await.expect("Failed to push job");
+    assert_eq!(plain.len().await.expect("Failed to get length"), 2);
+}
+
+#[tokio::test]
+async fn test_extend_lease_delays_redelivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(2);
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push(TestJob::new("Long job"))
+        .await
+        .expect("Failed to push job");
+
+    let deliveries = Arc::new(AtomicUsize::new(0));
+    let leases = Arc::new(Mutex::new(Vec::new()));
+
+    async fn long_job(
+        _job: TestJob,
+        ctx: NatsContext,
+        deliveries: Data<Arc<AtomicUsize>>,
+        leases: Data<Arc<Mutex<Vec<Duration>>>>,
+    ) -> Result<(), Error> {
+        deliveries.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let before = ctx.lease_remaining().expect("A delivered job has a lease");
+        let deadline = ctx
+            .extend_lease(Duration::from_secs(1))
+            .await?
+            .expect("A delivered job has a lease");
+        let after = ctx.lease_remaining().expect("A delivered job has a lease");
+        leases.lock().await.extend([before, after]);
+        assert!(deadline > std::time::Instant::now() + Duration::from_millis(1500));
+        // Runs past the original ack_wait, but within the extended one
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("lease-worker")
+        .concurrency(2)
+        .data(deliveries.clone())
+        .data(leases.clone())
+        .backend(storage.clone())
+        .build_fn(long_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(6)).await;
+
+    assert_eq!(
+        deliveries.load(Ordering::SeqCst),
+        1,
+        "The extended job should not be redelivered"
+    );
+    let leases = leases.lock().await.clone();
+    assert_eq!(leases.len(), 2);
+    assert!(leases[0] <= Duration::from_millis(500), "{:?}", leases[0]);
+    assert!(leases[1] > Duration::from_millis(1900), "{:?}", leases[1]);
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_for_priorities_ignores_other_streams() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let high_only = storage.clone().for_priorities(&[Priority::High]);
+    assert_eq!(high_only.priorities(), &[Priority::High]);
+
+    storage
+        .push_with_priority(TestJob::new("low 1"), Priority::Low)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push_with_priority(TestJob::new("low 2"), Priority::Low)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push_with_priority(TestJob::new("high"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let executed = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        job: TestJob,
+        executed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        executed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("high-only-worker")
+        .data(executed.clone())
+        .backend(high_only)
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(*executed.lock().await, vec!["high".to_string()]);
+
+    // The Low jobs are still waiting for a worker that consumes them
+    let jetstream = jetstream::new(client);
+    let mut low = jetstream
+        .get_stream(format!("{}_low", config.namespace))
+        .await
+        .expect("Low stream should exist");
+    let info = low.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_in_flight_counts_unacked_messages() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    assert_eq!(storage.in_flight(), 0);
+
+    storage
+        .push(TestJob::new("Slow job"))
+        .await
+        .expect("Failed to push job");
+
+    async fn slow_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("in-flight-worker")
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(storage.in_flight(), 1, "The slow job is running");
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(storage.in_flight(), 0, "The job was acked");
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_job_headers_are_set_and_preferred() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let task_id = storage
+        .push_with_priority(TestJob::new("With headers"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    // The envelope can be read without decoding the payload
+    let jetstream = jetstream::new(client.clone());
+    let high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let msg = high
+        .get_raw_message(1)
+        .await
+        .expect("High stream should contain the job");
+    let header = |name: &str| msg.headers.get(name).map(|value| value.as_str().to_string());
+    assert_eq!(header(TASK_ID_HEADER), Some(task_id.to_string()));
+    assert_eq!(header(NAMESPACE_HEADER), Some(config.namespace.clone()));
+    assert_eq!(header(PRIORITY_HEADER), Some("high".to_string()));
+    let created_at = chrono::DateTime::parse_from_rfc3339(&header(CREATED_AT_HEADER).unwrap())
+        .expect("created-at should be RFC 3339")
+        .with_timezone(&chrono::Utc);
+
+    // A copy whose headers were set by another tool: they win over the payload
+    let other_id = TaskId::new();
+    let other_created_at = chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let mut headers = msg.headers.clone();
+    headers.insert(TASK_ID_HEADER, other_id.to_string().as_str());
+    headers.insert(CREATED_AT_HEADER, other_created_at.to_rfc3339().as_str());
+    jetstream
+        .publish_with_headers(
+            format!("{}.high", config.namespace),
+            headers,
+            msg.payload.clone(),
+        )
+        .await
+        .expect("Failed to publish copy")
+        .await
+        .expect("Copy wasn't stored");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        _job: TestJob,
+        task_id: TaskId,
+        ctx: NatsContext,
+        seen: Data<Arc<Mutex<Vec<(TaskId, Option<Priority>, chrono::DateTime<chrono::Utc>)>>>>,
+    ) -> Result<(), Error> {
+        seen.lock()
+            .await
+            .push((task_id, ctx.priority(), ctx.created_at().unwrap()));
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("headers-worker")
+        .data(seen.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let seen = seen.lock().await.clone();
+    assert_eq!(
+        seen,
+        vec![
+            (task_id, Some(Priority::High), created_at),
+            (other_id, Some(Priority::High), other_created_at),
+        ]
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_filter_skip_acks_without_running() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_filter(|job: &TestJob| match job.message.as_str() {
+            "skip me" => FilterAction::Skip,
+            _ => FilterAction::Process,
+        });
+
+    let skipped = storage
+        .push_with_priority(TestJob::new("skip me"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push(TestJob::new("run me"))
+        .await
+        .expect("Failed to push job");
+
+    let executed = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        job: TestJob,
+        executed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        executed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("filter-worker")
+        .data(executed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    assert_eq!(*executed.lock().await, vec!["run me".to_string()]);
+    // Acked, so it won't come back
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+    assert_eq!(
+        storage.query_status(&skipped).await.expect("Failed to query status"),
+        JobStatus::Dead
+    );
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_republish_mirrors_jobs_to_audit_subject() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.republish = Some(RepublishConfig::new("audit.{namespace}.{priority}"));
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let mut subscriber = client
+        .subscribe(format!("audit.{}.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to audit subject");
+
+    let job = TestJob::new("Audited job");
+    storage
+        .push_with_priority(job.clone(), Priority::Low)
+        .await
+        .expect("Failed to push job");
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), subscriber.next())
+        .await
+        .expect("Timed out waiting for the audit copy")
+        .expect("Subscription closed");
+    assert_eq!(msg.subject.as_str(), format!("audit.{}.low", config.namespace));
+    let payload: serde_json::Value =
+        serde_json::from_slice(&msg.payload).expect("Copy should carry the job envelope");
+    assert_eq!(payload["data"]["id"], job.id);
+
+    // Consumption is unaffected
+    assert_eq!(storage.len().await.expect("Failed to get length"), 1);
+
+    // A stream has one republish setting, so it can't come from both options
+    config.subject_transform = Some((
+        format!("{}.*", config.namespace),
+        "elsewhere.{{wildcard(1)}}".to_string(),
+    ));
+    assert!(matches!(
+        NatsStorage::<TestJob>::plan(&config),
+        Err(NatsPollError::Storage(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_dlq_retry_after_is_set_for_exhausted_retries() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 1;
+    config.dlq_retry_after = Some(Duration::from_secs(3600));
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn outage_job(job: TestJob) -> Result<(), Error> {
+        let error = Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "dependency down",
+        )) as Box<dyn std::error::Error + Send + Sync>;
+        match job.message.as_str() {
+            "abort" => Err(Error::Abort(Arc::new(error))),
+            _ => Err(Error::Failed(Arc::new(error))),
+        }
+    }
+
+    storage
+        .push(TestJob::new("transient"))
+        .await
+        .expect("Failed to push job");
+    storage
+        .push(TestJob::new("abort"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("dlq-retry-after-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(outage_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let mut entries = Vec::new();
+    for sequence in 1..=2 {
+        let msg = dlq
+            .get_raw_message(sequence)
+            .await
+            .expect("DLQ should contain both jobs");
+        let entry: DlqEntry =
+            serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+        entries.push(entry);
+    }
+
+    let transient = entries
+        .iter()
+        .find(|entry| entry.dlq_reason == "max_deliver_exceeded")
+        .expect("The failing job should be dead-lettered");
+    let retry_after = transient
+        .retry_after
+        .expect("Exhausted retries should get a retry_after");
+    assert!(retry_after > chrono::Utc::now() + chrono::Duration::minutes(59));
+    assert!(retry_after <= chrono::Utc::now() + chrono::Duration::hours(1));
+
+    let aborted = entries
+        .iter()
+        .find(|entry| entry.dlq_reason == "abort_error")
+        .expect("The aborted job should be dead-lettered");
+    assert_eq!(aborted.retry_after, None);
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_run_until_drained_processes_queue_and_returns() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..5 {
+        storage
+            .push_with_priority(TestJob::new(format!("batch {}", i)), Priority::Low)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn batch_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("batch-worker")
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(batch_job);
+
+    tokio::time::timeout(
+        Duration::from_secs(15),
+        storage.run_until_drained(worker, Duration::from_secs(1)),
+    )
+    .await
+    .expect("The worker should stop once the queue is drained")
+    .expect("Failed to run worker");
+
+    assert_eq!(processed.load(Ordering::SeqCst), 5);
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+    assert_eq!(storage.in_flight(), 0);
+}
+
+#[tokio::test]
+async fn test_accept_bare_payloads_runs_unwrapped_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.accept_bare_payloads = true;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // A legacy producer publishing plain JSON next to a regular push
+    let js = jetstream::new(client);
+    let bare = TestJob::new("legacy producer");
+    js.publish(
+        format!("{}.low", config.namespace),
+        serde_json::to_vec(&bare).unwrap().into(),
+    )
+    .await
+    .expect("Failed to publish")
+    .await
+    .expect("Publish not acknowledged");
+    storage
+        .push(TestJob::new("regular push"))
+        .await
+        .expect("Failed to push job");
+
+    let processed = Arc::new(Mutex::new(Vec::<(String, Option<Priority>)>::new()));
+
+    async fn record_job(
+        job: TestJob,
+        ctx: NatsContext,
+        processed: Data<Arc<Mutex<Vec<(String, Option<Priority>)>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push((job.message, ctx.priority()));
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("bare-worker")
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let processed = processed.lock().await;
+    assert_eq!(processed.len(), 2, "Processed: {:?}", *processed);
+    assert!(processed.contains(&("legacy producer".to_string(), Some(Priority::Low))));
+    assert!(processed.contains(&("regular push".to_string(), Some(Priority::Medium))));
+
+    // Nothing was dead-lettered as malformed
+    let mut dlq = js
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 0);
+}
+
+#[tokio::test]
+async fn test_handler_outliving_ack_wait_without_progress_warns() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=warn")
+        .with_writer(move || writer.clone())
+        .finish();
+    // The test runtime is single threaded, so this covers the worker's tasks too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(2);
+    config.max_deliver = 1;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push(TestJob::new("slow"))
+        .await
+        .expect("Failed to push job");
+
+    // Runs past ack_wait without a single progress ack
+    async fn slow_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("slow-worker")
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    handle.abort();
+
+    let output = logs.take();
+    assert!(
+        output.contains("without a progress ack, longer than ack_wait"),
+        "Expected a lease warning, got: {}",
+        output
+    );
+    assert!(output.contains("ProgressHeartbeatLayer"));
+    assert_eq!(
+        output.matches("without a progress ack").count(),
+        1,
+        "Should only warn once"
+    );
+}
+
+#[tokio::test]
+async fn test_sourced_streams_consume_upstream_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let upstream_config = test_config();
+
+    // Upstream streams meant to be sourced, created up front with Limits retention
+    let js = jetstream::new(client.clone());
+    for priority in ["high", "medium", "low"] {
+        js.create_stream(jetstream::stream::Config {
+            name: format!("{}_{}", upstream_config.namespace, priority),
+            subjects: vec![format!("{}.{}", upstream_config.namespace, priority)],
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create upstream stream");
+    }
+    let mut upstream =
+        NatsStorage::<TestJob>::new_with_config(client.clone(), upstream_config.clone())
+            .await
+            .expect("Failed to create upstream storage");
+
+    let mut config = Config::default();
+    config.namespace = format!("{}_regional", upstream_config.namespace);
+    config.sources = vec![Source {
+        name: format!("{}_{{priority}}", upstream_config.namespace),
+        ..Default::default()
+    }];
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create regional storage");
+
+    let mut high = js
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("Regional stream should exist");
+    let info = high.info().await.expect("Failed to get stream info");
+    let sources = info.config.sources.expect("Regional stream should have sources");
+    assert_eq!(sources[0].name, format!("{}_high", upstream_config.namespace));
+
+    let job = TestJob::new("from upstream");
+    upstream
+        .push_with_priority(job.clone(), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.id);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("regional-worker")
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    assert_eq!(*processed.lock().await, vec![job.id]);
+    // Acked in the regional copy only
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+    assert_eq!(upstream.len().await.expect("Failed to get length"), 1);
+
+    // A stream can't be both a mirror and sourced
+    config.mirror = Some(Source {
+        name: format!("{}_{{priority}}", upstream_config.namespace),
+        ..Default::default()
+    });
+    assert!(matches!(
+        NatsStorage::<TestJob>::plan(&config),
+        Err(NatsPollError::Storage(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_labeled_pools_consume_matching_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let labels = |gpu: &str| {
+        HashMap::from([
+            ("gpu".to_string(), gpu.to_string()),
+            ("region".to_string(), "eu".to_string()),
+        ])
+    };
+    for i in 0..2 {
+        storage
+            .push_with_labels(TestJob::new(format!("gpu {}", i)), Priority::High, labels("true"))
+            .await
+            .expect("Failed to push labeled job");
+        storage
+            .push_with_labels(TestJob::new(format!("cpu {}", i)), Priority::Low, labels("false"))
+            .await
+            .expect("Failed to push labeled job");
+    }
+
+    // Labels end up in the subject, so they must be valid subject tokens
+    let invalid = HashMap::from([("region".to_string(), "eu.west".to_string())]);
+    assert!(matches!(
+        storage
+            .push_with_labels(TestJob::new("invalid"), Priority::High, invalid)
+            .await,
+        Err(NatsPollError::Storage(_))
+    ));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let gpu_processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let gpu_pool = storage
+        .clone()
+        .for_labels(labels("true"))
+        .expect("Valid label filter");
+    let gpu_worker = WorkerBuilder::new("gpu-worker")
+        .data(gpu_processed.clone())
+        .backend(gpu_pool)
+        .build_fn(record_job);
+
+    // Any region, but only CPU jobs
+    let cpu_processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let cpu_pool = storage
+        .clone()
+        .for_labels(HashMap::from([
+            ("gpu".to_string(), "false".to_string()),
+            ("region".to_string(), "*".to_string()),
+        ]))
+        .expect("Valid label filter");
+    let cpu_worker = WorkerBuilder::new("cpu-worker")
+        .data(cpu_processed.clone())
+        .backend(cpu_pool)
+        .build_fn(record_job);
+
+    // Unlabeled workers never see labeled jobs
+    let plain_processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let plain_worker = WorkerBuilder::new("plain-worker")
+        .data(plain_processed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+
+    let handles = vec![
+        tokio::spawn(async move { gpu_worker.run().await }),
+        tokio::spawn(async move { cpu_worker.run().await }),
+        tokio::spawn(async move { plain_worker.run().await }),
+    ];
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for handle in handles {
+        handle.abort();
+    }
+
+    let mut gpu_processed = gpu_processed.lock().await.clone();
+    gpu_processed.sort();
+    assert_eq!(gpu_processed, vec!["gpu 0", "gpu 1"]);
+    let mut cpu_processed = cpu_processed.lock().await.clone();
+    cpu_processed.sort();
+    assert_eq!(cpu_processed, vec!["cpu 0", "cpu 1"]);
+    assert!(plain_processed.lock().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_deleted_stream_is_recreated_or_stops_the_worker() {
+    let (_container, client) = setup_nats_raw().await;
+    let js = jetstream::new(client.clone());
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    // Recreate: the worker sets the stream up again and keeps consuming
+    let mut config = test_config();
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let worker = WorkerBuilder::new("recreating-worker")
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    js.delete_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("Failed to delete stream");
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    js.get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("The stream should have been re-created");
+
+    storage
+        .push_with_priority(TestJob::new("after recreate"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(!handle.is_finished(), "The worker should keep running");
+    handle.abort();
+    assert_eq!(*processed.lock().await, vec!["after recreate"]);
+
+    // Fail: the worker stops instead
+    let mut config = test_config();
+    config.on_stream_missing = OnStreamMissing::Fail;
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let worker = WorkerBuilder::new("failing-worker")
+        .data(Arc::new(Mutex::new(Vec::<String>::new())))
+        .backend(storage)
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    js.delete_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("Failed to delete stream");
+    tokio::time::timeout(Duration::from_secs(10), handle)
+        .await
+        .expect("The worker should stop once its stream is gone")
+        .expect("Worker task panicked");
+    assert!(js
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_with_layers_applies_bundled_layers() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(2);
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_layers(NatsLayers::recommended());
+
+    let runs = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    // Panics on request, and outlives ack_wait otherwise
+    async fn bundled_job(job: TestJob, runs: Data<Arc<Mutex<Vec<String>>>>) -> Result<(), Error> {
+        runs.lock().await.push(job.message.clone());
+        if job.message == "panic" {
+            panic!("handler blew up");
+        }
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        Ok(())
+    }
+
+    storage
+        .push(TestJob::new("panic"))
+        .await
+        .expect("Failed to push job");
+    storage
+        .push(TestJob::new("slow"))
+        .await
+        .expect("Failed to push job");
+
+    // No .layer(...) calls, everything comes with the backend
+    let worker = WorkerBuilder::new("bundled-worker")
+        .concurrency(2)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(bundled_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(7)).await;
+    handle.abort();
+
+    // The heartbeat kept the slow job from being redelivered, the panic didn't retry either
+    let mut runs = runs.lock().await.clone();
+    runs.sort();
+    assert_eq!(runs, vec!["panic", "slow"]);
+
+    // The panic became Error::Abort, so the job went straight to the DLQ
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the panicked job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    assert!(entry.error.contains("handler blew up"), "{}", entry.error);
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+}
+
+#[tokio::test]
+async fn test_tenant_failures_are_separately_filterable_in_dlq() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.tenant_label = Some("tenant".to_string());
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let tenant = |name: &str| HashMap::from([("tenant".to_string(), name.to_string())]);
+    for (message, name) in [("acme 1", "acme"), ("acme 2", "acme"), ("globex 1", "globex")] {
+        storage
+            .push_with_labels(TestJob::new(message), Priority::High, tenant(name))
+            .await
+            .expect("Failed to push tenant job");
+    }
+
+    async fn failing_job(job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} failed", job.message),
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let pool = storage
+        .clone()
+        .for_labels(tenant("*"))
+        .expect("Valid label filter");
+    let worker = WorkerBuilder::new("tenant-worker")
+        .backend(pool)
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let acme = storage
+        .read_dlq_for_tenant("acme")
+        .await
+        .expect("Failed to read acme's DLQ entries");
+    let mut errors: Vec<_> = acme.iter().map(|entry| entry.error.clone()).collect();
+    errors.sort();
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+    assert!(errors[0].contains("acme 1 failed"), "{:?}", errors);
+    assert!(errors[1].contains("acme 2 failed"), "{:?}", errors);
+
+    let globex = storage
+        .read_dlq_for_tenant("globex")
+        .await
+        .expect("Failed to read globex's DLQ entries");
+    assert_eq!(globex.len(), 1);
+    assert!(globex[0].error.contains("globex 1 failed"), "{}", globex[0].error);
+
+    assert!(storage
+        .read_dlq_for_tenant("initech")
+        .await
+        .expect("Failed to read initech's DLQ entries")
+        .is_empty());
+
+    // Each tenant's entries sit on their own subject of the DLQ stream
+    let js = jetstream::new(client);
+    let stream = js
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = stream
+        .get_last_raw_message_by_subject(&format!("{}.dlq.globex", config.namespace))
+        .await
+        .expect("globex should have a DLQ entry on its subject");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+}
+
+#[tokio::test]
+async fn test_raw_payload_matches_published_bytes() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.accept_bare_payloads = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Pretty-printed, so re-serializing the decoded job would give different bytes
+    let published = serde_json::to_vec_pretty(&TestJob::new("signed payload")).unwrap();
+    let js = jetstream::new(client);
+    js.publish(format!("{}.high", config.namespace), published.clone().into())
+        .await
+        .expect("Failed to publish")
+        .await
+        .expect("Publish not acknowledged");
+
+    let received = Arc::new(Mutex::new(Vec::<Option<Vec<u8>>>::new()));
+
+    async fn record_payload(
+        _job: TestJob,
+        ctx: NatsContext,
+        received: Data<Arc<Mutex<Vec<Option<Vec<u8>>>>>>,
+    ) -> Result<(), Error> {
+        received
+            .lock()
+            .await
+            .push(ctx.raw_payload().map(|payload| payload.to_vec()));
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("raw-payload-worker")
+        .data(received.clone())
+        .backend(storage)
+        .build_fn(record_payload);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let received = received.lock().await;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].as_deref(), Some(published.as_slice()));
+}
+
+#[tokio::test]
+async fn test_oversized_payload_is_rejected_before_publishing() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_msg_size = Some(1024);
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    let result = storage
+        .push_with_priority(TestJob::new("x".repeat(2048)), Priority::High)
+        .await;
+    match result {
+        Err(NatsPollError::PayloadTooLarge { size, limit }) => {
+            assert!(size > 2048, "size: {}", size);
+            assert_eq!(limit, 1024);
+        }
+        other => panic!("Expected PayloadTooLarge, got {:?}", other),
+    }
+
+    // Small jobs still go through, and the oversized one was never published
+    storage
+        .push_with_priority(TestJob::new("small"), Priority::High)
+        .await
+        .expect("Failed to push small job");
+    assert_eq!(storage.len().await.expect("Failed to get length"), 1);
+}
+
+#[tokio::test]
+async fn test_consumer_groups_each_receive_every_job() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let namespace = test_namespace();
+    let group_storage = |group: &str| {
+        let config = Config {
+            namespace: namespace.clone(),
+            consumer_group: Some(group.to_string()),
+            ..Default::default()
+        };
+        NatsStorage::<TestJob>::new_with_config(client.clone(), config)
+    };
+    let billing = group_storage("billing")
+        .await
+        .expect("Failed to create billing storage");
+    let audit = group_storage("audit")
+        .await
+        .expect("Failed to create audit storage");
+
+    assert!(matches!(
+        group_storage("not.valid").await,
+        Err(NatsPollError::Storage(_))
+    ));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    // Two billing workers share the billing group's jobs, one audit worker gets them all too
+    let billing_processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let audit_processed = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut handles = Vec::new();
+    for (name, storage, processed) in [
+        ("billing-1", billing.clone(), billing_processed.clone()),
+        ("billing-2", billing.clone(), billing_processed.clone()),
+        ("audit-1", audit.clone(), audit_processed.clone()),
+    ] {
+        let worker = WorkerBuilder::new(name)
+            .data(processed)
+            .backend(storage)
+            .build_fn(record_job);
+        handles.push(tokio::spawn(async move { worker.run().await }));
+    }
+
+    // Groups only receive jobs published once their consumers exist
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for i in 0..4 {
+        billing
+            .push_with_priority(TestJob::new(format!("order {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for handle in handles {
+        handle.abort();
+    }
+
+    let expected: Vec<String> = (0..4).map(|i| format!("order {}", i)).collect();
+    for processed in [billing_processed, audit_processed] {
+        let mut processed = processed.lock().await.clone();
+        processed.sort();
+        assert_eq!(processed, expected);
+    }
+
+    // Each group has its own durable per priority
+    let info = audit
+        .consumer_info(Priority::Medium)
+        .await
+        .expect("The audit group's consumer should exist");
+    assert_eq!(info.name, format!("{}_medium_audit_consumer", namespace));
+}
+
+/// Push a job per priority into each storage, run a worker on each and assert that neither
+/// worker saw a job pushed into the other storage
+async fn assert_isolated(a: NatsStorage<TestJob>, b: NatsStorage<TestJob>) {
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let mut handles = Vec::new();
+    let mut results = Vec::new();
+    for (name, storage) in [("a", a), ("b", b)] {
+        for priority in [Priority::High, Priority::Medium, Priority::Low] {
+            storage
+                .push_with_priority(TestJob::new(format!("{} {}", name, priority)), priority)
+                .await
+                .expect("Failed to push job");
+        }
+        let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+        let worker = WorkerBuilder::new(format!("isolation-worker-{}", name))
+            .data(processed.clone())
+            .backend(storage)
+            .build_fn(record_job);
+        handles.push(tokio::spawn(async move { worker.run().await }));
+        results.push((name, processed));
+    }
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for handle in handles {
+        handle.abort();
+    }
+
+    for (name, processed) in results {
+        let mut processed = processed.lock().await.clone();
+        processed.sort();
+        let expected = vec![
+            format!("{} high", name),
+            format!("{} low", name),
+            format!("{} medium", name),
+        ];
+        assert_eq!(processed, expected, "storage {} saw other jobs", name);
+    }
+}
+
+#[tokio::test]
+async fn test_namespaces_sharing_a_prefix_are_isolated() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let base = test_namespace();
+    let storage = |namespace: String| {
+        let config = Config {
+            namespace,
+            ..Default::default()
+        };
+        NatsStorage::<TestJob>::new_with_config(client.clone(), config)
+    };
+
+    // `{base}.high` is a prefix of `{base}_2.high` as a string, but not as a subject
+    let a = storage(base.clone()).await.expect("Failed to create storage");
+    let b = storage(format!("{}_2", base))
+        .await
+        .expect("Failed to create storage");
+    assert_isolated(a, b).await;
+
+    // Namespaces that would span or match several subject tokens are refused
+    for namespace in [format!("{}.eu", base), format!("{}_*", base), String::new()] {
+        assert!(
+            matches!(storage(namespace.clone()).await, Err(NatsPollError::Storage(_))),
+            "namespace {:?} was accepted",
+            namespace
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_flush_makes_pushed_jobs_visible_to_a_fresh_consumer() {
+    let (container, producer_client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    // A producer that pushes a burst, flushes and exits
+    let producer = NatsStorage::<TestJob>::new_with_config(producer_client, config.clone())
+        .await
+        .expect("Failed to create producer storage");
+    for i in 0..5 {
+        producer
+            .push_with_priority(TestJob::new(format!("burst {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+    producer.flush().await.expect("Failed to flush");
+    drop(producer);
+
+    // A consumer on its own connection sees every job
+    let host = container.get_host().await.expect("Failed to get host");
+    let port = container
+        .get_host_port_ipv4(4222)
+        .await
+        .expect("Failed to get port");
+    let consumer_client = apalis_nats::connect(format!("nats://{}:{}", host, port))
+        .await
+        .expect("Failed to connect to NATS");
+    let consumer = NatsStorage::<TestJob>::new_with_config(consumer_client, config)
+        .await
+        .expect("Failed to create consumer storage");
+
+    let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("fresh-consumer")
+        .data(processed.clone())
+        .backend(consumer.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let mut processed = processed.lock().await.clone();
+    processed.sort();
+    let expected: Vec<String> = (0..5).map(|i| format!("burst {}", i)).collect();
+    assert_eq!(processed, expected);
+
+    // The consumer's acks are flushed too, so nothing is left to redeliver
+    consumer.flush().await.expect("Failed to flush");
+    let info = consumer
+        .consumer_info(Priority::Medium)
+        .await
+        .expect("Failed to get consumer info");
+    assert_eq!(info.num_ack_pending, 0);
+}
+
+#[tokio::test]
+async fn test_idle_poll_intervals_are_jittered() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=trace")
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The test runtime is single threaded, so this covers the worker's tasks too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut config = test_config();
+    config.idle_jitter = 0.2;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Nothing is pushed, so the worker only ever backs off
+    let worker = WorkerBuilder::new("idle-worker")
+        .backend(storage)
+        .build_fn(noop);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let logs = logs.take();
+    let intervals: Vec<f64> = logs
+        .lines()
+        .filter_map(|line| line.split("fetching again in ").nth(1))
+        .map(|interval| {
+            interval
+                .trim()
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or_else(|| panic!("Unexpected interval {:?}", interval))
+        })
+        .collect();
+    assert!(intervals.len() >= 5, "Too few idle rounds: {:?}", intervals);
+    for interval in &intervals {
+        assert!(
+            (80.0..=120.0).contains(interval),
+            "{}ms is outside 100ms ± 20%",
+            interval
+        );
+    }
+    let first = intervals[0];
+    assert!(
+        intervals.iter().any(|interval| *interval != first),
+        "Idle intervals never varied: {:?}",
+        intervals
+    );
+}
+
+#[tokio::test]
+async fn test_placement_is_applied_to_every_stream() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.placement = Some(Placement {
+        cluster: None,
+        tags: vec!["region:eu".to_string()],
+    });
+
+    // Every stream and the status bucket are requested with the placement
+    let plan = NatsStorage::<TestJob>::plan(&config).expect("Failed to plan");
+    let mut placed = 0;
+    for resource in plan {
+        let placement = match resource {
+            PlannedResource::Stream(stream) => stream.placement,
+            PlannedResource::KeyValue(bucket) => bucket.placement,
+            PlannedResource::Consumer { .. } => continue,
+        };
+        assert_eq!(
+            placement.map(|placement| placement.tags),
+            Some(vec!["region:eu".to_string()])
+        );
+        placed += 1;
+    }
+    assert_eq!(placed, 5); // three priorities, the DLQ and the status bucket
+
+    // A single server accepts the placement without erroring
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage with a placement");
+    storage
+        .push(TestJob::new("placed"))
+        .await
+        .expect("Failed to push job");
+    assert_eq!(storage.len().await.expect("Failed to get length"), 1);
+}
+
+#[tokio::test]
+async fn test_dlq_stream_yields_entries_lazily() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(1);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    for i in 0..3 {
+        storage
+            .push_with_priority(TestJob::new(format!("broken {}", i)), Priority::High)
+            .await
+            .expect("Failed to push job");
+    }
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "send to DLQ",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let worker = WorkerBuilder::new("dlq-stream-worker")
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    // Take two entries without reading the rest, and discard the first
+    let mut entries = Box::pin(storage.dlq_stream());
+    let (first, ctx) = entries
+        .next()
+        .await
+        .expect("The DLQ should have entries")
+        .expect("Failed to read DLQ entry");
+    assert_eq!(first.dlq_reason, "abort_error");
+    ctx.ack().await.expect("Failed to ack DLQ entry");
+    let (second, _ctx) = entries
+        .next()
+        .await
+        .expect("The DLQ should have another entry")
+        .expect("Failed to read DLQ entry");
+    drop(entries);
+
+    // The entries left alone come back after ack_wait, the discarded one doesn't
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let remaining: Vec<DlqEntry> = storage
+        .dlq_stream()
+        .map(|entry| entry.expect("Failed to read DLQ entry").0)
+        .collect()
+        .await;
+    let ids: Vec<&str> = remaining
+        .iter()
+        .map(|entry| entry.original_task_id.as_str())
+        .collect();
+    assert_eq!(remaining.len(), 2, "{:?}", ids);
+    assert!(!ids.contains(&first.original_task_id.as_str()));
+    assert!(ids.contains(&second.original_task_id.as_str()));
+}
+
+#[tokio::test]
+async fn test_deadline_is_ack_wait_after_delivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(10);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("timed"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    // How far ahead of the handler starting the deadline was
+    let observed = Arc::new(Mutex::new(Vec::<Option<Duration>>::new()));
+
+    async fn record_deadline(
+        _job: TestJob,
+        ctx: NatsContext,
+        observed: Data<Arc<Mutex<Vec<Option<Duration>>>>>,
+    ) -> Result<(), Error> {
+        let now = std::time::Instant::now();
+        let left = ctx.deadline().map(|deadline| deadline.duration_since(now));
+        observed.lock().await.push(left);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("deadline-worker")
+        .data(observed.clone())
+        .backend(storage)
+        .build_fn(record_deadline);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+
+    let observed = observed.lock().await;
+    assert_eq!(observed.len(), 1);
+    let left = observed[0].expect("A delivered job should have a deadline");
+    assert!(
+        left <= Duration::from_secs(10) && left > Duration::from_secs(9),
+        "deadline was {:?} away",
+        left
+    );
+}
+
+#[tokio::test]
+async fn test_memory_storage_processes_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.storage_type = StorageType::Memory;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Every stream is kept in memory
+    let js = jetstream::new(client);
+    for suffix in ["high", "medium", "low", "dlq"] {
+        let mut stream = js
+            .get_stream(format!("{}_{}", config.namespace, suffix))
+            .await
+            .expect("Stream should exist");
+        let info = stream.info().await.expect("Failed to get stream info");
+        assert_eq!(info.config.storage, StorageType::Memory, "{}", suffix);
+    }
+
+    for i in 0..3 {
+        storage
+            .push_with_priority(TestJob::new(format!("in memory {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("memory-worker")
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let mut processed = processed.lock().await.clone();
+    processed.sort();
+    let expected: Vec<String> = (0..3).map(|i| format!("in memory {}", i)).collect();
+    assert_eq!(processed, expected);
+}
+
+#[tokio::test]
+async fn test_reprioritize_moves_pending_jobs_to_another_priority() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let mut pushed = Vec::new();
+    for i in 0..4 {
+        let task_id = storage
+            .push_with_priority(TestJob::new(format!("flood {}", i)), Priority::High)
+            .await
+            .expect("Failed to push job");
+        pushed.push(task_id.to_string());
+    }
+
+    // A limit leaves the rest where it is
+    let moved = storage
+        .reprioritize(Priority::High, Priority::Low, Some(3))
+        .await
+        .expect("Failed to reprioritize");
+    assert_eq!(moved, 3);
+
+    let js = jetstream::new(client);
+    let messages = |priority: &str| {
+        let js = js.clone();
+        let name = format!("{}_{}", config.namespace, priority);
+        async move {
+            let mut stream = js.get_stream(name).await.expect("Stream should exist");
+            stream
+                .info()
+                .await
+                .expect("Failed to get stream info")
+                .state
+                .messages
+        }
+    };
+    assert_eq!(messages("high").await, 1);
+    assert_eq!(messages("low").await, 3);
+
+    // Only consume Low, the moved jobs keep their task ids
+    let processed = Arc::new(Mutex::new(Vec::<(String, Option<Priority>)>::new()));
+
+    async fn record_job(
+        _job: TestJob,
+        task_id: TaskId,
+        ctx: NatsContext,
+        processed: Data<Arc<Mutex<Vec<(String, Option<Priority>)>>>>,
+    ) -> Result<(), Error> {
+        processed
+            .lock()
+            .await
+            .push((task_id.to_string(), ctx.priority()));
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("low-worker")
+        .data(processed.clone())
+        .backend(storage.clone().for_priorities(&[Priority::Low]))
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    let processed = processed.lock().await;
+    assert_eq!(processed.len(), 3, "{:?}", *processed);
+    for (task_id, priority) in processed.iter() {
+        assert!(pushed[..3].contains(task_id), "{} wasn't moved", task_id);
+        assert_eq!(*priority, Some(Priority::Low));
+    }
+    assert_eq!(messages("high").await, 1);
+}
+
+#[tokio::test]
+async fn test_reprioritize_drops_publish_expectations() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_expected_seq(TestJob::new("first"), Priority::High, 0)
+        .await
+        .expect("Failed to push job");
+    storage
+        .push_with_expected_seq(TestJob::new("second"), Priority::High, 1)
+        .await
+        .expect("Failed to push job");
+
+    // The first one ran, so the second expects a sequence the Low subject never had
+    let js = jetstream::new(client.clone());
+    js.get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist")
+        .delete_message(1)
+        .await
+        .expect("Failed to remove the first job");
+
+    let moved = storage
+        .reprioritize(Priority::High, Priority::Low, None)
+        .await
+        .expect("Stale expectations shouldn't abort the move");
+    assert_eq!(moved, 1);
+}
+
+#[tokio::test]
+async fn test_fetch_max_bytes_limits_jobs_per_fetch() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    // Room for ten jobs by count, but only one 10KB job by size
+    config.fetch_batch_size = 10;
+    config.fetch_max_bytes = Some(15_000);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    for i in 0..5 {
+        storage
+            .push_with_priority(
+                TestJob::new(format!("{} {}", i, "x".repeat(10_000))),
+                Priority::High,
+            )
+            .await
+            .expect("Failed to push job");
+    }
+
+    async fn slow_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("bounded-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(slow_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+
+    // While the first job runs, the server has handed out only what fit in the byte cap
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let info = storage
+        .consumer_info(Priority::High)
+        .await
+        .expect("Failed to get consumer info");
+    handle.abort();
+
+    assert_eq!(info.num_ack_pending, 1);
+    assert_eq!(info.num_pending, 4);
+}
+
+#[tokio::test]
+async fn test_final_attempt_is_detected_before_dead_lettering() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 3;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("doomed"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    // Whether each attempt saw itself as the final one
+    let observed = Arc::new(Mutex::new(Vec::<bool>::new()));
+
+    async fn always_failing(
+        _job: TestJob,
+        ctx: NatsContext,
+        observed: Data<Arc<Mutex<Vec<bool>>>>,
+    ) -> Result<(), Error> {
+        observed.lock().await.push(ctx.is_final_attempt());
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "still failing",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let worker = WorkerBuilder::new("final-attempt-worker")
+        .concurrency(1)
+        .data(observed.clone())
+        .backend(storage)
+        .build_fn(always_failing);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+
+    assert_eq!(*observed.lock().await, vec![false, false, true]);
+}
+
+#[tokio::test]
+async fn test_heartbeat_checks_health_until_shutdown() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("apalis_nats=debug")
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // The test runtime is single threaded, so this covers the worker's tasks too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut config = test_config();
+    config.heartbeat_interval = Duration::from_millis(200);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("heartbeat-worker")
+        .backend(storage)
+        .build_fn(noop);
+    let runnable = worker.run();
+    let worker_handle = runnable.get_handle();
+    let handle = tokio::spawn(runnable);
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let beats = logs.take().matches("Heartbeat of worker").count();
+    assert!(beats >= 3, "Expected a check every 200ms, got {}", beats);
+
+    worker_handle.stop();
+    tokio::time::timeout(Duration::from_secs(2), handle)
+        .await
+        .expect("The worker should stop")
+        .expect("The worker task should not fail");
+
+    // No more checks once the worker is gone
+    logs.take();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let output = logs.take();
+    assert!(
+        !output.contains("Heartbeat of worker"),
+        "Heartbeat kept running after shutdown: {}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn test_storage_uses_a_prebuilt_jetstream_context() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    // A context under an API prefix nobody answers must be used as is, not replaced
+    let mut unanswered = jetstream::with_prefix(client.clone(), "$JS.missing.API");
+    unanswered.set_timeout(Duration::from_millis(500));
+    let result = tokio::time::timeout(
+        Duration::from_secs(15),
+        NatsStorage::<TestJob>::new_with_jetstream(unanswered, config.clone()),
+    )
+    .await
+    .expect("Storage setup should fail rather than hang");
+    assert!(result.is_err(), "Streams should not be found under the prefix");
+
+    let mut custom = jetstream::new(client.clone());
+    custom.set_timeout(Duration::from_secs(10));
+    let storage = NatsStorage::<TestJob>::new_with_jetstream(custom, config.clone())
+        .await
+        .expect("Failed to create storage from a custom context");
+    storage
+        .push_with_priority(TestJob::new("custom context"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("custom-context-worker")
+        .data(processed.clone())
+        .backend(storage)
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_nak_with_delay_postpones_redelivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.manual_ack = true;
+    // Far from the delay the handler picks
+    config.nak_backoff = vec![Duration::from_millis(100)];
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("rate limited"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let calls = Arc::new(Mutex::new(Vec::<std::time::Instant>::new()));
+
+    // Rate limited on the first attempt, with a Retry-After of 2s
+    async fn rate_limited(
+        _job: TestJob,
+        ctx: NatsContext,
+        calls: Data<Arc<Mutex<Vec<std::time::Instant>>>>,
+    ) -> Result<(), Error> {
+        let mut calls = calls.lock().await;
+        calls.push(std::time::Instant::now());
+        match calls.len() {
+            1 => ctx.nak_with_delay(Duration::from_secs(2)).await,
+            _ => ctx.ack().await,
+        }
+    }
+
+    let worker = WorkerBuilder::new("retry-after-worker")
+        .concurrency(1)
+        .data(calls.clone())
+        .backend(storage)
+        .build_fn(rate_limited);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    handle.abort();
+
+    let calls = calls.lock().await;
+    assert_eq!(calls.len(), 2, "The job should be redelivered once");
+    let gap = calls[1] - calls[0];
+    assert!(
+        gap >= Duration::from_millis(1800) && gap < Duration::from_secs(3),
+        "Redelivered after {:?}",
+        gap
+    );
+}
+
+#[tokio::test]
+async fn test_progress_heartbeat_layer_prevents_redelivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.ack_wait = Duration::from_secs(5);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+    let task_id = storage
+        .push_with_priority(TestJob::new("long"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    // More than twice ack_wait, without a single progress call of its own
+    async fn long_job(_job: TestJob, runs: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("heartbeat-layer-worker")
+        .concurrency(2)
+        .layer(ProgressHeartbeatLayer::new(Duration::from_secs(2)))
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(long_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(14)).await;
+    handle.abort();
+
+    // A redelivery would have started a second run on the free slot
+    assert_eq!(runs.load(Ordering::SeqCst), 1, "The job should run exactly once");
+    assert_eq!(storage.query_status(&task_id).await.unwrap(), JobStatus::Completed);
+}
+
+#[tokio::test]
+async fn test_heartbeat_stops_when_handler_panics() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(3);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Watch the acks workers send for the high stream
+    let mut acks = client
+        .subscribe(format!("$JS.ACK.{}_high.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to acks");
+
+    // Heartbeats a few times, then dies
+    async fn panic_later(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        panic!("handler died mid-heartbeat");
+    }
+
+    storage
+        .push_with_priority(TestJob::new("doomed"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    // The panic unwinds through the heartbeat layer before it is caught
+    let worker = WorkerBuilder::new("panicking-heartbeat-worker")
+        .layer(CatchPanicLayer::new())
+        .layer(ProgressHeartbeatLayer::new(Duration::from_millis(300)))
+        .backend(storage.clone())
+        .build_fn(panic_later);
+    let handle = tokio::spawn(async move { worker.run().await });
+
+    let mut progress_before = 0;
+    let mut progress_after = 0;
+    let mut settled = false;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(4);
+    while let Ok(Some(ack)) = tokio::time::timeout_at(deadline, acks.next()).await {
+        match &ack.payload[..] {
+            b"+WPI" if settled => progress_after += 1,
+            b"+WPI" => progress_before += 1,
+            _ => settled = true,
+        }
+    }
+    handle.abort();
+
+    assert!(progress_before > 0, "The heartbeat should run while the handler does");
+    assert!(settled, "The panicked job should be settled");
+    assert_eq!(progress_after, 0, "The heartbeat should stop with the handler");
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the panicked job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    assert!(entry.error.contains("handler died"), "{}", entry.error);
+}
+
+#[tokio::test]
+async fn test_work_queue_dlq_drops_acked_entries() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.dlq_retention = RetentionPolicy::WorkQueue;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("broken {}", i)), Priority::High)
+            .await
+            .expect("Failed to push job");
+    }
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "send to DLQ",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let worker = WorkerBuilder::new("work-queue-dlq-worker")
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.config.retention, RetentionPolicy::WorkQueue);
+    assert_eq!(info.state.messages, 2);
+
+    // Re-drive one entry: acking it removes it from the DLQ
+    let mut entries = Box::pin(storage.dlq_stream());
+    let (_entry, ctx) = entries
+        .next()
+        .await
+        .expect("The DLQ should have entries")
+        .expect("Failed to read DLQ entry");
+    ctx.ack().await.expect("Failed to ack DLQ entry");
+    drop(entries);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.state.messages, 1, "The acked entry should be gone");
+}
+
+#[tokio::test]
+async fn test_requeued_dlq_job_returns_to_its_original_priority() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("moved"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    // An external tool moves the job to the low stream, leaving its payload as it was
+    let jetstream = jetstream::new(client);
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let pushed = high.get_raw_message(1).await.expect("Job should be stored");
+    jetstream
+        .publish(format!("{}.low", config.namespace), pushed.payload)
+        .await
+        .expect("Failed to publish")
+        .await
+        .expect("Failed to store moved job");
+    high.delete_message(1).await.expect("Failed to delete job");
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "send to DLQ",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let worker = WorkerBuilder::new("original-priority-worker")
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq.get_raw_message(1).await.expect("DLQ should have the job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(
+        entry.original_subject.as_deref(),
+        Some(format!("{}.low", config.namespace).as_str())
+    );
+    assert_eq!(entry.original_priority, Some(Priority::Low));
+    assert_eq!(entry.original_sequence, Some(1));
+
+    let requeued = storage
+        .requeue_dlq_edited(|_| true)
+        .await
+        .expect("Failed to requeue DLQ jobs");
+    assert_eq!(requeued, 1);
+
+    // Back in the low stream, not in the high one its payload came from
+    for (priority, expected) in [("high", 0), ("low", 1)] {
+        let mut stream = jetstream
+            .get_stream(format!("{}_{}", config.namespace, priority))
+            .await
+            .expect("Stream should exist");
+        let info = stream.info().await.expect("Failed to get stream info");
+        assert_eq!(info.state.messages, expected, "{} stream", priority);
+    }
+}
+
+#[tokio::test]
+async fn test_consumer_info_reflects_unconsumed_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // A worker polling the empty queue creates the consumers, then goes away
+    let worker = WorkerBuilder::new("consumer-info-worker")
+        .backend(storage.clone())
+        .build_fn(noop);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    for i in 0..3 {
+        storage
+            .push_with_priority(TestJob::new(format!("waiting {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let info = storage
+        .consumer_info(Priority::Medium)
+        .await
+        .expect("The consumer should exist");
+    assert_eq!(info.num_pending, 3);
+    assert_eq!(info.num_ack_pending, 0);
+    assert_eq!(info.num_redelivered, 0);
+    assert_eq!(info.ack_floor.consumer_sequence, 0);
+
+    let info = storage
+        .consumer_info(Priority::High)
+        .await
+        .expect("The consumer should exist");
+    assert_eq!(info.num_pending, 0);
+}
+
+#[tokio::test]
+async fn test_mirrored_job_reaches_subscribers_and_workers() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let mirror = format!("events.{}", config.namespace);
+    let mut subscriber = client
+        .subscribe(mirror.clone())
+        .await
+        .expect("Failed to subscribe to the mirror subject");
+
+    // Copies inside the namespace would be stored as jobs
+    let inside = format!("{}.high", config.namespace);
+    match storage
+        .push_with_mirror(TestJob::new("looped"), Priority::High, &[inside])
+        .await
+    {
+        Err(NatsPollError::Storage(_)) => {}
+        other => panic!("Expected a Storage error, got {:?}", other),
+    }
+
+    let task_id = storage
+        .push_with_mirror(TestJob::new("broadcast"), Priority::High, &[mirror])
+        .await
+        .expect("Failed to push job");
+
+    let copy = tokio::time::timeout(Duration::from_secs(2), subscriber.next())
+        .await
+        .expect("The mirror copy should arrive")
+        .expect("Subscription closed");
+    let headers = copy.headers.expect("The copy should carry the job headers");
+    assert_eq!(
+        headers.get(TASK_ID_HEADER).map(|v| v.as_str()),
+        Some(task_id.to_string().as_str())
+    );
+    let body: serde_json::Value = serde_json::from_slice(&copy.payload).expect("Invalid JSON");
+    assert_eq!(body["data"]["message"], "broadcast");
+
+    let processed = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn record_job(
+        job: TestJob,
+        processed: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        processed.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("mirror-worker")
+        .data(processed.clone())
+        .backend(storage)
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+
+    // The rejected push enqueued nothing
+    assert_eq!(*processed.lock().await, vec!["broadcast".to_string()]);
+}
+
+#[tokio::test]
+async fn test_classifier_dead_letters_matching_errors_immediately() {
+    let (_container, client) = setup_nats_raw().await;
+
+    #[derive(Debug)]
+    struct RecordNotFound;
+
+    impl std::fmt::Display for RecordNotFound {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "record not found")
+        }
+    }
+
+    impl std::error::Error for RecordNotFound {}
+
+    let mut config = test_config();
+    config.max_deliver = 5;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_classifier(|error: &Error| match error {
+            Error::Failed(e) if e.is::<RecordNotFound>() => Disposition::DeadLetter,
+            Error::Abort(_) => Disposition::DeadLetter,
+            _ => Disposition::Retry { delay: None },
+        });
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    async fn missing_record(_job: TestJob, attempts: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        // A plain failure, which would be retried up to max_deliver without the classifier
+        Err(Error::Failed(Arc::new(
+            Box::new(RecordNotFound) as Box<dyn std::error::Error + Send + Sync>
+        )))
+    }
+
+    storage
+        .push(TestJob::new("Missing record"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("classifier-worker")
+        .data(attempts.clone())
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(missing_record);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(attempts.load(Ordering::SeqCst), 1, "job should not be retried");
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the classified job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "classified");
+    assert_eq!(entry.delivered_count, 1);
+    assert_eq!(entry.retry_after, None);
+}
+
+#[tokio::test]
+async fn test_max_retry_duration_dead_letters_before_max_deliver() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    // Far more deliveries than fit in the retry window
+    config.max_deliver = 100;
+    config.nak_backoff = vec![Duration::from_millis(500)];
+    config.max_retry_duration = Some(Duration::from_secs(2));
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    async fn failing_job(_job: TestJob, attempts: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "downstream unavailable",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    storage
+        .push(TestJob::new("Outage job"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("retry-duration-worker")
+        .concurrency(1)
+        .data(attempts.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let attempts = attempts.load(Ordering::SeqCst);
+    assert!(attempts > 1, "job should be retried within the window");
+
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get DLQ info");
+    assert_eq!(info.state.messages, 1, "job should be dead-lettered once");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("Failed to read DLQ entry");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "max_retry_duration_exceeded");
+    assert_eq!(entry.delivered_count as usize, attempts);
+    assert!(entry.delivered_count < config.max_deliver);
+}
+
+#[tokio::test]
+async fn test_producer_storage_pushes_but_does_not_poll() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    // Without the streams, a producer that may not create them fails up front
+    let mut verify_only = config.clone();
+    verify_only.create_streams = false;
+    let result = NatsStorage::<TestJob>::producer(client.clone(), verify_only.clone()).await;
+    assert!(
+        matches!(result, Err(NatsPollError::StreamNotFound(_))),
+        "expected StreamNotFound, got {:?}",
+        result
+    );
+
+    // Once they exist (here created by a full storage), it only checks them
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let producer = NatsStorage::<TestJob>::producer(client.clone(), verify_only)
+        .await
+        .expect("Failed to create producer");
+    producer
+        .push_with_priority(TestJob::new("Produced job"), Priority::Medium)
+        .await
+        .expect("Producer should push");
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("producer-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(producer.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(processed.load(Ordering::SeqCst), 0, "producer should not poll");
+    let jetstream = jetstream::new(client);
+    for priority in ["high", "medium", "low"] {
+        let consumers: Vec<String> = jetstream
+            .get_stream(format!("{}_{}", config.namespace, priority))
+            .await
+            .expect("Stream should exist")
+            .consumer_names()
+            .try_collect()
+            .await
+            .expect("Failed to list consumers");
+        assert!(consumers.is_empty(), "producer created consumers {:?}", consumers);
+    }
+
+    // The job is still there for a regular worker
+    let worker = WorkerBuilder::new("consumer-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage)
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_queue_latency_grows_while_jobs_wait() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let latencies = Arc::new(Mutex::new(HashMap::<String, Duration>::new()));
+
+    async fn record_latency(
+        job: TestJob,
+        ctx: NatsContext,
+        latencies: Data<Arc<Mutex<HashMap<String, Duration>>>>,
+    ) -> Result<(), Error> {
+        let latency = ctx.queue_latency().expect("jobs carry their queue latency");
+        latencies.lock().await.insert(job.message, latency);
+        Ok(())
+    }
+
+    // Sits in the stream before any worker runs
+    storage
+        .push_with_priority(TestJob::new("queued"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let worker = WorkerBuilder::new("latency-worker")
+        .concurrency(1)
+        .data(latencies.clone())
+        .backend(storage.clone())
+        .build_fn(record_latency);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Picked up by the running worker right away
+    storage
+        .push_with_priority(TestJob::new("immediate"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let latencies = latencies.lock().await;
+    let queued = latencies["queued"];
+    let immediate = latencies["immediate"];
+    assert!(queued >= Duration::from_secs(2), "queued job waited {:?}", queued);
+    assert!(
+        immediate < queued,
+        "immediate job waited {:?}, queued job {:?}",
+        immediate,
+        queued
+    );
+}
+
+#[tokio::test]
+async fn test_original_replay_policy_paces_stored_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.replay_policy = ReplayPolicy::Original;
+
+    // The planned consumers carry the policy
+    for resource in NatsStorage::<TestJob>::plan(&config).expect("Failed to plan") {
+        if let PlannedResource::Consumer { config, .. } = resource {
+            assert_eq!(config.replay_policy, consumer::ReplayPolicy::Original);
+        }
+    }
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Captured two seconds apart, before any consumer exists
+    storage
+        .push_with_priority(TestJob::new("first"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    storage
+        .push_with_priority(TestJob::new("second"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let received = Arc::new(Mutex::new(Vec::<std::time::Instant>::new()));
+
+    async fn record_job(
+        _job: TestJob,
+        received: Data<Arc<Mutex<Vec<std::time::Instant>>>>,
+    ) -> Result<(), Error> {
+        received.lock().await.push(std::time::Instant::now());
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("replay-worker")
+        .concurrency(1)
+        .data(received.clone())
+        .backend(storage.clone())
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    let info = storage
+        .consumer_info(Priority::Medium)
+        .await
+        .expect("Consumer should exist");
+    assert_eq!(info.config.replay_policy, consumer::ReplayPolicy::Original);
+
+    handle.abort();
+    let _ = handle.await;
+
+    // Instant replay would deliver both right away
+    let received = received.lock().await;
+    assert_eq!(received.len(), 2, "both jobs should be replayed");
+    let gap = received[1] - received[0];
+    assert!(
+        gap >= Duration::from_millis(1500),
+        "jobs were replayed {:?} apart",
+        gap
+    );
+}
+
+#[tokio::test]
+async fn test_storages_of_different_types_share_a_namespace() {
+    let (_container, client) = setup_nats_raw().await;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Invoice {
+        number: u64,
+    }
+
+    let mut config = test_config();
+    // Room for a few bounces between the workers
+    config.max_deliver = 10;
+
+    let messages = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let invoices = NatsStorage::<Invoice>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    assert_ne!(messages.type_tag(), invoices.type_tag());
+
+    for i in 0..3 {
+        messages
+            .push_with_priority(TestJob::new(format!("message {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+        invoices
+            .push_with_priority(Invoice { number: i }, Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let jetstream = jetstream::new(client);
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Stream should exist");
+    let first = medium.get_raw_message(1).await.expect("Job should exist");
+    assert_eq!(
+        first.headers.get(JOB_TYPE_HEADER).map(|tag| tag.as_str()),
+        Some(messages.type_tag())
+    );
+
+    let handled_messages = Arc::new(AtomicUsize::new(0));
+    let handled_invoices = Arc::new(AtomicUsize::new(0));
+
+    async fn handle_message(
+        _job: TestJob,
+        handled: Data<Arc<AtomicUsize>>,
+    ) -> Result<(), Error> {
+        handled.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn handle_invoice(
+        _job: Invoice,
+        handled: Data<Arc<AtomicUsize>>,
+    ) -> Result<(), Error> {
+        handled.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let message_worker = WorkerBuilder::new("message-worker")
+        .concurrency(1)
+        .data(handled_messages.clone())
+        .backend(messages.clone())
+        .build_fn(handle_message);
+    let invoice_worker = WorkerBuilder::new("invoice-worker")
+        .concurrency(1)
+        .data(handled_invoices.clone())
+        .backend(invoices.clone())
+        .build_fn(handle_invoice);
+    let message_handle = tokio::spawn(async move {
+        message_worker.run().await;
+    });
+    let invoice_handle = tokio::spawn(async move {
+        invoice_worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    message_handle.abort();
+    invoice_handle.abort();
+    let _ = message_handle.await;
+    let _ = invoice_handle.await;
+
+    assert_eq!(handled_messages.load(Ordering::SeqCst), 3);
+    assert_eq!(handled_invoices.load(Ordering::SeqCst), 3);
+    assert_eq!(medium.info().await.unwrap().state.messages, 0);
+
+    // Nothing was mistaken for a malformed job
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    assert_eq!(dlq.info().await.unwrap().state.messages, 0);
+}
+
+#[tokio::test]
+async fn test_foreign_job_is_dead_lettered_on_its_last_delivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Invoice {
+        number: u64,
+    }
+
+    let mut config = test_config();
+    config.max_deliver = 2;
+
+    let messages = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let mut invoices = NatsStorage::<Invoice>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    invoices
+        .push_with_priority(Invoice { number: 1 }, Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    async fn handle_message(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // No invoice worker, every delivery reaches the wrong type
+    let worker = WorkerBuilder::new("message-worker")
+        .concurrency(1)
+        .backend(messages.clone())
+        .build_fn(handle_message);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let jetstream = jetstream::new(client);
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Stream should exist");
+    assert_eq!(medium.info().await.unwrap().state.messages, 0, "job left undelivered");
+
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("The foreign job should be dead-lettered");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "foreign_type");
+    assert_eq!(entry.delivered_count, 2);
+    assert_eq!(entry.job::<Invoice>().unwrap().number, 1);
+}
+
+#[tokio::test]
+async fn test_ordered_dlq_backend_delivers_entries_in_dead_letter_order() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "boom",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    for i in 0..5 {
+        storage
+            .push_with_priority(TestJob::new(format!("triage {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("dlq-source-worker")
+        .concurrency(4)
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    // The order the entries landed in the DLQ
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let mut dead_lettered = Vec::new();
+    for sequence in 1..=5 {
+        let msg = dlq
+            .get_raw_message(sequence)
+            .await
+            .expect("DLQ should hold every job");
+        let entry: DlqEntry =
+            serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+        dead_lettered.push(entry.original_task_id);
+    }
+
+    let received = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn triage(
+        entry: DlqEntry,
+        received: Data<Arc<Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        // Slow enough that a concurrent worker would have several entries in hand
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        received.lock().await.push(entry.original_task_id);
+        Ok(())
+    }
+
+    let triage_worker = WorkerBuilder::new("dlq-triage")
+        .concurrency(4)
+        .data(received.clone())
+        .backend(storage.dlq_backend().ordered())
+        .build_fn(triage);
+    let triage_handle = tokio::spawn(async move {
+        triage_worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    let info = dlq
+        .consumer_info(format!("{}_dlq_triage_consumer", config.namespace))
+        .await
+        .expect("Triage consumer should exist");
+    assert_eq!(info.config.max_ack_pending, 1);
+    assert_eq!(info.config.deliver_policy, consumer::DeliverPolicy::All);
+    assert_eq!(info.config.replay_policy, consumer::ReplayPolicy::Instant);
+
+    triage_handle.abort();
+    let _ = triage_handle.await;
+
+    assert_eq!(*received.lock().await, dead_lettered);
+}
+
+#[tokio::test]
+async fn test_summary_counts_pending_in_flight_and_dead_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    storage
+        .push_with_priority(TestJob::new("dead"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    // No consumer yet, the stream is counted
+    let summary = storage.summary().await.expect("Failed to get summary");
+    assert_eq!(
+        summary,
+        QueueSummary {
+            pending: HashMap::from([
+                (Priority::High, 0),
+                (Priority::Medium, 1),
+                (Priority::Low, 0),
+            ]),
+            in_flight: 0,
+            dead: 0,
+        }
+    );
+
+    async fn dead_or_slow(job: TestJob) -> Result<(), Error> {
+        if job.message == "dead" {
+            return Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "boom",
+            ))
+                as Box<dyn std::error::Error + Send + Sync>)));
+        }
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("summary-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(dead_or_slow);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // Occupies the only slot, so the jobs after it stay pending
+    storage
+        .push_with_priority(TestJob::new("slow"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for i in 0..2 {
+        storage
+            .push_with_priority(TestJob::new(format!("high {}", i)), Priority::High)
+            .await
+            .expect("Failed to push job");
+    }
+    for i in 0..3 {
+        storage
+            .push_with_priority(TestJob::new(format!("low {}", i)), Priority::Low)
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let summary = storage.summary().await.expect("Failed to get summary");
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(summary.pending[&Priority::High], 2);
+    assert_eq!(summary.pending[&Priority::Medium], 0);
+    assert_eq!(summary.pending[&Priority::Low], 3);
+    assert_eq!(summary.total_pending(), 5);
+    assert_eq!(summary.in_flight, 1);
+    assert_eq!(summary.dead, 1);
+}
+
+#[tokio::test]
+async fn test_unschedulable_job_does_not_block_later_ones() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+
+    // Due first, at a priority the workers no longer have enabled
+    let stuck = scheduled
+        .schedule_with_priority(
+            TestJob::new("high"),
+            Priority::High,
+            chrono::Utc::now() - chrono::Duration::seconds(2),
+        )
+        .await
+        .expect("Failed to schedule job");
+    let behind = scheduled
+        .schedule_with_priority(
+            TestJob::new("medium"),
+            Priority::Medium,
+            chrono::Utc::now() - chrono::Duration::seconds(1),
+        )
+        .await
+        .expect("Failed to schedule job");
+
+    let mut without_high = config.clone();
+    without_high.enabled_priorities = vec![Priority::Medium, Priority::Low];
+    let worker_storage = NatsStorage::<TestJob>::new_with_config(client.clone(), without_high)
+        .await
+        .expect("Failed to create storage");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    async fn record_job(
+        _job: TestJob,
+        task_id: TaskId,
+        seen: Data<Arc<Mutex<Vec<TaskId>>>>,
+    ) -> Result<(), Error> {
+        seen.lock().await.push(task_id);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("scheduled-worker")
+        .concurrency(1)
+        .data(seen.clone())
+        .backend(worker_storage)
+        .build_fn(record_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(*seen.lock().await, vec![behind]);
+    assert!(scheduled.list_pending().await.unwrap().is_empty());
+
+    let dlq = jetstream::new(client)
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the unschedulable job");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "unschedulable");
+    assert_eq!(entry.original_task_id, stuck.to_string());
+    assert_eq!(entry.original_priority, Some(Priority::High));
+    assert_eq!(entry.job::<TestJob>().unwrap().message, "high");
+}
+
+#[tokio::test]
+async fn test_job_is_rescheduled_after_max_deliver() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 2;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+    config.on_max_deliver = OnMaxDeliver::Reschedule(Duration::from_secs(3));
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let attempts = Arc::new(Mutex::new(Vec::<std::time::Instant>::new()));
+
+    async fn failing_job(
+        _job: TestJob,
+        attempts: Data<Arc<Mutex<Vec<std::time::Instant>>>>,
+    ) -> Result<(), Error> {
+        attempts.lock().await.push(std::time::Instant::now());
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "partner API down",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("Daily sync"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("reschedule-worker")
+        .concurrency(1)
+        .data(attempts.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // Both deliveries used up, the job waits in the scheduled bucket
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(attempts.lock().await.len(), 2);
+    let scheduled = storage
+        .scheduled_jobs()
+        .await
+        .expect("Failed to open scheduled jobs");
+    let pending = scheduled.list_pending().await.expect("Failed to list jobs");
+    assert_eq!(pending.len(), 1, "job should be rescheduled");
+    assert_eq!(pending[0].task_id, task_id);
+    assert_eq!(pending[0].priority, Priority::High);
+
+    // Back after the delay, with two fresh deliveries
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let attempts = attempts.lock().await;
+    assert_eq!(attempts.len(), 4, "job should run again after the delay");
+    assert!(attempts[2] - attempts[1] >= Duration::from_secs(2));
+
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    assert_eq!(dlq.info().await.unwrap().state.messages, 0, "nothing dead-lettered");
+}
+
+#[tokio::test]
+async fn test_test_clock_expires_jobs_without_waiting() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 100;
+    config.nak_backoff = vec![Duration::from_millis(200)];
+    // Far longer than the test runs on the wall clock
+    config.max_retry_duration = Some(Duration::from_secs(3600));
+
+    let clock = TestClock::default();
+    let start = clock.now();
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_clock(clock.clone());
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    async fn failing_job(
+        _job: TestJob,
+        attempts: Data<Arc<AtomicUsize>>,
+        clock: Data<TestClock>,
+    ) -> Result<(), Error> {
+        // The second attempt runs two hours after the job was pushed
+        if attempts.fetch_add(1, Ordering::SeqCst) == 1 {
+            clock.advance(Duration::from_secs(2 * 3600));
+        }
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "downstream unavailable",
+        ))
+            as Box<dyn std::error::Error + Send + Sync>)))
+    }
+
+    storage
+        .push(TestJob::new("Expiring job"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("test-clock-worker")
+        .concurrency(1)
+        .data(attempts.clone())
+        .data(clock.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(attempts.load(Ordering::SeqCst), 2, "expired on the second attempt");
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("Failed to read DLQ entry");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "max_retry_duration_exceeded");
+    assert_eq!(entry.delivered_count, 2);
+    assert_eq!(entry.timestamp, start + chrono::Duration::hours(2));
+}
+
+#[tokio::test]
+async fn test_ack_batch_settles_batch_with_one_ack_under_ack_all() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_policy = AckPolicy::All;
+    config.manual_ack = true;
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Watch the acks workers send for the medium stream
+    let mut acks = client
+        .subscribe(format!("$JS.ACK.{}_medium.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to acks");
+
+    let pending = Arc::new(Mutex::new(Vec::<NatsContext>::new()));
+
+    // Gather the jobs and ack them together once all four arrived
+    async fn gather(
+        _job: TestJob,
+        ctx: NatsContext,
+        pending: Data<Arc<Mutex<Vec<NatsContext>>>>,
+    ) -> Result<(), Error> {
+        let mut pending = pending.lock().await;
+        pending.push(ctx);
+        if pending.len() == 4 {
+            NatsContext::ack_batch(&pending).await?;
+        }
+        Ok(())
+    }
+
+    for i in 0..4 {
+        storage
+            .push(TestJob::new(format!("batch {}", i)))
+            .await
+            .expect("Failed to push job");
+    }
+
+    let worker = WorkerBuilder::new("ack-batch-worker")
+        .concurrency(1)
+        .data(pending.clone())
+        .backend(storage.clone())
+        .build_fn(gather);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(pending.lock().await.len(), 4);
+    let mut ack_count = 0;
+    while let Ok(Some(ack)) = tokio::time::timeout(Duration::from_millis(200), acks.next()).await {
+        assert_eq!(&ack.payload[..], b"+ACK", "Only a positive ack is expected");
+        ack_count += 1;
+    }
+    assert_eq!(ack_count, 1, "The last message's ack should cover the batch");
+
+    let jetstream = jetstream::new(client);
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Medium stream should exist");
+    let info = medium
+        .consumer_info(format!("{}_medium_consumer", config.namespace))
+        .await
+        .expect("Failed to get consumer info");
+    assert_eq!(info.num_ack_pending, 0, "the whole batch should be acked");
+    assert_eq!(info.ack_floor.consumer_sequence, 4);
+    assert_eq!(medium.info().await.unwrap().state.messages, 0);
+}
+
+#[tokio::test]
+async fn test_enabled_priorities_only_creates_their_streams() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.enabled_priorities = vec![Priority::Medium];
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    assert_eq!(storage.priorities(), &[Priority::Medium]);
+
+    let jetstream = jetstream::new(client);
+    let mut streams: Vec<String> = jetstream
+        .stream_names()
+        .try_collect::<Vec<String>>()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|name| name.starts_with(&format!("{}_", config.namespace)))
+        .collect();
+    streams.sort();
+    assert_eq!(
+        streams,
+        vec![
+            format!("{}_dlq", config.namespace),
+            format!("{}_medium", config.namespace),
+        ]
+    );
+
+    assert!(matches!(
+        storage
+            .push_with_priority(TestJob::new("urgent"), Priority::High)
+            .await,
+        Err(NatsPollError::Storage(_))
+    ));
+
+    let processed = Arc::new(AtomicUsize::new(0));
+
+    async fn count_job(_job: TestJob, processed: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        processed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    storage
+        .push(TestJob::new("normal"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("medium-only-worker")
+        .concurrency(1)
+        .data(processed.clone())
+        .backend(storage.clone())
+        .build_fn(count_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(processed.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_status_replies_are_not_lost_under_load() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn noop(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    let task_id = storage
+        .push_with_priority(TestJob::new("queried"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    // The worker answers status requests while it runs
+    let worker = WorkerBuilder::new("status-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(noop);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // Subscribe-before-publish with fresh inboxes, many at once
+    let replies = futures::future::join_all((0..200).map(|_| {
+        let client = client.clone();
+        let inbox = storage.new_inbox();
+        let subject = format!("{}.status", config.namespace);
+        let payload = task_id.to_string();
+        async move {
+            let mut replies = client.subscribe(inbox.clone()).await.unwrap();
+            client
+                .publish_with_reply(subject, inbox, payload.into())
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(5), replies.next())
+                .await
+                .ok()
+                .flatten()
+        }
+    }))
+    .await;
+    assert!(
+        replies.iter().all(|reply| reply
+            .as_ref()
+            .is_some_and(|reply| &reply.payload[..] == b"\"completed\"")),
+        "every request should get its reply"
+    );
+
+    let statuses =
+        futures::future::join_all((0..200).map(|_| storage.query_status(&task_id))).await;
+    assert!(statuses
+        .into_iter()
+        .all(|status| matches!(status, Ok(JobStatus::Completed))));
+
+    let inboxes: std::collections::HashSet<String> =
+        (0..100).map(|_| storage.new_inbox()).collect();
+    assert_eq!(inboxes.len(), 100, "inboxes should be unique");
+
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[tokio::test]
+async fn test_concurrent_consumer_creation_does_not_fail() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    // Separate storages, like a fleet of processes, so none shares a consumer cache
+    let storages = futures::future::try_join_all((0..20).map(|_| {
+        NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+    }))
+    .await
+    .expect("Failed to create storages");
+
+    let results = futures::future::join_all(
+        storages.iter().map(|storage| storage.create_consumers()),
+    )
+    .await;
+    for result in results {
+        result.expect("Concurrent consumer creation should not fail");
+    }
+
+    let jetstream = jetstream::new(client);
+    for priority in ["high", "medium", "low"] {
+        let mut stream = jetstream
+            .get_stream(format!("{}_{}", config.namespace, priority))
+            .await
+            .expect("Stream should exist");
+        assert_eq!(stream.info().await.unwrap().state.consumer_count, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_job_waiting_past_its_sla_is_reported_once() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.nak_backoff = vec![Duration::from_millis(100)];
+    config.sla = HashMap::from([(Priority::High, Duration::from_secs(60))]);
+
+    let clock = TestClock::default();
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_clock(clock.clone());
+
+    let late = storage
+        .push_with_priority(TestJob::new("late"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    // Waits as long under a priority without an SLA
+    storage
+        .push_with_priority(TestJob::new("no sla"), Priority::Low)
+        .await
+        .expect("Failed to push job");
+    // Both jobs wait 90s before a worker picks them up
+    clock.advance(Duration::from_secs(90));
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    // Fails the first attempt, so the late job is redelivered
+    async fn fail_once(_job: TestJob, attempts: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "flaky",
+            ))
+                as Box<dyn std::error::Error + Send + Sync>)));
+        }
+        Ok(())
+    }
+
+    let breaches = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let seen = breaches.clone();
+    let worker = WorkerBuilder::new("sla-worker")
+        .concurrency(1)
+        .data(attempts.clone())
+        .backend(storage.clone())
+        .build_fn(fail_once)
+        .on_event(move |event| {
+            if let Event::Custom(message) = event.inner() {
+                if message.starts_with("sla_breach") {
+                    seen.lock().unwrap().push(message.clone());
+                }
+            }
+        });
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(attempts.load(Ordering::SeqCst), 3, "late job retried once, plus the low job");
+    let breaches = breaches.lock().unwrap();
+    assert_eq!(breaches.len(), 1, "the breach should be reported exactly once");
+    assert_eq!(
+        breaches[0],
+        format!("sla_breach task_id={} priority=high overage_ms=30000", late)
+    );
+}
+
+#[tokio::test]
+async fn test_push_to_targets_other_namespaces() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut configs = Vec::new();
+    let mut storages = Vec::new();
+    for _ in 0..3 {
+        let mut config = test_config();
+        config.track_status = true;
+        storages.push(
+            NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+                .await
+                .expect("Failed to create storage"),
+        );
+        configs.push(config);
+    }
+    let (pusher, billing, reports) = (&storages[0], &storages[1], &storages[2]);
+
+    let billing_job = pusher
+        .push_to(&configs[1].namespace, TestJob::new("invoice"), Priority::High)
+        .await
+        .expect("Failed to push to billing");
+    let reports_job = pusher
+        .push_to(&configs[2].namespace, TestJob::new("weekly"), Priority::Low)
+        .await
+        .expect("Failed to push to reports");
+
+    // Statuses land in the target namespaces' buckets
+    assert_eq!(billing.query_status(&billing_job).await.unwrap(), JobStatus::Pending);
+    assert_eq!(reports.query_status(&reports_job).await.unwrap(), JobStatus::Pending);
+
+    assert!(matches!(
+        pusher
+            .push_to(&test_namespace(), TestJob::new("lost"), Priority::Medium)
+            .await,
+        Err(NatsPollError::StreamNotFound(_))
+    ));
+
+    async fn record(job: TestJob, seen: Data<Arc<Mutex<Vec<String>>>>) -> Result<(), Error> {
+        seen.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let mut seen = Vec::new();
+    let mut handles = Vec::new();
+    for (storage, config) in storages.iter().zip(&configs) {
+        let jobs = Arc::new(Mutex::new(Vec::<String>::new()));
+        let worker = WorkerBuilder::new(format!("{}-worker", config.namespace))
+            .concurrency(1)
+            .data(jobs.clone())
+            .backend(storage.clone())
+            .build_fn(record);
+        handles.push(tokio::spawn(async move {
+            worker.run().await;
+        }));
+        seen.push(jobs);
+    }
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    for handle in handles {
+        handle.abort();
+        let _ = handle.await;
+    }
+
+    assert!(seen[0].lock().await.is_empty(), "nothing stays in the pushing namespace");
+    assert_eq!(*seen[1].lock().await, vec!["invoice".to_string()]);
+    assert_eq!(*seen[2].lock().await, vec!["weekly".to_string()]);
+}
+
+#[tokio::test]
+async fn test_empty_high_priority_does_not_delay_medium_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    // Every pull on the always-empty High stream waits out its expiry
+    config.poll_mode = PollMode::LongPoll;
+    config.fetch_expiry = Duration::from_millis(200);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..20 {
+        storage
+            .push_with_priority(TestJob::new(format!("medium {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let done = Arc::new(Mutex::new(Vec::<std::time::Instant>::new()));
+
+    async fn record(
+        _job: TestJob,
+        done: Data<Arc<Mutex<Vec<std::time::Instant>>>>,
+    ) -> Result<(), Error> {
+        done.lock().await.push(std::time::Instant::now());
+        Ok(())
+    }
+
+    let started = std::time::Instant::now();
+    let worker = WorkerBuilder::new("medium-backlog-worker")
+        .concurrency(1)
+        .data(done.clone())
+        .backend(storage.clone())
+        .build_fn(record);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let done = done.lock().await;
+    assert_eq!(done.len(), 20);
+    // Waiting on High before each job would take at least 20 * 200ms
+    let elapsed = done[19] - started;
+    assert!(
+        elapsed < Duration::from_millis(2500),
+        "the backlog took {:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn test_high_job_pushed_during_medium_backlog_runs_next() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.poll_mode = PollMode::LongPoll;
+    config.fetch_expiry = Duration::from_millis(200);
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..20 {
+        storage
+            .push_with_priority(TestJob::new(format!("medium {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let order = Arc::new(Mutex::new(Vec::<String>::new()));
+
+    async fn record(job: TestJob, order: Data<Arc<Mutex<Vec<String>>>>) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        order.lock().await.push(job.message);
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("medium-backlog-worker")
+        .concurrency(1)
+        .data(order.clone())
+        .backend(storage.clone())
+        .build_fn(record);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // High is known to be empty by now and skipped while Medium has jobs
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    let done_before = order.lock().await.len();
+    storage
+        .push_with_priority(TestJob::new("high"), Priority::High)
+        .await
+        .expect("Failed to push job");
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let order = order.lock().await;
+    let position = order
+        .iter()
+        .position(|message| message == "high")
+        .expect("The High job should have run");
+    // At most the job running at the push, and one fetched while it was published, run first
+    assert!(
+        position <= done_before + 2,
+        "High ran at {} after {} Medium jobs: {:?}",
+        position,
+        done_before,
+        order
+    );
+}
+
+#[tokio::test]
+async fn test_ack_guard_acks_on_drop_and_naks_on_panic() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.manual_ack = true;
+    config.max_deliver = 5;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_layers(NatsLayers::recommended());
+
+    // Watch the acks workers send for the medium stream
+    let mut acks = client
+        .subscribe(format!("$JS.ACK.{}_medium.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to acks");
+
+    let panics = Arc::new(AtomicUsize::new(0));
+
+    // Never acks by hand, the guard settles every job. Panics on its first run only.
+    async fn guarded(
+        job: TestJob,
+        ctx: NatsContext,
+        panics: Data<Arc<AtomicUsize>>,
+    ) -> Result<(), Error> {
+        let _guard = ctx.auto_ack_on_success();
+        if job.message == "panic" && panics.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("handler blew up");
+        }
+        Ok(())
+    }
+
+    let ok_id = storage
+        .push_with_priority(TestJob::new("ok"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+    let panic_id = storage
+        .push_with_priority(TestJob::new("panic"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("ack-guard-worker")
+        .concurrency(1)
+        .data(panics.clone())
+        .backend(storage.clone())
+        .build_fn(guarded);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // Well within ack_wait, so nothing here is a redelivery after a missing ack
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let mut payloads = Vec::new();
+    while let Ok(Some(ack)) = tokio::time::timeout(Duration::from_millis(200), acks.next()).await {
+        payloads.push(String::from_utf8_lossy(&ack.payload).to_string());
+    }
+    let naks = payloads.iter().filter(|p| p.starts_with("-NAK")).count();
+    let positive = payloads.iter().filter(|p| *p == "+ACK").count();
+    assert_eq!(naks, 1, "The panicking attempt should be Naked: {:?}", payloads);
+    assert_eq!(positive, 2, "Both jobs should end up acked: {:?}", payloads);
+
+    assert_eq!(panics.load(Ordering::SeqCst), 2, "The Naked job should be retried once");
+    assert_eq!(storage.query_status(&ok_id).await.unwrap(), JobStatus::Completed);
+    assert_eq!(storage.query_status(&panic_id).await.unwrap(), JobStatus::Completed);
+}
+
+#[tokio::test]
+async fn test_job_state_survives_retry_and_is_removed_on_success() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Steps {
+        done: Vec<usize>,
+    }
+
+    let runs = Arc::new(Mutex::new(Vec::<Vec<usize>>::new()));
+
+    // Four steps, the first attempt fails after the second one
+    async fn stepwise(
+        _job: TestJob,
+        ctx: NatsContext,
+        runs: Data<Arc<Mutex<Vec<Vec<usize>>>>>,
+    ) -> Result<(), Error> {
+        let mut steps: Steps = ctx.state_get().await?.unwrap_or_default();
+        runs.lock().await.push(steps.done.clone());
+        let first_attempt = steps.done.is_empty();
+        for step in steps.done.len()..4 {
+            if first_attempt && step == 2 {
+                return Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "step 2 failed",
+                )))));
+            }
+            steps.done.push(step);
+            ctx.state_put(&steps).await?;
+        }
+        Ok(())
+    }
+
+    let task_id = storage
+        .push(TestJob::new("stepwise"))
+        .await
+        .expect("Failed to push job");
+
+    let worker = WorkerBuilder::new("state-worker")
+        .concurrency(1)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(stepwise);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    // The retry resumed after the two steps the first attempt finished
+    assert_eq!(*runs.lock().await, vec![vec![], vec![0, 1]]);
+    assert_eq!(storage.query_status(&task_id).await.unwrap(), JobStatus::Completed);
+
+    // And its state went away with the job
+    let state = jetstream::new(client)
+        .get_key_value(format!("{}_state", config.namespace))
+        .await
+        .expect("State bucket should exist");
+    let entry = state
+        .get(task_id.to_string())
+        .await
+        .expect("Failed to read state");
+    assert!(entry.is_none(), "State should be removed once the job succeeds");
+}
+
+#[tokio::test]
+async fn test_job_state_is_removed_when_the_succeeding_attempt_ignores_it() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    async fn ignore_state(_job: TestJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    let task_id = storage
+        .push(TestJob::new("state from an earlier attempt"))
+        .await
+        .expect("Failed to push job");
+
+    // Left by an attempt on another worker, which this one never looked at
+    let state = jetstream::new(client)
+        .create_key_value(jetstream::kv::Config {
+            bucket: format!("{}_state", config.namespace),
+            history: 1,
+            ..Default::default()
+        })
+        .await
+        .expect("Failed to create state bucket");
+    state
+        .put(task_id.to_string(), r#"{"done":[0,1]}"#.into())
+        .await
+        .expect("Failed to store state");
+
+    let worker = WorkerBuilder::new("state-ignoring-worker")
+        .concurrency(1)
+        .backend(storage.clone())
+        .build_fn(ignore_state);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let entry = state
+        .get(task_id.to_string())
+        .await
+        .expect("Failed to read state");
+    assert!(entry.is_none(), "State should be removed once the job succeeds");
+}
+
+#[tokio::test]
+async fn test_zero_duplicate_window_disables_dedup() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+    config.duplicate_window = Duration::ZERO;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let first = storage
+        .push_with_id(TestJob::new("invoice 42"), Priority::High, "invoice-42")
+        .await
+        .expect("Failed to push job");
+    // The shortest window JetStream accepts is already over
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let second = storage
+        .push_with_id(TestJob::new("invoice 42"), Priority::High, "invoice-42")
+        .await
+        .expect("Failed to push job");
+    assert!(!first.duplicate);
+    assert!(!second.duplicate, "The repeated id should not be deduplicated");
+    assert_eq!(second.sequence, first.sequence + 1);
+
+    let jetstream = jetstream::new(client);
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let info = high.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2, "Both pushes should be stored");
+    // Not the server's 2 minute default for a zero window
+    assert!(info.config.duplicate_window < Duration::from_secs(1));
+
+    for task_id in [&first.task_id, &second.task_id] {
+        assert_eq!(storage.query_status(task_id).await.unwrap(), JobStatus::Pending);
+    }
+}
+
+#[tokio::test]
+async fn test_push_durable_awaits_the_pub_ack() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.track_status = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let first = storage
+        .push_durable(TestJob::new("payout 42"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    let second = storage
+        .push_durable(TestJob::new("payout 43"), Priority::High)
+        .await
+        .expect("Failed to push job");
+    assert_eq!((first.sequence, second.sequence), (1, 2));
+    assert!(!first.duplicate && !second.duplicate);
+
+    // Both are stored by the time the pushes return
+    let jetstream = jetstream::new(client.clone());
+    let mut high = jetstream
+        .get_stream(format!("{}_high", config.namespace))
+        .await
+        .expect("High stream should exist");
+    let info = high.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2);
+    assert_eq!(
+        storage.query_status(&first.task_id).await.unwrap(),
+        JobStatus::Pending
+    );
+
+    // A storage expecting more replicas than the existing stream has refuses to push
+    let mut replicated = config.clone();
+    replicated.num_replicas = 3;
+    let replicated = NatsStorage::<TestJob>::new_with_config(client.clone(), replicated)
+        .await
+        .expect("Existing streams are used as they are");
+    let err = replicated
+        .push_durable(TestJob::new("payout 44"), Priority::High)
+        .await
+        .expect_err("The stream has a single replica");
+    assert!(matches!(err, NatsPollError::Storage(_)), "{:?}", err);
+    let info = high.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 2, "Nothing should be published");
+}
+
+#[tokio::test]
+async fn test_failing_job_type_is_quarantined_while_others_flow() {
+    let (_container, client) = setup_nats_raw().await;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Invoice {
+        number: u64,
+    }
+
+    let mut config = test_config();
+    config.max_deliver = 10;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+    config.quarantine = Some(Quarantine::new(
+        5,
+        0.8,
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+    ));
+
+    let messages = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    let invoices = NatsStorage::<Invoice>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..10 {
+        messages
+            .push_with_priority(TestJob::new(format!("message {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+        invoices
+            .push_with_priority(Invoice { number: i }, Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    let failed_runs = Arc::new(AtomicUsize::new(0));
+    let handled_invoices = Arc::new(AtomicUsize::new(0));
+
+    // A bad deploy: every message fails
+    async fn handle_message(_job: TestJob, runs: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "broken",
+        )))))
+    }
+
+    async fn handle_invoice(_job: Invoice, handled: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        handled.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    let quarantines = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let seen = quarantines.clone();
+    let message_worker = WorkerBuilder::new("message-worker")
+        .concurrency(1)
+        .data(failed_runs.clone())
+        .backend(messages.clone())
+        .build_fn(handle_message)
+        .on_event(move |event| {
+            if let Event::Custom(message) = event.inner() {
+                if message.starts_with("quarantine") {
+                    seen.lock().unwrap().push(message.clone());
+                }
+            }
+        });
+    let invoice_worker = WorkerBuilder::new("invoice-worker")
+        .concurrency(1)
+        .data(handled_invoices.clone())
+        .backend(invoices.clone())
+        .build_fn(handle_invoice);
+    let message_handle = tokio::spawn(async move {
+        message_worker.run().await;
+    });
+    let invoice_handle = tokio::spawn(async move {
+        invoice_worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // The messages stopped running after the threshold, at most one was already fetched
+    let runs = failed_runs.load(Ordering::SeqCst);
+    assert!((5..=6).contains(&runs), "messages ran {} times", runs);
+    assert_eq!(quarantines.lock().unwrap().len(), 1);
+    assert!(quarantines.lock().unwrap()[0].starts_with("quarantine job_type="));
+
+    // Invoices keep flowing during the quarantine
+    for i in 10..15 {
+        invoices
+            .push_with_priority(Invoice { number: i }, Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    message_handle.abort();
+    invoice_handle.abort();
+    let _ = message_handle.await;
+    let _ = invoice_handle.await;
+
+    assert_eq!(handled_invoices.load(Ordering::SeqCst), 15);
+    assert_eq!(failed_runs.load(Ordering::SeqCst), runs, "quarantined jobs should not run");
+
+    // Nothing went to the DLQ
+    let jetstream = jetstream::new(client);
+    let mut dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let info = dlq.info().await.expect("Failed to get stream info");
+    assert_eq!(info.state.messages, 0);
+}
+
+#[tokio::test]
+async fn test_quarantined_job_is_dead_lettered_on_its_last_delivery() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 2;
+    config.nak_backoff = vec![Duration::from_millis(100)];
+    // The first failure quarantines the type while its job still has a retry left
+    config.quarantine = Some(Quarantine::new(
+        1,
+        1.0,
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+    ));
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+    storage
+        .push_with_priority(TestJob::new("doomed"), Priority::Medium)
+        .await
+        .expect("Failed to push job");
+
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    async fn failing_job(_job: TestJob, runs: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "broken",
+        )))))
+    }
+
+    let worker = WorkerBuilder::new("quarantined-worker")
+        .concurrency(1)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(runs.load(Ordering::SeqCst), 1, "the retry should be held, not run");
+
+    let jetstream = jetstream::new(client);
+    let mut medium = jetstream
+        .get_stream(format!("{}_medium", config.namespace))
+        .await
+        .expect("Stream should exist");
+    assert_eq!(medium.info().await.unwrap().state.messages, 0, "job left undelivered");
+
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("The held job should be dead-lettered");
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "quarantined");
+    assert_eq!(entry.delivered_count, 2);
+}
+
+#[tokio::test]
+async fn test_quarantine_ends_by_the_storage_clock() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_deliver = 10;
+    // Failed jobs don't come back while the test runs
+    config.nak_backoff = vec![Duration::from_secs(3600)];
+    config.quarantine = Some(Quarantine::new(
+        2,
+        1.0,
+        Duration::from_secs(60),
+        Duration::from_secs(3600),
+    ));
+
+    let clock = TestClock::default();
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage")
+        .with_clock(clock.clone());
+
+    let runs = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+
+    async fn failing_job(
+        job: TestJob,
+        runs: Data<Arc<std::sync::Mutex<Vec<String>>>>,
+    ) -> Result<(), Error> {
+        runs.lock().unwrap().push(job.message);
+        Err(Error::Failed(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "broken",
+        )))))
+    }
+
+    let worker = WorkerBuilder::new("quarantine-clock-worker")
+        .concurrency(1)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(failing_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    for name in ["first", "second"] {
+        storage
+            .push(TestJob::new(name))
+            .await
+            .expect("Failed to push job");
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(runs.lock().unwrap().len(), 2, "both jobs should fail");
+
+    // Quarantined: held back without running
+    storage
+        .push(TestJob::new("held"))
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(runs.lock().unwrap().len(), 2, "the type should be quarantined");
+
+    // An hour later on the storage's clock the quarantine is over, with no real wait
+    clock.advance(Duration::from_secs(2 * 3600));
+    storage
+        .push(TestJob::new("after"))
+        .await
+        .expect("Failed to push job");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(runs.lock().unwrap().as_slice(), ["first", "second", "after"]);
+}
+
+#[tokio::test]
+async fn test_keep_alive_extends_leases_from_one_task() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.ack_wait = Duration::from_secs(3);
+    config.keep_alive = true;
+
+    let storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    let mut acks = client
+        .subscribe(format!("$JS.ACK.{}_medium.>", config.namespace))
+        .await
+        .expect("Failed to subscribe to acks");
+
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    // Runs for more than twice ack_wait, without any heartbeat of its own
+    async fn long_job(_job: TestJob, runs: Data<Arc<AtomicUsize>>) -> Result<(), Error> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        Ok(())
+    }
+
+    let worker = WorkerBuilder::new("keep-alive-worker")
+        .concurrency(20)
+        .data(runs.clone())
+        .backend(storage.clone())
+        .build_fn(long_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    // The worker's own tasks are up before any job arrives
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let idle_tasks = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+
+    for i in 0..20 {
+        storage
+            .push_with_priority(TestJob::new(format!("long {}", i)), Priority::Medium)
+            .await
+            .expect("Failed to push job");
+    }
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    let busy_tasks = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+    // At most a task per running job, a heartbeat task per job would double that
+    assert!(
+        busy_tasks <= idle_tasks + 20 + 2,
+        "{} tasks while idle, {} with 20 jobs running",
+        idle_tasks,
+        busy_tasks
+    );
+
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    assert_eq!(runs.load(Ordering::SeqCst), 20, "No job should be redelivered");
+
+    let mut progress = 0;
+    let mut completed = 0;
+    while let Ok(Some(ack)) = tokio::time::timeout(Duration::from_millis(200), acks.next()).await {
+        match &ack.payload[..] {
+            b"+WPI" => progress += 1,
+            b"+ACK" => completed += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(completed, 20);
+    assert!(progress >= 20, "every job should have its lease extended, got {}", progress);
+}
+
+#[tokio::test]
+async fn test_dlq_entry_has_numeric_attempts_and_error_detail() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    storage
+        .push(TestJob::new("Aborting job"))
+        .await
+        .expect("Failed to push job");
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "disk on fire",
+        )))))
+    }
+
+    let worker = WorkerBuilder::new("dlq-detail-worker")
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("DLQ should contain the aborted job");
+
+    let raw: serde_json::Value =
+        serde_json::from_slice(&msg.payload).expect("DLQ entry should be JSON");
+    assert_eq!(raw["attempts"], serde_json::json!(1), "attempts should be a number: {}", raw);
+    assert_eq!(raw["error_detail"]["kind"], "abort", "{}", raw);
+
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.attempts, 1);
+    assert_eq!(
+        entry.error_detail,
+        Some(DlqError {
+            kind: "abort".to_string(),
+            sources: vec!["disk on fire".to_string()],
+        })
+    );
+
+    // Entries written before attempts became a number still read back
+    let mut legacy = raw;
+    legacy["attempts"] = serde_json::json!("Attempt(5)");
+    legacy.as_object_mut().unwrap().remove("error_detail");
+    let entry: DlqEntry = serde_json::from_value(legacy).expect("Failed to parse legacy entry");
+    assert_eq!(entry.attempts, 5);
+    assert!(entry.error_detail.is_none());
+}
+
+#[tokio::test]
+async fn test_oversized_dlq_entry_is_truncated_instead_of_lost() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let mut config = test_config();
+    config.max_msg_size = Some(2048);
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client.clone(), config.clone())
+        .await
+        .expect("Failed to create storage");
+
+    // Fits the limit as a job, but not as the byte array a DLQ entry embeds it in
+    storage
+        .push(TestJob::new("x".repeat(1500)))
+        .await
+        .expect("Failed to push job");
+
+    async fn abort_job(_job: TestJob) -> Result<(), Error> {
+        Err(Error::Abort(Arc::new(Box::new(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "too big to fail",
+        )))))
+    }
+
+    let worker = WorkerBuilder::new("dlq-oversized-worker")
+        .backend(storage.clone())
+        .build_fn(abort_job);
+    let handle = tokio::spawn(async move {
+        worker.run().await;
+    });
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    handle.abort();
+    let _ = handle.await;
+
+    let jetstream = jetstream::new(client);
+    let dlq = jetstream
+        .get_stream(format!("{}_dlq", config.namespace))
+        .await
+        .expect("DLQ stream should exist");
+    let msg = dlq
+        .get_raw_message(1)
+        .await
+        .expect("The oversized job should be dead-lettered");
+    assert!(msg.payload.len() <= 2048, "entry of {} bytes", msg.payload.len());
+
+    let entry: DlqEntry = serde_json::from_slice(&msg.payload).expect("Failed to parse DLQ entry");
+    assert_eq!(entry.dlq_reason, "abort_error");
+    assert!(entry.error.contains("too big to fail"), "{}", entry.error);
+    let size = entry.payload_truncated.expect("The payload should be marked as truncated");
+    assert!(size > 1500, "original size: {}", size);
+    assert!(!entry.payload.is_empty() && entry.payload.len() < size);
+    assert!(entry.job::<TestJob>().is_err());
+
+    // The original was acked only once the entry was stored
+    assert_eq!(storage.len().await.expect("Failed to get length"), 0);
+}
+
+#[tokio::test]
+async fn test_active_heartbeats_follow_in_flight_jobs() {
+    let (_container, client) = setup_nats_raw().await;
+
+    let config = test_config();
+
+    let mut storage = NatsStorage::<TestJob>::new_with_config(client, config)
+        .await
+        .expect("Failed to create storage");
+
+    for i in 0..3 {
+        storage
+            .push(TestJob::new(format!("Long job {}", i)))
+            .await
+            .expect("Failed to push job");
+    }
+
+    async fn long_job(_job: TestJob) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        Ok(())
+    }
+
+    // A cap below the jobs in flight only warns, every job keeps its heartbeat
+    let heartbeats = ProgressHeartbeatLayer::new(Duration::from_millis(500)).max_active(2);
+    assert_eq!(heartbeats.active_heartbeats(), 0);
+
+    let worker = WorkerBuilder::new("active-heartbeats-worker")
+        .concurrency(4)
+        .layer(heartbeats.clone())
+        .backend(storage.clone())
+        .build_fn(long_job);
+    let handle = tokio::spawn(async move { worker.run().await });
+
+    let mut peak = 0;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(8);
+    while tokio::time::Instant::now() < deadline {
+        peak = peak.max(heartbeats.active_heartbeats());
+        if peak == 3 && heartbeats.active_heartbeats() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    handle.abort();
+
+    assert_eq!(peak, 3, "every running job should have a heartbeat");
+    assert_eq!(heartbeats.active_heartbeats(), 0, "heartbeats should stop with their jobs");
+}